@@ -1,17 +1,17 @@
 use crate::program::AquaDex;
 #[cfg(not(feature = "no-entrypoint"))]
 use solana_security_txt::security_txt;
-use std::{ io::Cursor, string::String, result::Result as FnResult, mem::size_of, convert::TryFrom };
+use std::{ io::Cursor, string::String, result::Result as FnResult, mem::{ size_of, align_of }, convert::TryFrom };
 use bytemuck::{ Pod, Zeroable, cast_slice_mut, cast_slice };
 use num_enum::{ TryFromPrimitive, IntoPrimitive };
 use arrayref::{ mut_array_refs, array_refs };
 use byte_slice_cast::{ AsByteSlice };
 use anchor_lang::prelude::*;
-use anchor_spl::token::{ self, Token, Transfer as SPL_Transfer, TokenAccount as SPL_TokenAccount };
+use anchor_spl::token::{ self, Token, Transfer as SPL_Transfer, CloseAccount as SPL_CloseAccount, TokenAccount as SPL_TokenAccount, Mint as SPL_Mint };
 use anchor_spl::associated_token::{ self, AssociatedToken };
 use solana_program::{
     sysvar, system_program,
-    program::{ invoke }, clock::Clock,
+    program::{ invoke, invoke_signed }, clock::Clock,
     account_info::AccountInfo,
     instruction::{ AccountMeta, Instruction }
 };
@@ -20,21 +20,52 @@ extern crate slab_alloc;
 use slab_alloc::{ SlabPageAlloc, CritMapHeader, CritMap, AnyNode, LeafNode, SlabVec, SlabTreeError };
 
 extern crate security_token;
-use security_token::{ SecurityTokenAccount as AST_TokenAccount, cpi::accounts::{ Transfer as AST_Transfer, CreateAccount as AST_CreateAccount } };
+use security_token::{ SecurityTokenAccount as AST_TokenAccount, SecurityTokenMint as AST_Mint, cpi::accounts::{ Transfer as AST_Transfer, CreateAccount as AST_CreateAccount } };
 
 declare_id!("AQUAvuZCFUGtSc8uQBaTXfJz3YjMUbinMeXDoDQmZLvX");
 
 pub const VERSION_MAJOR: u32 = 1;
 pub const VERSION_MINOR: u32 = 0;
 pub const VERSION_PATCH: u32 = 0;
+pub const MARKET_LAYOUT_VERSION: u8 = 1;    // Account layout version of "Market" and "MarketState" (0 is the original layout, migrated by "migrate_market")
+pub const ORDERBOOK_VERSION: u8 = 1;        // Order layout version of the orderbook (0 is the original 16 byte layout, migrated by "resize_orderbook")
+pub const STATE_SNAPSHOT_VERSION: u16 = 1;   // Format version of "StateSnapshot" (incremented when its fields change)
 
 // TESTING
-pub const MAX_ORDERS: u32 = 500;        // Max orders on each side of the orderbook
-pub const MAX_TRADES: u32 = 100;        // Max trade entries in the trade log
-pub const MAX_ACCOUNTS: u32 = 1000;     // Max number of accounts per settlement data file
-pub const MAX_EVICTIONS: u32 = 10;      // Max number of orders to evict before aborting
 pub const MAX_EXPIRATIONS: u32 = 10;    // Max number of expired orders to remove before proceeding with current order
+pub const MAX_EXPIRE_BUDGET: u32 = 100; // Max "expire_budget" an order may request (at most MAX_MATCH_SKIP expired orders are found while matching)
+pub const MAX_MATCH_SKIP: u32 = 100;    // Max number of expired or same-user orders skipped while matching an order (fails with TooManySkippedOrders)
 pub const MAX_RBAC: u32 = 100;          // Max number of RBAC entries
+pub const MAX_BATCH_CANCEL: u32 = 16;   // Max number of orders to cancel with "batch_cancel"
+pub const MAX_DEPTH: u8 = 25;           // Max number of price levels per side returned by "get_orderbook_depth"
+pub const MAX_FILLS: u32 = 16;          // Max number of fills recorded in the fill details result
+pub const MAX_FEE_TIERS: usize = 4;     // Max number of taker fee tiers per market
+pub const MAX_DECIMALS: u8 = 18;        // Max token decimals (the decimal factor must fit in a u64)
+pub const MAX_DUST_THRESHOLD: u64 = 1000;   // Max "dust_threshold" of a market in raw tokens (limits what "sweep_dust" can remove)
+pub const MAX_BATCH_LOGS: u8 = 4;       // Max number of settlement logs to withdraw from with "batch_withdraw"
+pub const MAX_LIST_LOGS: u8 = 8;        // Max number of settlement logs returned by "list_settlement_logs" (fits in the return data)
+pub const MAX_BULK_QUOTES: u8 = 8;      // Max number of quantities quoted by "bulk_quote"
+pub const MAX_CLIENT_ORDERS: usize = 16;    // Number of recent client order ids kept for each owner to reject duplicate orders
+pub const SECONDS_PER_DAY: i64 = 86400; // Trading session times are seconds of the UTC day
+pub const LEGACY_MAX_ORDERS: u32 = 500;     // Orderbook capacity of markets created with the original layout (set by "migrate_market")
+pub const LEGACY_MAX_ACCOUNTS: u32 = 1000;  // Settlement log capacity of markets created with the original layout
+pub const LEGACY_MAX_EVICTIONS: u32 = 10;   // Max evictions per order of markets created with the original layout
+pub const MARKET_V0_LEN: usize = 8 + 377;                               // Discriminator + Market (original layout)
+pub const MARKET_LEN: usize = MARKET_V0_LEN + 390;                      // Discriminator + Market (with the fields appended by layout version 1)
+pub const MARKET_STATE_V0_LEN: usize = 8 + 185;                         // Discriminator + MarketState (original layout)
+pub const MARKET_STATE_LEN: usize = MARKET_STATE_V0_LEN + 81;           // Discriminator + MarketState (with the fields appended by layout version 1)
+pub const TRADE_RESULT_LEN: usize = 8 + 48;                             // Discriminator + TradeResult
+pub const WITHDRAW_RESULT_LEN: usize = 8 + 16;                          // Discriminator + WithdrawResult
+pub const USER_VAULT_LEN: usize = 8 + 1 + 32 + 32 + 8 + 8;              // Discriminator + UserVault
+pub const USER_COOLDOWN_LEN: usize = 8 + 32 + 32 + 8;                   // Discriminator + UserCooldown
+pub const USER_CLIENT_ORDERS_LEN: usize = 8 + 32 + 32 + (MAX_CLIENT_ORDERS * 16) + 1;   // Discriminator + UserClientOrders
+pub const FILLS_RESULT_LEN: usize = 8 + 4 + (MAX_FILLS as usize * 56) + 1;  // Discriminator + FillsResult (with MAX_FILLS entries)
+
+// Slab page allocator layout (see slab-alloc)
+pub const SLAB_PAGE_SIZE: usize = 16384;    // Bytes per page
+pub const SLAB_PAGE_MAX: usize = 8;         // Max pages per slab
+pub const SLAB_TYPE_PAGES: usize = 4;       // Max pages per data type
+pub const SLAB_TABLE_SIZE: usize = 130;     // Page table header size
 
 #[repr(u8)]
 #[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
@@ -43,6 +74,22 @@ pub enum Side {
     Ask = 1,
 }
 
+#[repr(u8)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
+pub enum DustPolicy {
+    Keep = 0,                   // Partially filled orders stay in the orderbook at any remaining quantity
+    CancelRemainder = 1,        // A partially filled order left below "min_quantity" is removed and the remainder returned to its owner
+}
+
+#[repr(u8)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
+pub enum OrderMode {
+    Limit = 0,                  // Fill what is possible, then post the remainder to the orderbook
+    PostOnly = 1,               // Post the order to the orderbook, fail if it would be filled immediately
+    ImmediateOrCancel = 2,      // Fill what is possible, then cancel the remainder
+    FillOrKill = 3,             // Fill the entire order immediately, or fail
+}
+
 #[repr(u8)]
 #[derive(PartialEq, Debug, Eq, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
 pub enum MintType {
@@ -61,6 +108,10 @@ pub enum DT { // All data types
     Account,
     UserRBACMap,                 // CritMap
     UserRBAC,                    // Slabvec
+    BidStopMap,
+    BidStop,
+    AskStopMap,
+    AskStop,
 }
 
 #[repr(u32)]
@@ -130,6 +181,15 @@ pub enum OrderDT {          // Orders data types
     AskOrder,               // SlabVec - ask order details
 }
 
+#[repr(u16)]
+#[derive(PartialEq, Debug, Eq, Copy, Clone, IntoPrimitive)]
+pub enum StopDT {           // Stop orders data types
+    BidStopMap,             // CritMap - buy stops (triggered when the last price rises to the trigger price)
+    AskStopMap,             // CritMap - sell stops (triggered when the last price falls to the trigger price)
+    BidStop,                // SlabVec - buy stop details
+    AskStop,                // SlabVec - sell stop details
+}
+
 #[repr(u16)]
 #[derive(PartialEq, Debug, Eq, Copy, Clone, IntoPrimitive)]
 pub enum SettleDT {         // Account settlement data types
@@ -142,6 +202,10 @@ pub enum SettleDT {         // Account settlement data types
 pub struct Order {
     pub amount: u64,
     pub expiry: i64,
+    pub display_qty: u64,       // Iceberg order slice size (0 for regular orders)
+    pub hidden_qty: u64,        // Iceberg order quantity not yet displayed
+    pub placed_ts: i64,         // Placement timestamp
+    pub expire_to_market: u8,   // 1 - Execute as a market order with "expire_to_market" after expiration (otherwise settled to the owner)
 }
 unsafe impl Zeroable for Order {}
 unsafe impl Pod for Order {}
@@ -155,6 +219,15 @@ impl Order {
         self.amount = new_amount
     }
 
+    pub fn total_amount(&self) -> u64 {
+        // Displayed and hidden quantity (held in the vault)
+        self.amount + self.hidden_qty
+    }
+
+    pub fn expires_to_market(&self) -> bool {
+        self.expire_to_market != 0
+    }
+
     // Critbit Tree key functions
     pub fn new_key(state: &mut MarketState, side: Side, price: u64) -> u128 {
         let seq = state.order_counter;
@@ -193,6 +266,144 @@ impl Order {
     }
 }
 
+// Resting orders filled while previewing an order (the orderbook is not updated in preview mode)
+pub struct PreviewBook {
+    pub orders: Vec<(u128, u64, u64)>,  // Order ID, displayed quantity, hidden quantity after the preview fills
+}
+
+impl PreviewBook {
+    pub fn new() -> Self {
+        PreviewBook { orders: Vec::new() }
+    }
+
+    pub fn amount(&self, key: u128, order: &Order) -> u64 {
+        match self.orders.iter().find(|entry| entry.0 == key) {
+            Some(entry) => entry.1,
+            None => order.amount(),
+        }
+    }
+
+    pub fn filled(&self, key: u128) -> bool {
+        self.orders.iter().any(|entry| entry.0 == key && entry.1 == 0)
+    }
+
+    pub fn fill(&mut self, key: u128, order: &Order) {
+        // Fill the displayed quantity, and replenish an iceberg order from its hidden quantity like "remove_filled_order"
+        // (the replenished slice keeps its place in the preview, so fills at the same price may be split between makers differently)
+        let hidden_qty = match self.orders.iter().find(|entry| entry.0 == key) {
+            Some(entry) => entry.2,
+            None => order.hidden_qty,
+        };
+        let display_amount = if order.display_qty < hidden_qty { order.display_qty } else { hidden_qty };
+        let entry = (key, display_amount, hidden_qty - display_amount);
+        match self.orders.iter_mut().find(|entry| entry.0 == key) {
+            Some(prev) => *prev = entry,
+            None => self.orders.push(entry),
+        }
+    }
+}
+
+// Orders skipped while matching an order (expired or same-user orders), bounds the compute used by a book full of them
+pub struct MatchSkip {
+    pub count: u32,                 // Orders skipped
+    pub resume_key: Option<u128>,   // Key of the last order skipped
+    pub descending: bool,           // Bids are searched from the highest key
+}
+
+impl MatchSkip {
+    pub fn new(order_type: OrderDT) -> Self {
+        MatchSkip { count: 0, resume_key: None, descending: order_type == OrderDT::BidOrder }
+    }
+
+    pub fn limit_reached(&self) -> bool {
+        self.count >= MAX_MATCH_SKIP
+    }
+
+    pub fn check<F: FnOnce() -> bool>(&mut self, key: u128, valid: F) -> bool {
+        // Predicate for the orderbook search, each search starts again from the best price
+        // Orders up to the last skipped order were already skipped by an earlier search, so they are passed over without being counted again
+        if self.limit_reached() {
+            return true;
+        }
+        if let Some(resume_key) = self.resume_key {
+            if (self.descending && key >= resume_key) || (!self.descending && key <= resume_key) {
+                return false;
+            }
+        }
+        let valid = valid();
+        if !valid {
+            self.count = self.count + 1;
+            self.resume_key = Some(key);
+        }
+        valid
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct LegacyOrder {        // Original order layout (orderbook version 0, read by "resize_orderbook" to migrate an orderbook)
+    pub amount: u64,
+    pub expiry: i64,
+}
+unsafe impl Zeroable for LegacyOrder {}
+unsafe impl Pod for LegacyOrder {}
+
+#[derive(Copy, Clone)]
+#[repr(packed)]
+pub struct StopOrder {
+    pub amount: u64,        // Order quantity
+    pub price: u64,         // Limit price
+    pub deposit: u64,       // Tokens deposited (bids include the taker fee)
+    pub expiry: i64,        // Expiration timestamp (0 for none)
+    pub order_mode: u8,     // Order mode when triggered (Limit or ImmediateOrCancel)
+}
+unsafe impl Zeroable for StopOrder {}
+unsafe impl Pod for StopOrder {}
+
+impl StopOrder {
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    pub fn set_amount(&mut self, new_amount: u64) {
+        self.amount = new_amount
+    }
+
+    // Critbit Tree key functions (the trigger price is in the upper 64 bits, use Order::price to read it)
+    pub fn new_key(state: &mut MarketState, side: Side, trigger_price: u64) -> u128 {
+        let seq = state.order_counter;
+        state.order_counter = state.order_counter + 1;
+        let upper = (trigger_price as u128) << 64;
+        // Buy stops trigger lowest price first (map_min), sell stops trigger highest price first (map_max)
+        let lower = match side {
+            Side::Bid => seq,
+            Side::Ask => !seq,
+        };
+        upper | (lower as u128)
+    }
+
+    fn next_index(pt: &mut SlabPageAlloc, data_type: DT) -> FnResult<u32, ProgramError> {
+        let svec = pt.header_mut::<SlabVec>(index_datatype(data_type));
+        let free_top = svec.free_top();
+        if free_top == 0 { // Empty free list
+            return Ok(svec.next_index());
+        }
+        let free_index = free_top.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+        let index_act = pt.index::<StopOrder>(index_datatype(data_type), free_index as usize);
+        let index_ptr = u32::try_from(index_act.amount()).expect("Invalid index");
+        pt.header_mut::<SlabVec>(index_datatype(data_type)).set_free_top(index_ptr);
+        Ok(free_index)
+    }
+
+    fn free_index(pt: &mut SlabPageAlloc, data_type: DT, idx: u32) -> anchor_lang::Result<()> {
+        let free_top = pt.header::<SlabVec>(index_datatype(data_type)).free_top();
+        pt.index_mut::<StopOrder>(index_datatype(data_type), idx as usize).set_amount(free_top as u64);
+        let new_top = idx.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        pt.header_mut::<SlabVec>(index_datatype(data_type)).set_free_top(new_top);
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(packed)]
 pub struct AccountsHeader {
@@ -305,22 +516,49 @@ fn full_account_zero(account: &AccountInfo) -> bool {
     prefix.iter().all(|&x| x == 0) && suffix.iter().all(|&x| x == 0) && aligned.iter().all(|&x| x == 0)
 }
 
+fn close_data_account(account: &AccountInfo, receiver: &AccountInfo) -> anchor_lang::Result<u64> {
+    // Move all lamports to the receiver and clear the account data
+    let account_lamports = account.lamports();
+    let receiver_lamports = receiver.lamports().checked_add(account_lamports).ok_or(error!(ErrorCode::Overflow))?;
+    **receiver.lamports.borrow_mut() = receiver_lamports;
+    **account.lamports.borrow_mut() = 0;
+    account.try_borrow_mut_data()?.fill(0);
+    Ok(account_lamports)
+}
+
+fn slab_pages<H, T>(items: usize) -> usize {
+    // Matches SlabPageAlloc::allocate, allowing for the worst case header alignment offset
+    let items_per_page = (SLAB_PAGE_SIZE - (align_of::<H>() + size_of::<H>())) / size_of::<T>();
+    items.div_ceil(items_per_page)
+}
+
+fn settle_log_len(max_accounts: u32) -> Option<usize> {
+    // A settlement log needs a CritMap and a SlabVec sized for the market's "max_accounts" after the log header (None if over the page limits)
+    let map_pages = slab_pages::<CritMapHeader, AnyNode>(max_accounts as usize);
+    let vec_pages = slab_pages::<SlabVec, AccountEntry>(max_accounts as usize);
+    let log_pages = map_pages + vec_pages;
+    if map_pages > SLAB_TYPE_PAGES || vec_pages > SLAB_TYPE_PAGES || log_pages > SLAB_PAGE_MAX {
+        return None;
+    }
+    Some(size_of::<AccountsHeader>() + SLAB_TABLE_SIZE + (log_pages * SLAB_PAGE_SIZE))
+}
+
+fn check_account_len(acc: &AccountInfo, required: usize, name: &str) -> anchor_lang::Result<()> {
+    if acc.data_len() < required {
+        msg!("{} account too small: {} bytes ({} required)", name, acc.data_len().to_string(), required.to_string());
+        return Err(ErrorCode::AccountTooSmall.into());
+    }
+    Ok(())
+}
+
 fn map_datatype(data_type: DT) -> u16 {
     match data_type {
         DT::BidOrder => OrderDT::BidOrderMap as u16,
         DT::AskOrder => OrderDT::AskOrderMap as u16,
         DT::Account => SettleDT::AccountMap as u16,
         DT::UserRBAC => UserDT::UserRBACMap as u16,
-        _ => { panic!("Invalid datatype") },
-    }
-}
-
-fn map_len(data_type: DT) -> u32 {
-    match data_type {
-        DT::BidOrder => MAX_ORDERS,
-        DT::AskOrder => MAX_ORDERS,
-        DT::Account => MAX_ACCOUNTS,
-        DT::UserRBAC => MAX_RBAC,
+        DT::BidStop => StopDT::BidStopMap as u16,
+        DT::AskStop => StopDT::AskStopMap as u16,
         _ => { panic!("Invalid datatype") },
     }
 }
@@ -331,12 +569,14 @@ fn index_datatype(data_type: DT) -> u16 {
         DT::AskOrder => OrderDT::AskOrder as u16,
         DT::Account => SettleDT::Account as u16,
         DT::UserRBAC => UserDT::UserRBAC as u16,
+        DT::BidStop => StopDT::BidStop as u16,
+        DT::AskStop => StopDT::AskStop as u16,
         _ => { panic!("Invalid datatype") },
     }
 }
 
-fn map_get(pt: &mut SlabPageAlloc, data_type: DT, key: u128) -> Option<LeafNode> {
-    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_get(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32, key: u128) -> Option<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.get_key(key);
     match res {
         None => None,
@@ -344,8 +584,8 @@ fn map_get(pt: &mut SlabPageAlloc, data_type: DT, key: u128) -> Option<LeafNode>
     }
 }
 
-fn map_min(pt: &mut SlabPageAlloc, data_type: DT) -> Option<LeafNode> {
-    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_min(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32) -> Option<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.get_min();
     match res {
         None => None,
@@ -353,8 +593,8 @@ fn map_min(pt: &mut SlabPageAlloc, data_type: DT) -> Option<LeafNode> {
     }
 }
 
-fn map_max(pt: &mut SlabPageAlloc, data_type: DT) -> Option<LeafNode> {
-    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_max(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32) -> Option<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.get_max();
     match res {
         None => None,
@@ -362,8 +602,8 @@ fn map_max(pt: &mut SlabPageAlloc, data_type: DT) -> Option<LeafNode> {
     }
 }
 
-fn map_predicate_min<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabPageAlloc, data_type: DT, predicate: F) -> Option<LeafNode> {
-    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_predicate_min<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32, predicate: F) -> Option<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.predicate_min(predicate);
     match res {
         None => None,
@@ -371,8 +611,8 @@ fn map_predicate_min<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabP
     }
 }
 
-fn map_predicate_max<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabPageAlloc, data_type: DT, predicate: F) -> Option<LeafNode> {
-    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_predicate_max<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32, predicate: F) -> Option<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.predicate_max(predicate);
     match res {
         None => None,
@@ -380,8 +620,28 @@ fn map_predicate_max<F: FnMut(&SlabPageAlloc, &LeafNode) -> bool>(pt: &mut SlabP
     }
 }
 
-fn map_insert(pt: &mut SlabPageAlloc, data_type: DT, node: &LeafNode) -> FnResult<(), SlabTreeError> {
-    let mut cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_traverse(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32) -> Vec<LeafNode> {
+    let cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
+    cm.traverse().iter().map(|leaf| (*leaf).clone()).collect()
+}
+
+fn map_count(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32) -> u32 {
+    // Count the entries without collecting them (the predicate never matches, so every entry is visited)
+    let mut count: u32 = 0;
+    map_predicate_min(pt, data_type, capacity, |_sl, _leaf| {
+        count = count + 1;
+        false
+    });
+    count
+}
+
+fn map_len(pt: &mut SlabPageAlloc, data_type: DT) -> u32 {
+    // Allocated capacity of the map (settlement logs are allocated with the market's "max_accounts")
+    pt.len(map_datatype(data_type)) as u32
+}
+
+fn map_insert(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32, node: &LeafNode) -> FnResult<(), SlabTreeError> {
+    let mut cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     let res = cm.insert_leaf(node);
     match res {
         Err(SlabTreeError::OutOfSpace) => {
@@ -392,8 +652,8 @@ fn map_insert(pt: &mut SlabPageAlloc, data_type: DT, node: &LeafNode) -> FnResul
     }
 }
 
-fn map_remove(pt: &mut SlabPageAlloc, data_type: DT, key: u128) -> anchor_lang::Result<()> {
-    let mut cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: map_len(data_type) };
+fn map_remove(pt: &mut SlabPageAlloc, data_type: DT, capacity: u32, key: u128) -> anchor_lang::Result<()> {
+    let mut cm = CritMap { slab: pt, type_id: map_datatype(data_type), capacity: capacity };
     cm.remove_by_key(key).ok_or(error!(ErrorCode::RecordNotFound))?;
     Ok(())
 }
@@ -403,6 +663,43 @@ fn load_struct<T: AccountDeserialize>(acc: &AccountInfo) -> FnResult<T, ProgramE
     Ok(T::try_deserialize(&mut data)?)
 }
 
+fn check_global_pause(acc_config: &AccountInfo) -> anchor_lang::Result<()> {
+    // The global config address is verified by the account constraints, trading is not paused until it is created
+    if *acc_config.owner != ID {
+        return Ok(());
+    }
+    let config = load_struct::<GlobalConfig>(acc_config)?;
+    if config.paused {
+        msg!("Trading paused on all markets");
+        return Err(ErrorCode::MarketClosed.into());
+    }
+    Ok(())
+}
+
+fn verify_result_account<T: anchor_lang::Discriminator>(acc: &AccountInfo, min_len: usize) -> anchor_lang::Result<()> {
+    // Check the optional result account before any work is done so a bad account fails with a clear error
+    if *acc.owner != ID {
+        msg!("Result account not owned by program: {}", acc.key.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    if acc.data_len() < min_len {
+        msg!("Result account too small: {} (requires {})", acc.data_len().to_string(), min_len.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    if !Rent::get()?.is_exempt(acc.lamports(), acc.data_len()) {
+        msg!("Result account not rent-exempt: {}", acc.key.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    // New accounts are zeroed, reused accounts must hold the same result type
+    let data = acc.try_borrow_data()?;
+    let disc: &[u8] = &data[..8];
+    if disc != T::discriminator() && disc.iter().any(|&x| x != 0) {
+        msg!("Invalid result account type: {}", acc.key.to_string());
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok(())
+}
+
 fn store_struct<T: AccountSerialize>(obj: &T, acc: &AccountInfo) -> FnResult<(), Error> {
     let mut data = acc.try_borrow_mut_data()?;
     let dst: &mut [u8] = &mut data;
@@ -411,6 +708,7 @@ fn store_struct<T: AccountSerialize>(obj: &T, acc: &AccountInfo) -> FnResult<(),
 }
 
 fn scale_price(quantity: u64, price: u64, decimal_factor: u64) -> anchor_lang::Result<u64> {
+    // The product of two u64 values always fits in a u128, only the final conversion can overflow
     let mut tokens_calc: u128 = (quantity as u128).checked_mul(price as u128).ok_or(error!(ErrorCode::Overflow))?;
     tokens_calc = tokens_calc.checked_div(decimal_factor as u128).ok_or(error!(ErrorCode::Overflow))?;
     let tokens: u64 = u64::try_from(tokens_calc).map_err(|_| error!(ErrorCode::Overflow))?;
@@ -418,6 +716,7 @@ fn scale_price(quantity: u64, price: u64, decimal_factor: u64) -> anchor_lang::R
 }
 
 fn fill_quantity(input_price: u64, order_price: u64, decimal_factor: u64) -> anchor_lang::Result<u64> {
+    // The decimal factor is at most 10^MAX_DECIMALS so the product fits in a u128, only the final conversion can overflow
     let mut tokens_calc: u128 = (input_price as u128).checked_mul(decimal_factor as u128).ok_or(error!(ErrorCode::Overflow))?;
     tokens_calc = tokens_calc.checked_div(order_price as u128).ok_or(error!(ErrorCode::Overflow))?;
     let tokens: u64 = u64::try_from(tokens_calc).map_err(|_| error!(ErrorCode::Overflow))?;
@@ -428,7 +727,7 @@ fn has_role(acc_auth: &AccountInfo, role: Role, key: &Pubkey) -> anchor_lang::Re
     let auth_data: &mut [u8] = &mut acc_auth.try_borrow_mut_data()?;
     let rd = SlabPageAlloc::new(auth_data);
     let authhash: u128 = CritMap::bytes_hash([[role as u32].as_byte_slice(), key.as_ref()].concat().as_slice());
-    let authrec = map_get(rd, DT::UserRBAC, authhash);
+    let authrec = map_get(rd, DT::UserRBAC, MAX_RBAC, authhash);
     if ! authrec.is_some() {
         return Err(ErrorCode::AccessDenied.into());
     }
@@ -451,6 +750,15 @@ fn calculate_fee(fee_rate: u32, base_amount: u64) -> anchor_lang::Result<u64> {
     Ok(result)
 }
 
+fn fill_fees(taker_fee: u32, maker_rebate: u32, discount_rate: u32, fill_part: u64) -> anchor_lang::Result<(u64, u64, u64)> {
+    // Taker fee, maker rebate and fee discount of a fill costing "fill_part" pricing tokens
+    let fee_part = calculate_fee(taker_fee, fill_part)?;
+    let rebate_part = calculate_fee(maker_rebate, fill_part)?;
+    let discount_part = calculate_fee(discount_rate, fill_part)?;
+    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+    Ok((fee_part, rebate_part, discount_part))
+}
+
 fn decimal_factor(decimals: u8) -> u64 {
     let decimal_base: u64 = 10;
     let decimal_factor: u64 = decimal_base.pow(decimals as u32);
@@ -477,14 +785,15 @@ fn settle_account(settle: &AccountInfo, owner_id: u128, owner: &Pubkey, mkt_toke
     let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
     let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
     let sl = SlabPageAlloc::new(page_table);
-    let has_item = map_get(sl, DT::Account, owner_id);
+    let max_accounts = map_len(sl, DT::Account);
+    let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
     if has_item.is_none() {
         new_balance = amount;
-        let new_item = map_insert(sl, DT::Account, &LeafNode::new(owner_id, 0, owner));
+        let new_item = map_insert(sl, DT::Account, max_accounts, &LeafNode::new(owner_id, 0, owner));
         if new_item.is_ok() {
             // Delay setting the slot parameter so that AccountEntry SlabVec index is not updated unless a key is actually added to the CritMap
             let acct_idx = AccountEntry::next_index(sl, DT::Account)?;
-            let mut cm = CritMap { slab: sl, type_id: map_datatype(DT::Account), capacity: map_len(DT::Account) };
+            let mut cm = CritMap { slab: sl, type_id: map_datatype(DT::Account), capacity: max_accounts };
             cm.get_key_mut(owner_id).unwrap().set_slot(acct_idx);
             let mut mkt_bal: u64 = 0;
             let mut prc_bal: u64 = 0;
@@ -523,6 +832,241 @@ fn settle_account(settle: &AccountInfo, owner_id: u128, owner: &Pubkey, mkt_toke
     Ok(new_balance)
 }
 
+fn settle_available(settle: &AccountInfo, owner_id: u128) -> anchor_lang::Result<bool> {
+    // Check if a settlement log has an entry for an owner or space for a new one
+    // Each new entry uses a leaf node and an inner node in the CritMap
+    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+    let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+    let sl = SlabPageAlloc::new(page_table);
+    let max_accounts = map_len(sl, DT::Account);
+    if map_get(sl, DT::Account, max_accounts, owner_id).is_some() {
+        return Ok(true);
+    }
+    Ok(settle_header[0].items < (max_accounts + 1) / 2)
+}
+
+fn settle_entry(settle: &AccountInfo, owner: &Pubkey) -> anchor_lang::Result<Option<AccountEntry>> {
+    // Read an owner's settlement log entry
+    let owner_id: u128 = CritMap::bytes_hash(owner.as_ref());
+    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+    let (_header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let sl = SlabPageAlloc::new(page_table);
+    let max_accounts = map_len(sl, DT::Account);
+    let log_node = map_get(sl, DT::Account, max_accounts, owner_id);
+    if log_node.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(*sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.unwrap().slot() as usize)))
+}
+
+fn take_settle_entry(settle: &AccountInfo, owner: &Pubkey) -> anchor_lang::Result<Option<AccountEntry>> {
+    // Remove an owner's settlement log entry (the log account is not closed)
+    let owner_id: u128 = CritMap::bytes_hash(owner.as_ref());
+    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+    let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+    let sl = SlabPageAlloc::new(page_table);
+    let max_accounts = map_len(sl, DT::Account);
+    let log_node = map_get(sl, DT::Account, max_accounts, owner_id);
+    if log_node.is_none() {
+        return Ok(None);
+    }
+    let log_node = log_node.unwrap();
+    let log_entry = *sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+    settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+    map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+    AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+    Ok(Some(log_entry))
+}
+
+fn settle_withdraw<'info>(
+    market: &Market,
+    market_key: &Pubkey,
+    state: &mut MarketState,
+    remaining_accounts: &[AccountInfo<'info>],
+    ast_offset: usize,
+    market_tokens: u64,
+    pricing_tokens: u64,
+    agent: &AccountInfo<'info>,
+    mkt_vault: &AccountInfo<'info>,
+    prc_vault: &AccountInfo<'info>,
+    user_mkt_token: &AccountInfo<'info>,
+    user_prc_token: &AccountInfo<'info>,
+    spl_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Transfer balances removed from the settlement logs from the market vaults to the user's token accounts
+    let seeds = &[market_key.as_ref(), &[market.agent_nonce]];
+    let signer = &[&seeds[..]];
+    if market_tokens > 0 {
+        let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        perform_signed_transfer(remaining_accounts, signer, mint_type, ast_offset, market_tokens,
+            mkt_vault,          // From
+            user_mkt_token,     // To
+            agent,              // Auth
+            spl_prog,           // SPL Token Program
+        )?;
+        state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    if pricing_tokens > 0 {
+        let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        perform_signed_transfer(remaining_accounts, signer, mint_type, ast_offset, pricing_tokens,
+            prc_vault,          // From
+            user_prc_token,     // To
+            agent,              // Auth
+            spl_prog,           // SPL Token Program
+        )?;
+        state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(())
+}
+
+fn merge_settle_entry(settle: &AccountInfo, owner: &Pubkey, log_entry: &AccountEntry) -> anchor_lang::Result<()> {
+    // Add an entry moved from another settlement log (merged into the owner's existing entry, keeping the latest update time)
+    let owner_id: u128 = CritMap::bytes_hash(owner.as_ref());
+    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+    let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+    let sl = SlabPageAlloc::new(page_table);
+    let max_accounts = map_len(sl, DT::Account);
+    let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
+    if has_item.is_some() {
+        let entry = sl.index_mut::<AccountEntry>(SettleDT::Account.into(), has_item.unwrap().slot() as usize);
+        let mkt_bal: u64 = entry.mkt_token_balance().checked_add(log_entry.mkt_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+        let prc_bal: u64 = entry.prc_token_balance().checked_add(log_entry.prc_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+        let ts_updated: i64 = entry.ts_updated().max(log_entry.ts_updated());
+        entry.set_mkt_token_balance(mkt_bal);
+        entry.set_prc_token_balance(prc_bal);
+        entry.set_ts_updated(ts_updated);
+    } else {
+        let new_item = map_insert(sl, DT::Account, max_accounts, &LeafNode::new(owner_id, 0, owner));
+        if new_item.is_err() {
+            return Err(error!(ErrorCode::SettlementLogFull));
+        }
+        let acct_idx = AccountEntry::next_index(sl, DT::Account)?;
+        let mut cm = CritMap { slab: sl, type_id: map_datatype(DT::Account), capacity: max_accounts };
+        cm.get_key_mut(owner_id).unwrap().set_slot(acct_idx);
+        *sl.index_mut::<AccountEntry>(SettleDT::Account.into(), acct_idx as usize) = *log_entry;
+        settle_header[0].items = settle_header[0].items.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(())
+}
+
+fn pay_log_rebate(
+    market: &Market,
+    state: &mut MarketState,
+    user: &AccountInfo,
+    entries_removed: u64,
+) -> anchor_lang::Result<u64> {
+    // Rebate to the user for settlement log space (returns the lamports to deduct from the market state account)
+    let log_rebate: u64 = market.log_rebate.checked_mul(entries_removed).ok_or(error!(ErrorCode::Overflow))?;
+    state.log_deposit_balance = state.log_deposit_balance.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+
+    let mut user_lamports = user.lamports();
+    user_lamports = user_lamports.checked_add(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    **user.lamports.borrow_mut() = user_lamports;
+
+    Ok(log_rebate)
+}
+
+fn close_empty_log<'info>(
+    state: &mut MarketState,
+    settle: &AccountInfo<'info>,
+    log_prev: &AccountInfo<'info>,
+    log_next: &AccountInfo<'info>,
+) -> anchor_lang::Result<u64> {
+    // Close a settlement log emptied by a withdrawal if it is between two other logs (returns the lamports to credit to the market state account)
+    let close_log: bool = {
+        let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+        let (header, _page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+        settle_header[0].items == 0 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default()
+    };
+    if !close_log {
+        return Ok(0);
+    }
+    let log_lamports = log_close(state, settle, log_prev, log_next)?;
+    state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+    Ok(log_lamports)
+}
+
+fn withdraw_first<'info>(
+    market: &Account<'info, Market>,
+    state: &mut Account<'info, MarketState>,
+    remaining_accounts: &[AccountInfo<'info>],
+    user: &AccountInfo<'info>,
+    settle_a: &AccountInfo<'info>,
+    settle_b: &AccountInfo<'info>,
+    settle_prev: &AccountInfo<'info>,
+    agent: &AccountInfo<'info>,
+    mkt_vault: &AccountInfo<'info>,
+    prc_vault: &AccountInfo<'info>,
+    user_mkt_token: &AccountInfo<'info>,
+    user_prc_token: &AccountInfo<'info>,
+    spl_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Withdraw the user's entries in the current settlement logs to the user's token accounts before an order is placed
+    let mut market_tokens: u64 = 0;
+    let mut pricing_tokens: u64 = 0;
+    let mut entries_removed: u64 = 0;
+    for log_entry in [take_settle_entry(settle_a, user.key)?, take_settle_entry(settle_b, user.key)?].iter().flatten() {
+        market_tokens = market_tokens.checked_add(log_entry.mkt_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+        pricing_tokens = pricing_tokens.checked_add(log_entry.prc_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+        entries_removed = entries_removed + 1;
+    }
+    if entries_removed == 0 {
+        return Ok(());
+    }
+    settle_withdraw(market, &market.key(), state, remaining_accounts, 0, market_tokens, pricing_tokens,
+        agent, mkt_vault, prc_vault, user_mkt_token, user_prc_token, spl_prog,
+    )?;
+    let mut market_lamports = state.to_account_info().lamports();
+    let log_rebate = pay_log_rebate(market, state, user, entries_removed)?;
+    market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    // The last log is never closed, the first current log is closed if it was emptied and the log before it was passed
+    if settle_prev.key != user.key {
+        let log_lamports = close_empty_log(state, settle_a, settle_prev, settle_b)?;
+        market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    **state.to_account_info().lamports.borrow_mut() = market_lamports;
+
+    emit!(WithdrawEvent {
+        event_type: 66630595851577488384523413035784530500, // solana/program/aqua-dex/withdraw_first
+        action_id: state.action_counter,
+        market: market.key(),
+        owner: user.key(),
+        user: user.key(),
+        market_account: user_mkt_token.key(),
+        pricing_account: user_prc_token.key(),
+        manager: false,
+        market_tokens: market_tokens,
+        pricing_tokens: pricing_tokens,
+    });
+    Ok(())
+}
+
+fn settlement_available(
+    state: &mut MarketState,
+    settle_a: &AccountInfo,
+    settle_b: &AccountInfo,
+    owner: &Pubkey,
+) -> anchor_lang::Result<bool> {
+    // Check if log_settlement would succeed for an owner before committing a fill
+    let owner_id: u128 = CritMap::bytes_hash(owner.as_ref());
+    if settle_available(settle_a, owner_id)? {
+        return Ok(true);
+    }
+    // Request a rollover (persisted because the instruction completes)
+    state.log_rollover = true;
+    if settle_available(settle_b, owner_id)? {
+        return Ok(true);
+    }
+    msg!("Atellix: Both settlement logs are full - Owner: {}", owner.to_string());
+    Ok(false)
+}
+
 fn log_settlement(
     market_key: &Pubkey, 
     state: &mut MarketState, 
@@ -598,9 +1142,12 @@ fn log_settlement(
 fn log_rollover(
     market_state: &mut MarketState,
     market_key: Pubkey,
+    max_accounts: u32,
     settle_b: &AccountInfo,
     settle_n: &AccountInfo, // New log account
 ) -> anchor_lang::Result<()> {
+    let log_len = settle_log_len(max_accounts).ok_or(error!(ErrorCode::InvalidParameters))?;
+    check_account_len(settle_n, log_len, "Settlement log")?;
 
     // Add new log entry to linked-list
     let prev_data: &mut[u8] = &mut settle_b.try_borrow_mut_data()?;
@@ -624,49 +1171,160 @@ fn log_rollover(
     };
     let settle_slab = SlabPageAlloc::new(settle_pages);
     settle_slab.setup_page_table();
-    settle_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
-    settle_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
+    settle_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+    settle_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
 
     market_state.settle_a = *settle_b.key;
     market_state.settle_b = *settle_n.key;
     market_state.log_rollover = false;
 
+    emit!(LogRolloverEvent {
+        event_type: 190756812166746457411561588672050380193, // solana/program/aqua-dex/log_rollover
+        action_id: market_state.action_counter,
+        market: market_key,
+        prev_log: *settle_b.key,
+        new_log: *settle_n.key,
+    });
+
     Ok(())
 }
 
-fn log_reimburse(
-    market: &Market,
-    state: &mut MarketState,
-    user: &AccountInfo,
-) -> anchor_lang::Result<()> {
-    state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
-
-    let mut user_lamports = user.lamports();
-    user_lamports = user_lamports.checked_add(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
-    **user.lamports.borrow_mut() = user_lamports;
+fn accumulate_price(state: &mut MarketState, clock_ts: i64) -> anchor_lang::Result<()> {
+    // Add the previous price weighted by the time it was in effect, before it is replaced
+    let elapsed = clock_ts.checked_sub(state.price_cumulative_ts).ok_or(error!(ErrorCode::Overflow))?;
+    if elapsed > 0 {
+        let weighted = (state.last_price as u128).checked_mul(elapsed as u128).ok_or(error!(ErrorCode::Overflow))?;
+        state.price_cumulative = state.price_cumulative.checked_add(weighted).ok_or(error!(ErrorCode::Overflow))?;
+        state.price_cumulative_ts = clock_ts;
+    }
+    Ok(())
+}
 
+fn add_volume(state: &mut MarketState, mkt_amount: u64, prc_amount: u64) -> anchor_lang::Result<()> {
+    state.mkt_volume = state.mkt_volume.checked_add(mkt_amount as u128).ok_or(error!(ErrorCode::Overflow))?;
+    state.prc_volume = state.prc_volume.checked_add(prc_amount as u128).ok_or(error!(ErrorCode::Overflow))?;
     Ok(())
 }
 
-fn log_close<'info>(
+fn apply_taker_fees(
+    market_key: &Pubkey,
     state: &mut MarketState,
-    settle: &AccountInfo<'info>,
-    log_prev: &AccountInfo<'info>,
-    log_next: &AccountInfo<'info>,
-) -> anchor_lang::Result<u64> {
-    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
-    let (header, _page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
-    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
-    if settle_header[0].items > 0 {
-        msg!("Attempted to close settlement log with remaining accounts");
-        return Err(error!(ErrorCode::SettlementLogNotEmpty));
+    settle_a: &AccountInfo,
+    settle_b: &AccountInfo,
+    referrer: &Pubkey,
+    referral_share: u32,
+    tokens_fee: u64,
+    tokens_rebate: u64,
+    user_volume: &mut Option<UserVolume>,
+    acc_user_volume: &AccountInfo,
+    prc_volume_start: u128,
+    self_volume: u128,
+) -> anchor_lang::Result<()> {
+    // Add an order's taker fees to the market fees (less maker rebates), credit the referrer's share of the taker fee, and add the order to the user's volume
+    state.prc_vault_balance = state.prc_vault_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
+    state.prc_fees_balance = state.prc_fees_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
+    state.prc_fees_balance = state.prc_fees_balance.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    let net_fee = tokens_fee.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    let tokens_referral = calculate_fee(referral_share, tokens_fee)?.min(net_fee);
+    if tokens_referral > 0 {
+        // Credit the referrer's share of the fees to the settlement log (matching may have filled the logs since the referrer was checked)
+        if settlement_available(state, settle_a, settle_b, referrer)? {
+            state.prc_fees_balance = state.prc_fees_balance.checked_sub(tokens_referral).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_order_balance = state.prc_order_balance.checked_add(tokens_referral).ok_or(error!(ErrorCode::Overflow))?;
+            log_settlement(market_key, state, settle_a, settle_b, referrer, false, tokens_referral)?;
+            msg!("Atellix: Referral Fee: {}", tokens_referral.to_string());
+        } else {
+            // The referrer's share stays with the market fees
+            msg!("Atellix: Referral Fee Retained: {}", tokens_referral.to_string());
+        }
     }
-    if settle_header[0].prev == Pubkey::default() {
-        msg!("Cannot remove first settlement log");
-        return Err(error!(ErrorCode::InvalidAccount));
+    if let Some(vol) = user_volume.as_mut() {
+        // Add the filled pricing token amount to the user's cumulative volume (self-trades are not counted)
+        let mut traded = state.prc_volume.checked_sub(prc_volume_start).ok_or(error!(ErrorCode::Overflow))?;
+        traded = traded.checked_sub(self_volume).ok_or(error!(ErrorCode::Overflow))?;
+        vol.volume = vol.volume.checked_add(traded).ok_or(error!(ErrorCode::Overflow))?;
+        store_struct::<UserVolume>(vol, acc_user_volume)?;
     }
-    if settle_header[0].next == Pubkey::default() {
-        msg!("Cannot remove last settlement log");
+    Ok(())
+}
+
+fn add_self_volume(self_volume: &mut u128, maker: &Pubkey, taker: &Pubkey, prc_amount: u64) -> anchor_lang::Result<()> {
+    // Pricing token volume of self-trades (excluded from the taker's cumulative volume for fee tiers)
+    if *maker == *taker {
+        *self_volume = self_volume.checked_add(prc_amount as u128).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(())
+}
+
+fn settlement_items<'info>(settle_a: &AccountInfo<'info>, settle_b: &AccountInfo<'info>) -> anchor_lang::Result<u32> {
+    // Total entries in the two current settlement logs
+    let mut items: u32 = 0;
+    for settle in [settle_a, settle_b] {
+        let log_data: &[u8] = &settle.try_borrow_data()?;
+        let (header, _page_table) = array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &[AccountsHeader] = cast_slice(header);
+        items = items.checked_add(settle_header[0].items).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(items)
+}
+
+fn log_fill_fee<'info>(
+    market: &Market,
+    state: &mut Account<'info, MarketState>,
+    user: &AccountInfo<'info>,
+    settle_a: &AccountInfo<'info>,
+    settle_b: &AccountInfo<'info>,
+    log_items: u32,
+) -> anchor_lang::Result<()> {
+    // Charge the user for the settlement log entries created while matching (entries above "log_items") and deposit the lamports with the market state
+    let new_entries = settlement_items(settle_a, settle_b)?.saturating_sub(log_items);
+    let fee = market.log_fee_per_fill.checked_mul(new_entries as u64).ok_or(error!(ErrorCode::Overflow))?;
+    if fee > 0 {
+        msg!("Atellix: Settlement Log Fill Fee: {} ({} entries)", fee.to_string(), new_entries.to_string());
+        let mut user_lamports = user.lamports();
+        user_lamports = user_lamports.checked_sub(fee).ok_or(error!(ErrorCode::Overflow))?;
+        **user.lamports.borrow_mut() = user_lamports;
+        let mut market_lamports = state.to_account_info().lamports();
+        market_lamports = market_lamports.checked_add(fee).ok_or(error!(ErrorCode::Overflow))?;
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+        state.log_deposit_balance = state.log_deposit_balance.checked_add(fee).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(())
+}
+
+fn log_reimburse(
+    market: &Market,
+    state: &mut MarketState,
+    user: &AccountInfo,
+) -> anchor_lang::Result<()> {
+    state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
+
+    let mut user_lamports = user.lamports();
+    user_lamports = user_lamports.checked_add(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
+    **user.lamports.borrow_mut() = user_lamports;
+
+    Ok(())
+}
+
+fn log_close<'info>(
+    state: &mut MarketState,
+    settle: &AccountInfo<'info>,
+    log_prev: &AccountInfo<'info>,
+    log_next: &AccountInfo<'info>,
+) -> anchor_lang::Result<u64> {
+    let log_data: &mut[u8] = &mut settle.try_borrow_mut_data()?;
+    let (header, _page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+    if settle_header[0].items > 0 {
+        msg!("Attempted to close settlement log with remaining accounts");
+        return Err(error!(ErrorCode::SettlementLogNotEmpty));
+    }
+    if settle_header[0].prev == Pubkey::default() {
+        msg!("Cannot remove first settlement log");
+        return Err(error!(ErrorCode::InvalidAccount));
+    }
+    if settle_header[0].next == Pubkey::default() {
+        msg!("Cannot remove last settlement log");
         return Err(error!(ErrorCode::InvalidAccount));
     }
     let log_prev_data: &mut[u8] = &mut log_prev.try_borrow_mut_data()?;
@@ -685,8 +1343,8 @@ fn log_close<'info>(
     settle_prev[0].next = log_next.key();
     settle_next[0].prev = log_prev.key();
     if state.settle_a == *settle.key {
-        state.settle_a = log_next.key();
-        state.settle_b = settle_next[0].next;
+        // The previous log becomes the first current log ("settle_b" is always the last log)
+        state.settle_a = log_prev.key();
     } else if state.settle_b == *settle.key {
         state.settle_b = log_next.key();
     }
@@ -694,25 +1352,251 @@ fn log_close<'info>(
     let log_lamports = settle.lamports();
     **settle.lamports.borrow_mut() = 0;
 
+    emit!(LogCloseEvent {
+        event_type: 301076799434876782164405013900391828692, // solana/program/aqua-dex/log_close
+        action_id: state.action_counter,
+        market: settle_header[0].market,
+        closed_log: *settle.key,
+        prev: log_prev.key(),
+        next: log_next.key(),
+    });
+
     Ok(log_lamports)
 }
 
-fn valid_order(order_type: OrderDT, leaf: &LeafNode, user_key: &Pubkey, sl: &SlabPageAlloc, expired_orders: &mut Vec<u128>, clock_ts: i64) -> bool {
+fn order_expired(expiry: i64, clock_ts: i64) -> bool {
+    // Orders remain valid until the end of their expiry timestamp (0 for no expiration)
+    expiry != 0 && expiry < clock_ts
+}
+
+fn extend_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    space: usize,
+    system_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Grow a program-owned account to a new layout size, topping up the rent-exempt minimum from the payer (the appended bytes are zeroed)
+    let lamports = Rent::get()?.minimum_balance(space);
+    let current_lamports = account.lamports();
+    if current_lamports < lamports {
+        let ix = solana_program::system_instruction::transfer(payer.key, account.key, lamports - current_lamports);
+        invoke(&ix, &[payer.clone(), account.clone(), system_prog.clone()])?;
+    }
+    account.realloc(space, true)?;
+    Ok(())
+}
+
+fn check_order_age(market: &Market, order: &Order, clock_ts: i64) -> anchor_lang::Result<()> {
+    // Voluntary cancellation requires the order to rest for "min_rest_seconds" (expired orders are exempt)
+    if order_too_young(market, order, clock_ts) {
+        let placed_ts = order.placed_ts;
+        msg!("Atellix: Order too young to cancel - Placed: {} Min Rest: {}", placed_ts.to_string(), market.min_rest_seconds.to_string());
+        return Err(ErrorCode::OrderTooYoung.into());
+    }
+    Ok(())
+}
+
+fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    space: usize,
+    seeds: &[&[u8]],
+    system_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Create a program-owned PDA paid by the payer. An address that already holds lamports is topped up to the rent-exempt
+    // minimum, allocated and assigned instead ("create_account" fails for funded addresses, so anyone could block it)
+    let lamports = Rent::get()?.minimum_balance(space);
+    let current_lamports = account.lamports();
+    if current_lamports == 0 {
+        let ix = solana_program::system_instruction::create_account(payer.key, account.key, lamports, space as u64, &ID);
+        invoke_signed(&ix, &[payer.clone(), account.clone(), system_prog.clone()], &[seeds])?;
+        return Ok(());
+    }
+    if current_lamports < lamports {
+        let ix = solana_program::system_instruction::transfer(payer.key, account.key, lamports - current_lamports);
+        invoke(&ix, &[payer.clone(), account.clone(), system_prog.clone()])?;
+    }
+    let ix = solana_program::system_instruction::allocate(account.key, space as u64);
+    invoke_signed(&ix, &[account.clone(), system_prog.clone()], &[seeds])?;
+    let ix = solana_program::system_instruction::assign(account.key, &ID);
+    invoke_signed(&ix, &[account.clone(), system_prog.clone()], &[seeds])?;
+    Ok(())
+}
+
+fn check_order_cooldown<'info>(
+    market: &Market,
+    market_key: &Pubkey,
+    owner: &AccountInfo<'info>,
+    acc_cooldown: &AccountInfo<'info>,
+    system_prog: &AccountInfo<'info>,
+    clock_ts: i64,
+    preview: bool,
+) -> anchor_lang::Result<()> {
+    // Owners must wait "order_cooldown" seconds between orders, tracked in the owner's cooldown PDA (created with the first order)
+    if market.order_cooldown == 0 {
+        return Ok(());
+    }
+    let (cooldown_key, bump) = Pubkey::find_program_address(&[market_key.as_ref(), owner.key.as_ref(), b"cooldown"], &ID);
+    verify_matching_accounts(acc_cooldown.key, &cooldown_key, Some(String::from("Invalid user cooldown")))?;
+    let mut cooldown = if *acc_cooldown.owner == ID {
+        let cooldown = load_struct::<UserCooldown>(acc_cooldown)?;
+        let last_order_ts = cooldown.last_order_ts;
+        if clock_ts < last_order_ts.saturating_add(market.order_cooldown) {
+            msg!("Atellix: Order too soon - Last Order: {} Cooldown: {}", last_order_ts.to_string(), market.order_cooldown.to_string());
+            return Err(ErrorCode::OrderTooSoon.into());
+        }
+        cooldown
+    } else {
+        if preview {
+            return Ok(());
+        }
+        create_pda_account(owner, acc_cooldown, USER_COOLDOWN_LEN, &[market_key.as_ref(), owner.key.as_ref(), b"cooldown", &[bump]], system_prog)?;
+        UserCooldown { market: *market_key, owner: *owner.key, last_order_ts: 0 }
+    };
+    if !preview {
+        cooldown.last_order_ts = clock_ts;
+        store_struct::<UserCooldown>(&cooldown, acc_cooldown)?;
+    }
+    Ok(())
+}
+
+fn check_client_order<'info>(
+    market_key: &Pubkey,
+    owner: &AccountInfo<'info>,
+    acc_client_orders: &AccountInfo<'info>,
+    system_prog: &AccountInfo<'info>,
+    client_order_id: u128,
+    preview: bool,
+) -> anchor_lang::Result<()> {
+    // Reject a client order id already used within the owner's recent client orders PDA (created with the first client order id, 0 for no client order id)
+    if client_order_id == 0 {
+        return Ok(());
+    }
+    let (client_orders_key, bump) = Pubkey::find_program_address(&[market_key.as_ref(), owner.key.as_ref(), b"client_orders"], &ID);
+    verify_matching_accounts(acc_client_orders.key, &client_orders_key, Some(String::from("Invalid user client orders")))?;
+    let mut client_orders = if *acc_client_orders.owner == ID {
+        let client_orders = load_struct::<UserClientOrders>(acc_client_orders)?;
+        if client_orders.recent_ids.iter().any(|id| *id == client_order_id) {
+            msg!("Atellix: Duplicate client order id: {}", client_order_id.to_string());
+            return Err(ErrorCode::DuplicateOrder.into());
+        }
+        client_orders
+    } else {
+        if preview {
+            return Ok(());
+        }
+        create_pda_account(owner, acc_client_orders, USER_CLIENT_ORDERS_LEN, &[market_key.as_ref(), owner.key.as_ref(), b"client_orders", &[bump]], system_prog)?;
+        UserClientOrders { market: *market_key, owner: *owner.key, recent_ids: [0; MAX_CLIENT_ORDERS], next_index: 0 }
+    };
+    if !preview {
+        let index = client_orders.next_index as usize % MAX_CLIENT_ORDERS;
+        client_orders.recent_ids[index] = client_order_id;
+        client_orders.next_index = ((index + 1) % MAX_CLIENT_ORDERS) as u8;
+        store_struct::<UserClientOrders>(&client_orders, acc_client_orders)?;
+    }
+    Ok(())
+}
+
+fn expiration_limit(expire_budget: u32) -> anchor_lang::Result<u32> {
+    // Orders may opt into removing more expired orders than the default (0 uses MAX_EXPIRATIONS)
+    require!(expire_budget <= MAX_EXPIRE_BUDGET, ErrorCode::InvalidParameters);
+    Ok(if expire_budget == 0 { MAX_EXPIRATIONS } else { expire_budget })
+}
+
+fn order_too_young(market: &Market, order: &Order, clock_ts: i64) -> bool {
+    market.min_rest_seconds > 0 &&
+        !order_expired(order.expiry, clock_ts) &&
+        clock_ts < order.placed_ts.saturating_add(market.min_rest_seconds)
+}
+
+fn valid_order(order_type: OrderDT, leaf: &LeafNode, user_key: &Pubkey, allow_self_trade: bool, sl: &SlabPageAlloc, expired_orders: &mut Vec<u128>, clock_ts: i64) -> bool {
     let order = sl.index::<Order>(order_type as u16, leaf.slot() as usize);
-    let valid_expiry: bool = order.expiry == 0 || order.expiry < clock_ts;      // Check expiry timestamp if needed
-    let valid_user: bool = leaf.owner() != *user_key;                           // Prevent trades between the same user
+    let valid_expiry: bool = !order_expired(order.expiry, clock_ts);           // Check expiry timestamp if needed
+    let valid_user: bool = allow_self_trade || leaf.owner() != *user_key;       // Prevent trades between the same user (unless the market allows it)
     let valid = valid_expiry && valid_user;
     /*msg!("Atellix: Found {} [{}] {} @ {} Exp: {} Key: {} OK: {}",
         match order_type { OrderDT::BidOrder => "Bid", OrderDT::AskOrder => "Ask", _ => unreachable!() },
         leaf.slot().to_string(), order.amount().to_string(), Order::price(leaf.key()).to_string(),
         order.expiry.to_string(), leaf.owner().to_string(), valid.to_string(),
     );*/
-    if !valid_expiry {
+    if !valid_expiry && !expired_orders.contains(&leaf.key()) {
+        // Each search starts again from the best price, record each expired order once
         expired_orders.push(leaf.key());
     }
     valid
 }
 
+fn verify_orderbook_version(market: &Market) -> anchor_lang::Result<()> {
+    // Orderbooks with an older order layout can only be read by "resize_orderbook" until they are migrated
+    if market.orderbook_version != ORDERBOOK_VERSION {
+        msg!("Orderbook layout version {} must be migrated with resize_orderbook", market.orderbook_version.to_string());
+        return Err(ErrorCode::OrderbookMigrationRequired.into());
+    }
+    Ok(())
+}
+
+fn verify_vault_mint(vault: &AccountInfo, mint: &Pubkey, mint_type: u8) -> anchor_lang::Result<()> {
+    // Confirm the vault holds the market's configured mint before any transfers
+    let vault_mint: Pubkey = match MintType::try_from(mint_type).map_err(|_| ErrorCode::InvalidParameters)? {
+        MintType::SPLToken => load_struct::<SPL_TokenAccount>(vault)?.mint,
+        MintType::AtxSecurityToken => load_struct::<AST_TokenAccount>(vault)?.mint,
+    };
+    if vault_mint != *mint {
+        msg!("Invalid vault mint");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    Ok(())
+}
+
+fn verify_ast_holder(acc_token: Option<&AccountInfo>, mint: &Pubkey, owner: &Pubkey) -> anchor_lang::Result<()> {
+    // An owner can only hold orders on a security token market with an unfrozen security token account for each security token mint
+    let acc_token = acc_token.ok_or(error!(ErrorCode::InvalidParameters))?;
+    if *acc_token.owner != security_token::ID {
+        msg!("Invalid security token account");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    let token_acct = load_struct::<AST_TokenAccount>(acc_token)?;
+    if token_acct.mint != *mint || token_acct.owner != *owner {
+        msg!("Security token account not held by owner");
+        return Err(ErrorCode::AccessDenied.into());
+    }
+    if token_acct.frozen {
+        msg!("Atellix: Token account frozen");
+        return Err(ErrorCode::TokenAccountFrozen.into());
+    }
+    Ok(())
+}
+
+fn vault_token_balance(vault: &AccountInfo, mint_type: u8) -> anchor_lang::Result<u64> {
+    // On-chain token balance of a market vault
+    let amount: u64 = match MintType::try_from(mint_type).map_err(|_| ErrorCode::InvalidParameters)? {
+        MintType::SPLToken => load_struct::<SPL_TokenAccount>(vault)?.amount,
+        MintType::AtxSecurityToken => load_struct::<AST_TokenAccount>(vault)?.amount,
+    };
+    Ok(amount)
+}
+
+fn verify_mint_decimals(mint_type: MintType, acc_mint: &AccountInfo, decimals: u8, label: &str) -> anchor_lang::Result<()> {
+    // Token mints must match the decimals stored in the market
+    let mint_owner = match mint_type {
+        MintType::SPLToken => Token::id(),
+        MintType::AtxSecurityToken => security_token::ID,
+    };
+    if *acc_mint.owner != mint_owner {
+        msg!("Invalid {} token mint", label);
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    let mint_decimals: u8 = match mint_type {
+        MintType::SPLToken => load_struct::<SPL_Mint>(acc_mint)?.decimals,
+        MintType::AtxSecurityToken => load_struct::<AST_Mint>(acc_mint)?.decimals,
+    };
+    if mint_decimals != decimals {
+        msg!("Invalid {} token decimals - Mint: {} Market: {}", label, mint_decimals.to_string(), decimals.to_string());
+        return Err(ErrorCode::InvalidParameters.into());
+    }
+    Ok(())
+}
+
 fn perform_transfer<'info>(
     accounts: &[AccountInfo<'info>],
     mint_type: MintType,
@@ -728,9 +1612,11 @@ fn perform_transfer<'info>(
         if preview {
             let token_acct = load_struct::<SPL_TokenAccount>(&from.clone())?;
             if token_acct.is_frozen() {
-                return Err(ErrorCode::ExternalError.into());
+                msg!("Atellix: Token account frozen");
+                return Err(ErrorCode::TokenAccountFrozen.into());
             }
             if token_acct.amount < amount {
+                msg!("Atellix: Insufficient tokens - Balance: {} Required: {}", token_acct.amount.to_string(), amount.to_string());
                 return Err(ErrorCode::InsufficientTokens.into());
             }
             return Ok(());
@@ -746,12 +1632,21 @@ fn perform_transfer<'info>(
         }
     } else if mint_type == MintType::AtxSecurityToken {
         if preview {
-            let token_acct_info = &accounts.get(ast_offset + 1).unwrap().to_account_info();
-            let token_acct = load_struct::<AST_TokenAccount>(token_acct_info)?;
+            // The security token account is the "from" account, the program and auth accounts used by the transfer must also be present
+            let ast_prog = accounts.get(ast_offset).ok_or(error!(ErrorCode::InvalidParameters))?;
+            require!(*ast_prog.key == security_token::ID, ErrorCode::InvalidParameters);
+            require!(accounts.len() > ast_offset + 2, ErrorCode::InvalidParameters);
+            if *from.owner != security_token::ID {
+                msg!("Invalid security token account");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let token_acct = load_struct::<AST_TokenAccount>(from)?;
             if token_acct.frozen {
-                return Err(ErrorCode::ExternalError.into());
+                msg!("Atellix: Token account frozen");
+                return Err(ErrorCode::TokenAccountFrozen.into());
             }
             if token_acct.amount < amount {
+                msg!("Atellix: Insufficient tokens - Balance: {} Required: {}", token_acct.amount.to_string(), amount.to_string());
                 return Err(ErrorCode::InsufficientTokens.into());
             }
             return Ok(());
@@ -810,6 +1705,101 @@ fn perform_signed_transfer<'info>(
     Err(error!(ErrorCode::InvalidParameters))
 }
 
+fn verify_fee_receiver(
+    market: &Market,
+    mint_type: MintType,
+    accounts: &[AccountInfo],
+    ast_offset: usize,
+    token_account: &AccountInfo,
+) -> anchor_lang::Result<()> {
+    // The destination token account must be owned by the market's fee receiver (the "to_auth" account of AST-1 transfers at "ast_offset" + 2)
+    let fee_owner: Pubkey = match mint_type {
+        MintType::SPLToken => load_struct::<SPL_TokenAccount>(token_account)?.owner,
+        MintType::AtxSecurityToken => accounts.get(ast_offset + 2).ok_or(error!(ErrorCode::InvalidParameters))?.key(),
+    };
+    verify_matching_accounts(&market.fee_receiver, &fee_owner, Some(String::from("Invalid fee receiver")))
+}
+
+fn check_native_sol(market: &Market, native_sol: bool, use_vault: bool) -> anchor_lang::Result<()> {
+    if native_sol {
+        if market.prc_mint != token::spl_token::native_mint::ID {
+            msg!("Pricing token is not wrapped SOL");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        if use_vault {
+            msg!("Native SOL can not be used with the user vault");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+    }
+    Ok(())
+}
+
+fn wrap_native_sol<'info>(
+    amount: u64,
+    user: &AccountInfo<'info>,
+    user_token: &AccountInfo<'info>,
+    system_prog: &AccountInfo<'info>,
+    spl_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Move lamports from the user to their wrapped SOL token account and update the token balance
+    if amount > 0 {
+        let ix = solana_program::system_instruction::transfer(user.key, user_token.key, amount);
+        invoke(&ix, &[user.clone(), user_token.clone(), system_prog.clone()])?;
+    }
+    let sync_accounts = token::SyncNative {
+        account: user_token.clone(),
+    };
+    token::sync_native(CpiContext::new(spl_prog.clone(), sync_accounts))?;
+    Ok(())
+}
+
+fn preview_native_sol(amount: u64, user: &AccountInfo, user_token: &AccountInfo) -> anchor_lang::Result<()> {
+    // Preview of "wrap_native_sol" (nothing is wrapped): the user's SOL and wrapped SOL must cover the amount
+    let token_amount = load_struct::<SPL_TokenAccount>(user_token)?.amount;
+    let available = token_amount.checked_add(user.lamports()).ok_or(error!(ErrorCode::Overflow))?;
+    if available < amount {
+        msg!("Insufficient SOL: {} (requires {})", available.to_string(), amount.to_string());
+        return Err(ErrorCode::InsufficientTokens.into());
+    }
+    Ok(())
+}
+
+fn unwrap_native_sol<'info>(
+    user: &AccountInfo<'info>,
+    user_token: &AccountInfo<'info>,
+    spl_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Close the wrapped SOL token account, returning the tokens and rent to the user as SOL
+    let close_accounts = token::CloseAccount {
+        account: user_token.clone(),
+        destination: user.clone(),
+        authority: user.clone(),
+    };
+    token::close_account(CpiContext::new(spl_prog.clone(), close_accounts))?;
+    Ok(())
+}
+
+fn rounding_dust(
+    state: &mut MarketState,
+    total_qty: u64,
+    fill_qty: u64,
+    price: u64,
+    decimal_factor: u64,
+    paid: u64,
+) -> anchor_lang::Result<u64> {
+    // A resting bid holds scale_price() of its total quantity, truncated partial fills can release more than is paid
+    // Move the residual that no longer belongs to any order from the order balance to the dust balance
+    let remaining = total_qty.checked_sub(fill_qty).ok_or(error!(ErrorCode::Overflow))?;
+    let released = scale_price(total_qty, price, decimal_factor)?
+        .checked_sub(scale_price(remaining, price, decimal_factor)?).ok_or(error!(ErrorCode::Overflow))?;
+    let dust = released.saturating_sub(paid);
+    if dust > 0 {
+        state.prc_order_balance = state.prc_order_balance.checked_sub(dust).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_dust_balance = state.prc_dust_balance.checked_add(dust).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(dust)
+}
+
 fn log_trade(
     tlog: &mut SlabPageAlloc,
     event_type: u128,
@@ -824,6 +1814,10 @@ fn log_trade(
     price: u64,
     rebate: u64,
     fee: u64,
+    discount: u64,
+    dust: u64,
+    referrer: &Pubkey,
+    referral_share: u32,
     ts: i64,
 ) -> anchor_lang::Result<()> {
     let trade_header = tlog.header_mut::<TradeLogHeader>(0);
@@ -844,6 +1838,14 @@ fn log_trade(
     log_entry.price = price;
     log_entry.ts = ts;
 
+    // Referrer share of the taker fee for the fill (reported in the event, the referrer is credited once per order by "apply_taker_fees")
+    let mut referral_fee: u64 = 0;
+    let mut referral_key: Pubkey = Pubkey::default();
+    if referral_share > 0 {
+        referral_fee = calculate_fee(referral_share, fee)?;
+        referral_key = *referrer;
+    }
+
     emit!(MatchEvent {
         event_type: event_type,
         action_id: action_id,
@@ -856,6 +1858,10 @@ fn log_trade(
         taker: *taker,
         taker_side: taker_side,
         taker_fee: fee,
+        fee_discount: discount,
+        referrer: referral_key,
+        referral_fee: referral_fee,
+        rounding_dust: dust,
         amount: amount,
         price: price,
         ts: ts,
@@ -863,6 +1869,144 @@ fn log_trade(
     Ok(())
 }
 
+fn price_deviation(last_price: u64, price: u64) -> anchor_lang::Result<u128> {
+    // Difference between prices in basis points of the last price
+    let diff: u64 = if price > last_price { price - last_price } else { last_price - price };
+    let deviation: u128 = (diff as u128).checked_mul(10000).ok_or(error!(ErrorCode::Overflow))?.checked_div(last_price as u128).ok_or(error!(ErrorCode::Overflow))?;
+    Ok(deviation)
+}
+
+fn check_price_band(market: &Market, last_price: u64, price: u64) -> anchor_lang::Result<()> {
+    if market.max_deviation_bps == 0 || last_price == 0 {
+        return Ok(());
+    }
+    let deviation = price_deviation(last_price, price)?;
+    if deviation > market.max_deviation_bps as u128 {
+        msg!("Atellix: Price {} deviates from last price {} by more than {} basis points", price.to_string(), last_price.to_string(), market.max_deviation_bps.to_string());
+        return Err(ErrorCode::PriceOutsideBand.into());
+    }
+    Ok(())
+}
+
+fn breaker_halted(market: &Market, state: &MarketState, clock_ts: i64) -> anchor_lang::Result<bool> {
+    // Taker orders are halted until the cooldown after the circuit breaker is tripped has passed
+    if market.breaker_bps == 0 || state.breaker_tripped_ts == 0 {
+        return Ok(false);
+    }
+    let resume_ts = state.breaker_tripped_ts.checked_add(market.breaker_cooldown).ok_or(error!(ErrorCode::Overflow))?;
+    Ok(clock_ts <= resume_ts)
+}
+
+fn check_breaker(market: &Market, state: &MarketState, clock_ts: i64) -> anchor_lang::Result<()> {
+    if breaker_halted(market, state, clock_ts)? {
+        msg!("Atellix: Market halted by circuit breaker - Tripped: {} Cooldown: {}", state.breaker_tripped_ts.to_string(), market.breaker_cooldown.to_string());
+        return Err(ErrorCode::MarketHalted.into());
+    }
+    Ok(())
+}
+
+fn check_session(market: &Market, clock_ts: i64) -> anchor_lang::Result<()> {
+    // Daily trading session in seconds of the UTC day (both zero for 24 hour trading)
+    if market.session_open == 0 && market.session_close == 0 {
+        return Ok(());
+    }
+    let day_ts = clock_ts.rem_euclid(SECONDS_PER_DAY);
+    let in_session = if market.session_open < market.session_close {
+        day_ts >= market.session_open && day_ts < market.session_close
+    } else {
+        // Session wraps around midnight
+        day_ts >= market.session_open || day_ts < market.session_close
+    };
+    if !in_session {
+        msg!("Atellix: Market outside trading session - Open: {} Close: {} Time: {}", market.session_open.to_string(), market.session_close.to_string(), day_ts.to_string());
+        return Err(ErrorCode::MarketOutsideSession.into());
+    }
+    Ok(())
+}
+
+fn breaker_tripped(market: &Market, ref_price: u64, price: u64) -> anchor_lang::Result<bool> {
+    // Check if a fill price moves too far from the last price before the order
+    if market.breaker_bps == 0 || ref_price == 0 {
+        return Ok(false);
+    }
+    let deviation = price_deviation(ref_price, price)?;
+    if deviation > market.breaker_bps as u128 {
+        msg!("Atellix: Circuit breaker tripped - Price {} moved from last price {} by more than {} basis points", price.to_string(), ref_price.to_string(), market.breaker_bps.to_string());
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn check_min_notional(market: &Market, notional: u64) -> anchor_lang::Result<()> {
+    if market.min_notional > 0 && notional < market.min_notional {
+        msg!("Order value below minimum notional");
+        return Err(ErrorCode::NotionalBelowMinimum.into());
+    }
+    Ok(())
+}
+
+fn check_order_value(quantity: u64, value: u64) -> anchor_lang::Result<()> {
+    // The pricing token value of an order rounds down, so a small enough quantity would be exchanged for nothing
+    if quantity > 0 && value == 0 {
+        msg!("Order value rounds to zero pricing tokens");
+        return Err(ErrorCode::AmountTooSmall.into());
+    }
+    Ok(())
+}
+
+fn check_self_cross(ob: &mut SlabPageAlloc, market: &Market, side: Side, owner: &Pubkey, price: u64, clock_ts: i64) -> anchor_lang::Result<()> {
+    // Reject posting an order that crosses the owner's own best unexpired order on the opposite side
+    // The walk stops at the first order beyond the posted price, matching has already consumed other users' crossing orders
+    let own_order = |sl: &SlabPageAlloc, leaf: &LeafNode, order_type: OrderDT| {
+        let beyond = match side {
+            Side::Bid => Order::price(leaf.key()) > price,
+            Side::Ask => Order::price(leaf.key()) < price,
+        };
+        beyond || (leaf.owner() == *owner && !order_expired(sl.index::<Order>(order_type as u16, leaf.slot() as usize).expiry, clock_ts))
+    };
+    let node = match side {
+        Side::Bid => map_predicate_min(ob, DT::AskOrder, market.max_orders, |sl, leaf| own_order(sl, leaf, OrderDT::AskOrder)),
+        Side::Ask => map_predicate_max(ob, DT::BidOrder, market.max_orders, |sl, leaf| own_order(sl, leaf, OrderDT::BidOrder)),
+    };
+    if let Some(leaf) = node {
+        let own_price = Order::price(leaf.key());
+        let crossing = match side {
+            Side::Bid => own_price <= price,
+            Side::Ask => own_price >= price,
+        };
+        if crossing {
+            msg!("Atellix: Order crosses own resting order {} @ {}", leaf.key().to_string(), own_price.to_string());
+            return Err(ErrorCode::SelfCross.into());
+        }
+    }
+    Ok(())
+}
+
+fn check_owner_orders(ob: &mut SlabPageAlloc, market: &Market, state: &MarketState, owner: &Pubkey) -> anchor_lang::Result<()> {
+    // Count the owner's resting orders on both sides of the orderbook
+    // The market state order counters skip the scan while the whole orderbook is below the limit, and the scan stops at the limit
+    if market.max_orders_per_owner == 0 || state.active_bid.saturating_add(state.active_ask) < market.max_orders_per_owner {
+        return Ok(());
+    }
+    let mut order_count: u32 = 0;
+    for map_type in [DT::BidOrder, DT::AskOrder] {
+        if order_count >= market.max_orders_per_owner {
+            break;
+        }
+        map_predicate_min(ob, map_type, market.max_orders, |_sl, leaf| {
+            if leaf.owner() == *owner {
+                order_count = order_count + 1;
+            }
+            order_count >= market.max_orders_per_owner
+        });
+    }
+    if order_count >= market.max_orders_per_owner {
+        msg!("Atellix: Order limit reached - Owner: {} Orders: {}", owner.to_string(), order_count.to_string());
+        return Err(ErrorCode::OrderLimitExceeded.into());
+    }
+    Ok(())
+}
+
 fn get_tick_price(market: &Market, price: u64) -> anchor_lang::Result<u64> {
     let tick_decimals = market.tick_decimals;
     if tick_decimals == 0 {
@@ -880,167 +2024,1175 @@ fn get_tick_price(market: &Market, price: u64) -> anchor_lang::Result<u64> {
     Ok(rounded_price)
 }
 
-#[program]
-pub mod aqua_dex {
-    use super::*;
-
-    pub fn initialize(ctx: Context<Initialize>) -> anchor_lang::Result<()> {
-        let rt = &mut ctx.accounts.root_data;
-        rt.root_authority = ctx.accounts.auth_data.key();
+fn lot_quantity(market: &Market, quantity: u64) -> u64 {
+    // Round a quantity down to the lot size
+    if market.lot_size == 0 {
+        return quantity;
+    }
+    quantity - (quantity % market.lot_size)
+}
 
-        let auth_data: &mut[u8] = &mut ctx.accounts.auth_data.try_borrow_mut_data()?;
-        let rd = SlabPageAlloc::new(auth_data);
-        rd.setup_page_table();
-        rd.allocate::<CritMapHeader, AnyNode>(UserDT::UserRBACMap as u16, MAX_RBAC as usize).expect("Failed to allocate");
-        rd.allocate::<SlabVec, UserRBAC>(UserDT::UserRBAC as u16, MAX_RBAC as usize).expect("Failed to allocate");
+fn net_price_fill(market: &Market, price_to_fill: u64, posted_price: u64) -> anchor_lang::Result<Option<(u64, u64)>> {
+    // Part of a resting order filled by the rest of a net price: the quantity and its cost
+    // With a lot size the quantity is rounded down to the lot size and the rest of the net price is not filled (None if less than one lot)
+    let df = decimal_factor(market.mkt_decimals);
+    let fill_amount = fill_quantity(price_to_fill, posted_price, df)?;
+    if market.lot_size == 0 {
+        return Ok(Some((fill_amount, price_to_fill)));
+    }
+    let lot_amount = lot_quantity(market, fill_amount);
+    if lot_amount == 0 {
+        return Ok(None);
+    }
+    Ok(Some((lot_amount, scale_price(lot_amount, posted_price, df)?)))
+}
 
-        msg!("Atellix: Initialized AquaDEX Program");
+fn quote_quantity(market: &Market, amount: u64, price: u64) -> anchor_lang::Result<u64> {
+    // Largest market token quantity (in whole lots) that costs no more than a pricing token amount at a price
+    let mut quantity: u64 = fill_quantity(amount, price, decimal_factor(market.mkt_decimals))?;
+    if market.lot_size > 0 {
+        quantity = quantity.checked_sub(quantity % market.lot_size).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(quantity)
+}
 
-        Ok(())
+fn check_lot_size(market: &Market, quantity: u64) -> anchor_lang::Result<()> {
+    if market.lot_size > 0 && quantity % market.lot_size != 0 {
+        msg!("Quantity {} not a multiple of lot size {}", quantity.to_string(), market.lot_size.to_string());
+        return Err(ErrorCode::InvalidLotSize.into());
     }
+    Ok(())
+}
 
-    // Remove before deployment
-    /*pub fn deactivate(_ctx: Context<Deactivate>) -> anchor_lang::Result<()> {
-        msg!("Atellix: Deactivate");
-        Ok(())
-    }*/
+fn min_fill_met(filled: u64, requested: u64, min_fill_bps: u32) -> anchor_lang::Result<bool> {
+    // Check if the filled amount is at least "min_fill_bps" basis points of the requested amount
+    let filled_bps: u128 = (filled as u128).checked_mul(10000).ok_or(error!(ErrorCode::Overflow))?;
+    let min_bps: u128 = (requested as u128).checked_mul(min_fill_bps as u128).ok_or(error!(ErrorCode::Overflow))?;
+    Ok(filled_bps >= min_bps)
+}
 
-    // Program owner can close data accounts (for deprecated markets)
-    pub fn close_account(ctx: Context<CloseAccount>) -> anchor_lang::Result<()> {
+fn check_tick_size(market: &Market, price: u64) -> anchor_lang::Result<()> {
+    if market.tick_size > 0 && price % market.tick_size != 0 {
+        msg!("Price {} not a multiple of tick size {}", price.to_string(), market.tick_size.to_string());
+        return Err(ErrorCode::InvalidTickSize.into());
+    }
+    Ok(())
+}
 
-        // Move lamports from data_account to program_admin
-        let mut admin_lamports = ctx.accounts.program_admin.to_account_info().lamports();
-        admin_lamports = admin_lamports.checked_add(ctx.accounts.data_account.to_account_info().lamports()).ok_or(error!(ErrorCode::Overflow))?;
-        **ctx.accounts.program_admin.to_account_info().lamports.borrow_mut() = admin_lamports;
-        **ctx.accounts.data_account.to_account_info().lamports.borrow_mut() = 0;
+fn cancel_posted_order(
+    market: &Market,
+    state: &mut MarketState,
+    sl: &mut SlabPageAlloc,
+    side: Side,
+    leaf: &LeafNode,
+    result: &mut WithdrawResult,
+) -> anchor_lang::Result<(u64, u64)> {
+    // Release the tokens held by the order and remove it from the orderbook, returns the order quantity and the tokens withdrawn
+    let order_type = match side {
+        Side::Bid => DT::BidOrder,
+        Side::Ask => DT::AskOrder,
+    };
+    let order_qty = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize).total_amount();
+    state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    let tokens_out = match side {
+        Side::Bid => {
+            state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            let total = scale_price(order_qty, Order::price(leaf.key()), decimal_factor(market.mkt_decimals))?;
+            result.set_prc_tokens(result.prc_tokens.checked_add(total).ok_or(error!(ErrorCode::Overflow))?);
+            state.prc_vault_balance = state.prc_vault_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_order_balance = state.prc_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+            total
+        },
+        Side::Ask => {
+            state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            let total = order_qty;
+            result.set_mkt_tokens(result.mkt_tokens.checked_add(total).ok_or(error!(ErrorCode::Overflow))?);
+            state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+            state.mkt_order_balance = state.mkt_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+            total
+        }
+    };
+    map_remove(sl, order_type, market.max_orders, leaf.key())?;
+    Order::free_index(sl, order_type, leaf.slot())?;
+    state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    Ok((order_qty, tokens_out))
+}
 
-        msg!("Atellix: Close Account: {}", ctx.accounts.data_account.key().to_string());
-        Ok(())
+fn manager_settle_order<'info>(
+    accounts: &mut ManagerCancelOrder<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+    inp_side: u8,
+    inp_order_id: u128,
+    inp_rollover: bool,
+) -> anchor_lang::Result<(Side, LeafNode, u64, u64)> {
+    // Remove an order for the manager and credit its tokens to the owner in the settlement log ("manager_cancel_order" and "manager_force_settle")
+    // Returns the side, the removed order's leaf node, the order quantity and the tokens credited
+    let market = &accounts.market;
+    let market_state = &accounts.state;
+    let acc_manager = &accounts.manager.to_account_info();
+    let acc_orders = &accounts.orders.to_account_info();
+    let acc_settle1 = &accounts.settle_a.to_account_info();
+    let acc_settle2 = &accounts.settle_b.to_account_info();
+    let acc_result = &accounts.result.to_account_info();
+
+    if *acc_result.key != accounts.manager.key() {
+        verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
     }
 
-    pub fn store_metadata(ctx: Context<UpdateMetadata>,
-        inp_program_name: String,
-        inp_developer_name: String,
-        inp_developer_url: String,
-        inp_source_url: String,
-        inp_verify_url: String,
-    ) -> anchor_lang::Result<()> {
-        let md = &mut ctx.accounts.program_info;
-        md.semvar_major = VERSION_MAJOR;
-        md.semvar_minor = VERSION_MINOR;
-        md.semvar_patch = VERSION_PATCH;
-        md.program = ctx.accounts.program.key();
-        md.program_name = inp_program_name;
-        md.developer_name = inp_developer_name;
-        md.developer_url = inp_developer_url;
-        md.source_url = inp_source_url;
-        md.verify_url = inp_verify_url;
-        msg!("Program: {}", ctx.accounts.program.key.to_string());
-        msg!("Program Name: {}", md.program_name.as_str());
-        msg!("Version: {}.{}.{}", VERSION_MAJOR.to_string(), VERSION_MINOR.to_string(), VERSION_PATCH.to_string());
-        msg!("Developer Name: {}", md.developer_name.as_str());
-        msg!("Developer URL: {}", md.developer_url.as_str());
-        msg!("Source URL: {}", md.source_url.as_str());
-        msg!("Verify URL: {}", md.verify_url.as_str());
-        Ok(())
+    if market.manager != *acc_manager.key {
+        msg!("Not manager");
+        return Err(ErrorCode::AccessDenied.into());
+    }
+    verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+    verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+    verify_orderbook_version(market)?;
+
+    let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+    let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+    if s1.is_err() || s2.is_err() {
+        // This is expected to happen sometimes due to a race condition between settlment log rollovers and new orders
+        // Reload the current "market" account with the latest settlement log accounts and retry the transaction
+        msg!("Please update market data and retry");
+        return Err(ErrorCode::RetrySettlementAccount.into()); 
     }
 
-    pub fn grant(ctx: Context<UpdateRBAC>,
-        _inp_root_nonce: u8,
-        inp_role: u32,
-    ) -> anchor_lang::Result<()> {
-        let acc_rbac = &ctx.accounts.rbac_user.to_account_info();
-        let acc_admn = &ctx.accounts.program_admin.to_account_info();
-        let acc_auth = &ctx.accounts.auth_data.to_account_info();
-
-        // Check for NetworkAdmin authority
-        let admin_role = has_role(&acc_auth, Role::NetworkAdmin, acc_admn.key);
-        let mut program_owner: bool = false;
-        if admin_role.is_err() {
-            let acc_pdat = &ctx.accounts.program_data;
-            verify_matching_accounts(&acc_pdat.upgrade_authority_address.unwrap(), acc_admn.key, Some(String::from("Invalid program owner")))?;
-            program_owner = true;
+    // Append a settlement log account
+    let state_upd = &mut accounts.state;
+    if inp_rollover {
+        if !state_upd.log_rollover {
+            // Another market participant already appended a new log account (please retry transaction)
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
         }
+        let av = remaining_accounts;
+        let new_settlement_log = av.get(0).unwrap();
+        let market_pk: Pubkey = market.key();
+        log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
+        // Manager is not reimbursed for settlement log rollover
+    }
 
-        // Verify specified role
-        let role_item = Role::try_from_primitive(inp_role);
-        if role_item.is_err() {
-            msg!("Invalid role: {}", inp_role.to_string());
-            return Err(ErrorCode::InvalidParameters.into());
+    let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+    let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+    let sl = SlabPageAlloc::new(order_data);
+    let order_type = match side {
+        Side::Bid => DT::BidOrder,
+        Side::Ask => DT::AskOrder,
+    };
+    let item = map_get(sl, order_type, market.max_orders, inp_order_id);
+    if item.is_none() {
+        msg!("Order not found");
+        return Err(ErrorCode::OrderNotFound.into());
+    }
+    let leaf = item.unwrap();
+    let order = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+    let state = &mut accounts.state;
+    state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+    let order_id = leaf.key();
+    let order_owner: Pubkey = leaf.owner();
+    let order_price = Order::price(order_id);
+    let order_qty = order.total_amount();
+    let tokens_out = match side {
+        Side::Bid => {
+            let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+            result.set_prc_tokens(total);
+            log_settlement(&market.key(), state, acc_settle1, acc_settle2, &order_owner, false, total)?;
+            state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            total
+        },
+        Side::Ask => {
+            let total = order.total_amount();
+            result.set_mkt_tokens(total);
+            log_settlement(&market.key(), state, acc_settle1, acc_settle2, &order_owner, true, total)?;
+            state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            total
         }
-        let role = role_item.unwrap();
-        if role == Role::NetworkAdmin && ! program_owner {
-            msg!("Reserved for program owner");
-            return Err(ErrorCode::AccessDenied.into());
+    };
+    map_remove(sl, order_type, market.max_orders, leaf.key())?;
+    Order::free_index(sl, order_type, leaf.slot())?;
+
+    if *acc_result.key != *acc_manager.key {
+        store_struct::<WithdrawResult>(&result, acc_result)?;
+    }
+    Ok((side, leaf, order_qty, tokens_out))
+}
+
+fn cancel_owner_order<'info>(
+    market: &Account<'info, Market>,
+    state: &mut Account<'info, MarketState>,
+    acc_orders: &AccountInfo<'info>,
+    acc_owner: &AccountInfo<'info>,
+    inp_side: u8,
+    inp_order_id: u128,
+    clock_ts: i64,
+) -> anchor_lang::Result<(Side, u128, u64, u64, u64)> {
+    // Remove an order cancelled by its owner, pay the log rebate, and return the side, order id, price, quantity and tokens released
+    // The released tokens are removed from the order balance, the caller transfers them or credits them to the user vault
+    verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+    verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+    verify_orderbook_version(market)?;
+
+    let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+    let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+    let sl = SlabPageAlloc::new(order_data);
+    let order_type = match side {
+        Side::Bid => DT::BidOrder,
+        Side::Ask => DT::AskOrder,
+    };
+    let item = map_get(sl, order_type, market.max_orders, inp_order_id);
+    if item.is_none() {
+        msg!("Order not found");
+        return Err(ErrorCode::OrderNotFound.into());
+    }
+    let leaf = item.unwrap();
+    if leaf.owner() != *acc_owner.key {
+        msg!("Order not owned by user");
+        return Err(ErrorCode::AccessDenied.into());
+    }
+    let order = *sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+    check_order_age(market, &order, clock_ts)?;
+    state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    if side == Side::Bid {
+        state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+    } else if side == Side::Ask {
+        state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+    }
+
+    let order_id = leaf.key();
+    let order_price = Order::price(order_id);
+    let order_qty = order.total_amount();
+    let tokens_out = match side {
+        Side::Bid => {
+            let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+            state.prc_order_balance = state.prc_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+            total
+        },
+        Side::Ask => {
+            state.mkt_order_balance = state.mkt_order_balance.checked_sub(order_qty).ok_or(error!(ErrorCode::Overflow))?;
+            order_qty
         }
+    };
+    map_remove(sl, order_type, market.max_orders, leaf.key())?;
+    Order::free_index(sl, order_type, leaf.slot())?;
+
+    // Rebate to the user for settlement log space
+    state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    let mut market_lamports = state.to_account_info().lamports();
+    market_lamports = market_lamports.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    **state.to_account_info().lamports.borrow_mut() = market_lamports;
+    let mut user_lamports = acc_owner.lamports();
+    user_lamports = user_lamports.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    **acc_owner.lamports.borrow_mut() = user_lamports;
+
+    Ok((side, order_id, order_price, order_qty, tokens_out))
+}
 
-        let auth_data: &mut[u8] = &mut acc_auth.try_borrow_mut_data()?;
-        let rd = SlabPageAlloc::new(auth_data);
-        let authhash: u128 = CritMap::bytes_hash([[role as u32].as_byte_slice(), acc_rbac.key.as_ref()].concat().as_slice());
+fn remove_filled_order(
+    market: &Market,
+    market_key: &Pubkey,
+    state: &mut MarketState,
+    ob: &mut SlabPageAlloc,
+    side: Side,
+    leaf: &LeafNode,
+) -> anchor_lang::Result<()> {
+    // Remove a filled order from the orderbook, or replenish an iceberg order from its hidden quantity
+    let order_type = match side {
+        Side::Bid => DT::BidOrder,
+        Side::Ask => DT::AskOrder,
+    };
+    let order = *ob.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+    map_remove(ob, order_type, market.max_orders, leaf.key())?;
+    if order.hidden_qty > 0 {
+        // Re-key the order with a new sequence number so the replenished slice loses time priority
+        let display_amount = if order.display_qty < order.hidden_qty { order.display_qty } else { order.hidden_qty };
+        let hidden_qty = order.hidden_qty.checked_sub(display_amount).ok_or(error!(ErrorCode::Overflow))?;
+        let price = Order::price(leaf.key());
+        let order_id = Order::new_key(state, side, price);
+        let order_node = LeafNode::new(order_id, leaf.slot(), &leaf.owner());
+        map_insert(ob, order_type, market.max_orders, &order_node).map_err(|_| error!(ErrorCode::InternalError))?;
+        let order_upd = ob.index_mut::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        order_upd.set_amount(display_amount);
+        order_upd.hidden_qty = hidden_qty;
+        msg!("Atellix: Replenished Order[{}] - Owner: {} {} @ {} Hidden: {}",
+            leaf.slot().to_string(),
+            leaf.owner().to_string(),
+            display_amount.to_string(),
+            price.to_string(),
+            hidden_qty.to_string(),
+        );
+        emit!(ReplenishEvent {
+            event_type: 71367406305096297077397554319910278716, // solana/program/aqua-dex/replenish_event
+            action_id: state.action_counter,
+            market: *market_key,
+            owner: leaf.owner(),
+            order_side: side as u8,
+            prev_order_id: leaf.key(),
+            order_id: order_id,
+            price: price,
+            quantity: display_amount,
+            hidden_quantity: hidden_qty,
+        });
+        return Ok(());
+    }
+    Order::free_index(ob, order_type, leaf.slot())?;
+    match side {
+        Side::Bid => state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?,
+        Side::Ask => state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?,
+    };
+    Ok(())
+}
 
-        // Check if record exists
-        let authrec = map_get(rd, DT::UserRBAC, authhash);
-        if authrec.is_some() {
-            msg!("Role already active");
+fn cancel_dust_remainder(
+    market: &Market,
+    market_key: &Pubkey,
+    state: &mut MarketState,
+    ob: &mut SlabPageAlloc,
+    settle_a: &AccountInfo,
+    settle_b: &AccountInfo,
+    side: Side,
+    leaf: &LeafNode,
+) -> anchor_lang::Result<()> {
+    // Remove a partially filled maker order left below "min_quantity" and credit the remainder to the owner's settlement log entry
+    if market.dust_policy != DustPolicy::CancelRemainder as u8 || market.min_quantity == 0 {
+        return Ok(());
+    }
+    let order_type = match side {
+        Side::Bid => DT::BidOrder,
+        Side::Ask => DT::AskOrder,
+    };
+    let remaining: u64 = ob.index::<Order>(index_datatype(order_type), leaf.slot() as usize).total_amount();
+    if remaining == 0 || remaining >= market.min_quantity {
+        return Ok(());
+    }
+    // The remainder stays in the orderbook if the settlement logs are full (as with expired orders)
+    if !settlement_available(state, settle_a, settle_b, &leaf.owner())? {
+        msg!("Atellix: Dust order not cancelled - Settlement logs full");
+        return Ok(());
+    }
+    state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+    let price = Order::price(leaf.key());
+    // Bids return the pricing tokens for the remainder at the order price (as when an order expires)
+    let tokens: u64 = match side {
+        Side::Bid => scale_price(remaining, price, decimal_factor(market.mkt_decimals))?,
+        Side::Ask => remaining,
+    };
+    msg!("Atellix: Cancelled Dust Order[{}] - Owner: {} {} @ {}",
+        leaf.slot().to_string(),
+        leaf.owner().to_string(),
+        remaining.to_string(),
+        price.to_string(),
+    );
+    emit!(DustCancelEvent {
+        event_type: 304145527180135715336295281219160519643, // solana/program/aqua-dex/dust_cancel_event
+        action_id: state.action_counter,
+        market: *market_key,
+        owner: leaf.owner(),
+        order_side: side as u8,
+        order_id: leaf.key(),
+        price: price,
+        quantity: remaining,
+        tokens: tokens,
+    });
+    log_settlement(market_key, state, settle_a, settle_b, &leaf.owner(), side == Side::Ask, tokens)?;
+    map_remove(ob, order_type, market.max_orders, leaf.key())?;
+    Order::free_index(ob, order_type, leaf.slot())?;
+    match side {
+        Side::Bid => state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?,
+        Side::Ask => state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?,
+    };
+    Ok(())
+}
+
+fn execute_stop(
+    market: &Market,
+    market_key: &Pubkey,
+    state: &mut MarketState,
+    ob: &mut SlabPageAlloc,
+    tlog: &mut SlabPageAlloc,
+    settle_a: &AccountInfo,
+    settle_b: &AccountInfo,
+    side: Side,
+    owner: &Pubkey,
+    stop: &StopOrder,
+    match_event: u128,
+    clock_ts: i64,
+) -> anchor_lang::Result<(u64, u128)> {
+    // Match a triggered stop against the orderbook for its owner and post the remainder as a limit order (if allowed)
+    // Fills and unused deposits are credited to the owner in the settlement log, returns the quantity filled and the posted order id
+    let stop_qty = stop.amount;
+    let stop_price = stop.price;
+    let mut tokens_to_fill: u64 = stop_qty;
+    let mut tokens_filled: u64 = 0;
+    let mut tokens_paid: u64 = 0;
+    let mut tokens_fee: u64 = 0;
+    let mut fee_budget: u64 = 0;
+    let waived = fee_waived(market, clock_ts);
+    let taker_fee: u32 = if waived { 0 } else { market.taker_fee };
+    let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+    let mut deposit_limited: bool = stop_price == u64::MAX;
+    if side == Side::Bid {
+        // Fees can not exceed the amount deposited for them when the stop was placed (bids without a limit price are limited by the deposit)
+        // Bids with a deposit below the cost at the limit price ("expire_to_market" slippage) are also limited by the deposit
+        if !deposit_limited {
+            let stop_cost = scale_price(stop_qty, stop_price, decimal_factor(market.mkt_decimals))?;
+            deposit_limited = stop_cost > stop.deposit;
+            if !deposit_limited {
+                fee_budget = stop.deposit.checked_sub(stop_cost).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        }
+        if deposit_limited {
+            fee_budget = stop.deposit;
+        }
+    }
+    let mut expired_orders = Vec::new();
+    let book_type = match side {
+        Side::Bid => OrderDT::AskOrder,
+        Side::Ask => OrderDT::BidOrder,
+    };
+    let mut skip = MatchSkip::new(book_type);
+    let mut halted: bool = false;
+    let breaker_price = state.last_price;
+    while tokens_to_fill > 0 {
+        let mut scan = |sl: &SlabPageAlloc, leaf: &LeafNode| {
+            skip.check(leaf.key(), || valid_order(book_type, leaf, owner, market.allow_self_trade, sl, &mut expired_orders, clock_ts))
+        };
+        let node_res = match side {
+            Side::Bid => map_predicate_min(ob, DT::AskOrder, market.max_orders, &mut scan),
+            Side::Ask => map_predicate_max(ob, DT::BidOrder, market.max_orders, &mut scan),
+        };
+        if skip.limit_reached() {
+            // Stop without posting the remainder (it could cross the orders behind the skipped ones), failing would block the other stops
+            msg!("Atellix: Too many expired or same-user orders skipped - Matching stopped");
+            halted = true;
+            break;
+        }
+        if node_res.is_none() {
+            break;
+        }
+        let posted_node = node_res.unwrap();
+        let posted_price = Order::price(posted_node.key());
+        if (side == Side::Bid && posted_price > stop_price) || (side == Side::Ask && posted_price < stop_price) {
+            // Best price beyond limit price
+            break;
+        }
+        if breaker_tripped(market, breaker_price, posted_price)? {
+            // Halt trading without posting the remainder at a crossing price
+            state.breaker_tripped_ts = clock_ts;
+            halted = true;
+            break;
+        }
+        if !settlement_available(state, settle_a, settle_b, &posted_node.owner())? || !settlement_available(state, settle_a, settle_b, owner)? {
+            msg!("Atellix: Settlement logs full - Matching stopped");
+            break;
+        }
+        let posted_qty = ob.index::<Order>(book_type as u16, posted_node.slot() as usize).amount();
+        let posted_total = ob.index::<Order>(book_type as u16, posted_node.slot() as usize).total_amount();
+        let mut fill_qty = if posted_qty < tokens_to_fill { posted_qty } else { tokens_to_fill };
+        let mut tokens_part = scale_price(fill_qty, posted_price, decimal_factor(market.mkt_decimals))?;
+        if side == Side::Bid && deposit_limited {
+            // Buy only what the rest of the deposit can pay for, including the fee
+            let funds_left = stop.deposit.checked_sub(tokens_paid).ok_or(error!(ErrorCode::Overflow))?
+                .checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
+            let fill_cost = tokens_part.checked_add(calculate_fee(taker_fee, tokens_part)?).ok_or(error!(ErrorCode::Overflow))?;
+            if fill_cost > funds_left {
+                let net_funds = funds_left.checked_sub(calculate_fee(taker_fee, funds_left)?).ok_or(error!(ErrorCode::Overflow))?;
+                fill_qty = fill_quantity(net_funds, posted_price, decimal_factor(market.mkt_decimals))?;
+                if fill_qty == 0 {
+                    break;
+                }
+                tokens_part = scale_price(fill_qty, posted_price, decimal_factor(market.mkt_decimals))?;
+            }
+        }
+        let maker_filled = fill_qty == posted_qty;
+        let (mut fee_part, mut rebate_part, _) = fill_fees(taker_fee, maker_rebate, 0, tokens_part)?;
+        if side == Side::Bid {
+            let fee_left = fee_budget.checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
+            if fee_part > fee_left {
+                fee_part = fee_left;
+                if rebate_part > fee_part {
+                    rebate_part = fee_part;
+                }
+            }
+        }
+        tokens_to_fill = tokens_to_fill.checked_sub(fill_qty).ok_or(error!(ErrorCode::Overflow))?;
+        tokens_filled = tokens_filled.checked_add(fill_qty).ok_or(error!(ErrorCode::Overflow))?;
+        tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
+        tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
+        msg!("Atellix: Filling - {} @ {}", fill_qty.to_string(), posted_price.to_string());
+        let dust_part = match side {
+            Side::Bid => 0,
+            Side::Ask => rounding_dust(state, posted_total, fill_qty, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?,
+        };
+        log_trade(tlog,
+            match_event,
+            state.action_counter,
+            market_key,
+            posted_node.key(),
+            maker_filled,
+            &posted_node.owner(),
+            owner,
+            side as u8,
+            fill_qty,
+            posted_price,
+            rebate_part,
+            fee_part,
+            0,
+            dust_part,
+            &Pubkey::default(),
+            0,
+            clock_ts
+        )?;
+        if maker_filled {
+            let maker_side = match side {
+                Side::Bid => Side::Ask,
+                Side::Ask => Side::Bid,
+            };
+            remove_filled_order(market, market_key, state, ob, maker_side, &posted_node)?;
         } else {
-            // Add new record
-            let new_item = map_insert(rd, DT::UserRBAC, &LeafNode::new(authhash, 0, acc_rbac.key));
-            if new_item.is_err() {
-                msg!("Unable to insert role");
-                return Err(ErrorCode::InternalError.into());
+            let new_amount = posted_qty.checked_sub(fill_qty).ok_or(error!(ErrorCode::Overflow))?;
+            ob.index_mut::<Order>(book_type as u16, posted_node.slot() as usize).set_amount(new_amount);
+            let maker_side = match side {
+                Side::Bid => Side::Ask,
+                Side::Ask => Side::Bid,
+            };
+            cancel_dust_remainder(market, market_key, state, ob, settle_a, settle_b, maker_side, &posted_node)?;
+        }
+        accumulate_price(state, clock_ts)?;
+        state.last_price = posted_price;
+        state.last_ts = clock_ts;
+        add_volume(state, fill_qty, tokens_part)?;
+
+        // Apply fees
+        state.prc_order_balance = state.prc_order_balance.checked_sub(fee_part).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_fees_balance = state.prc_fees_balance.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_fees_balance = state.prc_fees_balance.checked_sub(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_order_balance = state.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
+        if side == Side::Bid {
+            let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
+            log_settlement(market_key, state, settle_a, settle_b, &posted_node.owner(), false, total)?;
+            log_settlement(market_key, state, settle_a, settle_b, owner, true, fill_qty)?;
+        } else {
+            log_settlement(market_key, state, settle_a, settle_b, &posted_node.owner(), true, fill_qty)?;
+            if rebate_part > 0 {
+                log_settlement(market_key, state, settle_a, settle_b, &posted_node.owner(), false, rebate_part)?;
             }
-            let rbac_idx = UserRBAC::next_index(rd, DT::UserRBAC)?;
-            let mut cm = CritMap { slab: rd, type_id: map_datatype(DT::UserRBAC), capacity: map_len(DT::UserRBAC) };
-            cm.get_key_mut(authhash).unwrap().set_slot(rbac_idx);
-            *rd.index_mut(index_datatype(DT::UserRBAC), rbac_idx as usize) = UserRBAC { role: role, free: 0 };
-            msg!("Atellix: Role granted");
+            let proceeds = tokens_part.checked_sub(fee_part).ok_or(error!(ErrorCode::Overflow))?;
+            log_settlement(market_key, state, settle_a, settle_b, owner, false, proceeds)?;
         }
-        Ok(())
     }
 
-    pub fn revoke(ctx: Context<UpdateRBAC>,
-        _inp_root_nonce: u8,
-        inp_role: u32,
-    ) -> anchor_lang::Result<()> {
-        let acc_admn = &ctx.accounts.program_admin.to_account_info(); // Program owner or network admin
-        let acc_auth = &ctx.accounts.auth_data.to_account_info();
-        let acc_rbac = &ctx.accounts.rbac_user.to_account_info();
+    // Post the remainder to the orderbook (unused tokens are returned if the order can not be posted)
+    let mut order_id: u128 = 0;
+    let mut posted_qty: u64 = 0;
+    let order_mode = OrderMode::try_from(stop.order_mode).or(Err(error!(ErrorCode::InvalidParameters)))?;
+    if tokens_to_fill > 0 && !halted && order_mode == OrderMode::Limit && check_price_band(market, state.last_price, stop_price).is_ok() &&
+        check_owner_orders(ob, market, state, owner).is_ok() {
+        let order_type = match side {
+            Side::Bid => DT::BidOrder,
+            Side::Ask => DT::AskOrder,
+        };
+        let new_id = Order::new_key(state, side, stop_price);
+        let order_idx = Order::next_index(ob, order_type)?;
+        let order_node = LeafNode::new(new_id, order_idx, owner);
+        if map_insert(ob, order_type, market.max_orders, &order_node).is_ok() {
+            *ob.index_mut::<Order>(index_datatype(order_type), order_idx as usize) = Order { amount: tokens_to_fill, expiry: stop.expiry, display_qty: 0, hidden_qty: 0, placed_ts: clock_ts, expire_to_market: 0 };
+            match side {
+                Side::Bid => state.active_bid = state.active_bid.checked_add(1).ok_or(error!(ErrorCode::Overflow))?,
+                Side::Ask => state.active_ask = state.active_ask.checked_add(1).ok_or(error!(ErrorCode::Overflow))?,
+            };
+            order_id = new_id;
+            posted_qty = tokens_to_fill;
+            msg!("Atellix: Posted Stop Remainder [{}] {} @ {}", order_idx.to_string(), posted_qty.to_string(), stop_price.to_string());
+        } else {
+            Order::free_index(ob, order_type, order_idx)?;
+            msg!("Atellix: Orderbook Full - Stop remainder not posted");
+        }
+    }
+    match side {
+        Side::Bid => {
+            let posted_cost = scale_price(posted_qty, stop_price, decimal_factor(market.mkt_decimals))?;
+            let refund = stop.deposit.checked_sub(tokens_paid).ok_or(error!(ErrorCode::Overflow))?
+                .checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?
+                .checked_sub(posted_cost).ok_or(error!(ErrorCode::Overflow))?;
+            if refund > 0 {
+                log_settlement(market_key, state, settle_a, settle_b, owner, false, refund)?;
+            }
+        },
+        Side::Ask => {
+            let refund = stop.deposit.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?
+                .checked_sub(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
+            if refund > 0 {
+                log_settlement(market_key, state, settle_a, settle_b, owner, true, refund)?;
+            }
+        },
+    };
+    Ok((tokens_filled, order_id))
+}
 
-        // Check for NetworkAdmin authority
-        let admin_role = has_role(&acc_auth, Role::NetworkAdmin, acc_admn.key);
-        let mut program_owner: bool = false;
-        if admin_role.is_err() {
-            let acc_pdat = &ctx.accounts.program_data;
-            verify_matching_accounts(&acc_pdat.upgrade_authority_address.unwrap(), acc_admn.key, Some(String::from("Invalid program owner")))?;
-            program_owner = true;
+fn load_user_volume(acc_volume: &AccountInfo, market: &Pubkey, owner: &Pubkey) -> anchor_lang::Result<UserVolume> {
+    if *acc_volume.owner != ID {
+        msg!("Invalid user volume");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    let volume = load_struct::<UserVolume>(acc_volume)?;
+    verify_matching_accounts(&volume.market, market, Some(String::from("Invalid user volume market")))?;
+    verify_matching_accounts(&volume.owner, owner, Some(String::from("Invalid user volume owner")))?;
+    Ok(volume)
+}
+
+fn quote_fill(
+    market: &Market,
+    ob: &mut SlabPageAlloc,
+    side: Side,
+    by_quantity: bool,
+    quantity: u64,
+    net_price: u64,
+    clock_ts: i64,
+) -> anchor_lang::Result<QuoteResult> {
+    // Collect the matching orders best price first (read-only), iceberg orders are replenished at their price from the hidden quantity
+    // Fills are priced with the market taker fee (fee tiers, utility token discounts and referrals depend on the user and are not applied)
+    let df = decimal_factor(market.mkt_decimals);
+    let waived = fee_waived(market, clock_ts);
+    let taker_fee: u32 = if waived { 0 } else { market.taker_fee };
+    let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+    let target: u128 = if by_quantity { quantity as u128 } else { net_price as u128 };
+    let mut book: Vec<(u64, u64)> = Vec::new();
+    let mut book_total: u128 = 0;
+    let mut expired_orders = Vec::new();
+    let user_key = Pubkey::default();
+    let order_type = match side {
+        Side::Bid => OrderDT::AskOrder,
+        Side::Ask => OrderDT::BidOrder,
+    };
+    let collect = |sl: &SlabPageAlloc, leaf: &LeafNode| {
+        if !valid_order(order_type, leaf, &user_key, market.allow_self_trade, sl, &mut expired_orders, clock_ts) {
+            return false;
+        }
+        let price = Order::price(leaf.key());
+        let amount = sl.index::<Order>(order_type as u16, leaf.slot() as usize).total_amount();
+        book.push((price, amount));
+        if by_quantity {
+            book_total = book_total.saturating_add(amount as u128);
+        } else {
+            book_total = book_total.saturating_add((amount as u128).saturating_mul(price as u128) / (df as u128));
         }
+        book_total >= target
+    };
+    match side {
+        Side::Bid => map_predicate_min(ob, DT::AskOrder, market.max_orders, collect),
+        Side::Ask => map_predicate_max(ob, DT::BidOrder, market.max_orders, collect),
+    };
 
-        // Verify specified role
-        let role_item = Role::try_from_primitive(inp_role);
-        if role_item.is_err() {
-            msg!("Invalid role: {}", inp_role.to_string());
-            return Err(ErrorCode::InvalidParameters.into());
+    // Apply the same fill calculations as "market_bid" and "market_ask" ("net_price_fill" and "fill_fees")
+    let mut tokens_to_fill: u64 = quantity;
+    let mut price_to_fill: u64 = net_price;
+    let mut tokens_filled: u64 = 0;
+    let mut tokens_paid: u64 = 0;
+    let mut tokens_fee: u64 = 0;
+    for (posted_price, posted_qty) in book.iter() {
+        let fill_amount: u64;
+        let fill_part: u64;
+        if by_quantity {
+            fill_amount = if *posted_qty < tokens_to_fill { *posted_qty } else { tokens_to_fill };
+            fill_part = scale_price(fill_amount, *posted_price, df)?;
+            tokens_to_fill = tokens_to_fill.checked_sub(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
+        } else {
+            let posted_part = scale_price(*posted_qty, *posted_price, df)?;
+            if posted_part <= price_to_fill {
+                fill_amount = *posted_qty;
+                fill_part = posted_part;
+            } else {
+                let fill = match net_price_fill(market, price_to_fill, *posted_price)? {
+                    Some(fill) => fill,
+                    None => break,
+                };
+                fill_amount = fill.0;
+                fill_part = fill.1;
+                price_to_fill = fill_part;
+            }
+            price_to_fill = price_to_fill.checked_sub(fill_part).ok_or(error!(ErrorCode::Overflow))?;
         }
-        let role = role_item.unwrap();
-        if role == Role::NetworkAdmin && ! program_owner {
-            msg!("Reserved for program owner");
-            return Err(ErrorCode::AccessDenied.into());
+        let (fee_part, _, _) = fill_fees(taker_fee, maker_rebate, 0, fill_part)?;
+        tokens_filled = tokens_filled.checked_add(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
+        tokens_paid = tokens_paid.checked_add(fill_part).ok_or(error!(ErrorCode::Overflow))?;
+        tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
+        if (by_quantity && tokens_to_fill == 0) || (!by_quantity && price_to_fill == 0) {
+            break;
         }
+    }
 
-        let auth_data: &mut[u8] = &mut acc_auth.try_borrow_mut_data()?;
-        let rd = SlabPageAlloc::new(auth_data);
-        let authhash: u128 = CritMap::bytes_hash([[role as u32].as_byte_slice(), acc_rbac.key.as_ref()].concat().as_slice());
+    let avg_price: u64 = if tokens_filled > 0 {
+        let avg_calc: u128 = (tokens_paid as u128).checked_mul(df as u128).ok_or(error!(ErrorCode::Overflow))?
+            .checked_div(tokens_filled as u128).ok_or(error!(ErrorCode::Overflow))?;
+        u64::try_from(avg_calc).map_err(|_| error!(ErrorCode::Overflow))?
+    } else {
+        0
+    };
+    let (tokens_in, tokens_out) = match side {
+        Side::Bid => (tokens_paid.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?, tokens_filled),
+        Side::Ask => (tokens_filled, tokens_paid.checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?),
+    };
+    Ok(QuoteResult {
+        tokens_in: tokens_in,
+        tokens_out: tokens_out,
+        avg_price: avg_price,
+        fee: tokens_fee,
+        fully_filled: if by_quantity { tokens_to_fill == 0 } else { price_to_fill == 0 },
+    })
+}
 
-        // Check if record exists
-        let authrec = map_get(rd, DT::UserRBAC, authhash);
-        if authrec.is_some() {
-            map_remove(rd, DT::UserRBAC, authhash).or(Err(error!(ErrorCode::InternalError)))?;
-            UserRBAC::free_index(rd, DT::UserRBAC, authrec.unwrap().slot())?;
-            msg!("Atellix: Role revoked");
-        } else {
-            msg!("Atellix: Role not found");
+fn fee_waived(market: &Market, clock_ts: i64) -> bool {
+    // Taker fees and maker rebates are not charged during the promotional period before "fee_waiver_until"
+    clock_ts < market.fee_waiver_until
+}
+
+fn taker_fee_rate(market: &Market, volume: u128) -> u32 {
+    // Use the fee of the highest tier reached by the user's volume (tiers with a zero threshold are unused)
+    let mut fee_rate: u32 = market.taker_fee;
+    let mut tier_threshold: u128 = 0;
+    for tier in market.fee_tiers.iter() {
+        if tier.volume_threshold > 0 && volume >= tier.volume_threshold && tier.volume_threshold > tier_threshold {
+            fee_rate = tier.taker_fee;
+            tier_threshold = tier.volume_threshold;
         }
-        Ok(())
+    }
+    fee_rate
+}
+
+fn fee_discount_rate(
+    market: &Market,
+    taker_fee: u32,
+    user: &Pubkey,
+    discount_token: &AccountInfo,
+    discount_vault: &AccountInfo,
+) -> anchor_lang::Result<u32> {
+    // Part of the taker fee rate paid in utility tokens instead (the discount token account is the user account if not used)
+    if *discount_token.key == *user {
+        return Ok(0);
+    }
+    if market.discount_mint == Pubkey::default() || market.discount_bps == 0 {
+        msg!("Atellix: Fee discount not available");
+        return Ok(0);
+    }
+    verify_matching_accounts(&market.discount_vault, discount_vault.key, Some(String::from("Invalid discount vault")))?;
+    let token_acct = load_struct::<SPL_TokenAccount>(discount_token)?;
+    verify_matching_accounts(&token_acct.mint, &market.discount_mint, Some(String::from("Invalid discount token mint")))?;
+    let discount: u64 = (taker_fee as u64).checked_mul(market.discount_bps as u64).ok_or(error!(ErrorCode::Overflow))? / 10000;
+    // The discounted fee must still cover the maker rebate
+    let max_discount: u32 = taker_fee.saturating_sub(market.maker_rebate);
+    Ok(u32::min(discount as u32, max_discount))
+}
+
+fn collect_fee_discount<'info>(
+    market: &Market,
+    discount: u64,
+    preview: bool,
+    discount_token: &AccountInfo<'info>,
+    discount_vault: &AccountInfo<'info>,
+    user: &AccountInfo<'info>,
+    spl_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<u64> {
+    // Charge the fee discount in utility tokens at "discount_rate" utility tokens per whole pricing token
+    if discount == 0 {
+        return Ok(0);
+    }
+    // Rounded up so a discount always costs at least one raw utility token (the rate is positive when discounts are enabled)
+    let df = decimal_factor(market.prc_decimals) as u128;
+    let utility_calc: u128 = (discount as u128).checked_mul(market.discount_rate as u128).ok_or(error!(ErrorCode::Overflow))?
+        .checked_add(df - 1).ok_or(error!(ErrorCode::Overflow))? / df;
+    let utility_fee: u64 = u64::try_from(utility_calc).map_err(|_| error!(ErrorCode::Overflow))?;
+    msg!("Atellix: Fee Discount: {} Utility Token Fee: {}", discount.to_string(), utility_fee.to_string());
+    if utility_fee > 0 {
+        perform_transfer(&[], MintType::SPLToken, 0, utility_fee, preview,
+            discount_token,     // From
+            discount_vault,     // To
+            user,               // Auth
+            spl_prog,           // SPL Token Program
+        )?;
+    }
+    Ok(utility_fee)
+}
+
+fn create_user_vault<'info>(
+    market: &Pubkey,
+    owner: &AccountInfo<'info>,
+    vault: &AccountInfo<'info>,
+    system_prog: &AccountInfo<'info>,
+) -> anchor_lang::Result<UserVault> {
+    // Create the owner's user vault PDA (the same account as "vault_fund"), paid by the owner
+    let (_, bump) = Pubkey::find_program_address(&[market.as_ref(), owner.key.as_ref()], &ID);
+    create_pda_account(owner, vault, USER_VAULT_LEN, &[market.as_ref(), owner.key.as_ref(), &[bump]], system_prog)?;
+    Ok(UserVault { initialized: true, market: *market, owner: *owner.key, mkt_tokens: 0, prc_tokens: 0 })
+}
+
+fn withdraw_log_entry(
+    market: &Pubkey,
+    acc_settle: &AccountInfo,
+    owner: &Pubkey,
+    withdraw_mkt: bool,
+    withdraw_prc: bool,
+) -> anchor_lang::Result<(WithdrawResult, bool)> {
+    // Remove the selected balances from the owner's settlement log entry, and the entry once both balances are withdrawn
+    // Returns the balances removed and if the entry was removed (the caller pays the log rebate with "close_log_entry")
+    if !withdraw_mkt && !withdraw_prc {
+        msg!("No tokens selected to withdraw");
+        return Err(ErrorCode::InvalidParameters.into());
+    }
+    let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+    let owner_id: u128 = CritMap::bytes_hash(owner.as_ref());
+    let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+    let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+    let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+    verify_matching_accounts(&settle_header[0].market, market, Some(String::from("Invalid market")))?;
+    let sl = SlabPageAlloc::new(page_table);
+    let max_accounts = map_len(sl, DT::Account);
+    let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
+    if has_item.is_none() {
+        msg!("Account not found");
+        return Err(ErrorCode::AccountNotFound.into());
+    }
+    let log_node = has_item.unwrap();
+    let log_entry = sl.index_mut::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+    if withdraw_mkt {
+        result.set_mkt_tokens(log_entry.mkt_token_balance());
+        log_entry.set_mkt_token_balance(0);
+    }
+    if withdraw_prc {
+        result.set_prc_tokens(log_entry.prc_token_balance());
+        log_entry.set_prc_token_balance(0);
+    }
+
+    // Keep the log entry if the other token balance has not been withdrawn
+    let entry_removed = log_entry.mkt_token_balance() == 0 && log_entry.prc_token_balance() == 0;
+    if entry_removed {
+        settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+        map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+        AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+    }
+    Ok((result, entry_removed))
+}
+
+fn close_log_entry<'info>(
+    market: &Market,
+    state: &mut Account<'info, MarketState>,
+    owner: &AccountInfo<'info>,
+    acc_settle: &AccountInfo<'info>,
+    acc_settle_prev: &AccountInfo<'info>,
+    acc_settle_next: &AccountInfo<'info>,
+) -> anchor_lang::Result<()> {
+    // Pay the log rebate for a removed settlement log entry, and close the log if it is left empty
+    let mut market_lamports = state.to_account_info().lamports();
+    let log_rebate = pay_log_rebate(market, state, owner, 1)?;
+    market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+    let log_lamports = close_empty_log(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+    market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+    **state.to_account_info().lamports.borrow_mut() = market_lamports;
+    Ok(())
+}
+
+fn credit_user_vault<'info>(
+    market: &Pubkey,
+    state: &mut MarketState,
+    owner: &AccountInfo<'info>,
+    acc_vault: &AccountInfo<'info>,
+    system_prog: &AccountInfo<'info>,
+    mkt_tokens: u64,
+    prc_tokens: u64,
+) -> anchor_lang::Result<UserVault> {
+    // Credit tokens held in the market vaults to the owner's user vault (created if needed)
+    let mut vault = if *acc_vault.owner == ID {
+        load_user_vault(acc_vault, market, owner.key)?
+    } else {
+        create_user_vault(market, owner, acc_vault, system_prog)?
+    };
+    vault.mkt_tokens = vault.mkt_tokens.checked_add(mkt_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    vault.prc_tokens = vault.prc_tokens.checked_add(prc_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_add(mkt_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    state.prc_user_vault_balance = state.prc_user_vault_balance.checked_add(prc_tokens).ok_or(error!(ErrorCode::Overflow))?;
+    store_struct::<UserVault>(&vault, acc_vault)?;
+    Ok(vault)
+}
+
+fn load_user_vault(acc_vault: &AccountInfo, market: &Pubkey, owner: &Pubkey) -> anchor_lang::Result<UserVault> {
+    if *acc_vault.owner != ID {
+        msg!("Invalid user vault");
+        return Err(ErrorCode::InvalidAccount.into());
+    }
+    let vault = load_struct::<UserVault>(acc_vault)?;
+    verify_matching_accounts(&vault.market, market, Some(String::from("Invalid user vault market")))?;
+    verify_matching_accounts(&vault.owner, owner, Some(String::from("Invalid user vault owner")))?;
+    Ok(vault)
+}
+
+fn reducible_balance(
+    acc_vault: &AccountInfo,
+    market: &Pubkey,
+    owner: &Pubkey,
+    settle_a: &AccountInfo,
+    settle_b: &AccountInfo,
+    mkt_token: bool,
+) -> anchor_lang::Result<u64> {
+    // Balance available to a reduce-only order: the user vault if one is passed (otherwise the user account),
+    // or the owner's entries in the current settlement logs
+    // Only "settle_a" and "settle_b" are read, entries in older logs of the chain and tokens in resting orders are not counted
+    if acc_vault.key != owner {
+        let vault = load_user_vault(acc_vault, market, owner)?;
+        return Ok(if mkt_token { vault.mkt_tokens } else { vault.prc_tokens });
+    }
+    let mut balance: u64 = 0;
+    for entry in [settle_entry(settle_a, owner)?, settle_entry(settle_b, owner)?].iter().flatten() {
+        let entry_balance = if mkt_token { entry.mkt_token_balance() } else { entry.prc_token_balance() };
+        balance = balance.checked_add(entry_balance).ok_or(error!(ErrorCode::Overflow))?;
+    }
+    Ok(balance)
+}
+
+fn orderbook_levels(ob: &mut SlabPageAlloc, side: Side, max_orders: u32, depth: u8, clock_ts: i64) -> Vec<OrderbookLevel> {
+    let mut levels: Vec<OrderbookLevel> = Vec::new();
+    let order_type = match side {
+        Side::Bid => OrderDT::BidOrder,
+        Side::Ask => OrderDT::AskOrder,
+    };
+    // Orders are visited best price first, aggregate until the next distinct price would exceed the requested depth
+    // Expired orders are skipped with the same filter used by matching so the displayed book is the executable book
+    let aggregate = |sl: &SlabPageAlloc, leaf: &LeafNode| {
+        let order = sl.index::<Order>(order_type as u16, leaf.slot() as usize);
+        if order_expired(order.expiry, clock_ts) {
+            return false;
+        }
+        let price = Order::price(leaf.key());
+        let amount = order.amount();
+        if let Some(level) = levels.last_mut() {
+            if level.price == price {
+                level.total_quantity = level.total_quantity.saturating_add(amount);
+                level.order_count = level.order_count.saturating_add(1);
+                return false;
+            }
+        }
+        if levels.len() == depth as usize {
+            return true;
+        }
+        levels.push(OrderbookLevel { price: price, total_quantity: amount, order_count: 1 });
+        false
+    };
+    match side {
+        Side::Bid => map_predicate_max(ob, DT::BidOrder, max_orders, aggregate),
+        Side::Ask => map_predicate_min(ob, DT::AskOrder, max_orders, aggregate),
+    };
+    levels
+}
+
+fn best_level(ob: &mut SlabPageAlloc, side: Side, max_orders: u32, clock_ts: i64) -> (u64, u64) {
+    // Price and displayed quantity of the best unexpired price level (zero if the side is empty)
+    let order_type = match side {
+        Side::Bid => OrderDT::BidOrder,
+        Side::Ask => OrderDT::AskOrder,
+    };
+    let mut best_price: u64 = 0;
+    let mut best_qty: u64 = 0;
+    let level = |sl: &SlabPageAlloc, leaf: &LeafNode| {
+        let order = sl.index::<Order>(order_type as u16, leaf.slot() as usize);
+        if order_expired(order.expiry, clock_ts) {
+            return false;
+        }
+        let price = Order::price(leaf.key());
+        if best_qty > 0 && price != best_price {
+            return true;
+        }
+        best_price = price;
+        best_qty = best_qty.saturating_add(order.amount());
+        false
+    };
+    match side {
+        Side::Bid => map_predicate_max(ob, DT::BidOrder, max_orders, level),
+        Side::Ask => map_predicate_min(ob, DT::AskOrder, max_orders, level),
+    };
+    (best_price, best_qty)
+}
+
+#[program]
+pub mod aqua_dex {
+    use super::*;
+
+    pub fn initialize(ctx: Context<Initialize>) -> anchor_lang::Result<()> {
+        let rt = &mut ctx.accounts.root_data;
+        rt.root_authority = ctx.accounts.auth_data.key();
+
+        let auth_data: &mut[u8] = &mut ctx.accounts.auth_data.try_borrow_mut_data()?;
+        let rd = SlabPageAlloc::new(auth_data);
+        rd.setup_page_table();
+        rd.allocate::<CritMapHeader, AnyNode>(UserDT::UserRBACMap as u16, MAX_RBAC as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        rd.allocate::<SlabVec, UserRBAC>(UserDT::UserRBAC as u16, MAX_RBAC as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+
+        msg!("Atellix: Initialized AquaDEX Program");
+
+        Ok(())
+    }
+
+    // Remove before deployment
+    /*pub fn deactivate(_ctx: Context<Deactivate>) -> anchor_lang::Result<()> {
+        msg!("Atellix: Deactivate");
+        Ok(())
+    }*/
+
+    // Program owner can close data accounts (for deprecated markets)
+    pub fn close_account(ctx: Context<CloseAccount>) -> anchor_lang::Result<()> {
+
+        // Move lamports from data_account to program_admin
+        let mut admin_lamports = ctx.accounts.program_admin.to_account_info().lamports();
+        admin_lamports = admin_lamports.checked_add(ctx.accounts.data_account.to_account_info().lamports()).ok_or(error!(ErrorCode::Overflow))?;
+        **ctx.accounts.program_admin.to_account_info().lamports.borrow_mut() = admin_lamports;
+        **ctx.accounts.data_account.to_account_info().lamports.borrow_mut() = 0;
+
+        msg!("Atellix: Close Account: {}", ctx.accounts.data_account.key().to_string());
+        Ok(())
+    }
+
+    pub fn set_global_config(ctx: Context<SetGlobalConfig>,
+        inp_authority: Pubkey,      // Authority that can pause trading on all markets
+    ) -> anchor_lang::Result<()> {
+        let config = &mut ctx.accounts.global_config;
+        config.authority = inp_authority;
+        msg!("Atellix: Global Pause Authority: {}", inp_authority.to_string());
+        Ok(())
+    }
+
+    pub fn set_global_pause(ctx: Context<SetGlobalPause>,
+        inp_paused: bool,           // Pause order placement on all markets (cancellation and withdrawal remain enabled)
+    ) -> anchor_lang::Result<()> {
+        let config = &mut ctx.accounts.global_config;
+        if config.authority != ctx.accounts.authority.key() {
+            msg!("Not global pause authority");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        config.paused = inp_paused;
+        msg!("Atellix: Global Pause: {}", inp_paused.to_string());
+        Ok(())
+    }
+
+    pub fn store_metadata(ctx: Context<UpdateMetadata>,
+        inp_program_name: String,
+        inp_developer_name: String,
+        inp_developer_url: String,
+        inp_source_url: String,
+        inp_verify_url: String,
+    ) -> anchor_lang::Result<()> {
+        let md = &mut ctx.accounts.program_info;
+        md.semvar_major = VERSION_MAJOR;
+        md.semvar_minor = VERSION_MINOR;
+        md.semvar_patch = VERSION_PATCH;
+        md.program = ctx.accounts.program.key();
+        md.program_name = inp_program_name;
+        md.developer_name = inp_developer_name;
+        md.developer_url = inp_developer_url;
+        md.source_url = inp_source_url;
+        md.verify_url = inp_verify_url;
+        msg!("Program: {}", ctx.accounts.program.key.to_string());
+        msg!("Program Name: {}", md.program_name.as_str());
+        msg!("Version: {}.{}.{}", VERSION_MAJOR.to_string(), VERSION_MINOR.to_string(), VERSION_PATCH.to_string());
+        msg!("Developer Name: {}", md.developer_name.as_str());
+        msg!("Developer URL: {}", md.developer_url.as_str());
+        msg!("Source URL: {}", md.source_url.as_str());
+        msg!("Verify URL: {}", md.verify_url.as_str());
+        Ok(())
+    }
+
+    pub fn grant(ctx: Context<UpdateRBAC>,
+        _inp_root_nonce: u8,
+        inp_role: u32,
+    ) -> anchor_lang::Result<()> {
+        let acc_rbac = &ctx.accounts.rbac_user.to_account_info();
+        let acc_admn = &ctx.accounts.program_admin.to_account_info();
+        let acc_auth = &ctx.accounts.auth_data.to_account_info();
+
+        // Check for NetworkAdmin authority
+        let admin_role = has_role(&acc_auth, Role::NetworkAdmin, acc_admn.key);
+        let mut program_owner: bool = false;
+        if admin_role.is_err() {
+            let acc_pdat = &ctx.accounts.program_data;
+            verify_matching_accounts(&acc_pdat.upgrade_authority_address.unwrap(), acc_admn.key, Some(String::from("Invalid program owner")))?;
+            program_owner = true;
+        }
+
+        // Verify specified role
+        let role_item = Role::try_from_primitive(inp_role);
+        if role_item.is_err() {
+            msg!("Invalid role: {}", inp_role.to_string());
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        let role = role_item.unwrap();
+        if role == Role::NetworkAdmin && ! program_owner {
+            msg!("Reserved for program owner");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+
+        let auth_data: &mut[u8] = &mut acc_auth.try_borrow_mut_data()?;
+        let rd = SlabPageAlloc::new(auth_data);
+        let authhash: u128 = CritMap::bytes_hash([[role as u32].as_byte_slice(), acc_rbac.key.as_ref()].concat().as_slice());
+
+        // Check if record exists
+        let authrec = map_get(rd, DT::UserRBAC, MAX_RBAC, authhash);
+        if authrec.is_some() {
+            msg!("Role already active");
+        } else {
+            // Add new record
+            let new_item = map_insert(rd, DT::UserRBAC, MAX_RBAC, &LeafNode::new(authhash, 0, acc_rbac.key));
+            if new_item.is_err() {
+                msg!("Unable to insert role");
+                return Err(ErrorCode::InternalError.into());
+            }
+            let rbac_idx = UserRBAC::next_index(rd, DT::UserRBAC)?;
+            let mut cm = CritMap { slab: rd, type_id: map_datatype(DT::UserRBAC), capacity: MAX_RBAC };
+            cm.get_key_mut(authhash).unwrap().set_slot(rbac_idx);
+            *rd.index_mut(index_datatype(DT::UserRBAC), rbac_idx as usize) = UserRBAC { role: role, free: 0 };
+            msg!("Atellix: Role granted");
+        }
+        Ok(())
+    }
+
+    pub fn revoke(ctx: Context<UpdateRBAC>,
+        _inp_root_nonce: u8,
+        inp_role: u32,
+    ) -> anchor_lang::Result<()> {
+        let acc_admn = &ctx.accounts.program_admin.to_account_info(); // Program owner or network admin
+        let acc_auth = &ctx.accounts.auth_data.to_account_info();
+        let acc_rbac = &ctx.accounts.rbac_user.to_account_info();
+
+        // Check for NetworkAdmin authority
+        let admin_role = has_role(&acc_auth, Role::NetworkAdmin, acc_admn.key);
+        let mut program_owner: bool = false;
+        if admin_role.is_err() {
+            let acc_pdat = &ctx.accounts.program_data;
+            verify_matching_accounts(&acc_pdat.upgrade_authority_address.unwrap(), acc_admn.key, Some(String::from("Invalid program owner")))?;
+            program_owner = true;
+        }
+
+        // Verify specified role
+        let role_item = Role::try_from_primitive(inp_role);
+        if role_item.is_err() {
+            msg!("Invalid role: {}", inp_role.to_string());
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        let role = role_item.unwrap();
+        if role == Role::NetworkAdmin && ! program_owner {
+            msg!("Reserved for program owner");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+
+        let auth_data: &mut[u8] = &mut acc_auth.try_borrow_mut_data()?;
+        let rd = SlabPageAlloc::new(auth_data);
+        let authhash: u128 = CritMap::bytes_hash([[role as u32].as_byte_slice(), acc_rbac.key.as_ref()].concat().as_slice());
+
+        // Check if record exists
+        let authrec = map_get(rd, DT::UserRBAC, MAX_RBAC, authhash);
+        if authrec.is_some() {
+            map_remove(rd, DT::UserRBAC, MAX_RBAC, authhash).or(Err(error!(ErrorCode::InternalError)))?;
+            UserRBAC::free_index(rd, DT::UserRBAC, authrec.unwrap().slot())?;
+            msg!("Atellix: Role revoked");
+        } else {
+            msg!("Atellix: Role not found");
+        }
+        Ok(())
     }
 
     pub fn create_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CreateMarket<'info>>,
@@ -1052,23 +3204,49 @@ pub mod aqua_dex {
         inp_mkt_mint_type: u8,
         inp_prc_mint_type: u8,
         inp_manager_actions: bool,
+        inp_manager_force: bool,
         inp_expire_enable: bool,
         inp_expire_min: i64,
         inp_min_quantity: u64,
+        inp_min_notional: u64,
         inp_tick_decimals: u8,
+        inp_tick_size: u64,
+        inp_lot_size: u64,
+        inp_max_orders: u32,
+        inp_max_orders_per_owner: u32,
+        inp_max_trades: u32,
+        inp_max_accounts: u32,
+        inp_eviction_enabled: bool,
+        inp_eviction_max: u32,
         inp_taker_fee: u32,
         inp_maker_rebate: u32,
+        inp_referral_fee_share: u32,
         inp_log_fee: u64,
         inp_log_rebate: u64,
         inp_log_reimburse: u64,
         inp_mkt_vault_uuid: u128,
         inp_prc_vault_uuid: u128,
+        inp_eviction_min_improvement: u64,
+        inp_fee_waiver_until: i64,
     ) -> anchor_lang::Result<()> {
         msg!("Begin Market Setup");
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
 
         require!(inp_tick_decimals <= 16, ErrorCode::InvalidParameters);
+        if inp_mkt_decimals > MAX_DECIMALS || inp_prc_decimals > MAX_DECIMALS {
+            msg!("Token decimals exceed maximum of {}", MAX_DECIMALS.to_string());
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_max_orders > 0, ErrorCode::InvalidParameters);
+        require!(inp_max_trades > 0, ErrorCode::InvalidParameters);
+        require!(inp_max_accounts >= 3, ErrorCode::InvalidParameters);
+        require!(!inp_eviction_enabled || inp_eviction_max > 0, ErrorCode::InvalidParameters);
+        if inp_maker_rebate > inp_taker_fee {
+            msg!("Maker rebate exceeds taker fee");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_referral_fee_share <= 10000000, ErrorCode::InvalidParameters);
 
         let acc_market = &ctx.accounts.market.to_account_info();
         let acc_state = &ctx.accounts.state.to_account_info();
@@ -1111,6 +3289,10 @@ pub mod aqua_dex {
             }
         }
 
+        // Verify token decimals (used to scale prices in every trade)
+        verify_mint_decimals(mkt_mint_type, acc_mkt_mint, inp_mkt_decimals, "market")?;
+        verify_mint_decimals(prc_mint_type, acc_prc_mint, inp_prc_decimals, "pricing")?;
+
         // Check expiration parameters
         if inp_expire_min < 1 {
             msg!("Invalid order expiration duration");
@@ -1213,6 +3395,31 @@ pub mod aqua_dex {
         let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
         let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
 
+        // Each side needs a CritMap and a SlabVec sized for the requested capacity, all within the orders account
+        let map_pages = slab_pages::<CritMapHeader, AnyNode>(inp_max_orders as usize);
+        let vec_pages = slab_pages::<SlabVec, Order>(inp_max_orders as usize);
+        let order_pages = map_pages.checked_add(vec_pages).ok_or(error!(ErrorCode::Overflow))?.checked_mul(2).ok_or(error!(ErrorCode::Overflow))?;
+        if map_pages > SLAB_TYPE_PAGES || vec_pages > SLAB_TYPE_PAGES || order_pages > SLAB_PAGE_MAX {
+            msg!("Invalid orderbook capacity");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        check_account_len(acc_orders, SLAB_TABLE_SIZE + (order_pages * SLAB_PAGE_SIZE), "Orderbook")?;
+        let trade_pages = slab_pages::<TradeLogHeader, TradeEntry>(inp_max_trades as usize);
+        if trade_pages > SLAB_TYPE_PAGES {
+            msg!("Invalid trade log capacity");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        check_account_len(acc_trade_log, SLAB_TABLE_SIZE + (trade_pages * SLAB_PAGE_SIZE), "Trade log")?;
+        let settle_len = match settle_log_len(inp_max_accounts) {
+            Some(len) => len,
+            None => {
+                msg!("Invalid settlement log capacity");
+                return Err(ErrorCode::InvalidParameters.into());
+            },
+        };
+        check_account_len(acc_settle1, settle_len, "Settlement log 1")?;
+        check_account_len(acc_settle2, settle_len, "Settlement log 2")?;
+
         let market = Market {
             active: true,
             manager_actions: inp_manager_actions,
@@ -1240,6 +3447,41 @@ pub mod aqua_dex {
             prc_mint_type: inp_prc_mint_type,
             orders: *acc_orders.key,
             settle_0: *acc_settle1.key,
+            layout_version: MARKET_LAYOUT_VERSION,
+            max_orders: inp_max_orders,
+            max_deviation_bps: 0,
+            eviction_enabled: inp_eviction_enabled,
+            eviction_max: inp_eviction_max,
+            stops: Pubkey::default(),
+            max_stops: 0,
+            orderbook_version: ORDERBOOK_VERSION,
+            min_notional: inp_min_notional,
+            max_orders_per_owner: inp_max_orders_per_owner,
+            pending_manager: Pubkey::default(),
+            fee_receiver: ctx.accounts.fee_receiver.key(),
+            referral_fee_share: inp_referral_fee_share,
+            breaker_bps: 0,
+            breaker_cooldown: 0,
+            max_accounts: inp_max_accounts,
+            fee_tiers: [FeeTier::default(); MAX_FEE_TIERS],
+            dust_threshold: 0,
+            manager_force: inp_manager_force,
+            tick_size: inp_tick_size,
+            lot_size: inp_lot_size,
+            session_open: 0,
+            session_close: 0,
+            min_rest_seconds: 0,
+            discount_mint: Pubkey::default(),
+            discount_vault: Pubkey::default(),
+            discount_bps: 0,
+            discount_rate: 0,
+            expire_slippage_bps: 0,
+            log_fee_per_fill: 0,
+            eviction_min_improvement: inp_eviction_min_improvement,
+            allow_self_trade: false,
+            order_cooldown: 0,
+            fee_waiver_until: inp_fee_waiver_until,
+            dust_policy: DustPolicy::Keep as u8,
         };
         msg!("Atellix: Store Market Data");
         store_struct::<Market>(&market, acc_market)?;
@@ -1264,6 +3506,14 @@ pub mod aqua_dex {
             prc_fees_balance: 0,
             last_ts: clock_ts,
             last_price: 0,
+            layout_version: MARKET_LAYOUT_VERSION,
+            mkt_volume: 0,
+            prc_volume: 0,
+            price_cumulative: 0,
+            price_cumulative_ts: clock_ts,
+            breaker_tripped_ts: 0,
+            mkt_dust_balance: 0,
+            prc_dust_balance: 0,
         };
         msg!("Atellix: Store Market State");
         store_struct::<MarketState>(&state, acc_state)?;
@@ -1276,20 +3526,20 @@ pub mod aqua_dex {
         let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
         let order_slab = SlabPageAlloc::new(order_data);
         order_slab.setup_page_table();
-        order_slab.allocate::<CritMapHeader, AnyNode>(OrderDT::BidOrderMap as u16, MAX_ORDERS as usize).expect("Failed to allocate");
-        order_slab.allocate::<CritMapHeader, AnyNode>(OrderDT::AskOrderMap as u16, MAX_ORDERS as usize).expect("Failed to allocate");
-        order_slab.allocate::<SlabVec, Order>(OrderDT::BidOrder as u16, MAX_ORDERS as usize).expect("Failed to allocate");
-        order_slab.allocate::<SlabVec, Order>(OrderDT::AskOrder as u16, MAX_ORDERS as usize).expect("Failed to allocate");
+        order_slab.allocate::<CritMapHeader, AnyNode>(OrderDT::BidOrderMap as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        order_slab.allocate::<CritMapHeader, AnyNode>(OrderDT::AskOrderMap as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        order_slab.allocate::<SlabVec, Order>(OrderDT::BidOrder as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        order_slab.allocate::<SlabVec, Order>(OrderDT::AskOrder as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
 
         msg!("Atellix: Allocate Trade Log");
         let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
         let trade_slab = SlabPageAlloc::new(trade_data);
         trade_slab.setup_page_table();
-        trade_slab.allocate::<TradeLogHeader, TradeEntry>(0, MAX_TRADES as usize).expect("Failed to allocate");
+        trade_slab.allocate::<TradeLogHeader, TradeEntry>(0, inp_max_trades as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
         let trade_header = trade_slab.header_mut::<TradeLogHeader>(0);
         trade_header.market = acc_market.key();
         trade_header.trade_count = 0;
-        trade_header.entry_max = MAX_TRADES as u64;
+        trade_header.entry_max = inp_max_trades as u64;
 
         msg!("Atellix: Allocate Settlement Log 1");
         let settle1_data: &mut[u8] = &mut acc_settle1.try_borrow_mut_data()?;
@@ -1303,8 +3553,8 @@ pub mod aqua_dex {
         };
         let settle1_slab = SlabPageAlloc::new(settle1_pages);
         settle1_slab.setup_page_table();
-        settle1_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
-        settle1_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
+        settle1_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, inp_max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        settle1_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, inp_max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
 
         msg!("Atellix: Allocate Settlement Log 2");
         let settle2_data: &mut[u8] = &mut acc_settle2.try_borrow_mut_data()?;
@@ -1318,8 +3568,8 @@ pub mod aqua_dex {
         };
         let settle2_slab = SlabPageAlloc::new(settle2_pages);
         settle2_slab.setup_page_table();
-        settle2_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
-        settle2_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, MAX_ACCOUNTS as usize).expect("Failed to allocate");
+        settle2_slab.allocate::<CritMapHeader, AnyNode>(SettleDT::AccountMap as u16, inp_max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        settle2_slab.allocate::<SlabVec, AccountEntry>(SettleDT::Account as u16, inp_max_accounts as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
 
         //msg!("Atellix: Account Entry Size: {}", size_of::<AccountEntry>());
 
@@ -1331,11 +3581,19 @@ pub mod aqua_dex {
     pub fn limit_bid<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderContext<'info>>,
         inp_quantity: u64,
         inp_price_request: u64,
-        inp_post: bool,     // Post the order order to the orderbook, otherwise fill based on parameter below
-        inp_fill: bool,     // Require orders that are not posted to be filled completely
+        inp_mode: u8,       // Order mode: 0 - Limit, 1 - Post only, 2 - Immediate or cancel, 3 - Fill or kill
         inp_expires: i64,   // Unix timestamp for order expiration (must be in the future, must exceed minimum duration)
         inp_preview: bool,  // Preview execution and check taker token balance, but do not perform transfer
         inp_rollover: bool, // Perform settlement log rollover
+        inp_use_vault: bool,    // Debit and credit tokens through the user vault instead of the user's token accounts
+        inp_display_qty: u64,   // Iceberg order displayed quantity (0 to display the entire order)
+        inp_native_sol: bool,   // Wrap and unwrap SOL for the pricing token (the "userPrcToken" account is closed afterwards)
+        inp_withdraw_first: bool,   // Withdraw the user's settled tokens from the current settlement logs before placing the order
+        inp_reject_self_cross: bool,    // Fail instead of posting an order that crosses the user's own resting orders
+        inp_expire_to_market: bool,     // Execute the posted order as a market order after it expires (with "expire_to_market")
+        inp_by_quote: bool,     // Quantity is the pricing token amount to spend (the market token quantity is derived at the limit price)
+        inp_expire_budget: u32, // Max expired orders to remove after matching (0 for the default of MAX_EXPIRATIONS, up to MAX_EXPIRE_BUDGET)
+        inp_client_order_id: u128,  // Client order id to reject duplicate submissions (0 for none)
     ) -> anchor_lang::Result<TradeResult> {
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
@@ -1350,26 +3608,81 @@ pub mod aqua_dex {
         let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
         let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
+        let acc_fills = &ctx.accounts.fills.to_account_info();
+        let acc_referrer = &ctx.accounts.referrer.to_account_info();
+        let acc_user_volume = &ctx.accounts.user_volume.to_account_info();
+        let acc_user_vault = &ctx.accounts.user_vault.to_account_info();
 
-        if inp_post && inp_fill {
-            msg!("Require fill cannot be used with order posting");
-            return Err(ErrorCode::InvalidParameters.into());
+        if *acc_result.key != ctx.accounts.user.key() {
+            verify_result_account::<TradeResult>(acc_result, TRADE_RESULT_LEN)?;
+        }
+        if *acc_fills.key != ctx.accounts.user.key() {
+            verify_result_account::<FillsResult>(acc_fills, FILLS_RESULT_LEN)?;
         }
+
+        let order_mode = OrderMode::try_from(inp_mode).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let mut post_order: bool = order_mode == OrderMode::Limit || order_mode == OrderMode::PostOnly;
+        let fill_order: bool = order_mode == OrderMode::FillOrKill;
         if !market.active {
             msg!("Market closed");
             return Err(ErrorCode::MarketClosed.into());
         }
-
-        require!(inp_quantity > 0 && inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        let expire_limit = expiration_limit(inp_expire_budget)?;
+        check_order_cooldown(market, &ctx.accounts.market.key(), acc_user, &ctx.accounts.user_cooldown.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), clock_ts, inp_preview)?;
+        check_native_sol(market, inp_native_sol, inp_use_vault)?;
+        if inp_withdraw_first && inp_use_vault {
+            msg!("Withdraw first can not be used with the user vault");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        let mut user_vault: Option<UserVault> = None;
+        if inp_use_vault {
+            user_vault = Some(load_user_vault(acc_user_vault, &market.key(), acc_user.key)?);
+        }
+
         require!(inp_price_request > 0, ErrorCode::InvalidParameters);
         let inp_price = get_tick_price(&market, inp_price_request)?;
         require!(inp_price > 0, ErrorCode::InvalidParameters);
+        check_tick_size(market, inp_price)?;
+
+        // Taker fee tier from the user's cumulative volume (the volume account is the user account if fee tiers are not used)
+        let mut user_volume: Option<UserVolume> = None;
+        if acc_user_volume.key() != acc_user.key() {
+            user_volume = Some(load_user_volume(acc_user_volume, &market.key(), acc_user.key)?);
+        }
+        let waived = fee_waived(market, clock_ts);
+        let full_taker_fee: u32 = if waived { 0 } else { taker_fee_rate(market, user_volume.as_ref().map_or(0, |v| v.volume)) };
+        let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+
+        let inp_quantity: u64 = match inp_by_quote {
+            true => {
+                // The taker fee is paid from the amount, so the quantity is derived from the amount net of the fee
+                let fee = calculate_fee(full_taker_fee, inp_quantity)?;
+                let net_amount = inp_quantity.checked_sub(fee).ok_or(error!(ErrorCode::Overflow))?;
+                let quantity = quote_quantity(market, net_amount, inp_price)?;
+                msg!("Atellix: Quote Amount: {} Fee: {} Quantity: {}", inp_quantity.to_string(), fee.to_string(), quantity.to_string());
+                quantity
+            },
+            false => inp_quantity,
+        };
+        require!(inp_quantity > 0 && inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        require!(inp_display_qty == 0 || inp_display_qty >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        check_lot_size(market, inp_quantity)?;
+        check_lot_size(market, inp_display_qty)?;
+        let order_value = scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?;
+        check_order_value(inp_quantity, order_value)?;
+        check_min_notional(market, order_value)?;
 
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
         verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
         let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
         let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
@@ -1380,8 +3693,11 @@ pub mod aqua_dex {
             return Err(ErrorCode::RetrySettlementAccount.into());
         }
 
-        // Append a settlement log account
         let state_upd = &mut ctx.accounts.state;
+        check_client_order(&market.key(), acc_user, &ctx.accounts.user_client_orders.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), inp_client_order_id, inp_preview)?;
+
+        // Append a settlement log account
         if inp_rollover && !inp_preview {
             if !state_upd.log_rollover {
                 // Another market participant already appended a new log account (please retry transaction)
@@ -1391,13 +3707,29 @@ pub mod aqua_dex {
             let av = ctx.remaining_accounts;
             let new_settlement_log = av.get(0).unwrap();
             let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
+            log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
             log_reimburse(market, state_upd, acc_user)?;
             let mut market_lamports = state_upd.to_account_info().lamports();
             market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
             **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
         }
 
+        // Withdraw settled tokens to the user's token accounts so they are available for this order
+        if inp_withdraw_first && !inp_preview {
+            withdraw_first(market, state_upd, ctx.remaining_accounts, acc_user, acc_settle1, acc_settle2,
+                &ctx.accounts.settle_prev.to_account_info(),
+                &ctx.accounts.agent.to_account_info(),
+                &ctx.accounts.mkt_vault.to_account_info(),
+                &ctx.accounts.prc_vault.to_account_info(),
+                &ctx.accounts.user_mkt_token.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
+            )?;
+        }
+        // If the withdrawal closed the first current log the log before it takes its place
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle1 = if state_upd.settle_a == acc_settle_prev.key() { acc_settle_prev } else { acc_settle1 };
+
         // Check expiration parameters
         let mut expiry: i64 = 0;
         // If expire timestamp is 0 then order does not expire
@@ -1413,6 +3745,10 @@ pub mod aqua_dex {
             }
             expiry = inp_expires;
         }
+        if inp_expire_to_market && expiry == 0 {
+            msg!("Expire to market requires an order expiration");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
 
         msg!("Atellix: Limit Bid: {} @ {}", inp_quantity.to_string(), inp_price.to_string());
 
@@ -1425,45 +3761,99 @@ pub mod aqua_dex {
             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(tokens_in).ok_or(error!(ErrorCode::Overflow))?;
         }
 
+        // Utility token fee discount (the discount token account is the user account if no discount is requested)
+        let discount_rate: u32 = fee_discount_rate(market, full_taker_fee, acc_user.key,
+            &ctx.accounts.discount_token.to_account_info(),
+            &ctx.accounts.discount_vault.to_account_info(),
+        )?;
+        let taker_fee: u32 = full_taker_fee.checked_sub(discount_rate).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Referrer share of taker fees (the referrer account is the user account if there is no referrer)
+        let mut referral_share: u32 = 0;
+        if market.referral_fee_share > 0 && acc_referrer.key() != acc_user.key() {
+            if settlement_available(state_upd, acc_settle1, acc_settle2, acc_referrer.key)? {
+                referral_share = market.referral_fee_share;
+            } else {
+                msg!("Atellix: Referral fee not applied");
+            }
+        }
+
         let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
         let ob = SlabPageAlloc::new(orderbook_data);
 
+        // Settlement log entries before matching, after any withdrawal (entries created by this order are charged "log_fee_per_fill")
+        let log_items: u32 = settlement_items(acc_settle1, acc_settle2)?;
+
         // Check if order can be filled
         let mut tokens_to_fill: u64 = inp_quantity;
         let mut tokens_filled: u64 = 0;
         let mut tokens_paid: u64 = 0;
         let mut tokens_fee: u64 = 0;
+        let mut tokens_discount: u64 = 0;
         let mut tokens_rebate: u64 = 0;
+        let breaker_price: u64 = state_upd.last_price;
+        let prc_volume_start: u128 = state_upd.prc_volume;
+        let mut self_volume: u128 = 0;
+        let mut fills = FillsResult { fills: Vec::new(), truncated: false };
         let mut expired_orders = Vec::new();
+        let mut skip = MatchSkip::new(OrderDT::AskOrder);
+        let mut preview_book = PreviewBook::new();
         let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
         verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
         let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
         let tlog = SlabPageAlloc::new(trade_data);
         loop {
-            let node_res = map_predicate_min(ob, DT::AskOrder, |sl, leaf|
-                valid_order(OrderDT::AskOrder, leaf, acc_user.key, sl, &mut expired_orders, clock_ts)
-            );
+            let node_res = map_predicate_min(ob, DT::AskOrder, market.max_orders, |sl, leaf| {
+                if preview_book.filled(leaf.key()) {
+                    return false;
+                }
+                skip.check(leaf.key(), || valid_order(OrderDT::AskOrder, leaf, acc_user.key, market.allow_self_trade, sl, &mut expired_orders, clock_ts))
+            });
+            if skip.limit_reached() {
+                // Fail instead of stopping early, the unfilled remainder could cross the orders behind the skipped ones
+                msg!("Atellix: Too many expired or same-user orders skipped - Use crank_expirations to remove expired orders");
+                return Err(ErrorCode::TooManySkippedOrders.into());
+            }
             if node_res.is_none() {
                 msg!("Atellix: No Match");
                 break;
             }
             let posted_node = node_res.unwrap();
-            let posted_order = ob.index::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize);
-            let posted_qty = posted_order.amount;
+            let posted_order = *ob.index::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize);
+            let posted_qty = preview_book.amount(posted_node.key(), &posted_order);
             let posted_price = Order::price(posted_node.key());
+            if posted_price <= inp_price && order_mode == OrderMode::PostOnly {
+                msg!("Post only order would be filled");
+                return Err(ErrorCode::OrderWouldFill.into());
+            }
             if posted_price <= inp_price {
                 // Fill order
+                check_breaker(market, state_upd, clock_ts)?;
+                if breaker_tripped(market, breaker_price, posted_price)? {
+                    // Halt trading without posting a crossing order, the unfilled remainder is cancelled
+                    if !inp_preview {
+                        state_upd.breaker_tripped_ts = clock_ts;
+                    }
+                    post_order = false;
+                    break;
+                }
+                if !inp_preview && !settlement_available(state_upd, acc_settle1, acc_settle2, &posted_node.owner())? {
+                    // Stop matching without posting a crossing order, the unfilled remainder is cancelled
+                    msg!("Atellix: Settlement logs full - Matching stopped");
+                    post_order = false;
+                    break;
+                }
                 msg!("Atellix: Matched Ask [{}] {} @ {}", posted_node.slot().to_string(), posted_qty.to_string(), posted_price.to_string());
                 if posted_qty == tokens_to_fill {         // Match the entire order exactly
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
-                    msg!("Atellix: Filling - {} @ {}", tokens_part.to_string(), posted_price.to_string());
+                    msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
                         log_trade(tlog,
                             207368829214137069500050352632921761096, // solana/program/aqua-dex/limit_bid/match/exact
@@ -1478,13 +3868,18 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -1499,12 +3894,15 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(posted_qty, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
                         log_trade(tlog,
                             227168296477409633500015956081940497570, // solana/program/aqua-dex/limit_bid/match/entire
@@ -1519,13 +3917,18 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -1538,12 +3941,12 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
                         log_trade(tlog,
                             94062763214239030578622318919331863353, // solana/program/aqua-dex/limit_bid/match/partial
@@ -1558,12 +3961,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Ask, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -1586,17 +3997,20 @@ pub mod aqua_dex {
             let mut expired_count: u32 = 0;
             if expired_orders.len() > 0 {
                 loop {
-                    if expired_orders.len() == 0 || expired_count == MAX_EXPIRATIONS {
+                    if expired_orders.len() == 0 || expired_count == expire_limit {
                         break;
                     }
                     let expired_id: u128 = expired_orders.pop().unwrap();
-                    let expire_leaf = map_get(ob, DT::AskOrder, expired_id).unwrap();
+                    let expire_leaf = map_get(ob, DT::AskOrder, market.max_orders, expired_id).unwrap();
+                    if !settlement_available(state_upd, acc_settle1, acc_settle2, &expire_leaf.owner())? {
+                        break;
+                    }
                     let expire_order = *ob.index::<Order>(OrderDT::AskOrder as u16, expire_leaf.slot() as usize);
-                    let expire_amount: u64 = expire_order.amount();
+                    let expire_amount: u64 = expire_order.total_amount();
                     msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
                         expire_leaf.slot().to_string(),
                         expire_leaf.owner().to_string(),
-                        expire_order.amount().to_string(),
+                        expire_amount.to_string(),
                         Order::price(expire_leaf.key()).to_string(),
                     );
                     emit!(ExpireEvent {
@@ -1611,7 +4025,7 @@ pub mod aqua_dex {
                         tokens: expire_amount,
                     });
                     log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &expire_leaf.owner(), true, expire_amount)?; // No multiply for Ask order
-                    map_remove(ob, DT::AskOrder, expire_leaf.key())?;
+                    map_remove(ob, DT::AskOrder, market.max_orders, expire_leaf.key())?;
                     Order::free_index(ob, DT::AskOrder, expire_leaf.slot())?;
                     state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                     expired_count = expired_count + 1;
@@ -1623,44 +4037,81 @@ pub mod aqua_dex {
 
         // Add order to orderbook if not filled
         let tokens_remaining = inp_quantity.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
-        if tokens_remaining > 0 && inp_fill {
+        if tokens_remaining > 0 && fill_order {
             msg!("Order not filled");
+            msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_paid.to_string(), tokens_fee.to_string());
             return Err(ErrorCode::OrderNotFilled.into());
         }
-        if tokens_remaining > 0 && inp_post {
+        if tokens_remaining > 0 && post_order {
+            check_price_band(market, state_upd.last_price, inp_price)?;
+            check_owner_orders(ob, market, state_upd, acc_user.key)?;
+            if inp_reject_self_cross {
+                check_self_cross(ob, market, Side::Bid, acc_user.key, inp_price, clock_ts)?;
+            }
             let mut order_id: u128 = u128::MAX;
             let mut order_idx: u32 = 1;
             if !inp_preview {
                 order_id = Order::new_key(state_upd, Side::Bid, inp_price);
                 order_idx = Order::next_index(ob, DT::BidOrder)?;
                 let order_node = LeafNode::new(order_id, order_idx, &acc_user.key);
-                let order = Order { amount: tokens_remaining, expiry: expiry };
+                let mut display_amount: u64 = tokens_remaining;
+                let mut display_qty: u64 = 0;
+                let mut hidden_qty: u64 = 0;
+                if inp_display_qty > 0 && inp_display_qty < tokens_remaining {
+                    // Iceberg order: only display part of the remaining quantity
+                    display_amount = inp_display_qty;
+                    display_qty = inp_display_qty;
+                    hidden_qty = tokens_remaining.checked_sub(inp_display_qty).ok_or(error!(ErrorCode::Overflow))?;
+                }
+                let order = Order { amount: display_amount, expiry: expiry, display_qty: display_qty, hidden_qty: hidden_qty, placed_ts: clock_ts, expire_to_market: inp_expire_to_market as u8 };
                 let mut eviction_count: u32 = 0;
+                // Each pass posts the order, fails, or evicts one order at a strictly worse price (an order at the same
+                // price is never evicted), so the loop ends after at most "eviction_max" evictions
                 loop {
-                    let entry = map_insert(ob, DT::BidOrder, &order_node);
+                    let entry = map_insert(ob, DT::BidOrder, market.max_orders, &order_node);
                     if entry.is_err() {
                         // Evict orders if necessary
-                        if eviction_count == MAX_EVICTIONS {
-                            msg!("Failed to add order");
+                        if !market.eviction_enabled {
+                            msg!("Atellix: Orderbook Full - Eviction disabled");
+                            return Err(ErrorCode::OrderbookFull.into());
+                        }
+                        if eviction_count >= market.eviction_max {
+                            msg!("Failed to add order - Eviction limit reached: {}", market.eviction_max.to_string());
                             return Err(ErrorCode::InternalError.into());
                         }
-                        let evict_node = map_min(ob, DT::BidOrder).unwrap();
+                        let evict_node = map_min(ob, DT::BidOrder, market.max_orders).ok_or(error!(ErrorCode::InternalError))?;
                         let evict_order = ob.index::<Order>(OrderDT::BidOrder as u16, evict_node.slot() as usize);
-                        // Only evict if the price is better and quantity is equal or more
-                        if inp_price <= Order::price(evict_node.key()) || tokens_remaining < evict_order.amount() {
+                        // Only evict if the price is better (by at least the minimum improvement) and quantity is equal or more
+                        let evict_price: u64 = Order::price(evict_node.key());
+                        if inp_price <= evict_price || inp_price - evict_price < market.eviction_min_improvement || tokens_remaining < evict_order.total_amount() {
                             msg!("Atellix: Orderbook Full - Price does not exceed evicted order or posted quantity less than evicted order");
                             return Err(ErrorCode::OrderbookFull.into());
                         }
-                        let evict_amount: u64 = evict_order.amount();
+                        let evict_amount: u64 = evict_order.total_amount();
+                        if !settlement_available(state_upd, acc_settle1, acc_settle2, &evict_node.owner())? {
+                            msg!("Atellix: Orderbook Full - Settlement logs full, can not evict order");
+                            return Err(ErrorCode::SettlementLogFull.into());
+                        }
                         msg!("Atellix: Evicting Order[{}] - Owner: {} {} @ {}",
                             evict_node.slot().to_string(),
                             evict_node.owner().to_string(),
-                            evict_order.amount().to_string(),
+                            evict_amount.to_string(),
                             Order::price(evict_node.key()).to_string(),
                         );
                         let evict_total = scale_price(evict_amount, Order::price(evict_node.key()), decimal_factor(market.mkt_decimals))?;
+                        emit!(EvictEvent {
+                            event_type: 332864934724696862523374391891968586946, // solana/program/aqua-dex/evict_event
+                            action_id: state_upd.action_counter,
+                            market: market.key(),
+                            owner: evict_node.owner(),
+                            order_side: Side::Bid as u8,
+                            order_id: evict_node.key(),
+                            price: Order::price(evict_node.key()),
+                            quantity: evict_amount,
+                            tokens: evict_total,
+                        });
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &evict_node.owner(), false, evict_total)?;
-                        map_remove(ob, DT::BidOrder, evict_node.key())?;
+                        map_remove(ob, DT::BidOrder, market.max_orders, evict_node.key())?;
                         Order::free_index(ob, DT::BidOrder, evict_node.slot())?;
                         state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                         eviction_count = eviction_count + 1;
@@ -1686,9 +4137,8 @@ pub mod aqua_dex {
             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_sub(discount).ok_or(error!(ErrorCode::Overflow))?;
 
             // Apply fees
-            state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-            state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-            state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            apply_taker_fees(&market.key(), state_upd, acc_settle1, acc_settle2, acc_referrer.key, referral_share, tokens_fee, tokens_rebate,
+                &mut user_volume, acc_user_volume, prc_volume_start, self_volume)?;
 
             /*msg!("Atellix: Pricing Token Vault Deposit: {}", total_cost.to_string());
             msg!("Atellix: Pricing Token Vault Balance: {} (Orderbook: {})",
@@ -1707,18 +4157,42 @@ pub mod aqua_dex {
         }
 
         // Send tokens to the vault
-        let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-        perform_transfer(ctx.remaining_accounts, mint_type, 0, total_cost, inp_preview,
-            &ctx.accounts.user_prc_token.to_account_info(),  // From
-            &ctx.accounts.prc_vault.to_account_info(),       // To
-            &ctx.accounts.user.to_account_info(),            // Auth
-            &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
-        )?;
+        if let Some(vault) = user_vault.as_mut() {
+            if vault.prc_tokens < total_cost {
+                msg!("Insufficient tokens in user vault");
+                return Err(ErrorCode::InsufficientTokens.into());
+            }
+            if !inp_preview {
+                // Tokens are already in the market vault
+                vault.prc_tokens = vault.prc_tokens.checked_sub(total_cost).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.prc_user_vault_balance = state_upd.prc_user_vault_balance.checked_sub(total_cost).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_sub(total_cost).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        } else {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            if inp_native_sol && inp_preview {
+                preview_native_sol(total_cost, &ctx.accounts.user.to_account_info(), &ctx.accounts.user_prc_token.to_account_info())?;
+            } else {
+                if inp_native_sol {
+                    wrap_native_sol(total_cost,
+                        &ctx.accounts.user.to_account_info(),
+                        &ctx.accounts.user_prc_token.to_account_info(),
+                        &ctx.accounts.system_program.to_account_info(),
+                        &ctx.accounts.spl_token_prog.to_account_info(),
+                    )?;
+                }
+                perform_transfer(ctx.remaining_accounts, mint_type, 0, total_cost, inp_preview,
+                    &ctx.accounts.user_prc_token.to_account_info(),  // From
+                    &ctx.accounts.prc_vault.to_account_info(),       // To
+                    &ctx.accounts.user.to_account_info(),            // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+                )?;
+            }
+        }
         result.set_tokens_sent(total_cost);
 
         if tokens_filled > 0 && !inp_preview {
             // Withdraw tokens from the vault
-            state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
             state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
 
             /*msg!("Atellix: Market Token Vault Withdraw: {}", tokens_filled.to_string());
@@ -1727,19 +4201,49 @@ pub mod aqua_dex {
                 state_upd.mkt_order_balance,
             );*/
 
-            let seeds = &[market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
-            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_filled,
-                &ctx.accounts.mkt_vault.to_account_info(),          // From
-                &ctx.accounts.user_mkt_token.to_account_info(),     // To
-                &ctx.accounts.agent.to_account_info(),              // Auth
-                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            if let Some(vault) = user_vault.as_mut() {
+                vault.mkt_tokens = vault.mkt_tokens.checked_add(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.mkt_user_vault_balance = state_upd.mkt_user_vault_balance.checked_add(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
+            } else {
+                state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
+                let seeds = &[market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+                let signer = &[&seeds[..]];
+                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_filled,
+                    &ctx.accounts.mkt_vault.to_account_info(),          // From
+                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+            }
+        }
+        if let Some(vault) = user_vault.as_ref() {
+            if !inp_preview {
+                store_struct::<UserVault>(vault, acc_user_vault)?;
+            }
+        }
+        if inp_native_sol && !inp_preview {
+            unwrap_native_sol(
+                &ctx.accounts.user.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
             )?;
         }
+        if !inp_preview {
+            log_fill_fee(market, state_upd, acc_user, acc_settle1, acc_settle2, log_items)?;
+        }
+        collect_fee_discount(market, tokens_discount, inp_preview,
+            &ctx.accounts.discount_token.to_account_info(),     // From
+            &ctx.accounts.discount_vault.to_account_info(),     // To
+            &ctx.accounts.user.to_account_info(),               // Auth
+            &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+        )?;
         if *acc_result.key != *acc_user.key {
             store_struct::<TradeResult>(&result, acc_result)?;
         }
+        if *acc_fills.key != *acc_user.key {
+            store_struct::<FillsResult>(&fills, acc_fills)?;
+        }
 
         if !inp_preview {
             emit!(OrderEvent {
@@ -1751,6 +4255,7 @@ pub mod aqua_dex {
                 pricing_token: ctx.accounts.user_prc_token.key(),
                 order_id: result.order_id,
                 order_side: Side::Bid as u8,
+                order_mode: order_mode as u8,
                 filled: tokens_remaining == 0,
                 tokens_received: result.tokens_received,
                 tokens_sent: result.tokens_sent,
@@ -1760,6 +4265,11 @@ pub mod aqua_dex {
                 order_price: inp_price,
                 order_quantity: inp_quantity,
                 expires: expiry,
+                reduce_only: false,
+                mkt_vault_balance: state_upd.mkt_vault_balance,
+                prc_vault_balance: state_upd.prc_vault_balance,
+                mkt_order_balance: state_upd.mkt_order_balance,
+                prc_order_balance: state_upd.prc_order_balance,
             });
         }
 
@@ -1769,11 +4279,18 @@ pub mod aqua_dex {
     pub fn limit_ask<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderContext<'info>>,
         inp_quantity: u64,
         inp_price_request: u64,
-        inp_post: bool,     // Post the order order to the orderbook, otherwise fill based on parameter below
-        inp_fill: bool,     // Require orders that are not posted to be filled completely
+        inp_mode: u8,       // Order mode: 0 - Limit, 1 - Post only, 2 - Immediate or cancel, 3 - Fill or kill
         inp_expires: i64,   // Unix timestamp for order expiration (must be in the future, must exceed minimum duration)
         inp_preview: bool,  // Preview mode
         inp_rollover: bool, // Perform settlement log rollover
+        inp_use_vault: bool,    // Debit and credit tokens through the user vault instead of the user's token accounts
+        inp_display_qty: u64,   // Iceberg order displayed quantity (0 to display the entire order)
+        inp_native_sol: bool,   // Wrap and unwrap SOL for the pricing token (the "userPrcToken" account is closed afterwards)
+        inp_withdraw_first: bool,   // Withdraw the user's settled tokens from the current settlement logs before placing the order
+        inp_reject_self_cross: bool,    // Fail instead of posting an order that crosses the user's own resting orders
+        inp_expire_to_market: bool,     // Execute the posted order as a market order after it expires (with "expire_to_market")
+        inp_expire_budget: u32, // Max expired orders to remove after matching (0 for the default of MAX_EXPIRATIONS, up to MAX_EXPIRE_BUDGET)
+        inp_client_order_id: u128,  // Client order id to reject duplicate submissions (0 for none)
     ) -> anchor_lang::Result<TradeResult> {
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
@@ -1788,26 +4305,60 @@ pub mod aqua_dex {
         let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
         let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
+        let acc_fills = &ctx.accounts.fills.to_account_info();
+        let acc_referrer = &ctx.accounts.referrer.to_account_info();
+        let acc_user_volume = &ctx.accounts.user_volume.to_account_info();
+        let acc_user_vault = &ctx.accounts.user_vault.to_account_info();
 
-        if inp_post && inp_fill {
-            msg!("Require fill cannot be used with order posting");
-            return Err(ErrorCode::InvalidParameters.into());
+        if *acc_result.key != ctx.accounts.user.key() {
+            verify_result_account::<TradeResult>(acc_result, TRADE_RESULT_LEN)?;
         }
+        if *acc_fills.key != ctx.accounts.user.key() {
+            verify_result_account::<FillsResult>(acc_fills, FILLS_RESULT_LEN)?;
+        }
+
+        let order_mode = OrderMode::try_from(inp_mode).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let mut post_order: bool = order_mode == OrderMode::Limit || order_mode == OrderMode::PostOnly;
+        let fill_order: bool = order_mode == OrderMode::FillOrKill;
         if !market.active {
             msg!("Market closed");
             return Err(ErrorCode::MarketClosed.into());
         }
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        let expire_limit = expiration_limit(inp_expire_budget)?;
+        check_order_cooldown(market, &ctx.accounts.market.key(), acc_user, &ctx.accounts.user_cooldown.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), clock_ts, inp_preview)?;
+        check_native_sol(market, inp_native_sol, inp_use_vault)?;
+        if inp_withdraw_first && inp_use_vault {
+            msg!("Withdraw first can not be used with the user vault");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        let mut user_vault: Option<UserVault> = None;
+        if inp_use_vault {
+            user_vault = Some(load_user_vault(acc_user_vault, &market.key(), acc_user.key)?);
+        }
 
         require!(inp_quantity > 0 && inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        require!(inp_display_qty == 0 || inp_display_qty >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        check_lot_size(market, inp_quantity)?;
+        check_lot_size(market, inp_display_qty)?;
         require!(inp_price_request > 0, ErrorCode::InvalidParameters);
         let inp_price = get_tick_price(&market, inp_price_request)?;
         require!(inp_price > 0, ErrorCode::InvalidParameters);
+        check_tick_size(market, inp_price)?;
+        let order_value = scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?;
+        check_order_value(inp_quantity, order_value)?;
+        check_min_notional(market, order_value)?;
 
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
         verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
         let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
         let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
@@ -1818,8 +4369,11 @@ pub mod aqua_dex {
             return Err(ErrorCode::RetrySettlementAccount.into()); 
         }
 
-        // Append a settlement log account
         let state_upd = &mut ctx.accounts.state;
+        check_client_order(&market.key(), acc_user, &ctx.accounts.user_client_orders.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), inp_client_order_id, inp_preview)?;
+
+        // Append a settlement log account
         if inp_rollover && !inp_preview {
             if !state_upd.log_rollover {
                 // Another market participant already appended a new log account (please retry transaction)
@@ -1829,13 +4383,29 @@ pub mod aqua_dex {
             let av = ctx.remaining_accounts;
             let new_settlement_log = av.get(0).unwrap();
             let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
+            log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
             log_reimburse(market, state_upd, acc_user)?;
             let mut market_lamports = state_upd.to_account_info().lamports();
             market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
             **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
         }
 
+        // Withdraw settled tokens to the user's token accounts so they are available for this order
+        if inp_withdraw_first && !inp_preview {
+            withdraw_first(market, state_upd, ctx.remaining_accounts, acc_user, acc_settle1, acc_settle2,
+                &ctx.accounts.settle_prev.to_account_info(),
+                &ctx.accounts.agent.to_account_info(),
+                &ctx.accounts.mkt_vault.to_account_info(),
+                &ctx.accounts.prc_vault.to_account_info(),
+                &ctx.accounts.user_mkt_token.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
+            )?;
+        }
+        // If the withdrawal closed the first current log the log before it takes its place
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle1 = if state_upd.settle_a == acc_settle_prev.key() { acc_settle_prev } else { acc_settle1 };
+
         // Check expiration parameters
         let mut expiry: i64 = 0;
         // If expire timestamp is 0 then order does not expire
@@ -1851,6 +4421,10 @@ pub mod aqua_dex {
             }
             expiry = inp_expires;
         }
+        if inp_expire_to_market && expiry == 0 {
+            msg!("Expire to market requires an order expiration");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
 
         msg!("Atellix: Limit Ask: {} @ {}", inp_quantity.to_string(), inp_price.to_string());
 
@@ -1860,46 +4434,111 @@ pub mod aqua_dex {
             state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(inp_quantity).ok_or(error!(ErrorCode::Overflow))?;
         }
 
+        // Taker fee tier from the user's cumulative volume (the volume account is the user account if fee tiers are not used)
+        let mut user_volume: Option<UserVolume> = None;
+        if acc_user_volume.key() != acc_user.key() {
+            user_volume = Some(load_user_volume(acc_user_volume, &market.key(), acc_user.key)?);
+        }
+        let waived = fee_waived(market, clock_ts);
+        let full_taker_fee: u32 = if waived { 0 } else { taker_fee_rate(market, user_volume.as_ref().map_or(0, |v| v.volume)) };
+        let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+
+        // Utility token fee discount (the discount token account is the user account if no discount is requested)
+        let discount_rate: u32 = fee_discount_rate(market, full_taker_fee, acc_user.key,
+            &ctx.accounts.discount_token.to_account_info(),
+            &ctx.accounts.discount_vault.to_account_info(),
+        )?;
+        let taker_fee: u32 = full_taker_fee.checked_sub(discount_rate).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Referrer share of taker fees (the referrer account is the user account if there is no referrer)
+        let mut referral_share: u32 = 0;
+        if market.referral_fee_share > 0 && acc_referrer.key() != acc_user.key() {
+            if settlement_available(state_upd, acc_settle1, acc_settle2, acc_referrer.key)? {
+                referral_share = market.referral_fee_share;
+            } else {
+                msg!("Atellix: Referral fee not applied");
+            }
+        }
+
         let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
         let ob = SlabPageAlloc::new(orderbook_data);
 
+        // Settlement log entries before matching, after any withdrawal (entries created by this order are charged "log_fee_per_fill")
+        let log_items: u32 = settlement_items(acc_settle1, acc_settle2)?;
+
         // Check if order can be filled
         let mut tokens_to_fill: u64 = inp_quantity;
         let mut tokens_filled: u64 = 0;
         let mut tokens_received: u64 = 0;
         let mut tokens_fee: u64 = 0;
+        let mut tokens_discount: u64 = 0;
         let mut tokens_rebate: u64 = 0;
+        let breaker_price: u64 = state_upd.last_price;
+        let prc_volume_start: u128 = state_upd.prc_volume;
+        let mut self_volume: u128 = 0;
+        let mut fills = FillsResult { fills: Vec::new(), truncated: false };
         let mut expired_orders = Vec::new();
+        let mut skip = MatchSkip::new(OrderDT::BidOrder);
+        let mut preview_book = PreviewBook::new();
         let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
         verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
         let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
         let tlog = SlabPageAlloc::new(trade_data);
         loop {
-            let node_res = map_predicate_max(ob, DT::BidOrder, |sl, leaf|
-                valid_order(OrderDT::BidOrder, leaf, acc_user.key, sl, &mut expired_orders, clock_ts)
-            );
+            let node_res = map_predicate_max(ob, DT::BidOrder, market.max_orders, |sl, leaf| {
+                if preview_book.filled(leaf.key()) {
+                    return false;
+                }
+                skip.check(leaf.key(), || valid_order(OrderDT::BidOrder, leaf, acc_user.key, market.allow_self_trade, sl, &mut expired_orders, clock_ts))
+            });
+            if skip.limit_reached() {
+                // Fail instead of stopping early, the unfilled remainder could cross the orders behind the skipped ones
+                msg!("Atellix: Too many expired or same-user orders skipped - Use crank_expirations to remove expired orders");
+                return Err(ErrorCode::TooManySkippedOrders.into());
+            }
             if node_res.is_none() {
                 msg!("Atellix: No Match");
                 break;
             }
             let posted_node = node_res.unwrap();
-            let posted_order = ob.index::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize);
-            let posted_qty = posted_order.amount;
+            let posted_order = *ob.index::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize);
+            let posted_qty = preview_book.amount(posted_node.key(), &posted_order);
+            let posted_total = posted_order.total_amount();
             let posted_price = Order::price(posted_node.key());
+            if posted_price >= inp_price && order_mode == OrderMode::PostOnly {
+                msg!("Post only order would be filled");
+                return Err(ErrorCode::OrderWouldFill.into());
+            }
             if posted_price >= inp_price {
                 // Fill order
+                check_breaker(market, state_upd, clock_ts)?;
+                if breaker_tripped(market, breaker_price, posted_price)? {
+                    // Halt trading without posting a crossing order, the unfilled remainder is cancelled
+                    if !inp_preview {
+                        state_upd.breaker_tripped_ts = clock_ts;
+                    }
+                    post_order = false;
+                    break;
+                }
+                if !inp_preview && !settlement_available(state_upd, acc_settle1, acc_settle2, &posted_node.owner())? {
+                    // Stop matching without posting a crossing order, the unfilled remainder is cancelled
+                    msg!("Atellix: Settlement logs full - Matching stopped");
+                    post_order = false;
+                    break;
+                }
                 msg!("Atellix: Matched Bid [{}] {} @ {}", posted_node.slot().to_string(), posted_qty.to_string(), posted_price.to_string());
                 if posted_qty == tokens_to_fill {         // Match the entire order exactly
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
-                    msg!("Atellix: Filling - {} @ {}", tokens_part.to_string(), posted_price.to_string());
+                    msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             325819153524900178081877579778492284961, // solana/program/aqua-dex/limit_ask/match/exact
                             state_upd.action_counter,
@@ -1913,13 +4552,18 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, tokens_to_fill)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -1932,13 +4576,17 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(posted_qty, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, posted_qty, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             114544905925567569513505448268003180936, // solana/program/aqua-dex/limit_ask/match/entire
                             state_upd.action_counter,
@@ -1952,13 +4600,18 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, posted_qty)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -1969,13 +4622,14 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             282510189476950091999666304965232626740, // solana/program/aqua-dex/limit_ask/match/partial
                             state_upd.action_counter,
@@ -1989,12 +4643,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Bid, &posted_node)?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, tokens_to_fill)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2014,17 +4676,20 @@ pub mod aqua_dex {
         let mut expired_count: u32 = 0;
         if expired_orders.len() > 0 && !inp_preview {
             loop {
-                if expired_orders.len() == 0 || expired_count == MAX_EXPIRATIONS {
+                if expired_orders.len() == 0 || expired_count == expire_limit {
                     break;
                 }
                 let expired_id: u128 = expired_orders.pop().unwrap();
-                let expire_leaf = map_get(ob, DT::BidOrder, expired_id).unwrap();
+                let expire_leaf = map_get(ob, DT::BidOrder, market.max_orders, expired_id).unwrap();
+                if !settlement_available(state_upd, acc_settle1, acc_settle2, &expire_leaf.owner())? {
+                    break;
+                }
                 let expire_order = *ob.index::<Order>(OrderDT::BidOrder as u16, expire_leaf.slot() as usize);
-                let expire_amount: u64 = expire_order.amount();
+                let expire_amount: u64 = expire_order.total_amount();
                 msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
                     expire_leaf.slot().to_string(),
                     expire_leaf.owner().to_string(),
-                    expire_order.amount().to_string(),
+                    expire_amount.to_string(),
                     Order::price(expire_leaf.key()).to_string(),
                 );
                 let expire_price = Order::price(expire_leaf.key());
@@ -2041,7 +4706,7 @@ pub mod aqua_dex {
                     tokens: expire_total,
                 });
                 log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &expire_leaf.owner(), false, expire_total)?; // Total calculated
-                map_remove(ob, DT::BidOrder, expire_leaf.key())?;
+                map_remove(ob, DT::BidOrder, market.max_orders, expire_leaf.key())?;
                 Order::free_index(ob, DT::BidOrder, expire_leaf.slot())?;
                 state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                 expired_count = expired_count + 1;
@@ -2052,11 +4717,17 @@ pub mod aqua_dex {
 
         // Add order to orderbook if not filled
         let tokens_remaining = inp_quantity.checked_sub(tokens_filled).ok_or(error!(ErrorCode::Overflow))?;
-        if tokens_remaining > 0 && inp_fill {
+        if tokens_remaining > 0 && fill_order {
             msg!("Order not filled");
+            msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_received.to_string(), tokens_fee.to_string());
             return Err(ErrorCode::OrderNotFilled.into());
         }
-        if tokens_remaining > 0 && inp_post {
+        if tokens_remaining > 0 && post_order {
+            check_price_band(market, state_upd.last_price, inp_price)?;
+            check_owner_orders(ob, market, state_upd, acc_user.key)?;
+            if inp_reject_self_cross {
+                check_self_cross(ob, market, Side::Ask, acc_user.key, inp_price, clock_ts)?;
+            }
             // Add order to orderbook
             let mut order_id: u128 = u128::MAX;
             let mut order_idx: u32 = 1;
@@ -2064,32 +4735,63 @@ pub mod aqua_dex {
                 order_id = Order::new_key(state_upd, Side::Ask, inp_price);
                 order_idx = Order::next_index(ob, DT::AskOrder)?;
                 let order_node = LeafNode::new(order_id, order_idx, &acc_user.key);
-                let order = Order { amount: tokens_remaining, expiry: expiry };
+                let mut display_amount: u64 = tokens_remaining;
+                let mut display_qty: u64 = 0;
+                let mut hidden_qty: u64 = 0;
+                if inp_display_qty > 0 && inp_display_qty < tokens_remaining {
+                    // Iceberg order: only display part of the remaining quantity
+                    display_amount = inp_display_qty;
+                    display_qty = inp_display_qty;
+                    hidden_qty = tokens_remaining.checked_sub(inp_display_qty).ok_or(error!(ErrorCode::Overflow))?;
+                }
+                let order = Order { amount: display_amount, expiry: expiry, display_qty: display_qty, hidden_qty: hidden_qty, placed_ts: clock_ts, expire_to_market: inp_expire_to_market as u8 };
                 let mut eviction_count: u32 = 0;
+                // Each pass posts the order, fails, or evicts one order at a strictly worse price (an order at the same
+                // price is never evicted), so the loop ends after at most "eviction_max" evictions
                 loop {
-                    let entry = map_insert(ob, DT::AskOrder, &order_node);
+                    let entry = map_insert(ob, DT::AskOrder, market.max_orders, &order_node);
                     if entry.is_err() {
                         // Evict orders if necessary
-                        if eviction_count == MAX_EVICTIONS {
-                            msg!("Failed to add order");
+                        if !market.eviction_enabled {
+                            msg!("Atellix: Orderbook Full - Eviction disabled");
+                            return Err(ErrorCode::OrderbookFull.into());
+                        }
+                        if eviction_count >= market.eviction_max {
+                            msg!("Failed to add order - Eviction limit reached: {}", market.eviction_max.to_string());
                             return Err(ErrorCode::InternalError.into());
                         }
-                        let evict_node = map_max(ob, DT::AskOrder).unwrap();
+                        let evict_node = map_max(ob, DT::AskOrder, market.max_orders).ok_or(error!(ErrorCode::InternalError))?;
                         let evict_order = ob.index::<Order>(OrderDT::AskOrder as u16, evict_node.slot() as usize);
-                        // Only evict if the price is better and quantity is equal or more
-                        if inp_price >= Order::price(evict_node.key()) || tokens_remaining < evict_order.amount() {
+                        // Only evict if the price is better (by at least the minimum improvement) and quantity is equal or more
+                        let evict_price: u64 = Order::price(evict_node.key());
+                        if inp_price >= evict_price || evict_price - inp_price < market.eviction_min_improvement || tokens_remaining < evict_order.total_amount() {
                             msg!("Atellix: Orderbook Full - Price is not below evicted order or posted quantity is less than evicted order");
                             return Err(ErrorCode::OrderbookFull.into());
                         }
-                        let evict_amount: u64 = evict_order.amount();
+                        let evict_amount: u64 = evict_order.total_amount();
+                        if !settlement_available(state_upd, acc_settle1, acc_settle2, &evict_node.owner())? {
+                            msg!("Atellix: Orderbook Full - Settlement logs full, can not evict order");
+                            return Err(ErrorCode::SettlementLogFull.into());
+                        }
                         msg!("Atellix: Evicting Order[{}] - Owner: {} {} @ {}",
                             evict_node.slot().to_string(),
                             evict_node.owner().to_string(),
-                            evict_order.amount().to_string(),
+                            evict_amount.to_string(),
                             Order::price(evict_node.key()).to_string(),
                         );
+                        emit!(EvictEvent {
+                            event_type: 332864934724696862523374391891968586946, // solana/program/aqua-dex/evict_event
+                            action_id: state_upd.action_counter,
+                            market: market.key(),
+                            owner: evict_node.owner(),
+                            order_side: Side::Ask as u8,
+                            order_id: evict_node.key(),
+                            price: Order::price(evict_node.key()),
+                            quantity: evict_amount,
+                            tokens: evict_amount,
+                        });
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &evict_node.owner(), true, evict_amount)?;
-                        map_remove(ob, DT::AskOrder, evict_node.key())?;
+                        map_remove(ob, DT::AskOrder, market.max_orders, evict_node.key())?;
                         Order::free_index(ob, DT::AskOrder, evict_node.slot())?;
                         state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                         eviction_count = eviction_count + 1;
@@ -2105,6 +4807,17 @@ pub mod aqua_dex {
             msg!("Atellix: Posted Ask [{}] {} @ {}", order_idx.to_string(), inp_quantity.to_string(), inp_price.to_string());
         }
 
+        // Only deposit the unfilled remainder if it was posted
+        let mut tokens_sent: u64 = inp_quantity;
+        if tokens_remaining > 0 && !post_order {
+            tokens_sent = tokens_filled;
+            result.set_tokens_sent(tokens_sent);
+            if !inp_preview {
+                state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_sub(tokens_remaining).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_sub(tokens_remaining).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        }
+
         /*msg!("Atellix: Market Token Vault Deposit: {}", inp_quantity.to_string());
         msg!("Atellix: Market Token Vault Balance: {} (Orderbook: {})",
             state_upd.mkt_vault_balance,
@@ -2123,26 +4836,37 @@ pub mod aqua_dex {
         }
 
         // Send tokens to the vault
-        let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-        perform_transfer(ctx.remaining_accounts, mint_type, 0, inp_quantity, inp_preview,
-            &ctx.accounts.user_mkt_token.to_account_info(),  // From
-            &ctx.accounts.mkt_vault.to_account_info(),       // To
-            &ctx.accounts.user.to_account_info(),            // Auth
-            &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
-        )?;
+        if let Some(vault) = user_vault.as_mut() {
+            if vault.mkt_tokens < tokens_sent {
+                msg!("Insufficient tokens in user vault");
+                return Err(ErrorCode::InsufficientTokens.into());
+            }
+            if !inp_preview {
+                // Tokens are already in the market vault
+                vault.mkt_tokens = vault.mkt_tokens.checked_sub(tokens_sent).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.mkt_user_vault_balance = state_upd.mkt_user_vault_balance.checked_sub(tokens_sent).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_sub(tokens_sent).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        } else {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_sent, inp_preview,
+                &ctx.accounts.user_mkt_token.to_account_info(),  // From
+                &ctx.accounts.mkt_vault.to_account_info(),       // To
+                &ctx.accounts.user.to_account_info(),            // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+        }
 
         if tokens_filled > 0 {
             tokens_received = tokens_received.checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
             result.set_tokens_received(tokens_received);
             if !inp_preview {
                 // Withdraw tokens from the vault
-                state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_sub(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
                 state_upd.prc_order_balance = state_upd.prc_order_balance.checked_sub(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
 
                 // Apply fees
-                state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-                state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-                state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+                apply_taker_fees(&market.key(), state_upd, acc_settle1, acc_settle2, acc_referrer.key, referral_share, tokens_fee, tokens_rebate,
+                    &mut user_volume, acc_user_volume, prc_volume_start, self_volume)?;
 
                 //msg!("Atellix: Pricing Token Vault Withdraw: {}", tokens_received.to_string());
                 /*msg!("Atellix: Pricing Token Vault Balance: {} (Orderbook: {})",
@@ -2150,23 +4874,53 @@ pub mod aqua_dex {
                     state_upd.prc_order_balance,
                 );*/
 
-                let seeds = &[
-                    market.to_account_info().key.as_ref(),
-                    &[market.agent_nonce],
-                ];
-                let signer = &[&seeds[..]];
-                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_received,
-                    &ctx.accounts.prc_vault.to_account_info(),          // From
-                    &ctx.accounts.user_prc_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
+                if let Some(vault) = user_vault.as_mut() {
+                    vault.prc_tokens = vault.prc_tokens.checked_add(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
+                    state_upd.prc_user_vault_balance = state_upd.prc_user_vault_balance.checked_add(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
+                } else {
+                    state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_sub(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
+                    let seeds = &[
+                        market.to_account_info().key.as_ref(),
+                        &[market.agent_nonce],
+                    ];
+                    let signer = &[&seeds[..]];
+                    let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                    perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_received,
+                        &ctx.accounts.prc_vault.to_account_info(),          // From
+                        &ctx.accounts.user_prc_token.to_account_info(),     // To
+                        &ctx.accounts.agent.to_account_info(),              // Auth
+                        &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                    )?;
+                }
             }
         }
+        if let Some(vault) = user_vault.as_ref() {
+            if !inp_preview {
+                store_struct::<UserVault>(vault, acc_user_vault)?;
+            }
+        }
+        if inp_native_sol && !inp_preview {
+            unwrap_native_sol(
+                &ctx.accounts.user.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
+            )?;
+        }
+        if !inp_preview {
+            log_fill_fee(market, state_upd, acc_user, acc_settle1, acc_settle2, log_items)?;
+        }
+        collect_fee_discount(market, tokens_discount, inp_preview,
+            &ctx.accounts.discount_token.to_account_info(),     // From
+            &ctx.accounts.discount_vault.to_account_info(),     // To
+            &ctx.accounts.user.to_account_info(),               // Auth
+            &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+        )?;
         if *acc_result.key != *acc_user.key {
             store_struct::<TradeResult>(&result, acc_result)?;
         }
+        if *acc_fills.key != *acc_user.key {
+            store_struct::<FillsResult>(&fills, acc_fills)?;
+        }
 
         if !inp_preview {
             emit!(OrderEvent {
@@ -2178,6 +4932,7 @@ pub mod aqua_dex {
                 pricing_token: ctx.accounts.user_prc_token.key(),
                 order_id: result.order_id,
                 order_side: Side::Ask as u8,
+                order_mode: order_mode as u8,
                 filled: tokens_remaining == 0,
                 tokens_received: result.tokens_received,
                 tokens_sent: result.tokens_sent,
@@ -2187,6 +4942,11 @@ pub mod aqua_dex {
                 order_price: inp_price,
                 order_quantity: inp_quantity,
                 expires: expiry,
+                reduce_only: false,
+                mkt_vault_balance: state_upd.mkt_vault_balance,
+                prc_vault_balance: state_upd.prc_vault_balance,
+                mkt_order_balance: state_upd.mkt_order_balance,
+                prc_order_balance: state_upd.prc_order_balance,
             });
         }
 
@@ -2197,15 +4957,22 @@ pub mod aqua_dex {
         inp_by_quantity: bool,  // Fill by quantity (otherwise price)
         inp_quantity: u64,      // Fill until quantity
         inp_net_price: u64,     // Fill until net price is reached
+        inp_limit_price: u64,   // Stop filling at orders priced worse than this price (0 for no limit)
         inp_fill: bool,         // Require order to be filled completely
         inp_preview: bool,      // Preview mode
         inp_rollover: bool,     // Perform settlement log rollover
+        inp_native_sol: bool,   // Wrap and unwrap SOL for the pricing token (the "userPrcToken" account is closed afterwards)
+        inp_reduce_only: bool,  // Cap the fill at the user's pricing token balance (from the "userVault" account or the settlement logs)
+        inp_min_fill_bps: u32,  // Minimum filled share of the quantity or net price in basis points (0 for no minimum)
+        inp_expire_budget: u32, // Max expired orders to remove after matching (0 for the default of MAX_EXPIRATIONS, up to MAX_EXPIRE_BUDGET)
+        inp_client_order_id: u128,  // Client order id to reject duplicate submissions (0 for none)
     ) -> anchor_lang::Result<TradeResult> {
         if inp_by_quantity {
             require!(inp_quantity > 0, ErrorCode::InvalidParameters);
         } else {
             require!(inp_net_price > 0, ErrorCode::InvalidParameters);
         }
+        require!(inp_min_fill_bps <= 10000, ErrorCode::InvalidParameters);
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
 
@@ -2219,20 +4986,45 @@ pub mod aqua_dex {
         let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
         let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
+        let acc_fills = &ctx.accounts.fills.to_account_info();
+        let acc_referrer = &ctx.accounts.referrer.to_account_info();
+        let acc_user_volume = &ctx.accounts.user_volume.to_account_info();
+        let acc_user_vault = &ctx.accounts.user_vault.to_account_info();
+
+        if *acc_result.key != ctx.accounts.user.key() {
+            verify_result_account::<TradeResult>(acc_result, TRADE_RESULT_LEN)?;
+        }
+        if *acc_fills.key != ctx.accounts.user.key() {
+            verify_result_account::<FillsResult>(acc_fills, FILLS_RESULT_LEN)?;
+        }
 
         if !market.active {
             msg!("Market closed");
             return Err(ErrorCode::MarketClosed.into());
         }
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        let expire_limit = expiration_limit(inp_expire_budget)?;
+        check_order_cooldown(market, &ctx.accounts.market.key(), acc_user, &ctx.accounts.user_cooldown.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), clock_ts, inp_preview)?;
+        check_native_sol(market, inp_native_sol, false)?;
+        check_breaker(market, market_state, clock_ts)?;
         if inp_by_quantity {
             require!(inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+            check_lot_size(market, inp_quantity)?;
+        }
+        if inp_limit_price > 0 {
+            check_tick_size(market, inp_limit_price)?;
         }
 
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
         verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
         let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
         let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
@@ -2243,8 +5035,11 @@ pub mod aqua_dex {
             return Err(ErrorCode::RetrySettlementAccount.into());
         }
 
-        // Append a settlement log account
         let state_upd = &mut ctx.accounts.state;
+        check_client_order(&market.key(), acc_user, &ctx.accounts.user_client_orders.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), inp_client_order_id, inp_preview)?;
+
+        // Append a settlement log account
         if inp_rollover && !inp_preview {
             if !state_upd.log_rollover {
                 // Another market participant already appended a new log account (please retry transaction)
@@ -2254,7 +5049,7 @@ pub mod aqua_dex {
             let av = ctx.remaining_accounts;
             let new_settlement_log = av.get(0).unwrap();
             let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
+            log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
             log_reimburse(market, state_upd, acc_user)?;
             let mut market_lamports = state_upd.to_account_info().lamports();
             market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
@@ -2267,33 +5062,119 @@ pub mod aqua_dex {
             state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
         }
 
+        // Taker fee tier from the user's cumulative volume (the volume account is the user account if fee tiers are not used)
+        let mut user_volume: Option<UserVolume> = None;
+        if acc_user_volume.key() != acc_user.key() {
+            user_volume = Some(load_user_volume(acc_user_volume, &market.key(), acc_user.key)?);
+        }
+        let waived = fee_waived(market, clock_ts);
+        let full_taker_fee: u32 = if waived { 0 } else { taker_fee_rate(market, user_volume.as_ref().map_or(0, |v| v.volume)) };
+        let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+
+        // Utility token fee discount (the discount token account is the user account if no discount is requested)
+        let discount_rate: u32 = fee_discount_rate(market, full_taker_fee, acc_user.key,
+            &ctx.accounts.discount_token.to_account_info(),
+            &ctx.accounts.discount_vault.to_account_info(),
+        )?;
+        let taker_fee: u32 = full_taker_fee.checked_sub(discount_rate).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Referrer share of taker fees (the referrer account is the user account if there is no referrer)
+        let mut referral_share: u32 = 0;
+        if market.referral_fee_share > 0 && acc_referrer.key() != acc_user.key() {
+            if settlement_available(state_upd, acc_settle1, acc_settle2, acc_referrer.key)? {
+                referral_share = market.referral_fee_share;
+            } else {
+                msg!("Atellix: Referral fee not applied");
+            }
+        }
+
         let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
         let ob = SlabPageAlloc::new(orderbook_data);
 
+        // Settlement log entries before matching (entries created by this order are charged "log_fee_per_fill")
+        let log_items: u32 = settlement_items(acc_settle1, acc_settle2)?;
+
         // Check if order can be filled
         let mut price_to_fill: u64 = inp_net_price;
         let mut tokens_to_fill: u64 = inp_quantity;
         let mut tokens_filled: u64 = 0;
         let mut tokens_paid: u64 = 0;
         let mut tokens_fee: u64 = 0;
+        let mut tokens_discount: u64 = 0;
         let mut tokens_rebate: u64 = 0;
+        let breaker_price: u64 = state_upd.last_price;
+        let prc_volume_start: u128 = state_upd.prc_volume;
+        let mut self_volume: u128 = 0;
+        let mut fills = FillsResult { fills: Vec::new(), truncated: false };
+
+        // Reduce-only orders are capped at the user's pricing token balance
+        let mut reduce_balance: u64 = 0;
+        let mut reduce_capped: bool = false;
+        if inp_reduce_only {
+            reduce_balance = reducible_balance(acc_user_vault, &market.key(), acc_user.key, acc_settle1, acc_settle2, false)?;
+            msg!("Atellix: Reduce Only - Balance: {}", reduce_balance.to_string());
+        }
         let mut expired_orders = Vec::new();
+        let mut skip = MatchSkip::new(OrderDT::AskOrder);
+        let mut preview_book = PreviewBook::new();
         let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
         verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
         let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
         let tlog = SlabPageAlloc::new(trade_data);
         loop {
-            let node_res = map_predicate_min(ob, DT::AskOrder, |sl, leaf|
-                valid_order(OrderDT::AskOrder, leaf, acc_user.key, sl, &mut expired_orders, clock_ts)
-            );
+            let node_res = map_predicate_min(ob, DT::AskOrder, market.max_orders, |sl, leaf| {
+                if preview_book.filled(leaf.key()) {
+                    return false;
+                }
+                skip.check(leaf.key(), || valid_order(OrderDT::AskOrder, leaf, acc_user.key, market.allow_self_trade, sl, &mut expired_orders, clock_ts))
+            });
+            if skip.limit_reached() {
+                // Fail instead of stopping early, the unfilled remainder could cross the orders behind the skipped ones
+                msg!("Atellix: Too many expired or same-user orders skipped - Use crank_expirations to remove expired orders");
+                return Err(ErrorCode::TooManySkippedOrders.into());
+            }
             if node_res.is_none() {
                 msg!("Atellix: No Match");
                 break;
             }
             let posted_node = node_res.unwrap();
-            let posted_order = ob.index::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize);
-            let posted_qty = posted_order.amount;
+            let posted_order = *ob.index::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize);
+            let posted_qty = preview_book.amount(posted_node.key(), &posted_order);
             let posted_price = Order::price(posted_node.key());
+            if inp_limit_price > 0 && posted_price > inp_limit_price {
+                // Keep the fills so far and stop at the worst acceptable price
+                msg!("Atellix: Limit price reached - Matching stopped");
+                break;
+            }
+            if breaker_tripped(market, breaker_price, posted_price)? {
+                // Halt trading, the unfilled remainder is cancelled
+                if !inp_preview {
+                    state_upd.breaker_tripped_ts = clock_ts;
+                }
+                break;
+            }
+            if !inp_preview && !settlement_available(state_upd, acc_settle1, acc_settle2, &posted_node.owner())? {
+                msg!("Atellix: Settlement logs full - Matching stopped");
+                break;
+            }
+            if inp_reduce_only {
+                // Stop once the reducible balance is consumed (fees are not included)
+                let reduce_remaining = reduce_balance.saturating_sub(tokens_paid);
+                if inp_by_quantity {
+                    let reduce_qty = lot_quantity(market, fill_quantity(reduce_remaining, posted_price, decimal_factor(market.mkt_decimals))?);
+                    if reduce_qty < tokens_to_fill {
+                        tokens_to_fill = reduce_qty;
+                        reduce_capped = true;
+                    }
+                } else if reduce_remaining < price_to_fill {
+                    price_to_fill = reduce_remaining;
+                    reduce_capped = true;
+                }
+                if (inp_by_quantity && tokens_to_fill == 0) || (!inp_by_quantity && price_to_fill == 0) {
+                    msg!("Atellix: Reduce only balance consumed - Matching stopped");
+                    break;
+                }
+            }
             msg!("Atellix: Matched Ask [{}] {} @ {}", posted_node.slot().to_string(), posted_qty.to_string(), posted_price.to_string());
             // Fill order
             if inp_by_quantity {
@@ -2302,12 +5183,12 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
-                    msg!("Atellix: Filling - {} @ {}", tokens_part.to_string(), posted_price.to_string());
+                    msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
                         log_trade(tlog,
                             97879353062914658353780090028087623355, // solana/program/aqua-dex/market_bid/match/quantity/exact
@@ -2322,15 +5203,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2345,12 +5231,15 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(posted_qty, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
                         log_trade(tlog,
                             98887148454935384202006639804150096432, // solana/program/aqua-dex/market_bid/match/quantity/entire
@@ -2365,15 +5254,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2386,12 +5280,12 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_paid = tokens_paid.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
                         log_trade(tlog,
                             241528249049192735796332143519520355761, // solana/program/aqua-dex/market_bid/match/quantity/partial
@@ -2406,14 +5300,22 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = tokens_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2430,12 +5332,15 @@ pub mod aqua_dex {
                 if posted_part == price_to_fill {         // Match the entire order exactly
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_paid = tokens_paid.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, posted_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, posted_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, posted_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
-                    msg!("Atellix: Filling - {} @ {}", tokens_filled.to_string(), posted_price.to_string());
+                    msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
                         log_trade(tlog,
                             331852354717548342008417076114136032746, // solana/program/aqua-dex/market_bid/match/net_price/exact
@@ -2450,15 +5355,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, posted_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, posted_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = posted_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2472,12 +5382,15 @@ pub mod aqua_dex {
                     price_to_fill = price_to_fill.checked_sub(posted_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_paid = tokens_paid.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, posted_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, posted_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, posted_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
                         log_trade(tlog,
                             30314321964017162377189412309266042294, // solana/program/aqua-dex/market_bid/match/net_price/entire
@@ -2492,15 +5405,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::AskOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::AskOrder, posted_node.slot())?;
-                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, posted_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, posted_part)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = posted_part.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2510,16 +5428,20 @@ pub mod aqua_dex {
                         }
                     }
                 } else if posted_part > price_to_fill {   // Match part of the order
-                    // Calculate filled tokens
-                    let fill_amount = fill_quantity(price_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
+                    // Calculate filled tokens (rounded down to the lot size, the rest of the net price is not filled)
+                    let (fill_amount, fill_cost) = match net_price_fill(market, price_to_fill, posted_price)? {
+                        Some(fill) => fill,
+                        None => break,
+                    };
+                    price_to_fill = fill_cost;
                     tokens_filled = tokens_filled.checked_add(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_paid = tokens_paid.checked_add(price_to_fill).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, price_to_fill)?;
-                    let fee_part = calculate_fee(market.taker_fee, price_to_fill)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, price_to_fill)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", fill_amount.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, fill_amount, fee_part);
                     if !inp_preview {
                         log_trade(tlog,
                             237563056127520713232024370460619306548, // solana/program/aqua-dex/market_bid/match/net_price/partial
@@ -2534,14 +5456,22 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            0,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::AskOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Ask, &posted_node)?;
                         state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(price_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(price_to_fill).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, fill_amount, price_to_fill)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, price_to_fill)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                             let total = price_to_fill.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2555,21 +5485,28 @@ pub mod aqua_dex {
             }
         }
         msg!("Atellix: Fee: {}", tokens_fee.to_string());
+        if tokens_filled > 0 {
+            check_order_value(tokens_filled, tokens_paid)?;
+            check_min_notional(market, tokens_paid)?;
+        }
 
         let mut expired_count: u32 = 0;
         if expired_orders.len() > 0 && !inp_preview {
             loop {
-                if expired_orders.len() == 0 || expired_count == MAX_EXPIRATIONS {
+                if expired_orders.len() == 0 || expired_count == expire_limit {
                     break;
                 }
                 let expired_id: u128 = expired_orders.pop().unwrap();
-                let expire_leaf = map_get(ob, DT::AskOrder, expired_id).unwrap();
+                let expire_leaf = map_get(ob, DT::AskOrder, market.max_orders, expired_id).unwrap();
+                if !settlement_available(state_upd, acc_settle1, acc_settle2, &expire_leaf.owner())? {
+                    break;
+                }
                 let expire_order = *ob.index::<Order>(OrderDT::AskOrder as u16, expire_leaf.slot() as usize);
-                let expire_amount: u64 = expire_order.amount();
+                let expire_amount: u64 = expire_order.total_amount();
                 msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
                     expire_leaf.slot().to_string(),
                     expire_leaf.owner().to_string(),
-                    expire_order.amount().to_string(),
+                    expire_amount.to_string(),
                     Order::price(expire_leaf.key()).to_string(),
                 );
                 emit!(ExpireEvent {
@@ -2584,7 +5521,7 @@ pub mod aqua_dex {
                     tokens: expire_amount,
                 });
                 log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &expire_leaf.owner(), true, expire_amount)?; // No multiply for Ask order
-                map_remove(ob, DT::AskOrder, expire_leaf.key())?;
+                map_remove(ob, DT::AskOrder, market.max_orders, expire_leaf.key())?;
                 Order::free_index(ob, DT::AskOrder, expire_leaf.slot())?;
                 state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                 expired_count = expired_count + 1;
@@ -2593,26 +5530,39 @@ pub mod aqua_dex {
 
         let mut result = TradeResult { tokens_received: tokens_filled, posted_quantity: 0, tokens_sent: 0, tokens_fee: tokens_fee, order_id: 0 };
 
-        if inp_fill {
+        if inp_fill && !reduce_capped {
             if inp_by_quantity {
                 if tokens_filled != inp_quantity {
                     msg!("Order not filled");
+                    msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_paid.to_string(), tokens_fee.to_string());
                     return Err(ErrorCode::OrderNotFilled.into());
                 }
             } else {
                 if tokens_paid != inp_net_price {
                     msg!("Order not filled");
+                    msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_paid.to_string(), tokens_fee.to_string());
                     return Err(ErrorCode::OrderNotFilled.into());
                 }
             }
         }
+        if inp_min_fill_bps > 0 && !reduce_capped {
+            let fill_met = if inp_by_quantity {
+                min_fill_met(tokens_filled, inp_quantity, inp_min_fill_bps)?
+            } else {
+                min_fill_met(tokens_paid, inp_net_price, inp_min_fill_bps)?
+            };
+            if !fill_met {
+                msg!("Order below minimum fill of {} basis points", inp_min_fill_bps.to_string());
+                msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_paid.to_string(), tokens_fee.to_string());
+                return Err(ErrorCode::OrderNotFilled.into());
+            }
+        }
 
         // Apply fees
         tokens_paid = tokens_paid.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
         if !inp_preview {
-            state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-            state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-            state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            apply_taker_fees(&market.key(), state_upd, acc_settle1, acc_settle2, acc_referrer.key, referral_share, tokens_fee, tokens_rebate,
+                &mut user_volume, acc_user_volume, prc_volume_start, self_volume)?;
         }
 
         /*msg!("Atellix: Pricing Token Vault Deposit: {}", total_cost.to_string());
@@ -2621,14 +5571,28 @@ pub mod aqua_dex {
             state_upd.prc_order_balance,
         );*/
 
-        // Send tokens to the vault
-        let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-        perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_paid, inp_preview,
-            &ctx.accounts.user_prc_token.to_account_info(),  // From
-            &ctx.accounts.prc_vault.to_account_info(),       // To
-            &ctx.accounts.user.to_account_info(),            // Auth
-            &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
-        )?;
+        // Send tokens to the vault (no transfer if nothing was filled)
+        if tokens_filled == 0 {
+            msg!("Atellix: No Liquidity - Nothing filled");
+        } else if inp_native_sol && inp_preview {
+            preview_native_sol(tokens_paid, &ctx.accounts.user.to_account_info(), &ctx.accounts.user_prc_token.to_account_info())?;
+        } else {
+            if inp_native_sol {
+                wrap_native_sol(tokens_paid,
+                    &ctx.accounts.user.to_account_info(),
+                    &ctx.accounts.user_prc_token.to_account_info(),
+                    &ctx.accounts.system_program.to_account_info(),
+                    &ctx.accounts.spl_token_prog.to_account_info(),
+                )?;
+            }
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_paid, inp_preview,
+                &ctx.accounts.user_prc_token.to_account_info(),  // From
+                &ctx.accounts.prc_vault.to_account_info(),       // To
+                &ctx.accounts.user.to_account_info(),            // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+        }
         result.set_tokens_sent(tokens_paid);
 
         if tokens_filled > 0 && !inp_preview {
@@ -2652,9 +5616,28 @@ pub mod aqua_dex {
                 &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
             )?;
         }
+        if inp_native_sol && !inp_preview {
+            unwrap_native_sol(
+                &ctx.accounts.user.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
+            )?;
+        }
+        if !inp_preview {
+            log_fill_fee(market, state_upd, acc_user, acc_settle1, acc_settle2, log_items)?;
+        }
+        collect_fee_discount(market, tokens_discount, inp_preview,
+            &ctx.accounts.discount_token.to_account_info(),     // From
+            &ctx.accounts.discount_vault.to_account_info(),     // To
+            &ctx.accounts.user.to_account_info(),               // Auth
+            &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+        )?;
         if *acc_result.key != *acc_user.key {
             store_struct::<TradeResult>(&result, acc_result)?;
         }
+        if *acc_fills.key != *acc_user.key {
+            store_struct::<FillsResult>(&fills, acc_fills)?;
+        }
         let was_filled: bool = if inp_by_quantity {
             tokens_filled == inp_quantity
         } else {
@@ -2672,6 +5655,7 @@ pub mod aqua_dex {
                 pricing_token: ctx.accounts.user_prc_token.key(),
                 order_id: 0,
                 order_side: Side::Bid as u8,
+                order_mode: if inp_fill { OrderMode::FillOrKill as u8 } else { OrderMode::ImmediateOrCancel as u8 },
                 filled: was_filled,
                 tokens_received: result.tokens_received,
                 tokens_sent: result.tokens_sent,
@@ -2681,6 +5665,11 @@ pub mod aqua_dex {
                 order_price: inp_net_price,
                 order_quantity: inp_quantity,
                 expires: 0,
+                reduce_only: inp_reduce_only,
+                mkt_vault_balance: state_upd.mkt_vault_balance,
+                prc_vault_balance: state_upd.prc_vault_balance,
+                mkt_order_balance: state_upd.mkt_order_balance,
+                prc_order_balance: state_upd.prc_order_balance,
             });
         }
 
@@ -2691,15 +5680,22 @@ pub mod aqua_dex {
         inp_by_quantity: bool,  // Fill by quantity (otherwise price)
         inp_quantity: u64,      // Fill until quantity
         inp_net_price: u64,     // Fill until net price is reached
+        inp_limit_price: u64,   // Stop filling at orders priced worse than this price (0 for no limit)
         inp_fill: bool,         // Require order to be filled completely
         inp_preview: bool,      // Preview mode
         inp_rollover: bool,     // Perform settlement log rollover
+        inp_native_sol: bool,   // Wrap and unwrap SOL for the pricing token (the "userPrcToken" account is closed afterwards)
+        inp_reduce_only: bool,  // Cap the fill at the user's market token balance (from the "userVault" account or the settlement logs)
+        inp_min_fill_bps: u32,  // Minimum filled share of the quantity or net price in basis points (0 for no minimum)
+        inp_expire_budget: u32, // Max expired orders to remove after matching (0 for the default of MAX_EXPIRATIONS, up to MAX_EXPIRE_BUDGET)
+        inp_client_order_id: u128,  // Client order id to reject duplicate submissions (0 for none)
     ) -> anchor_lang::Result<TradeResult> {
         if inp_by_quantity {
             require!(inp_quantity > 0, ErrorCode::InvalidParameters);
         } else {
             require!(inp_net_price > 0, ErrorCode::InvalidParameters);
         }
+        require!(inp_min_fill_bps <= 10000, ErrorCode::InvalidParameters);
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
 
@@ -2713,20 +5709,45 @@ pub mod aqua_dex {
         let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
         let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
+        let acc_fills = &ctx.accounts.fills.to_account_info();
+        let acc_referrer = &ctx.accounts.referrer.to_account_info();
+        let acc_user_volume = &ctx.accounts.user_volume.to_account_info();
+        let acc_user_vault = &ctx.accounts.user_vault.to_account_info();
+
+        if *acc_result.key != ctx.accounts.user.key() {
+            verify_result_account::<TradeResult>(acc_result, TRADE_RESULT_LEN)?;
+        }
+        if *acc_fills.key != ctx.accounts.user.key() {
+            verify_result_account::<FillsResult>(acc_fills, FILLS_RESULT_LEN)?;
+        }
 
         if !market.active {
             msg!("Market closed");
             return Err(ErrorCode::MarketClosed.into());
         }
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        let expire_limit = expiration_limit(inp_expire_budget)?;
+        check_order_cooldown(market, &ctx.accounts.market.key(), acc_user, &ctx.accounts.user_cooldown.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), clock_ts, inp_preview)?;
+        check_native_sol(market, inp_native_sol, false)?;
+        check_breaker(market, market_state, clock_ts)?;
         if inp_by_quantity {
             require!(inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+            check_lot_size(market, inp_quantity)?;
+        }
+        if inp_limit_price > 0 {
+            check_tick_size(market, inp_limit_price)?;
         }
 
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
         verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
         let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
         let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
@@ -2737,8 +5758,11 @@ pub mod aqua_dex {
             return Err(ErrorCode::RetrySettlementAccount.into()); 
         }
 
-        // Append a settlement log account
         let state_upd = &mut ctx.accounts.state;
+        check_client_order(&market.key(), acc_user, &ctx.accounts.user_client_orders.to_account_info(),
+            &ctx.accounts.system_program.to_account_info(), inp_client_order_id, inp_preview)?;
+
+        // Append a settlement log account
         if inp_rollover && !inp_preview {
             if !state_upd.log_rollover {
                 // Another market participant already appended a new log account (please retry transaction)
@@ -2748,7 +5772,7 @@ pub mod aqua_dex {
             let av = ctx.remaining_accounts;
             let new_settlement_log = av.get(0).unwrap();
             let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
+            log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
             log_reimburse(market, state_upd, acc_user)?;
             let mut market_lamports = state_upd.to_account_info().lamports();
             market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
@@ -2762,47 +5786,137 @@ pub mod aqua_dex {
             state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
         }
 
+        // Taker fee tier from the user's cumulative volume (the volume account is the user account if fee tiers are not used)
+        let mut user_volume: Option<UserVolume> = None;
+        if acc_user_volume.key() != acc_user.key() {
+            user_volume = Some(load_user_volume(acc_user_volume, &market.key(), acc_user.key)?);
+        }
+        let waived = fee_waived(market, clock_ts);
+        let full_taker_fee: u32 = if waived { 0 } else { taker_fee_rate(market, user_volume.as_ref().map_or(0, |v| v.volume)) };
+        let maker_rebate: u32 = if waived { 0 } else { market.maker_rebate };
+
+        // Utility token fee discount (the discount token account is the user account if no discount is requested)
+        let discount_rate: u32 = fee_discount_rate(market, full_taker_fee, acc_user.key,
+            &ctx.accounts.discount_token.to_account_info(),
+            &ctx.accounts.discount_vault.to_account_info(),
+        )?;
+        let taker_fee: u32 = full_taker_fee.checked_sub(discount_rate).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Referrer share of taker fees (the referrer account is the user account if there is no referrer)
+        let mut referral_share: u32 = 0;
+        if market.referral_fee_share > 0 && acc_referrer.key() != acc_user.key() {
+            if settlement_available(state_upd, acc_settle1, acc_settle2, acc_referrer.key)? {
+                referral_share = market.referral_fee_share;
+            } else {
+                msg!("Atellix: Referral fee not applied");
+            }
+        }
+
         let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
         let ob = SlabPageAlloc::new(orderbook_data);
 
+        // Settlement log entries before matching (entries created by this order are charged "log_fee_per_fill")
+        let log_items: u32 = settlement_items(acc_settle1, acc_settle2)?;
+
         // Check if order can be filled
         let mut price_to_fill: u64 = inp_net_price;
         let mut tokens_to_fill: u64 = inp_quantity;
         let mut tokens_filled: u64 = 0;
         let mut tokens_received: u64 = 0;
         let mut tokens_fee: u64 = 0;
+        let mut tokens_discount: u64 = 0;
         let mut tokens_rebate: u64 = 0;
+        let breaker_price: u64 = state_upd.last_price;
+        let prc_volume_start: u128 = state_upd.prc_volume;
+        let mut self_volume: u128 = 0;
+        let mut fills = FillsResult { fills: Vec::new(), truncated: false };
+
+        // Reduce-only orders are capped at the user's market token balance
+        let mut reduce_balance: u64 = 0;
+        let mut reduce_capped: bool = false;
+        if inp_reduce_only {
+            reduce_balance = reducible_balance(acc_user_vault, &market.key(), acc_user.key, acc_settle1, acc_settle2, true)?;
+            msg!("Atellix: Reduce Only - Balance: {}", reduce_balance.to_string());
+        }
         let mut expired_orders = Vec::new();
+        let mut skip = MatchSkip::new(OrderDT::BidOrder);
+        let mut preview_book = PreviewBook::new();
         let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
         verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
         let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
         let tlog = SlabPageAlloc::new(trade_data);
         loop {
-            let node_res = map_predicate_max(ob, DT::BidOrder, |sl, leaf|
-                valid_order(OrderDT::BidOrder, leaf, acc_user.key, sl, &mut expired_orders, clock_ts)
-            );
+            let node_res = map_predicate_max(ob, DT::BidOrder, market.max_orders, |sl, leaf| {
+                if preview_book.filled(leaf.key()) {
+                    return false;
+                }
+                skip.check(leaf.key(), || valid_order(OrderDT::BidOrder, leaf, acc_user.key, market.allow_self_trade, sl, &mut expired_orders, clock_ts))
+            });
+            if skip.limit_reached() {
+                // Fail instead of stopping early, the unfilled remainder could cross the orders behind the skipped ones
+                msg!("Atellix: Too many expired or same-user orders skipped - Use crank_expirations to remove expired orders");
+                return Err(ErrorCode::TooManySkippedOrders.into());
+            }
             if node_res.is_none() {
                 msg!("Atellix: No Match");
                 break;
             }
             let posted_node = node_res.unwrap();
-            let posted_order = ob.index::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize);
-            let posted_qty = posted_order.amount;
+            let posted_order = *ob.index::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize);
+            let posted_qty = preview_book.amount(posted_node.key(), &posted_order);
+            let posted_total = posted_order.total_amount();
             let posted_price = Order::price(posted_node.key());
-            msg!("Atellix: Matched Bid [{}] {} @ {}", posted_node.slot().to_string(), posted_qty.to_string(), posted_price.to_string());
-            if inp_by_quantity {
-                // Fill order by quantity
+            if inp_limit_price > 0 && posted_price < inp_limit_price {
+                // Keep the fills so far and stop at the worst acceptable price
+                msg!("Atellix: Limit price reached - Matching stopped");
+                break;
+            }
+            if breaker_tripped(market, breaker_price, posted_price)? {
+                // Halt trading, the unfilled remainder is cancelled
+                if !inp_preview {
+                    state_upd.breaker_tripped_ts = clock_ts;
+                }
+                break;
+            }
+            if !inp_preview && !settlement_available(state_upd, acc_settle1, acc_settle2, &posted_node.owner())? {
+                msg!("Atellix: Settlement logs full - Matching stopped");
+                break;
+            }
+            if inp_reduce_only {
+                // Stop once the reducible balance is consumed
+                let reduce_remaining = lot_quantity(market, reduce_balance.saturating_sub(tokens_filled));
+                if inp_by_quantity {
+                    if reduce_remaining < tokens_to_fill {
+                        tokens_to_fill = reduce_remaining;
+                        reduce_capped = true;
+                    }
+                } else {
+                    let reduce_price = scale_price(reduce_remaining, posted_price, decimal_factor(market.mkt_decimals))?;
+                    if reduce_price < price_to_fill {
+                        price_to_fill = reduce_price;
+                        reduce_capped = true;
+                    }
+                }
+                if (inp_by_quantity && tokens_to_fill == 0) || (!inp_by_quantity && price_to_fill == 0) {
+                    msg!("Atellix: Reduce only balance consumed - Matching stopped");
+                    break;
+                }
+            }
+            msg!("Atellix: Matched Bid [{}] {} @ {}", posted_node.slot().to_string(), posted_qty.to_string(), posted_price.to_string());
+            if inp_by_quantity {
+                // Fill order by quantity
                 if posted_qty == tokens_to_fill {         // Match the entire order exactly
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
-                    msg!("Atellix: Filling - {} @ {}", tokens_part.to_string(), posted_price.to_string());
+                    msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             176535012143782409593813433848999612355, // solana/program/aqua-dex/market_ask/match/quantity/exact
                             state_upd.action_counter,
@@ -2816,15 +5930,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, tokens_to_fill)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2837,13 +5956,17 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(posted_qty, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, posted_qty, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             277111811349020061708541382826182055538, // solana/program/aqua-dex/market_ask/match/quantity/entire
                             state_upd.action_counter,
@@ -2857,15 +5980,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, posted_qty)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2876,13 +6004,14 @@ pub mod aqua_dex {
                     tokens_filled = tokens_filled.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                     let tokens_part = scale_price(tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
                     tokens_received = tokens_received.checked_add(tokens_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, tokens_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, tokens_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, tokens_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", tokens_to_fill.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, tokens_to_fill, fee_part);
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, tokens_to_fill, posted_price, decimal_factor(market.mkt_decimals), tokens_part)?;
                         log_trade(tlog,
                             338129135642557935308794285239529753670, // solana/program/aqua-dex/market_ask/match/quantity/partial
                             state_upd.action_counter,
@@ -2896,14 +6025,22 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(tokens_to_fill).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, tokens_to_fill, tokens_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, tokens_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, tokens_to_fill)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2918,13 +6055,17 @@ pub mod aqua_dex {
                 if posted_part == price_to_fill {         // Match the entire order exactly
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_received = tokens_received.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, posted_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, posted_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, posted_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, posted_qty, posted_price, decimal_factor(market.mkt_decimals), posted_part)?;
                         log_trade(tlog,
                             38185514874311817824997288786026180382, // solana/program/aqua-dex/market_ask/match/net_price/exact
                             state_upd.action_counter,
@@ -2938,15 +6079,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, posted_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, posted_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, posted_qty)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2958,13 +6104,17 @@ pub mod aqua_dex {
                     price_to_fill = price_to_fill.checked_sub(posted_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_filled = tokens_filled.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_received = tokens_received.checked_add(posted_part).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, posted_part)?;
-                    let fee_part = calculate_fee(market.taker_fee, posted_part)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, posted_part)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", posted_qty.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, posted_qty, fee_part);
+                    if inp_preview {
+                        preview_book.fill(posted_node.key(), &posted_order);
+                    }
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, posted_qty, posted_price, decimal_factor(market.mkt_decimals), posted_part)?;
                         log_trade(tlog,
                             48115079441646063920817461881527222742, // solana/program/aqua-dex/market_ask/match/net_price/entire
                             state_upd.action_counter,
@@ -2978,15 +6128,20 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
-                        map_remove(ob, DT::BidOrder, posted_node.key())?;
-                        Order::free_index(ob, DT::BidOrder, posted_node.slot())?;
-                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        remove_filled_order(market, &market.key(), state_upd, ob, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(posted_qty).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, posted_qty, posted_part)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, posted_part)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, posted_qty)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -2994,16 +6149,22 @@ pub mod aqua_dex {
                         }
                     }
                 } else if posted_part > price_to_fill {   // Match part of the order
-                    let fill_amount = fill_quantity(price_to_fill, posted_price, decimal_factor(market.mkt_decimals))?;
+                    // Calculate filled tokens (rounded down to the lot size, the rest of the net price is not filled)
+                    let (fill_amount, fill_cost) = match net_price_fill(market, price_to_fill, posted_price)? {
+                        Some(fill) => fill,
+                        None => break,
+                    };
+                    price_to_fill = fill_cost;
                     tokens_filled = tokens_filled.checked_add(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_received = tokens_received.checked_add(price_to_fill).ok_or(error!(ErrorCode::Overflow))?;
-                    let rebate_part = calculate_fee(market.maker_rebate, price_to_fill)?;
-                    let fee_part = calculate_fee(market.taker_fee, price_to_fill)?;
-                    require!(fee_part >= rebate_part, ErrorCode::RebateExceedsFee);
+                    let (fee_part, rebate_part, discount_part) = fill_fees(taker_fee, maker_rebate, discount_rate, price_to_fill)?;
+                    tokens_discount = tokens_discount.checked_add(discount_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_rebate = tokens_rebate.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
                     tokens_fee = tokens_fee.checked_add(fee_part).ok_or(error!(ErrorCode::Overflow))?;
                     msg!("Atellix: Filling - {} @ {}", fill_amount.to_string(), posted_price.to_string());
+                    fills.add_fill(&posted_node.owner(), posted_price, fill_amount, fee_part);
                     if !inp_preview {
+                        let dust_part = rounding_dust(state_upd, posted_total, fill_amount, posted_price, decimal_factor(market.mkt_decimals), price_to_fill)?;
                         log_trade(tlog,
                             338446361041777477888718125403430758950, // solana/program/aqua-dex/market_ask/match/net_price/partial
                             state_upd.action_counter,
@@ -3017,14 +6178,22 @@ pub mod aqua_dex {
                             posted_price,
                             rebate_part,
                             fee_part,
+                            discount_part,
+                            dust_part,
+                            &acc_referrer.key(),
+                            referral_share,
                             clock_ts
                         )?;
                         let new_amount = posted_qty.checked_sub(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
                         ob.index_mut::<Order>(OrderDT::BidOrder as u16, posted_node.slot() as usize).set_amount(new_amount);
+                        cancel_dust_remainder(market, &market.key(), state_upd, ob, acc_settle1, acc_settle2, Side::Bid, &posted_node)?;
                         state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
                         state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(fill_amount).ok_or(error!(ErrorCode::Overflow))?;
+                        accumulate_price(state_upd, clock_ts)?;
                         state_upd.last_price = posted_price;
                         state_upd.last_ts = clock_ts;
+                        add_volume(state_upd, fill_amount, price_to_fill)?;
+                        add_self_volume(&mut self_volume, &posted_node.owner(), acc_user.key, price_to_fill)?;
                         log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &posted_node.owner(), true, fill_amount)?;
                         if rebate_part > 0 {
                             state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(rebate_part).ok_or(error!(ErrorCode::Overflow))?;
@@ -3037,21 +6206,28 @@ pub mod aqua_dex {
         }
 
         msg!("Atellix: Fee: {}", tokens_fee.to_string());
+        if tokens_filled > 0 {
+            check_order_value(tokens_filled, tokens_received)?;
+            check_min_notional(market, tokens_received)?;
+        }
 
         let mut expired_count: u32 = 0;
         if expired_orders.len() > 0 && !inp_preview {
             loop {
-                if expired_orders.len() == 0 || expired_count == MAX_EXPIRATIONS {
+                if expired_orders.len() == 0 || expired_count == expire_limit {
                     break;
                 }
                 let expired_id: u128 = expired_orders.pop().unwrap();
-                let expire_leaf = map_get(ob, DT::BidOrder, expired_id).unwrap();
+                let expire_leaf = map_get(ob, DT::BidOrder, market.max_orders, expired_id).unwrap();
+                if !settlement_available(state_upd, acc_settle1, acc_settle2, &expire_leaf.owner())? {
+                    break;
+                }
                 let expire_order = *ob.index::<Order>(OrderDT::BidOrder as u16, expire_leaf.slot() as usize);
-                let expire_amount: u64 = expire_order.amount();
+                let expire_amount: u64 = expire_order.total_amount();
                 msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
                     expire_leaf.slot().to_string(),
                     expire_leaf.owner().to_string(),
-                    expire_order.amount().to_string(),
+                    expire_amount.to_string(),
                     Order::price(expire_leaf.key()).to_string(),
                 );
                 let expire_price = Order::price(expire_leaf.key());
@@ -3068,7 +6244,7 @@ pub mod aqua_dex {
                     tokens: expire_total,
                 });
                 log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &expire_leaf.owner(), false, expire_total)?; // Total calculated
-                map_remove(ob, DT::BidOrder, expire_leaf.key())?;
+                map_remove(ob, DT::BidOrder, market.max_orders, expire_leaf.key())?;
                 Order::free_index(ob, DT::BidOrder, expire_leaf.slot())?;
                 state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
                 expired_count = expired_count + 1;
@@ -3077,19 +6253,33 @@ pub mod aqua_dex {
 
         let mut result = TradeResult { tokens_received: 0, posted_quantity: 0, tokens_sent: tokens_filled, tokens_fee: tokens_fee, order_id: 0 };
 
-        if inp_fill {
+        if inp_fill && !reduce_capped {
             if inp_by_quantity {
                 if tokens_filled != inp_quantity {
                     msg!("Order not filled");
+                    msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_received.to_string(), tokens_fee.to_string());
                     return Err(ErrorCode::OrderNotFilled.into());
                 }
             } else {
                 if tokens_received != inp_net_price {
                     msg!("Order not filled");
+                    msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_received.to_string(), tokens_fee.to_string());
                     return Err(ErrorCode::OrderNotFilled.into());
                 }
             }
         }
+        if inp_min_fill_bps > 0 && !reduce_capped {
+            let fill_met = if inp_by_quantity {
+                min_fill_met(tokens_filled, inp_quantity, inp_min_fill_bps)?
+            } else {
+                min_fill_met(tokens_received, inp_net_price, inp_min_fill_bps)?
+            };
+            if !fill_met {
+                msg!("Order below minimum fill of {} basis points", inp_min_fill_bps.to_string());
+                msg!("Atellix: Max Fillable - Quantity: {} Tokens: {} Fee: {}", tokens_filled.to_string(), tokens_received.to_string(), tokens_fee.to_string());
+                return Err(ErrorCode::OrderNotFilled.into());
+            }
+        }
 
         /*msg!("Atellix: Market Token Vault Deposit: {}", inp_quantity.to_string());
         msg!("Atellix: Market Token Vault Balance: {} (Orderbook: {})",
@@ -3097,14 +6287,18 @@ pub mod aqua_dex {
             state_upd.mkt_order_balance,
         );*/
 
-        // Send tokens to the vault
-        let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-        perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_filled, inp_preview,
-            &ctx.accounts.user_mkt_token.to_account_info(),  // From
-            &ctx.accounts.mkt_vault.to_account_info(),       // To
-            &ctx.accounts.user.to_account_info(),            // Auth
-            &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
-        )?;
+        // Send tokens to the vault (no transfer if nothing was filled)
+        if tokens_filled == 0 {
+            msg!("Atellix: No Liquidity - Nothing filled");
+        } else {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_filled, inp_preview,
+                &ctx.accounts.user_mkt_token.to_account_info(),  // From
+                &ctx.accounts.mkt_vault.to_account_info(),       // To
+                &ctx.accounts.user.to_account_info(),            // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+        }
 
         if tokens_filled > 0 {
             tokens_received = tokens_received.checked_sub(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
@@ -3115,9 +6309,8 @@ pub mod aqua_dex {
                 state_upd.prc_order_balance = state_upd.prc_order_balance.checked_sub(tokens_received).ok_or(error!(ErrorCode::Overflow))?;
 
                 // Apply fees
-                state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-                state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_add(tokens_fee).ok_or(error!(ErrorCode::Overflow))?;
-                state_upd.prc_fees_balance = state_upd.prc_fees_balance.checked_sub(tokens_rebate).ok_or(error!(ErrorCode::Overflow))?;
+                apply_taker_fees(&market.key(), state_upd, acc_settle1, acc_settle2, acc_referrer.key, referral_share, tokens_fee, tokens_rebate,
+                    &mut user_volume, acc_user_volume, prc_volume_start, self_volume)?;
 
                 //msg!("Atellix: Pricing Token Vault Withdraw: {}", tokens_received.to_string());
                 /*msg!("Atellix: Pricing Token Vault Balance: {} (Orderbook: {})",
@@ -3139,9 +6332,28 @@ pub mod aqua_dex {
                 )?;
             }
         }
+        if inp_native_sol && !inp_preview {
+            unwrap_native_sol(
+                &ctx.accounts.user.to_account_info(),
+                &ctx.accounts.user_prc_token.to_account_info(),
+                &ctx.accounts.spl_token_prog.to_account_info(),
+            )?;
+        }
+        if !inp_preview {
+            log_fill_fee(market, state_upd, acc_user, acc_settle1, acc_settle2, log_items)?;
+        }
+        collect_fee_discount(market, tokens_discount, inp_preview,
+            &ctx.accounts.discount_token.to_account_info(),     // From
+            &ctx.accounts.discount_vault.to_account_info(),     // To
+            &ctx.accounts.user.to_account_info(),               // Auth
+            &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+        )?;
         if *acc_result.key != *acc_user.key {
             store_struct::<TradeResult>(&result, acc_result)?;
         }
+        if *acc_fills.key != *acc_user.key {
+            store_struct::<FillsResult>(&fills, acc_fills)?;
+        }
         let was_filled: bool = if inp_by_quantity {
             tokens_filled == inp_quantity
         } else {
@@ -3159,6 +6371,7 @@ pub mod aqua_dex {
                 pricing_token: ctx.accounts.user_prc_token.key(),
                 order_id: result.order_id,
                 order_side: Side::Ask as u8,
+                order_mode: if inp_fill { OrderMode::FillOrKill as u8 } else { OrderMode::ImmediateOrCancel as u8 },
                 filled: was_filled,
                 tokens_received: result.tokens_received,
                 tokens_sent: result.tokens_sent,
@@ -3168,6 +6381,11 @@ pub mod aqua_dex {
                 order_price: inp_net_price,
                 order_quantity: inp_quantity,
                 expires: 0,
+                reduce_only: inp_reduce_only,
+                mkt_vault_balance: state_upd.mkt_vault_balance,
+                prc_vault_balance: state_upd.prc_vault_balance,
+                mkt_order_balance: state_upd.mkt_order_balance,
+                prc_order_balance: state_upd.prc_order_balance,
             });
         }
 
@@ -3178,78 +6396,39 @@ pub mod aqua_dex {
         inp_side: u8,               // 0 - Bid, 1 - Ask
         inp_order_id: u128,
     ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let market_state = &ctx.accounts.state;
         let acc_agent = &ctx.accounts.agent.to_account_info();
         let acc_owner = &ctx.accounts.owner.to_account_info();
         let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
         let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
-        let acc_orders = &ctx.accounts.orders.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
 
-        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
+        }
+
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
-        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
 
-        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
-        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
-        let sl = SlabPageAlloc::new(order_data);
-        let order_type = match side {
-            Side::Bid => DT::BidOrder,
-            Side::Ask => DT::AskOrder,
-        };
-        let item = map_get(sl, order_type, inp_order_id);
-        if item.is_none() {
-            msg!("Order not found");
-            return Err(ErrorCode::OrderNotFound.into());
-        }
-        let leaf = item.unwrap();
-        if leaf.owner() != *acc_owner.key {
-            msg!("Order not owned by user");
-            return Err(ErrorCode::AccessDenied.into());
-        }
-        let order = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
         let state = &mut ctx.accounts.state;
-        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-        if side == Side::Bid {
-            state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-        } else if side == Side::Ask {
-            state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-        }
-
+        let (side, order_id, order_price, order_qty, tokens_out) = cancel_owner_order(market, state, &ctx.accounts.orders.to_account_info(), acc_owner,
+            inp_side, inp_order_id, clock_ts,
+        )?;
         let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
-        let order_id = leaf.key();
-        let order_price = Order::price(order_id);
-        let order_qty = order.amount();
-        let tokens_out = match side {
-            Side::Bid => {
-                let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
-                result.set_prc_tokens(total);
-                state.prc_vault_balance = state.prc_vault_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_order_balance = state.prc_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
-                total
-            },
-            Side::Ask => {
-                let total = order.amount();
-                result.set_mkt_tokens(total);
-                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_order_balance = state.mkt_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
-                total
-            }
+        match side {
+            Side::Bid => result.set_prc_tokens(tokens_out),
+            Side::Ask => result.set_mkt_tokens(tokens_out),
+        };
+        match side {
+            Side::Bid => state.prc_vault_balance = state.prc_vault_balance.checked_sub(tokens_out).ok_or(error!(ErrorCode::Overflow))?,
+            Side::Ask => state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(tokens_out).ok_or(error!(ErrorCode::Overflow))?,
         };
-        map_remove(sl, order_type, leaf.key())?;
-        Order::free_index(sl, order_type, leaf.slot())?;
-
-        // Rebate to the user for settlement log space
-        state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-        let mut market_lamports = state.to_account_info().lamports();
-        market_lamports = market_lamports.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-        **state.to_account_info().lamports.borrow_mut() = market_lamports;
-        let mut user_lamports = ctx.accounts.owner.lamports();
-        user_lamports = user_lamports.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-        **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
 
         let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
         let signer = &[&seeds[..]];
@@ -3293,159 +6472,89 @@ pub mod aqua_dex {
         Ok(())
     }
 
-    // Withdraw tokens from the settlement vault
-    pub fn withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, Withdraw<'info>>) -> anchor_lang::Result<()> {
+    pub fn cancel_order_to_vault<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CancelOrderVault<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,
+    ) -> anchor_lang::Result<u64> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let state = &mut ctx.accounts.state;
-        let acc_agent = &ctx.accounts.agent.to_account_info();
         let acc_owner = &ctx.accounts.owner.to_account_info();
-        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
-        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
-        let acc_settle = &ctx.accounts.settle.to_account_info();
-        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
-        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
-        let acc_result = &ctx.accounts.result.to_account_info();
-
-        // Verify 
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
-        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
-        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
-        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
-
-        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-
-        let mut market_tokens: u64 = 0;
-        let mut pricing_tokens: u64 = 0;
-        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
-        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
-        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
-        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
-        verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
-        let close_log: bool = settle_header[0].items == 1 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
-        let sl = SlabPageAlloc::new(page_table);
-        let has_item = map_get(sl, DT::Account, owner_id);
-        if has_item.is_some() {
-            let log_node = has_item.unwrap();
-            let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
-            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
-            let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
-            if log_entry.mkt_token_balance() > 0 {
-                market_tokens = log_entry.mkt_token_balance();
-                result.set_mkt_tokens(market_tokens);
-                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.mkt_token_balance(),
-                    &ctx.accounts.mkt_vault.to_account_info(),          // From
-                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            if log_entry.prc_token_balance() > 0 {
-                pricing_tokens = log_entry.prc_token_balance();
-                result.set_prc_tokens(pricing_tokens);
-                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.prc_token_balance(),
-                    &ctx.accounts.prc_vault.to_account_info(),          // From
-                    &ctx.accounts.user_prc_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            // Remove log entry
-            settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-            map_remove(sl, DT::Account, log_node.key())?;
-            AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
-
-            // Rebate to the user for settlement log space
-            state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-            let mut market_lamports = state.to_account_info().lamports();
-            market_lamports = market_lamports.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-            let mut user_lamports = ctx.accounts.owner.lamports();
-            user_lamports = user_lamports.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
-            **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
+        let acc_user_vault = &ctx.accounts.vault.to_account_info();
 
-            // Write result
-            if *acc_result.key != ctx.accounts.owner.key() {
-                store_struct::<WithdrawResult>(&result, acc_result)?;
-            }
+        let state = &mut ctx.accounts.state;
+        let (side, order_id, order_price, order_qty, tokens_out) = cancel_owner_order(market, state, &ctx.accounts.orders.to_account_info(), acc_owner,
+            inp_side, inp_order_id, clock_ts,
+        )?;
 
-            // Close log if necessary
-            if close_log {
-                let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
-                market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
-                state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            **state.to_account_info().lamports.borrow_mut() = market_lamports;
-        } else {
-            msg!("Account not found");
-            return Err(ErrorCode::AccountNotFound.into());
-        }
+        // Tokens stay in the market vault and are credited to the user vault, so no token transfer can fail
+        let (mkt_tokens, prc_tokens) = match side {
+            Side::Bid => (0, tokens_out),
+            Side::Ask => (tokens_out, 0),
+        };
+        let vault = credit_user_vault(&market.key(), state, acc_owner, acc_user_vault, &ctx.accounts.system_program.to_account_info(), mkt_tokens, prc_tokens)?;
 
-        emit!(WithdrawEvent {
-            event_type: 206836899720010235937021599972903459637, // solana/program/aqua-dex/withdraw
+        emit!(CancelEvent {
+            event_type: 268521925402245926932106364993938337040, // solana/program/aqua-dex/cancel_order/vault
             action_id: state.action_counter,
             market: ctx.accounts.market.key(),
-            owner: ctx.accounts.owner.key(),
-            user: ctx.accounts.owner.key(),
-            market_account: ctx.accounts.user_mkt_token.key(),
-            pricing_account: ctx.accounts.user_prc_token.key(),
+            owner: acc_owner.key(),
+            user: acc_owner.key(),
+            market_token: Pubkey::default(),
+            pricing_token: Pubkey::default(),
             manager: false,
-            market_tokens: market_tokens,
-            pricing_tokens: pricing_tokens,
+            order_side: side as u8,
+            order_id: order_id,
+            order_price: order_price,
+            order_quantity: order_qty,
+            token_withdrawn: 0,
+        });
+        emit!(VaultDepositEvent {
+            event_type: 86366436385447004733826040917262604566, // solana/program/aqua-dex/user_vault/cancel_order
+            action_id: state.action_counter,
+            market: market.key(),
+            owner: *acc_owner.key,
+            vault: acc_user_vault.key(),
+            market_tokens: mkt_tokens,
+            market_balance: vault.mkt_tokens,
+            pricing_tokens: prc_tokens,
+            pricing_balance: vault.prc_tokens,
         });
 
-        Ok(())
+        Ok(tokens_out)
     }
 
-    pub fn expire_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExpireOrder<'info>>,
+    pub fn reduce_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CancelOrder<'info>>,
         inp_side: u8,               // 0 - Bid, 1 - Ask
         inp_order_id: u128,
-        inp_rollover: bool,
+        inp_reduce_by: u64,         // Quantity to remove from the order (in market tokens)
     ) -> anchor_lang::Result<()> {
         let clock = Clock::get()?;
         let clock_ts = clock.unix_timestamp;
 
         let market = &ctx.accounts.market;
         let market_state = &ctx.accounts.state;
-        let acc_user = &ctx.accounts.user.to_account_info();
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
         let acc_orders = &ctx.accounts.orders.to_account_info();
-        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
-        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
-
-        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
-        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        let acc_result = &ctx.accounts.result.to_account_info();
 
-        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
-        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
-        if s1.is_err() || s2.is_err() {
-            // This is expected to happen sometimes due to a race condition between settlment log rollovers and new orders
-            // Reload the current "market" account with the latest settlement log accounts and retry the transaction
-            msg!("Please update market data and retry");
-            return Err(ErrorCode::RetrySettlementAccount.into()); 
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
         }
 
-        // Append a settlement log account
-        let state_upd = &mut ctx.accounts.state;
-        if inp_rollover {
-            if !state_upd.log_rollover {
-                // Another market participant already appended a new log account (please retry transaction)
-                msg!("Please update market data and retry");
-                return Err(ErrorCode::RetrySettlementAccount.into());
-            }
-            let av = ctx.remaining_accounts;
-            let new_settlement_log = av.get(0).unwrap();
-            let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
-            log_reimburse(market, state_upd, acc_user)?;
-            let mut market_lamports = state_upd.to_account_info().lamports();
-            market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
-            **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
-        }
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        require!(inp_reduce_by > 0, ErrorCode::InvalidParameters);
 
         let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
         let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
@@ -3454,99 +6563,171 @@ pub mod aqua_dex {
             Side::Bid => DT::BidOrder,
             Side::Ask => DT::AskOrder,
         };
-        let item = map_get(sl, order_type, inp_order_id);
+        let item = map_get(sl, order_type, market.max_orders, inp_order_id);
         if item.is_none() {
             msg!("Order not found");
             return Err(ErrorCode::OrderNotFound.into());
         }
         let leaf = item.unwrap();
-        let order = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
-        let expired: bool = order.expiry != 0 && order.expiry >= clock_ts;      // Check expiry timestamp if needed
-        if expired {
-            state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-            let order_id = leaf.key();
-            let order_owner: Pubkey = leaf.owner();
-            let order_price = Order::price(order_id);
-            let order_qty = order.amount();
-            let tokens = match side {
-                Side::Bid => {
-                    let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
-                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &order_owner, false, total)?;
-                    state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+        if leaf.owner() != *acc_owner.key {
+            msg!("Order not owned by user");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let order = *sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        check_order_age(market, &order, clock_ts)?;
+        let order_id = leaf.key();
+        let order_price = Order::price(order_id);
+        let prev_qty = order.total_amount();
+        let new_qty = prev_qty.checked_sub(inp_reduce_by).ok_or(error!(ErrorCode::Overflow))?;
+        if new_qty > 0 && new_qty < market.min_quantity {
+            msg!("Remaining quantity below minimum");
+            return Err(ErrorCode::QuantityBelowMinimum.into());
+        }
+        check_lot_size(market, new_qty)?;
+
+        let state = &mut ctx.accounts.state;
+        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+        let tokens_out: u64;
+        if new_qty == 0 {
+            // Reduced to zero, cancel the order
+            let (_, cancel_tokens) = cancel_posted_order(market, state, sl, side, &leaf, &mut result)?;
+            tokens_out = cancel_tokens;
+
+            // Rebate to the user for settlement log space
+            let mut market_lamports = state.to_account_info().lamports();
+            market_lamports = market_lamports.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            **state.to_account_info().lamports.borrow_mut() = market_lamports;
+            let mut user_lamports = ctx.accounts.owner.lamports();
+            user_lamports = user_lamports.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
+        } else {
+            // Reduce the hidden quantity of iceberg orders first, the key is unchanged to keep time priority
+            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            let hidden_reduce = if inp_reduce_by < order.hidden_qty { inp_reduce_by } else { order.hidden_qty };
+            let amount_reduce = inp_reduce_by.checked_sub(hidden_reduce).ok_or(error!(ErrorCode::Overflow))?;
+            let entry = sl.index_mut::<Order>(index_datatype(order_type), leaf.slot() as usize);
+            entry.hidden_qty = order.hidden_qty.checked_sub(hidden_reduce).ok_or(error!(ErrorCode::Overflow))?;
+            entry.set_amount(order.amount().checked_sub(amount_reduce).ok_or(error!(ErrorCode::Overflow))?);
+            tokens_out = match side {
+                Side::Bid => {
+                    // Refund the difference between the old and new totals to avoid rounding drift
+                    let prev_total = scale_price(prev_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                    let new_total = scale_price(new_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                    let total = prev_total.checked_sub(new_total).ok_or(error!(ErrorCode::Overflow))?;
+                    result.set_prc_tokens(total);
+                    state.prc_vault_balance = state.prc_vault_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
+                    state.prc_order_balance = state.prc_order_balance.checked_sub(total).ok_or(error!(ErrorCode::Overflow))?;
                     total
                 },
                 Side::Ask => {
-                    let total = order.amount();
-                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &order_owner, true, total)?;
-                    state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-                    total
+                    result.set_mkt_tokens(inp_reduce_by);
+                    state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(inp_reduce_by).ok_or(error!(ErrorCode::Overflow))?;
+                    state.mkt_order_balance = state.mkt_order_balance.checked_sub(inp_reduce_by).ok_or(error!(ErrorCode::Overflow))?;
+                    inp_reduce_by
                 }
             };
-            map_remove(sl, order_type, leaf.key())?;
-            Order::free_index(sl, order_type, leaf.slot())?;
+        }
 
-            emit!(ExpireEvent {
-                event_type: 16332991664789055110548783525139174482, // solana/program/aqua-dex/expire_event
-                action_id: state_upd.action_counter,
-                market: market.key(),
-                owner: leaf.owner(),
-                order_side: Side::Bid as u8,
-                order_id: order_id,
-                price: order_price,
-                quantity: order_qty,
-                tokens: tokens,
-            });
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if side == Side::Bid {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_out,
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.user_prc_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        } else if side == Side::Ask {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_out,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
         }
+        if *acc_result.key != *acc_owner.key {
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
+
+        emit!(ModifyEvent {
+            event_type: 50434913198931648469464555397073061190, // solana/program/aqua-dex/reduce_order
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: acc_owner.key(),
+            order_side: side as u8,
+            order_id: order_id,
+            order_price: order_price,
+            prev_quantity: prev_qty,
+            order_quantity: new_qty,
+            token_withdrawn: tokens_out,
+        });
 
         Ok(())
     }
 
-    pub fn manager_cancel_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerCancelOrder<'info>>,
+    pub fn replace_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ReplaceOrder<'info>>,
         inp_side: u8,               // 0 - Bid, 1 - Ask
-        inp_order_id: u128,
-        inp_rollover: bool,
-    ) -> anchor_lang::Result<()> {
+        inp_order_id: u128,         // Order to replace
+        inp_price_request: u64,     // New price (time priority is kept if the price is unchanged)
+        inp_quantity: u64,          // New quantity (including hidden quantity of iceberg orders)
+        inp_expires: i64,           // New expiration timestamp (0 for no expiration)
+    ) -> anchor_lang::Result<ReplaceResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
         let market_state = &ctx.accounts.state;
-        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
         let acc_orders = &ctx.accounts.orders.to_account_info();
-        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
-        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
 
-        if !market.manager_actions {
-            msg!("Manager actions disabled");
-            return Err(ErrorCode::AccessDenied.into());
-        }
-        if market.manager != *acc_manager.key {
-            msg!("Not manager");
-            return Err(ErrorCode::AccessDenied.into());
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
         }
+
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
         verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
-
-        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
-        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
-        if s1.is_err() || s2.is_err() {
-            // This is expected to happen sometimes due to a race condition between settlment log rollovers and new orders
-            // Reload the current "market" account with the latest settlement log accounts and retry the transaction
-            msg!("Please update market data and retry");
-            return Err(ErrorCode::RetrySettlementAccount.into()); 
+        verify_orderbook_version(market)?;
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
         }
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
 
-        // Append a settlement log account
-        let state_upd = &mut ctx.accounts.state;
-        if inp_rollover {
-            if !state_upd.log_rollover {
-                // Another market participant already appended a new log account (please retry transaction)
-                msg!("Please update market data and retry");
-                return Err(ErrorCode::RetrySettlementAccount.into());
+        require!(inp_quantity > 0 && inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        check_lot_size(market, inp_quantity)?;
+        require!(inp_price_request > 0, ErrorCode::InvalidParameters);
+        let inp_price = get_tick_price(&market, inp_price_request)?;
+        require!(inp_price > 0, ErrorCode::InvalidParameters);
+        check_tick_size(market, inp_price)?;
+        let order_value = scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?;
+        check_order_value(inp_quantity, order_value)?;
+        check_min_notional(market, order_value)?;
+
+        // Check expiration parameters
+        let mut expiry: i64 = 0;
+        if market.expire_enable && inp_expires != 0 {
+            let expire_dur = inp_expires.checked_sub(clock_ts).ok_or(error!(ErrorCode::Overflow))?;
+            if expire_dur <= 0 {
+                msg!("Order already expired");
+                return Err(ErrorCode::InvalidParameters.into());
             }
-            let av = ctx.remaining_accounts;
-            let new_settlement_log = av.get(0).unwrap();
-            let market_pk: Pubkey = market.key();
-            log_rollover(state_upd, market_pk, acc_settle2, new_settlement_log)?;
-            // Manager is not reimbursed for settlement log rollover
+            if expire_dur < market.expire_min {
+                msg!("Order expires before minimum duration of {} seconds", market.expire_min.to_string());
+                return Err(ErrorCode::InvalidParameters.into());
+            }
+            expiry = inp_expires;
         }
 
         let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
@@ -3556,784 +6737,4841 @@ pub mod aqua_dex {
             Side::Bid => DT::BidOrder,
             Side::Ask => DT::AskOrder,
         };
-        let item = map_get(sl, order_type, inp_order_id);
+        let item = map_get(sl, order_type, market.max_orders, inp_order_id);
         if item.is_none() {
             msg!("Order not found");
             return Err(ErrorCode::OrderNotFound.into());
         }
         let leaf = item.unwrap();
-        let order = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        if leaf.owner() != *acc_owner.key {
+            msg!("Order not owned by user");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let order = *sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        check_order_age(market, &order, clock_ts)?;
+        let prev_id = leaf.key();
+        let prev_price = Order::price(prev_id);
+        let prev_qty = order.total_amount();
         let state = &mut ctx.accounts.state;
         state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
-        let order_id = leaf.key();
-        let order_owner: Pubkey = leaf.owner();
-        let order_price = Order::price(order_id);
-        let order_qty = order.amount();
-        let tokens_out = match side {
-            Side::Bid => {
-                let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
-                result.set_prc_tokens(total);
-                log_settlement(&market.key(), state, acc_settle1, acc_settle2, &order_owner, false, total)?;
-                state.active_bid = state.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-                total
-            },
-            Side::Ask => {
-                let total = order.amount();
-                result.set_mkt_tokens(total);
-                log_settlement(&market.key(), state, acc_settle1, acc_settle2, &order_owner, true, total)?;
-                state.active_ask = state.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-                total
+
+        let mut order_upd = order;
+        order_upd.expiry = expiry;
+        let order_id: u128;
+        if inp_price == prev_price {
+            // Adjust the quantity in place, the key is unchanged to keep time priority
+            order_id = prev_id;
+            if inp_quantity < prev_qty {
+                // Reduce the hidden quantity of iceberg orders first
+                let reduce_by = prev_qty.checked_sub(inp_quantity).ok_or(error!(ErrorCode::Overflow))?;
+                let hidden_reduce = if reduce_by < order.hidden_qty { reduce_by } else { order.hidden_qty };
+                let amount_reduce = reduce_by.checked_sub(hidden_reduce).ok_or(error!(ErrorCode::Overflow))?;
+                order_upd.hidden_qty = order.hidden_qty.checked_sub(hidden_reduce).ok_or(error!(ErrorCode::Overflow))?;
+                order_upd.set_amount(order.amount().checked_sub(amount_reduce).ok_or(error!(ErrorCode::Overflow))?);
+            } else if inp_quantity > prev_qty {
+                // Add to the hidden quantity of iceberg orders, otherwise to the displayed quantity
+                let add_qty = inp_quantity.checked_sub(prev_qty).ok_or(error!(ErrorCode::Overflow))?;
+                if order.display_qty > 0 {
+                    order_upd.hidden_qty = order.hidden_qty.checked_add(add_qty).ok_or(error!(ErrorCode::Overflow))?;
+                } else {
+                    order_upd.set_amount(order.amount().checked_add(add_qty).ok_or(error!(ErrorCode::Overflow))?);
+                }
             }
+        } else {
+            // Re-key the order at the new price, the replacement must rest on the orderbook without filling
+            let (best_price, _) = match side {
+                Side::Bid => best_level(sl, Side::Ask, market.max_orders, clock_ts),
+                Side::Ask => best_level(sl, Side::Bid, market.max_orders, clock_ts),
+            };
+            let crossing = best_price > 0 && match side {
+                Side::Bid => inp_price >= best_price,
+                Side::Ask => inp_price <= best_price,
+            };
+            if crossing {
+                msg!("Replacement order would be filled");
+                return Err(ErrorCode::OrderWouldFill.into());
+            }
+            check_price_band(market, state.last_price, inp_price)?;
+            order_id = Order::new_key(state, side, inp_price);
+            order_upd.placed_ts = clock_ts;
+            if order.display_qty > 0 && order.display_qty < inp_quantity {
+                order_upd.set_amount(order.display_qty);
+                order_upd.hidden_qty = inp_quantity.checked_sub(order.display_qty).ok_or(error!(ErrorCode::Overflow))?;
+            } else {
+                order_upd.set_amount(inp_quantity);
+                order_upd.hidden_qty = 0;
+            }
+            map_remove(sl, order_type, market.max_orders, prev_id)?;
+            let order_node = LeafNode::new(order_id, leaf.slot(), &leaf.owner());
+            map_insert(sl, order_type, market.max_orders, &order_node).map_err(|_| error!(ErrorCode::InternalError))?;
+        }
+        *sl.index_mut::<Order>(index_datatype(order_type), leaf.slot() as usize) = order_upd;
+
+        // Reconcile the tokens held by the order with the market vault
+        let (prev_total, new_total) = match side {
+            Side::Bid => (
+                scale_price(prev_qty, prev_price, decimal_factor(market.mkt_decimals))?,
+                scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?,
+            ),
+            Side::Ask => (prev_qty, inp_quantity),
         };
-        map_remove(sl, order_type, leaf.key())?;
-        Order::free_index(sl, order_type, leaf.slot())?;
-
-        if *acc_result.key != *acc_manager.key {
+        let mut tokens_deposited: u64 = 0;
+        let mut tokens_withdrawn: u64 = 0;
+        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+        let mint_type = match side {
+            Side::Bid => MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?,
+            Side::Ask => MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?,
+        };
+        let (acc_user_token, acc_vault) = match side {
+            Side::Bid => (ctx.accounts.user_prc_token.to_account_info(), ctx.accounts.prc_vault.to_account_info()),
+            Side::Ask => (ctx.accounts.user_mkt_token.to_account_info(), ctx.accounts.mkt_vault.to_account_info()),
+        };
+        if new_total > prev_total {
+            tokens_deposited = new_total.checked_sub(prev_total).ok_or(error!(ErrorCode::Overflow))?;
+            match side {
+                Side::Bid => {
+                    state.prc_vault_balance = state.prc_vault_balance.checked_add(tokens_deposited).ok_or(error!(ErrorCode::Overflow))?;
+                    state.prc_order_balance = state.prc_order_balance.checked_add(tokens_deposited).ok_or(error!(ErrorCode::Overflow))?;
+                },
+                Side::Ask => {
+                    state.mkt_vault_balance = state.mkt_vault_balance.checked_add(tokens_deposited).ok_or(error!(ErrorCode::Overflow))?;
+                    state.mkt_order_balance = state.mkt_order_balance.checked_add(tokens_deposited).ok_or(error!(ErrorCode::Overflow))?;
+                },
+            };
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, tokens_deposited, false,
+                &acc_user_token,                                    // From
+                &acc_vault,                                         // To
+                &ctx.accounts.owner.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        } else if new_total < prev_total {
+            tokens_withdrawn = prev_total.checked_sub(new_total).ok_or(error!(ErrorCode::Overflow))?;
+            match side {
+                Side::Bid => {
+                    result.set_prc_tokens(tokens_withdrawn);
+                    state.prc_vault_balance = state.prc_vault_balance.checked_sub(tokens_withdrawn).ok_or(error!(ErrorCode::Overflow))?;
+                    state.prc_order_balance = state.prc_order_balance.checked_sub(tokens_withdrawn).ok_or(error!(ErrorCode::Overflow))?;
+                },
+                Side::Ask => {
+                    result.set_mkt_tokens(tokens_withdrawn);
+                    state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(tokens_withdrawn).ok_or(error!(ErrorCode::Overflow))?;
+                    state.mkt_order_balance = state.mkt_order_balance.checked_sub(tokens_withdrawn).ok_or(error!(ErrorCode::Overflow))?;
+                },
+            };
+            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, tokens_withdrawn,
+                &acc_vault,                                         // From
+                &acc_user_token,                                    // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if *acc_result.key != *acc_owner.key {
             store_struct::<WithdrawResult>(&result, acc_result)?;
         }
 
-        emit!(CancelEvent {
-            event_type: 149668793492806786255339444097076784738, // solana/program/aqua-dex/manager_cancel_order
+        emit!(ReplaceEvent {
+            event_type: 320839648203473916332201629728552698914, // solana/program/aqua-dex/replace_order
             action_id: state.action_counter,
             market: ctx.accounts.market.key(),
-            owner: order_owner,
-            user: acc_manager.key(),
-            market_token: Pubkey::default(),
-            pricing_token: Pubkey::default(),
-            manager: true,
+            owner: acc_owner.key(),
             order_side: side as u8,
+            prev_order_id: prev_id,
+            prev_price: prev_price,
+            prev_quantity: prev_qty,
             order_id: order_id,
-            order_price: order_price,
-            order_quantity: order_qty,
-            token_withdrawn: tokens_out,
+            order_price: inp_price,
+            order_quantity: inp_quantity,
+            expires: expiry,
+            tokens_deposited: tokens_deposited,
+            tokens_withdrawn: tokens_withdrawn,
         });
 
-        Ok(())
+        Ok(ReplaceResult {
+            order_id: order_id,
+            priority_kept: order_id == prev_id,
+            tokens_deposited: tokens_deposited,
+            tokens_withdrawn: tokens_withdrawn,
+        })
     }
-    
-    pub fn extend_log<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExtendLog<'info>>) -> anchor_lang::Result<()> {
+
+    pub fn transfer_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TransferOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,
+        inp_new_owner: Pubkey,      // New owner (receives the tokens when the order is filled or cancelled)
+    ) -> anchor_lang::Result<()> {
         let market = &ctx.accounts.market;
         let market_state = &ctx.accounts.state;
-        let acc_user = &ctx.accounts.user.to_account_info();
-        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
 
-        // Verify 
         verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
-
-        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle.key, Some(String::from("Settlement log 2")));
-        if s2.is_err() {
-            // This is expected to happen sometimes due to a race condition between settlement log rollovers and new orders
-            // Reload the current "market" account with the latest settlement log accounts and retry the transaction
-            msg!("Please update market data and retry");
-            return Err(ErrorCode::RetrySettlementAccount.into());
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        if inp_new_owner == Pubkey::default() || inp_new_owner == *acc_owner.key {
+            msg!("Invalid new owner");
+            return Err(ErrorCode::InvalidParameters.into());
         }
 
-        // Append a settlement log account
-        let state_upd = &mut ctx.accounts.state;
-        if !state_upd.log_rollover {
-            // Another market participant already appended a new log account (please retry transaction)
-            msg!("Please update market data and retry");
-            return Err(ErrorCode::RetrySettlementAccount.into());
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let order_type = match side {
+            Side::Bid => DT::BidOrder,
+            Side::Ask => DT::AskOrder,
+        };
+        let item = map_get(sl, order_type, market.max_orders, inp_order_id);
+        if item.is_none() {
+            msg!("Order not found");
+            return Err(ErrorCode::OrderNotFound.into());
         }
-        let av = ctx.remaining_accounts;
-        let new_settlement_log = av.get(0).unwrap();
-        let market_pk: Pubkey = market.key();
-        log_rollover(state_upd, market_pk, acc_settle, new_settlement_log)?;
-        log_reimburse(market, state_upd, acc_user)?;
-        let mut market_lamports = state_upd.to_account_info().lamports();
-        market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
-        **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+        let leaf = item.unwrap();
+        if leaf.owner() != *acc_owner.key {
+            msg!("Order not owned by user");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        check_owner_orders(sl, market, market_state, &inp_new_owner)?;
+
+        // The new owner receives both tokens (fills and cancellation), so it must be eligible to hold each security token
+        let mkt_mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        let prc_mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        let mut ast_index: usize = 0;
+        if mkt_mint_type == MintType::AtxSecurityToken {
+            verify_ast_holder(ctx.remaining_accounts.get(ast_index), &market.mkt_mint, &inp_new_owner)?;
+            ast_index = ast_index + 1;
+        }
+        if prc_mint_type == MintType::AtxSecurityToken {
+            verify_ast_holder(ctx.remaining_accounts.get(ast_index), &market.prc_mint, &inp_new_owner)?;
+        }
+
+        // Replace the owner in place, the order keeps its key (and queue priority) and slot
+        // Fills, cancellations and expirations settle to the owner recorded in the leaf node
+        let mut cm = CritMap { slab: sl, type_id: map_datatype(order_type), capacity: market.max_orders };
+        *cm.get_key_mut(leaf.key()).unwrap() = LeafNode::new(leaf.key(), leaf.slot(), &inp_new_owner);
+
+        let state = &mut ctx.accounts.state;
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        msg!("Atellix: Transfer Order: {} From: {} To: {}", leaf.key().to_string(), acc_owner.key.to_string(), inp_new_owner.to_string());
+        emit!(TransferOrderEvent {
+            event_type: 136381406519003819606629778957333828716, // solana/program/aqua-dex/transfer_order
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            prev_owner: acc_owner.key(),
+            new_owner: inp_new_owner,
+            order_side: side as u8,
+            order_id: leaf.key(),
+        });
 
         Ok(())
     }
 
-    // Withdraw tokens from the settlement vault (manager)
-    pub fn manager_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerWithdraw<'info>>) -> anchor_lang::Result<()> {
+    pub fn batch_cancel<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CancelOrder<'info>>,
+        inp_orders: Vec<(u8, u128)>,    // Side (0 - Bid, 1 - Ask) and order id pairs
+    ) -> anchor_lang::Result<Vec<bool>> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let state = &mut ctx.accounts.state;
+        let market_state = &ctx.accounts.state;
         let acc_agent = &ctx.accounts.agent.to_account_info();
-        let acc_manager = &ctx.accounts.manager.to_account_info();
         let acc_owner = &ctx.accounts.owner.to_account_info();
         let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
         let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
-        let acc_settle = &ctx.accounts.settle.to_account_info();
-        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
-        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
         let acc_result = &ctx.accounts.result.to_account_info();
 
-        // Verify 
-        if !market.manager_actions {
-            msg!("Manager actions disabled");
-            return Err(ErrorCode::AccessDenied.into());
-        }
-        if market.manager != *acc_manager.key {
-            msg!("Not manager");
-            return Err(ErrorCode::AccessDenied.into());
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
         }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
-        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        if inp_orders.len() == 0 || inp_orders.len() > MAX_BATCH_CANCEL as usize {
+            msg!("Invalid number of orders");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
 
-        let mut market_tokens: u64 = 0;
-        let mut pricing_tokens: u64 = 0;
-        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
-        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
-        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
-        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
-        verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
-        let close_log: bool = settle_header[0].items == 1 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
-        let sl = SlabPageAlloc::new(page_table);
-        let has_item = map_get(sl, DT::Account, owner_id);
-        if has_item.is_some() {
-            let log_node = has_item.unwrap();
-            let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
-            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
-            let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
-            if log_entry.mkt_token_balance() > 0 {
-                market_tokens = log_entry.mkt_token_balance();
-                result.set_mkt_tokens(market_tokens);
-                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.mkt_token_balance(),
-                    &ctx.accounts.mkt_vault.to_account_info(),          // From
-                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let state = &mut ctx.accounts.state;
+        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+        let mut cancelled: Vec<bool> = Vec::new();
+        let mut log_rebate: u64 = 0;
+        for (inp_side, inp_order_id) in inp_orders.iter() {
+            // Orders that can not be cancelled are skipped, and reported in the result
+            let side_res = Side::try_from(*inp_side);
+            if side_res.is_err() {
+                msg!("Atellix: Invalid side: {}", inp_side.to_string());
+                cancelled.push(false);
+                continue;
             }
-            if log_entry.prc_token_balance() > 0 {
-                pricing_tokens = log_entry.prc_token_balance();
-                result.set_prc_tokens(pricing_tokens);
-                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.prc_token_balance(),
-                    &ctx.accounts.prc_vault.to_account_info(),          // From
-                    &ctx.accounts.user_prc_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            let side = side_res.unwrap();
+            let order_type = match side {
+                Side::Bid => DT::BidOrder,
+                Side::Ask => DT::AskOrder,
+            };
+            let item = map_get(sl, order_type, market.max_orders, *inp_order_id);
+            if item.is_none() {
+                msg!("Atellix: Order not found: {}", inp_order_id.to_string());
+                cancelled.push(false);
+                continue;
             }
-            // Remove log entry
-            settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-            map_remove(sl, DT::Account, log_node.key())?;
-            AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
-            // Write result
-            if *acc_result.key != ctx.accounts.manager.key() {
-                store_struct::<WithdrawResult>(&result, acc_result)?;
+            let leaf = item.unwrap();
+            if leaf.owner() != *acc_owner.key {
+                msg!("Atellix: Order not owned by user: {}", inp_order_id.to_string());
+                cancelled.push(false);
+                continue;
             }
-
-            // Close log if necessary
-            if close_log {
-                let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
-                let mut market_lamports = state.to_account_info().lamports();
-                market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
-                **state.to_account_info().lamports.borrow_mut() = market_lamports;
-                state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            if order_too_young(market, sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize), clock_ts) {
+                msg!("Atellix: Order too young to cancel: {}", inp_order_id.to_string());
+                cancelled.push(false);
+                continue;
             }
-        } else {
-            msg!("Account not found");
-            return Err(ErrorCode::AccountNotFound.into());
+            let order_id = leaf.key();
+            let order_price = Order::price(order_id);
+            let (order_qty, tokens_out) = cancel_posted_order(market, state, sl, side, &leaf, &mut result)?;
+            log_rebate = log_rebate.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            cancelled.push(true);
+
+            emit!(CancelEvent {
+                event_type: 80941766873992229586089855487021729071, // solana/program/aqua-dex/cancel_order
+                action_id: state.action_counter,
+                market: ctx.accounts.market.key(),
+                owner: acc_owner.key(),
+                user: acc_owner.key(),
+                market_token: ctx.accounts.user_mkt_token.key(),
+                pricing_token: ctx.accounts.user_prc_token.key(),
+                manager: false,
+                order_side: side as u8,
+                order_id: order_id,
+                order_price: order_price,
+                order_quantity: order_qty,
+                token_withdrawn: tokens_out,
+            });
         }
 
-        emit!(WithdrawEvent {
-            event_type: 246174444212986798995680456134066592430, // solana/program/aqua-dex/manager_withdraw
-            action_id: state.action_counter,
-            market: ctx.accounts.market.key(),
-            owner: ctx.accounts.owner.key(),
-            user: ctx.accounts.manager.key(),
-            market_account: ctx.accounts.user_mkt_token.key(),
-            pricing_account: ctx.accounts.user_prc_token.key(),
-            manager: true,
-            market_tokens: market_tokens,
-            pricing_tokens: pricing_tokens,
-        });
+        // Rebate to the user for settlement log space
+        let mut market_lamports = state.to_account_info().lamports();
+        market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+        let mut user_lamports = ctx.accounts.owner.lamports();
+        user_lamports = user_lamports.checked_add(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
 
-        Ok(())
-    }
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if result.prc_tokens > 0 {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.prc_tokens,
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.user_prc_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if result.mkt_tokens > 0 {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.mkt_tokens,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if *acc_result.key != *acc_owner.key {
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
 
-    pub fn log_status<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, LogStatus<'info>>) -> anchor_lang::Result<LogStatusResult> {
-        let acc_settle = &ctx.accounts.settle.to_account_info();
-        let log_data: &[u8] = &acc_settle.try_borrow_data()?;
-        let (header, _page_table) = array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
-        let settle_header: &[AccountsHeader] = cast_slice(header);
-        Ok(LogStatusResult {
-            prev: settle_header[0].prev,
-            next: settle_header[0].next,
-            items: settle_header[0].items,
-        })
+        Ok(cancelled)
     }
 
-    // Withdraw lamports for user vault space, etc.
-    pub fn manager_transfer_sol<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerTransferSol<'info>>,
-        inp_withdraw: bool,
-        inp_all: bool,
-        inp_amount: u64,
-    ) -> anchor_lang::Result<()> {
+    pub fn cancel_all_orders<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CancelOrder<'info>>,
+        inp_limit: u32,             // Max number of orders to cancel
+        inp_max_scan: u32,          // Max number of orders to scan (on both sides of the orderbook)
+        inp_continue: bool,         // Continue a previous call from "next_side" and "next_key" (otherwise start with the bid side)
+        inp_side: u8,               // Continue from side: 0 - Bid, 1 - Ask
+        inp_key: u128,              // Continue from order key (inclusive)
+    ) -> anchor_lang::Result<CancelAllResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let admin = &ctx.accounts.admin;
-        let state = &mut ctx.accounts.state;
-        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let market_state = &ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_result = &ctx.accounts.result.to_account_info();
 
-        if admin.vault_manager != *acc_manager.key {
-            msg!("Not vault manager");
-            return Err(ErrorCode::AccessDenied.into());
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
         }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
-        let mut market_lamports = state.to_account_info().lamports();
-        let mut manager_lamports = acc_manager.lamports();
 
-        if inp_withdraw {
-            let withdraw_amount: u64;
-            if inp_all {
-                withdraw_amount = state.log_deposit_balance;
-            } else {
-                withdraw_amount = inp_amount;
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        require!(inp_limit > 0 && inp_max_scan > 0, ErrorCode::InvalidParameters);
+        let start_side = if inp_continue { Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))? } else { Side::Bid };
+        let start_key: u128 = if inp_continue { inp_key } else { 0 };
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let state = &mut ctx.accounts.state;
+        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+        let mut log_rebate: u64 = 0;
+
+        // Find the user's orders on both sides of the orderbook, up to the limit
+        // Every visited order counts against "max_scan", including orders before the continuation key and other owners' orders
+        let mut user_orders: Vec<(Side, LeafNode)> = Vec::new();
+        let mut scanned: u32 = 0;
+        let mut next_side: u8 = 0;
+        let mut next_key: u128 = 0;
+        let mut more_orders: bool = false;
+        for side in [Side::Bid, Side::Ask] {
+            if side == Side::Bid && start_side == Side::Ask {
+                continue;
             }
-            state.log_deposit_balance = state.log_deposit_balance.checked_sub(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
-            market_lamports = market_lamports.checked_sub(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
-            manager_lamports = manager_lamports.checked_add(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
-        } else { // Deposit lamports
-            manager_lamports = manager_lamports.checked_sub(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
-            market_lamports = market_lamports.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
-            state.log_deposit_balance = state.log_deposit_balance.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+            let min_key: u128 = if side == start_side { start_key } else { 0 };
+            let order_type = match side {
+                Side::Bid => DT::BidOrder,
+                Side::Ask => DT::AskOrder,
+            };
+            let next_leaf = map_predicate_min(sl, order_type, market.max_orders, |sl, leaf| {
+                if scanned >= inp_max_scan || user_orders.len() == inp_limit as usize {
+                    return true;
+                }
+                scanned = scanned + 1;
+                if leaf.key() < min_key || leaf.owner() != *acc_owner.key {
+                    return false;
+                }
+                // Orders within the minimum resting time are left on the orderbook
+                if order_too_young(market, sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize), clock_ts) {
+                    return false;
+                }
+                user_orders.push((side, *leaf));
+                false
+            });
+            if let Some(leaf) = next_leaf {
+                if leaf.key() < min_key {
+                    msg!("Max scan does not reach the continuation key");
+                    return Err(ErrorCode::InvalidParameters.into());
+                }
+                more_orders = true;
+                next_side = side as u8;
+                next_key = leaf.key();
+                break;
+            }
+        }
+
+        for (side, leaf) in user_orders.iter() {
+            let side = *side;
+            let order_id = leaf.key();
+            let order_price = Order::price(order_id);
+            let (order_qty, tokens_out) = cancel_posted_order(market, state, sl, side, leaf, &mut result)?;
+            log_rebate = log_rebate.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+
+            emit!(CancelEvent {
+                event_type: 79986174191413492150026637909252047120, // solana/program/aqua-dex/cancel_all_orders
+                action_id: state.action_counter,
+                market: ctx.accounts.market.key(),
+                owner: acc_owner.key(),
+                user: acc_owner.key(),
+                market_token: ctx.accounts.user_mkt_token.key(),
+                pricing_token: ctx.accounts.user_prc_token.key(),
+                manager: false,
+                order_side: side as u8,
+                order_id: order_id,
+                order_price: order_price,
+                order_quantity: order_qty,
+                token_withdrawn: tokens_out,
+            });
         }
 
+        // Rebate to the user for settlement log space
+        let mut market_lamports = state.to_account_info().lamports();
+        market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
         **state.to_account_info().lamports.borrow_mut() = market_lamports;
-        **acc_manager.lamports.borrow_mut() = manager_lamports;
- 
-        Ok(())
+        let mut user_lamports = ctx.accounts.owner.lamports();
+        user_lamports = user_lamports.checked_add(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
+
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if result.prc_tokens > 0 {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.prc_tokens,
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.user_prc_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if result.mkt_tokens > 0 {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.mkt_tokens,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if *acc_result.key != *acc_owner.key {
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
+
+        Ok(CancelAllResult { cancelled: user_orders.len() as u32, more: more_orders, next_side: next_side, next_key: next_key })
     }
 
-    pub fn manager_withdraw_fees<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerWithdrawFees<'info>>) -> anchor_lang::Result<u64> {
+    pub fn cancel_expired_for_owner<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CancelOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_limit: u32,             // Max number of expired orders to cancel
+    ) -> anchor_lang::Result<CrankResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let admin = &ctx.accounts.admin;
-        let state = &mut ctx.accounts.state;
-        let acc_auth = &ctx.accounts.auth_data.to_account_info();
+        let market_state = &ctx.accounts.state;
         let acc_agent = &ctx.accounts.agent.to_account_info();
-        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
         let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_result = &ctx.accounts.result.to_account_info();
 
-        if admin.fee_manager != *acc_manager.key {
-            msg!("Not fee manager");
-            return Err(ErrorCode::AccessDenied.into());
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
         }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
 
-        has_role(&acc_auth, Role::FeeManager, acc_manager.key)?;
+        require!(inp_limit > 0, ErrorCode::InvalidParameters);
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let (order_type, order_dt) = match side {
+            Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+            Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+        };
 
-        let fee_tokens = state.prc_fees_balance;
-        if fee_tokens > 0 {
-            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
-            state.prc_vault_balance = state.prc_vault_balance.checked_sub(fee_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            state.prc_fees_balance = 0;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let state = &mut ctx.accounts.state;
+        let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+        let mut log_rebate: u64 = 0;
+
+        // Find the owner's expired orders on one side of the orderbook, up to the limit (live orders are not touched)
+        let mut expired_orders: Vec<LeafNode> = Vec::new();
+        let mut more: bool = false;
+        map_predicate_min(sl, order_type, market.max_orders, |sl, leaf| {
+            if leaf.owner() != *acc_owner.key {
+                return false;
+            }
+            if !order_expired(sl.index::<Order>(order_dt as u16, leaf.slot() as usize).expiry, clock_ts) {
+                return false;
+            }
+            if expired_orders.len() == inp_limit as usize {
+                more = true;
+                return true;
+            }
+            expired_orders.push(*leaf);
+            false
+        });
 
-            let seeds = &[market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
+        for leaf in expired_orders.iter() {
+            let order_price = Order::price(leaf.key());
+            let (order_qty, tokens_out) = cancel_posted_order(market, state, sl, side, leaf, &mut result)?;
+            log_rebate = log_rebate.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
+                leaf.slot().to_string(),
+                leaf.owner().to_string(),
+                order_qty.to_string(),
+                order_price.to_string(),
+            );
+            emit!(ExpireEvent {
+                event_type: 16332991664789055110548783525139174482, // solana/program/aqua-dex/expire_event
+                action_id: state.action_counter,
+                market: market.key(),
+                owner: leaf.owner(),
+                order_side: side as u8,
+                order_id: leaf.key(),
+                price: order_price,
+                quantity: order_qty,
+                tokens: tokens_out,
+            });
+        }
+
+        // Rebate to the user for settlement log space
+        let mut market_lamports = state.to_account_info().lamports();
+        market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+        let mut user_lamports = ctx.accounts.owner.lamports();
+        user_lamports = user_lamports.checked_add(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **ctx.accounts.owner.lamports.borrow_mut() = user_lamports;
+
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if result.prc_tokens > 0 {
             let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, fee_tokens, 
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.prc_tokens,
                 &ctx.accounts.prc_vault.to_account_info(),          // From
-                &ctx.accounts.manager_prc_token.to_account_info(),  // To
+                &ctx.accounts.user_prc_token.to_account_info(),     // To
                 &ctx.accounts.agent.to_account_info(),              // Auth
                 &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
             )?;
-
-            emit!(WithdrawEvent {
-                event_type: 68727559793861179499689993618056023286, // solana/program/aqua-dex/manager_withdraw/fees
-                action_id: state.action_counter,
-                market: ctx.accounts.market.key(),
-                owner: Pubkey::default(),
-                user: ctx.accounts.manager.key(),
-                market_account: Pubkey::default(),
-                pricing_account: ctx.accounts.manager_prc_token.key(),
-                manager: true,
-                market_tokens: 0,
-                pricing_tokens: fee_tokens,
-            });
         }
-        Ok(fee_tokens)
+        if result.mkt_tokens > 0 {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, result.mkt_tokens,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if *acc_result.key != *acc_owner.key {
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
+        msg!("Atellix: Removed {} expired orders", expired_orders.len().to_string());
+
+        Ok(CrankResult {
+            removed: expired_orders.len() as u32,
+            more: more,
+        })
     }
 
-    pub fn manager_update_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerUpdateMarket<'info>>,
-        inp_active: bool,
-        inp_expire_enable: bool,
-        inp_expire_min: i64,
-        inp_min_quantity: u64,
-        inp_tick_decimals: u8,
-        inp_taker_fee: u32,
-        inp_maker_rebate: u32,
-        inp_log_fee: u64,
-        inp_log_rebate: u64,
-        inp_log_reimburse: u64,
+    // Allocate the stop order book
+    pub fn create_stop_book<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CreateStopBook<'info>>,
+        inp_max_stops: u32,     // Max stop orders on each side
     ) -> anchor_lang::Result<()> {
-        let admin = &mut ctx.accounts.admin;
         let market = &mut ctx.accounts.market;
         let acc_manager = &ctx.accounts.manager.to_account_info();
-
-        require!(inp_tick_decimals <= 16, ErrorCode::InvalidParameters);
+        let acc_stops = &ctx.accounts.stops.to_account_info();
 
         if market.manager != *acc_manager.key {
             msg!("Not manager");
             return Err(ErrorCode::AccessDenied.into());
         }
-        
-        market.active = inp_active;
-        market.expire_enable = inp_expire_enable;
-        market.expire_min = inp_expire_min;
-        market.min_quantity = inp_min_quantity;
-        market.tick_decimals = inp_tick_decimals;
-        market.taker_fee = inp_taker_fee;
-        market.maker_rebate = inp_maker_rebate;
-        market.log_fee = inp_log_fee;
-        market.log_rebate = inp_log_rebate;
-        market.log_reimburse = inp_log_reimburse;
+        if market.stops != Pubkey::default() {
+            msg!("Stop book already created");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_max_stops > 0, ErrorCode::InvalidParameters);
+
+        // Each side needs a CritMap and a SlabVec sized for the requested capacity, all within the stops account
+        let map_pages = slab_pages::<CritMapHeader, AnyNode>(inp_max_stops as usize);
+        let vec_pages = slab_pages::<SlabVec, StopOrder>(inp_max_stops as usize);
+        let stop_pages = map_pages.checked_add(vec_pages).ok_or(error!(ErrorCode::Overflow))?.checked_mul(2).ok_or(error!(ErrorCode::Overflow))?;
+        if map_pages > SLAB_TYPE_PAGES || vec_pages > SLAB_TYPE_PAGES || stop_pages > SLAB_PAGE_MAX ||
+            acc_stops.data_len() < SLAB_TABLE_SIZE + (stop_pages * SLAB_PAGE_SIZE) {
+            msg!("Invalid stop book capacity");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
 
-        admin.fee_manager = ctx.accounts.fee_manager.key();
-        admin.vault_manager = ctx.accounts.vault_manager.key();
+        market.stops = *acc_stops.key;
+        market.max_stops = inp_max_stops;
+
+        msg!("Atellix: Allocate Stop Book");
+        let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+        let stop_slab = SlabPageAlloc::new(stop_data);
+        stop_slab.setup_page_table();
+        stop_slab.allocate::<CritMapHeader, AnyNode>(StopDT::BidStopMap as u16, inp_max_stops as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        stop_slab.allocate::<CritMapHeader, AnyNode>(StopDT::AskStopMap as u16, inp_max_stops as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        stop_slab.allocate::<SlabVec, StopOrder>(StopDT::BidStop as u16, inp_max_stops as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+        stop_slab.allocate::<SlabVec, StopOrder>(StopDT::AskStop as u16, inp_max_stops as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
 
         Ok(())
     }
 
-    // Move tokens from the settlement log to a user's individual vault (vault manager only)
-    // This is optional market "housekeeping". If a market manager moves balances from the settlement logs to user vaults before the
-    // 1st settlement log file fills up then there will never be a need to rollover settlement logs and possibly require repeating trade transactions.
-    // If markets opt out of this housekeeping then the settlement log will grow if more and more users leave settled trades in the logs.
-    // Traders will need to keep their "Market Data" account up-to-date as settlement log rollovers occur unless managers do housekeeping.
-    // Resending trades is automated in the API frontend but requires possibly resending transactions. (Based on "RetrySettlementAccount" error.)
-    // Keeping open slots in the 1st settlement log enables all trades to be processed immediately without any need of a possible data refresh.
-    pub fn vault_deposit<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VaultDeposit<'info>>) -> anchor_lang::Result<()> {
-        let market = &ctx.accounts.market;
-        let admin = &ctx.accounts.admin;
-        let state = &mut ctx.accounts.state;
-        let vault = &mut ctx.accounts.vault;
+    pub fn migrate_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, MigrateMarket<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let acc_market = &ctx.accounts.market.to_account_info();
+        let acc_state = &ctx.accounts.state.to_account_info();
         let acc_manager = &ctx.accounts.manager.to_account_info();
-        let acc_owner = &ctx.accounts.owner.to_account_info();
-        let acc_settle = &ctx.accounts.settle.to_account_info();
-        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
-        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+        let acc_sys = &ctx.accounts.system_program.to_account_info();
 
-        // Verify
-        if admin.vault_manager != *acc_manager.key {
-            msg!("Not vault manager");
+        // Markets created with the original layout can not be loaded until the fields of layout version 1 are appended
+        if *acc_market.owner != ID || *acc_state.owner != ID {
+            msg!("Invalid market");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        if acc_market.data_len() != MARKET_V0_LEN || acc_state.data_len() != MARKET_STATE_V0_LEN {
+            msg!("Market layout already migrated");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        {
+            let market_data = acc_market.try_borrow_data()?;
+            let state_data = acc_state.try_borrow_data()?;
+            if market_data[..8] != <Market as anchor_lang::Discriminator>::discriminator() || state_data[..8] != <MarketState as anchor_lang::Discriminator>::discriminator() {
+                msg!("Invalid market");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+        }
+        extend_account(acc_manager, acc_market, MARKET_LEN, acc_sys)?;
+        extend_account(acc_manager, acc_state, MARKET_STATE_LEN, acc_sys)?;
+
+        // The original fields keep their positions, and the appended fields are zero (disabled) unless set below
+        let mut market = load_struct::<Market>(acc_market)?;
+        let mut state = load_struct::<MarketState>(acc_state)?;
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
             return Err(ErrorCode::AccessDenied.into());
         }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.state, &acc_state.key, Some(String::from("Invalid market state")))?;
+
+        // Keep the capacities and behavior of the original program (the orderbook keeps version 0 until "resize_orderbook")
+        market.layout_version = MARKET_LAYOUT_VERSION;
+        market.max_orders = LEGACY_MAX_ORDERS;
+        market.max_accounts = LEGACY_MAX_ACCOUNTS;
+        market.eviction_enabled = true;
+        market.eviction_max = LEGACY_MAX_EVICTIONS;
+        market.fee_receiver = market.manager;
+        store_struct::<Market>(&market, acc_market)?;
 
-        if !vault.initialized { // Only initialize once
-            vault.initialized = true;
-            vault.market = ctx.accounts.market.key();
-            vault.owner = ctx.accounts.owner.key();
-            vault.mkt_tokens = 0;
-            vault.prc_tokens = 0;
+        state.layout_version = MARKET_LAYOUT_VERSION;
+        state.price_cumulative_ts = clock_ts;
+        store_struct::<MarketState>(&state, acc_state)?;
+
+        msg!("Atellix: Migrated market layout to version {}", MARKET_LAYOUT_VERSION.to_string());
+        Ok(())
+    }
+
+    pub fn resize_orderbook<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ResizeOrderbook<'info>>,
+        inp_max_orders: u32,    // New max orders on each side of the orderbook
+    ) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_new_orders = &ctx.accounts.new_orders.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        // Orderbooks with the original layout are migrated to the current layout, and may keep the same capacity
+        let migrate: bool = market.orderbook_version != ORDERBOOK_VERSION;
+        require!(market.orderbook_version == 0 || !migrate, ErrorCode::InvalidParameters);
+        if inp_max_orders < market.max_orders || (inp_max_orders == market.max_orders && !migrate) {
+            msg!("Orderbook capacity must increase");
+            return Err(ErrorCode::InvalidParameters.into());
         }
 
-        let mut market_tokens: u64 = 0;
-        let mut pricing_tokens: u64 = 0;
-        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
-        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
-        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
-        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
-        let close_log: bool = settle_header[0].items == 1 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
-        verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
-        let sl = SlabPageAlloc::new(page_table);
-        let has_item = map_get(sl, DT::Account, owner_id);
-        if has_item.is_some() {
-            let log_node = has_item.unwrap();
-            let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
-            if log_entry.mkt_token_balance() > 0 {
-                market_tokens = log_entry.mkt_token_balance();
-                state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                vault.mkt_tokens = vault.mkt_tokens.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            if log_entry.prc_token_balance() > 0 {
-                pricing_tokens = log_entry.prc_token_balance();
-                state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                vault.prc_tokens = vault.prc_tokens.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            // Remove log entry
-            settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
-            map_remove(sl, DT::Account, log_node.key())?;
-            AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+        let map_pages = slab_pages::<CritMapHeader, AnyNode>(inp_max_orders as usize);
+        let vec_pages = slab_pages::<SlabVec, Order>(inp_max_orders as usize);
+        let order_pages = map_pages.checked_add(vec_pages).ok_or(error!(ErrorCode::Overflow))?.checked_mul(2).ok_or(error!(ErrorCode::Overflow))?;
+        if map_pages > SLAB_TYPE_PAGES || vec_pages > SLAB_TYPE_PAGES || order_pages > SLAB_PAGE_MAX ||
+            acc_new_orders.data_len() < SLAB_TABLE_SIZE + (order_pages * SLAB_PAGE_SIZE) {
+            msg!("Invalid orderbook capacity");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
 
-            // Close log if necessary
-            if close_log {
-                let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
-                let mut market_lamports = state.to_account_info().lamports();
-                market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
-                **state.to_account_info().lamports.borrow_mut() = market_lamports;
-                state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        msg!("Atellix: Allocate Orderbook");
+        {
+            let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+            let ob = SlabPageAlloc::new(order_data);
+            let new_data: &mut[u8] = &mut acc_new_orders.try_borrow_mut_data()?;
+            let new_ob = SlabPageAlloc::new(new_data);
+            new_ob.setup_page_table();
+            new_ob.allocate::<CritMapHeader, AnyNode>(OrderDT::BidOrderMap as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+            new_ob.allocate::<CritMapHeader, AnyNode>(OrderDT::AskOrderMap as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+            new_ob.allocate::<SlabVec, Order>(OrderDT::BidOrder as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+            new_ob.allocate::<SlabVec, Order>(OrderDT::AskOrder as u16, inp_max_orders as usize).map_err(|_| error!(ErrorCode::AccountTooSmall))?;
+
+            // Copy each order with its original key to preserve price and time priority
+            for (map_type, order_type) in [(DT::BidOrder, OrderDT::BidOrder), (DT::AskOrder, OrderDT::AskOrder)] {
+                let leaves = map_traverse(ob, map_type, market.max_orders);
+                for leaf in leaves.iter() {
+                    let order = if migrate {
+                        // Original orders are not iceberg orders, and their placement time is unknown
+                        let legacy = *ob.index::<LegacyOrder>(order_type as u16, leaf.slot() as usize);
+                        Order { amount: legacy.amount, expiry: legacy.expiry, display_qty: 0, hidden_qty: 0, placed_ts: 0, expire_to_market: 0 }
+                    } else {
+                        *ob.index::<Order>(order_type as u16, leaf.slot() as usize)
+                    };
+                    let order_idx = Order::next_index(new_ob, map_type)?;
+                    let order_node = LeafNode::new(leaf.key(), order_idx, &leaf.owner());
+                    let res = map_insert(new_ob, map_type, inp_max_orders, &order_node);
+                    if res.is_err() {
+                        msg!("Failed to copy order");
+                        return Err(ErrorCode::InternalError.into());
+                    }
+                    *new_ob.index_mut::<Order>(order_type.into(), order_idx as usize) = order;
+                }
+                msg!("Atellix: Copied {} {} orders", leaves.len().to_string(), match order_type { OrderDT::BidOrder => "bid", _ => "ask" });
             }
-        } else {
-            msg!("Account not found");
-            return Err(ErrorCode::AccountNotFound.into());
         }
 
-        if market_tokens > 0 || pricing_tokens > 0 {
-            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        market.orders = *acc_new_orders.key;
+        market.max_orders = inp_max_orders;
+        market.orderbook_version = ORDERBOOK_VERSION;
 
-            emit!(VaultDepositEvent {
-                event_type: 116949236330450057903776475751429156227, // solana/program/aqua-dex/user_vault/deposit
-                action_id: state.action_counter,
-                market: market.key(),
-                owner: *acc_owner.key,
-                vault: vault.key(),
-                market_tokens: market_tokens,
-                market_balance: vault.mkt_tokens,
-                pricing_tokens: pricing_tokens,
-                pricing_balance: vault.prc_tokens,
-            });
-        }
+        // Close the previous orderbook account
+        let mut manager_lamports = acc_manager.lamports();
+        manager_lamports = manager_lamports.checked_add(acc_orders.lamports()).ok_or(error!(ErrorCode::Overflow))?;
+        **acc_manager.lamports.borrow_mut() = manager_lamports;
+        **acc_orders.lamports.borrow_mut() = 0;
 
         Ok(())
     }
 
-    // Users can withdraw tokens from their own vaults
-    pub fn vault_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VaultWithdraw<'info>>) -> anchor_lang::Result<()> {
+    pub fn place_stop_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, StopOrderContext<'info>>,
+        inp_side: u8,               // 0 - Bid (buy when the last price rises to the trigger), 1 - Ask (sell when the last price falls to the trigger)
+        inp_quantity: u64,          // Order quantity
+        inp_trigger_price: u64,     // Trigger price
+        inp_price_request: u64,     // Limit price
+        inp_mode: u8,               // Order mode when triggered: 0 - Limit (post the unfilled remainder), 2 - Immediate or cancel
+        inp_expires: i64,           // Unix timestamp for order expiration (0 for none)
+    ) -> anchor_lang::Result<u128> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
         let market = &ctx.accounts.market;
-        let state = &mut ctx.accounts.state;
-        let vault = &mut ctx.accounts.vault;
-        let acc_agent = &ctx.accounts.agent.to_account_info();
-        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let market_state = &ctx.accounts.state;
+        let acc_user = &ctx.accounts.user.to_account_info();
         let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
         let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_stops = &ctx.accounts.stops.to_account_info();
 
-        // Verify 
-        if vault.owner != *acc_owner.key {
-            msg!("Not owner");
-            return Err(ErrorCode::AccessDenied.into());
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
         }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
-        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.stops, &acc_stops.key, Some(String::from("Invalid stop book")))?;
 
-        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
-            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let order_mode = OrderMode::try_from(inp_mode).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        require!(order_mode == OrderMode::Limit || order_mode == OrderMode::ImmediateOrCancel, ErrorCode::InvalidParameters);
+        require!(inp_quantity > 0 && inp_quantity >= market.min_quantity, ErrorCode::QuantityBelowMinimum);
+        check_lot_size(market, inp_quantity)?;
+        require!(inp_trigger_price > 0 && inp_price_request > 0, ErrorCode::InvalidParameters);
+        let trigger_price = get_tick_price(&market, inp_trigger_price)?;
+        let inp_price = get_tick_price(&market, inp_price_request)?;
+        require!(trigger_price > 0 && inp_price > 0, ErrorCode::InvalidParameters);
+        check_tick_size(market, trigger_price)?;
+        check_tick_size(market, inp_price)?;
+        let order_value = scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?;
+        check_order_value(inp_quantity, order_value)?;
+        check_min_notional(market, order_value)?;
 
-            let mut market_tokens: u64 = 0;
-            let mut pricing_tokens: u64 = 0;
-            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
-            if vault.mkt_tokens > 0 {
-                market_tokens = vault.mkt_tokens;
-                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, market_tokens,
-                    &ctx.accounts.mkt_vault.to_account_info(),          // From
-                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                vault.mkt_tokens = 0;
-                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            if vault.prc_tokens > 0 {
-                pricing_tokens = vault.prc_tokens;
-                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, pricing_tokens,
-                    &ctx.accounts.prc_vault.to_account_info(),          // From
-                    &ctx.accounts.user_prc_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                vault.prc_tokens = 0;
-                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        let state_upd = &mut ctx.accounts.state;
+        // Before the first trade (last price of 0) no stop has been reached: bids trigger once the last price rises to them,
+        // and asks are held until there is a last price to fall from
+        let last_price = state_upd.last_price;
+        if (side == Side::Bid && trigger_price <= last_price) || (side == Side::Ask && last_price > 0 && trigger_price >= last_price) {
+            msg!("Stop would trigger immediately");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+
+        // Check expiration parameters
+        let mut expiry: i64 = 0;
+        if market.expire_enable && inp_expires != 0 {
+            let expire_dur = inp_expires.checked_sub(clock_ts).ok_or(error!(ErrorCode::Overflow))?;
+            if expire_dur <= 0 {
+                msg!("Order already expired");
+                return Err(ErrorCode::InvalidParameters.into());
+            }
+            if expire_dur < market.expire_min {
+                msg!("Order expires before minimum duration of {} seconds", market.expire_min.to_string());
+                return Err(ErrorCode::InvalidParameters.into());
             }
+            expiry = inp_expires;
+        }
 
-            // Close the vault and transfer lamports to the market
-            let vault_lamports = vault.to_account_info().lamports();
-            **vault.to_account_info().lamports.borrow_mut() = 0;
-            let mut market_lamports = state.to_account_info().lamports();
-            market_lamports = market_lamports.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
-            **state.to_account_info().lamports.borrow_mut() = market_lamports;
-            state.log_deposit_balance = state.log_deposit_balance.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        // Bids deposit the limit price cost plus the max taker fee, asks deposit the market tokens
+        let deposit: u64 = match side {
+            Side::Bid => {
+                let tokens_in = scale_price(inp_quantity, inp_price, decimal_factor(market.mkt_decimals))?;
+                let fee = calculate_fee(market.taker_fee, tokens_in)?;
+                tokens_in.checked_add(fee).ok_or(error!(ErrorCode::Overflow))?
+            },
+            Side::Ask => inp_quantity,
+        };
+        require!(deposit > 0, ErrorCode::InvalidParameters);
 
-            emit!(VaultWithdrawEvent {
-                event_type: 222531087088795477156040686028020078326, // solana/program/aqua-dex/user_vault/withdraw
-                action_id: state.action_counter,
-                market: market.key(),
-                owner: *acc_owner.key,
-                user: *acc_owner.key,
-                vault: vault.key(),
-                market_account: ctx.accounts.user_mkt_token.key(),
-                pricing_account: ctx.accounts.user_prc_token.key(),
-                manager: false,
-                market_tokens: market_tokens,
-                pricing_tokens: pricing_tokens,
-            });
+        msg!("Atellix: Stop {}: {} @ {} Trigger: {}",
+            match side { Side::Bid => "Bid", Side::Ask => "Ask" },
+            inp_quantity.to_string(), inp_price.to_string(), trigger_price.to_string(),
+        );
+
+        state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        match side {
+            Side::Bid => {
+                state_upd.prc_vault_balance = state_upd.prc_vault_balance.checked_add(deposit).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.prc_order_balance = state_upd.prc_order_balance.checked_add(deposit).ok_or(error!(ErrorCode::Overflow))?;
+            },
+            Side::Ask => {
+                state_upd.mkt_vault_balance = state_upd.mkt_vault_balance.checked_add(deposit).ok_or(error!(ErrorCode::Overflow))?;
+                state_upd.mkt_order_balance = state_upd.mkt_order_balance.checked_add(deposit).ok_or(error!(ErrorCode::Overflow))?;
+            },
+        };
+
+        let stop_type = match side {
+            Side::Bid => DT::BidStop,
+            Side::Ask => DT::AskStop,
+        };
+        let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(stop_data);
+        let stop_id = StopOrder::new_key(state_upd, side, trigger_price);
+        let stop_idx = StopOrder::next_index(sl, stop_type)?;
+        let stop_node = LeafNode::new(stop_id, stop_idx, &acc_user.key);
+        if map_insert(sl, stop_type, market.max_stops, &stop_node).is_err() {
+            msg!("Stop book full");
+            return Err(ErrorCode::OrderbookFull.into());
         }
+        *sl.index_mut::<StopOrder>(index_datatype(stop_type), stop_idx as usize) = StopOrder {
+            amount: inp_quantity,
+            price: inp_price,
+            deposit: deposit,
+            expiry: expiry,
+            order_mode: order_mode as u8,
+        };
 
-        Ok(())
+        // Deposit lamports for settlement log space
+        let mut user_lamports = acc_user.lamports();
+        user_lamports = user_lamports.checked_sub(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+        **acc_user.lamports.borrow_mut() = user_lamports;
+        let mut market_lamports = state_upd.to_account_info().lamports();
+        market_lamports = market_lamports.checked_add(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+        **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+        state_upd.log_deposit_balance = state_upd.log_deposit_balance.checked_add(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Send tokens to the vault
+        if side == Side::Bid {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, deposit, false,
+                &ctx.accounts.user_prc_token.to_account_info(),  // From
+                &ctx.accounts.prc_vault.to_account_info(),       // To
+                &ctx.accounts.user.to_account_info(),            // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+        } else {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, deposit, false,
+                &ctx.accounts.user_mkt_token.to_account_info(),  // From
+                &ctx.accounts.mkt_vault.to_account_info(),       // To
+                &ctx.accounts.user.to_account_info(),            // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+        }
+
+        emit!(StopEvent {
+            event_type: 295528542125081308042677540185820008448, // solana/program/aqua-dex/stop/place
+            action_id: state_upd.action_counter,
+            market: market.key(),
+            owner: acc_user.key(),
+            order_side: side as u8,
+            stop_id: stop_id,
+            trigger_price: trigger_price,
+            price: inp_price,
+            quantity: inp_quantity,
+            deposit: deposit,
+            filled: 0,
+            order_id: 0,
+        });
+
+        Ok(stop_id)
     }
 
-    // Manager withdrawal from user vaults
-    pub fn manager_vault_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerVaultWithdraw<'info>>) -> anchor_lang::Result<()> {
+    pub fn cancel_stop_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, StopOrderContext<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_stop_id: u128,
+    ) -> anchor_lang::Result<()> {
         let market = &ctx.accounts.market;
-        let state = &mut ctx.accounts.state;
-        let vault = &mut ctx.accounts.vault;
+        let market_state = &ctx.accounts.state;
         let acc_agent = &ctx.accounts.agent.to_account_info();
-        let acc_owner = &ctx.accounts.owner.to_account_info();
-        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_user = &ctx.accounts.user.to_account_info();
         let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
         let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_stops = &ctx.accounts.stops.to_account_info();
 
-        // Verify 
-        if !market.manager_actions {
-            msg!("Manager actions disabled");
-            return Err(ErrorCode::AccessDenied.into());
-        }
-        if market.manager != *acc_manager.key {
-            msg!("Not owner");
-            return Err(ErrorCode::AccessDenied.into());
-        }
-        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
         verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
         verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
         verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        verify_matching_accounts(&market.stops, &acc_stops.key, Some(String::from("Invalid stop book")))?;
 
-        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
-            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let stop_type = match side {
+            Side::Bid => DT::BidStop,
+            Side::Ask => DT::AskStop,
+        };
+        let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(stop_data);
+        let item = map_get(sl, stop_type, market.max_stops, inp_stop_id);
+        if item.is_none() {
+            msg!("Order not found");
+            return Err(ErrorCode::OrderNotFound.into());
+        }
+        let leaf = item.unwrap();
+        if leaf.owner() != *acc_user.key {
+            msg!("Order not owned by user");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let stop = *sl.index::<StopOrder>(index_datatype(stop_type), leaf.slot() as usize);
+        map_remove(sl, stop_type, market.max_stops, leaf.key())?;
+        StopOrder::free_index(sl, stop_type, leaf.slot())?;
 
-            let mut market_tokens: u64 = 0;
-            let mut pricing_tokens: u64 = 0;
-            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
-            let signer = &[&seeds[..]];
-            if vault.mkt_tokens > 0 {
-                market_tokens = vault.mkt_tokens;
-                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, market_tokens,
-                    &ctx.accounts.mkt_vault.to_account_info(),          // From
-                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                vault.mkt_tokens = 0;
-                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
-            if vault.prc_tokens > 0 {
-                pricing_tokens = vault.prc_tokens;
-                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
-                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, pricing_tokens,
-                    &ctx.accounts.prc_vault.to_account_info(),          // From
-                    &ctx.accounts.user_prc_token.to_account_info(),     // To
-                    &ctx.accounts.agent.to_account_info(),              // Auth
-                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
-                )?;
-                vault.prc_tokens = 0;
-                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
-            }
+        let state = &mut ctx.accounts.state;
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        match side {
+            Side::Bid => {
+                state.prc_vault_balance = state.prc_vault_balance.checked_sub(stop.deposit).ok_or(error!(ErrorCode::Overflow))?;
+                state.prc_order_balance = state.prc_order_balance.checked_sub(stop.deposit).ok_or(error!(ErrorCode::Overflow))?;
+            },
+            Side::Ask => {
+                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(stop.deposit).ok_or(error!(ErrorCode::Overflow))?;
+                state.mkt_order_balance = state.mkt_order_balance.checked_sub(stop.deposit).ok_or(error!(ErrorCode::Overflow))?;
+            },
+        };
 
-            // Close the vault and transfer lamports to the market
-            let vault_lamports = vault.to_account_info().lamports();
-            **vault.to_account_info().lamports.borrow_mut() = 0;
-            let mut market_lamports = state.to_account_info().lamports();
-            market_lamports = market_lamports.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
-            **state.to_account_info().lamports.borrow_mut() = market_lamports;
-            state.log_deposit_balance = state.log_deposit_balance.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        // Rebate to the user for settlement log space
+        state.log_deposit_balance = state.log_deposit_balance.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        let mut market_lamports = state.to_account_info().lamports();
+        market_lamports = market_lamports.checked_sub(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+        let mut user_lamports = acc_user.lamports();
+        user_lamports = user_lamports.checked_add(market.log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **acc_user.lamports.borrow_mut() = user_lamports;
 
-            emit!(VaultWithdrawEvent {
-                event_type: 155648231829618734246883800498177854177, // solana/program/aqua-dex/user_vault/manager_withdraw
-                action_id: state.action_counter,
-                market: market.key(),
-                owner: *acc_owner.key,
-                user: *acc_manager.key,
-                vault: vault.key(),
-                market_account: ctx.accounts.user_mkt_token.key(),
-                pricing_account: ctx.accounts.user_prc_token.key(),
-                manager: true,
-                market_tokens: market_tokens,
-                pricing_tokens: pricing_tokens,
-            });
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if side == Side::Bid {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, stop.deposit,
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.user_prc_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        } else {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, stop.deposit,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
         }
 
+        emit!(StopEvent {
+            event_type: 3399505018546459385681943058636726022, // solana/program/aqua-dex/stop/cancel
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: acc_user.key(),
+            order_side: side as u8,
+            stop_id: inp_stop_id,
+            trigger_price: Order::price(inp_stop_id),
+            price: stop.price,
+            quantity: stop.amount,
+            deposit: stop.deposit,
+            filled: 0,
+            order_id: 0,
+        });
+
         Ok(())
     }
 
-    pub fn close_vault(ctx: Context<CloseVault>) -> anchor_lang::Result<()> {
-        let admin = &ctx.accounts.admin;
-        let vault = &mut ctx.accounts.vault;
-        let acc_manager = &ctx.accounts.manager.to_account_info();
+    pub fn trigger_stops<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TriggerStops<'info>>,
+        inp_limit: u32,             // Max number of stop orders to trigger
+    ) -> anchor_lang::Result<u32> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
 
-        if admin.vault_manager != *acc_manager.key {
-            msg!("Not vault manager");
-            return Err(ErrorCode::AccessDenied.into());
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_stops = &ctx.accounts.stops.to_account_info();
+        let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
         }
-        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
-            msg!("Vault not empty");
-            return Err(ErrorCode::VaultNotEmpty.into());
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        require!(inp_limit > 0, ErrorCode::InvalidParameters);
+        check_breaker(market, market_state, clock_ts)?;
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        verify_matching_accounts(&market.stops, &acc_stops.key, Some(String::from("Invalid stop book")))?;
+        verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
+
+        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
         }
 
-        Ok(())
-    }
+        let state_upd = &mut ctx.accounts.state;
+        let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(orderbook_data);
+        let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(stop_data);
+        let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
+        let tlog = SlabPageAlloc::new(trade_data);
 
-    pub fn close_trade_result(_ctx: Context<CloseTradeResult>) -> anchor_lang::Result<()> {
-        Ok(())
+        let mut triggered: u32 = 0;
+        while triggered < inp_limit {
+            if breaker_halted(market, state_upd, clock_ts)? {
+                // A triggered stop tripped the circuit breaker
+                break;
+            }
+            // Fills from a triggered stop update the last price, which can trigger further stops
+            let last_price = state_upd.last_price;
+            let mut next_stop: Option<(Side, LeafNode)> = None;
+            if let Some(leaf) = map_min(sl, DT::BidStop, market.max_stops) {
+                if Order::price(leaf.key()) <= last_price {
+                    next_stop = Some((Side::Bid, leaf));
+                }
+            }
+            if next_stop.is_none() && last_price > 0 {
+                if let Some(leaf) = map_max(sl, DT::AskStop, market.max_stops) {
+                    if Order::price(leaf.key()) >= last_price {
+                        next_stop = Some((Side::Ask, leaf));
+                    }
+                }
+            }
+            if next_stop.is_none() {
+                break;
+            }
+            let (side, leaf) = next_stop.unwrap();
+            if !settlement_available(state_upd, acc_settle1, acc_settle2, &leaf.owner())? {
+                // Leave the stop in place until the settlement logs are rolled over
+                break;
+            }
+            let stop_type = match side {
+                Side::Bid => DT::BidStop,
+                Side::Ask => DT::AskStop,
+            };
+            let stop = *sl.index::<StopOrder>(index_datatype(stop_type), leaf.slot() as usize);
+            map_remove(sl, stop_type, market.max_stops, leaf.key())?;
+            StopOrder::free_index(sl, stop_type, leaf.slot())?;
+            state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+            let mut tokens_filled: u64 = 0;
+            let mut order_id: u128 = 0;
+            let event_type: u128;
+            if order_expired(stop.expiry, clock_ts) {
+                msg!("Atellix: Expired Stop - Owner: {} {} @ {}", leaf.owner().to_string(), stop.amount().to_string(), Order::price(leaf.key()).to_string());
+                log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &leaf.owner(), side == Side::Ask, stop.deposit)?;
+                event_type = 309201414144276639383515068405382874201; // solana/program/aqua-dex/stop/expire
+            } else {
+                msg!("Atellix: Triggered Stop - Owner: {} {} @ {}", leaf.owner().to_string(), stop.amount().to_string(), Order::price(leaf.key()).to_string());
+                let (stop_filled, stop_order_id) = execute_stop(market, &market.key(), state_upd, ob, tlog, acc_settle1, acc_settle2, side, &leaf.owner(), &stop,
+                    131796566500056797180570047630981443891, // solana/program/aqua-dex/trigger_stops/match
+                    clock_ts,
+                )?;
+                tokens_filled = stop_filled;
+                order_id = stop_order_id;
+                event_type = 138873913116199457476300338490785783536; // solana/program/aqua-dex/stop/trigger
+            }
+            emit!(StopEvent {
+                event_type: event_type,
+                action_id: state_upd.action_counter,
+                market: market.key(),
+                owner: leaf.owner(),
+                order_side: side as u8,
+                stop_id: leaf.key(),
+                trigger_price: Order::price(leaf.key()),
+                price: stop.price,
+                quantity: stop.amount,
+                deposit: stop.deposit,
+                filled: tokens_filled,
+                order_id: order_id,
+            });
+            triggered = triggered + 1;
+        }
+        msg!("Atellix: Triggered {} stop orders", triggered.to_string());
+
+        Ok(triggered)
     }
 
-    pub fn close_withdraw_result(_ctx: Context<CloseWithdrawResult>) -> anchor_lang::Result<()> {
+    pub fn withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, Withdraw<'info>>,
+        inp_withdraw_mkt: bool,     // Withdraw market tokens
+        inp_withdraw_prc: bool,     // Withdraw pricing tokens
+    ) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_result = &ctx.accounts.result.to_account_info();
+
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
+        }
+
+        // Verify 
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Remove the selected balances from the owner's log entry
+        let (result, entry_removed) = withdraw_log_entry(&market.key(), acc_settle, acc_owner.key, inp_withdraw_mkt, inp_withdraw_prc)?;
+        let market_tokens: u64 = result.mkt_tokens;
+        let pricing_tokens: u64 = result.prc_tokens;
+
+        settle_withdraw(market, &market.key(), state, ctx.remaining_accounts, 0, market_tokens, pricing_tokens,
+            acc_agent, acc_mkt_vault, acc_prc_vault,
+            &ctx.accounts.user_mkt_token.to_account_info(),
+            &ctx.accounts.user_prc_token.to_account_info(),
+            &ctx.accounts.spl_token_prog.to_account_info(),
+        )?;
+
+        // Write result
+        if *acc_result.key != ctx.accounts.owner.key() {
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
+
+        if entry_removed {
+            close_log_entry(market, state, acc_owner, acc_settle, &ctx.accounts.settle_prev.to_account_info(), &ctx.accounts.settle_next.to_account_info())?;
+        }
+
+        emit!(WithdrawEvent {
+            event_type: 206836899720010235937021599972903459637, // solana/program/aqua-dex/withdraw
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: ctx.accounts.owner.key(),
+            user: ctx.accounts.owner.key(),
+            market_account: ctx.accounts.user_mkt_token.key(),
+            pricing_account: ctx.accounts.user_prc_token.key(),
+            manager: false,
+            market_tokens: market_tokens,
+            pricing_tokens: pricing_tokens,
+        });
+
+        Ok(())
+    }
+
+    pub fn withdraw_to_vault<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, WithdrawVault<'info>>,
+        inp_withdraw_mkt: bool,     // Withdraw market tokens
+        inp_withdraw_prc: bool,     // Withdraw pricing tokens
+    ) -> anchor_lang::Result<WithdrawResult> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_user_vault = &ctx.accounts.vault.to_account_info();
+
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+        // Remove the selected balances from the owner's log entry
+        let (result, entry_removed) = withdraw_log_entry(&market.key(), acc_settle, acc_owner.key, inp_withdraw_mkt, inp_withdraw_prc)?;
+        let market_tokens: u64 = result.mkt_tokens;
+        let pricing_tokens: u64 = result.prc_tokens;
+
+        // Tokens stay in the market vault and are credited to the user vault, so no token transfer can fail
+        state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+        let vault = credit_user_vault(&market.key(), state, acc_owner, acc_user_vault, &ctx.accounts.system_program.to_account_info(), market_tokens, pricing_tokens)?;
+
+        if entry_removed {
+            close_log_entry(market, state, acc_owner, acc_settle, &ctx.accounts.settle_prev.to_account_info(), &ctx.accounts.settle_next.to_account_info())?;
+        }
+
+        emit!(VaultDepositEvent {
+            event_type: 163170545797400858669346925567425393702, // solana/program/aqua-dex/user_vault/withdraw
+            action_id: state.action_counter,
+            market: market.key(),
+            owner: *acc_owner.key,
+            vault: acc_user_vault.key(),
+            market_tokens: market_tokens,
+            market_balance: vault.mkt_tokens,
+            pricing_tokens: pricing_tokens,
+            pricing_balance: vault.prc_tokens,
+        });
+
+        Ok(result)
+    }
+
+    pub fn batch_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, BatchWithdraw<'info>>,
+        inp_log_count: u8,          // Number of settlement logs passed as (settle, prev, next) groups at the start of the remaining accounts
+    ) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_result = &ctx.accounts.result.to_account_info();
+
+        if *acc_result.key != ctx.accounts.owner.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
+        }
+
+        // Verify
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        require!(inp_log_count > 0 && inp_log_count <= MAX_BATCH_LOGS, ErrorCode::InvalidParameters);
+        let log_accounts: usize = (inp_log_count as usize).checked_mul(3).ok_or(error!(ErrorCode::Overflow))?;
+        if ctx.remaining_accounts.len() < log_accounts {
+            msg!("Missing settlement log accounts");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+        let mut market_tokens: u64 = 0;
+        let mut pricing_tokens: u64 = 0;
+        let mut entries_removed: u64 = 0;
+        let mut market_lamports = state.to_account_info().lamports();
+        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
+        for group in ctx.remaining_accounts[..log_accounts].chunks(3) {
+            let acc_settle = &group[0];
+            let acc_settle_prev = &group[1];
+            let acc_settle_next = &group[2];
+            if *acc_settle.owner != ID {
+                msg!("Invalid settlement log");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            {
+                let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+                let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+                let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+                verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+                let sl = SlabPageAlloc::new(page_table);
+                let max_accounts = map_len(sl, DT::Account);
+                let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
+                if has_item.is_none() {
+                    msg!("Account not found: {}", acc_settle.key.to_string());
+                    return Err(ErrorCode::AccountNotFound.into());
+                }
+                let log_node = has_item.unwrap();
+                let log_entry = *sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+                market_tokens = market_tokens.checked_add(log_entry.mkt_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+                pricing_tokens = pricing_tokens.checked_add(log_entry.prc_token_balance()).ok_or(error!(ErrorCode::Overflow))?;
+
+                // Remove log entry (both balances are withdrawn)
+                settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+                AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+                entries_removed = entries_removed + 1;
+            }
+
+            // Close log if necessary (following groups must pass the relinked neighbors)
+            let log_lamports = close_empty_log(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+            market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        }
+
+        // Rebate to the user for settlement log space
+        let log_rebate = pay_log_rebate(market, state, acc_owner, entries_removed)?;
+        market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+
+        // Transfer the combined balances (security token accounts follow the settlement logs)
+        settle_withdraw(market, &market.key(), state, ctx.remaining_accounts, log_accounts, market_tokens, pricing_tokens,
+            acc_agent, acc_mkt_vault, acc_prc_vault,
+            &ctx.accounts.user_mkt_token.to_account_info(),
+            &ctx.accounts.user_prc_token.to_account_info(),
+            &ctx.accounts.spl_token_prog.to_account_info(),
+        )?;
+
+        // Write result
+        if *acc_result.key != ctx.accounts.owner.key() {
+            let result = WithdrawResult { mkt_tokens: market_tokens, prc_tokens: pricing_tokens };
+            store_struct::<WithdrawResult>(&result, acc_result)?;
+        }
+
+        emit!(WithdrawEvent {
+            event_type: 109064752221736150076187378383777277537, // solana/program/aqua-dex/batch_withdraw
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: ctx.accounts.owner.key(),
+            user: ctx.accounts.owner.key(),
+            market_account: ctx.accounts.user_mkt_token.key(),
+            pricing_account: ctx.accounts.user_prc_token.key(),
+            manager: false,
+            market_tokens: market_tokens,
+            pricing_tokens: pricing_tokens,
+        });
+
+        Ok(())
+    }
+
+    pub fn reserve_settlement<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ReserveSettlement<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
+        }
+        verify_matching_accounts(&market.state, &ctx.accounts.state.key(), Some(String::from("Invalid market state")))?;
+        let state_upd = &mut ctx.accounts.state;
+        let s1 = verify_matching_accounts(&state_upd.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&state_upd.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+
+        // Nothing to do if the owner already has an entry in one of the current logs
+        if settle_entry(acc_settle1, acc_owner.key)?.is_some() || settle_entry(acc_settle2, acc_owner.key)?.is_some() {
+            msg!("Atellix: Settlement entry exists - Owner: {}", acc_owner.key.to_string());
+            return Ok(());
+        }
+
+        state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        // Insert a zero-balance entry (into the second log if the first is full)
+        log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, acc_owner.key, true, 0)?;
+
+        // Deposit lamports for settlement log space
+        let mut user_lamports = acc_owner.lamports();
+        user_lamports = user_lamports.checked_sub(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+        **acc_owner.lamports.borrow_mut() = user_lamports;
+        let mut market_lamports = state_upd.to_account_info().lamports();
+        market_lamports = market_lamports.checked_add(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+        **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+        state_upd.log_deposit_balance = state_upd.log_deposit_balance.checked_add(market.log_fee).ok_or(error!(ErrorCode::Overflow))?;
+
+        Ok(())
+    }
+
+    pub fn expire_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExpireOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,
+        inp_rollover: bool,
+    ) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_user = &ctx.accounts.user.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            // This is expected to happen sometimes due to a race condition between settlment log rollovers and new orders
+            // Reload the current "market" account with the latest settlement log accounts and retry the transaction
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into()); 
+        }
+
+        // Append a settlement log account
+        let state_upd = &mut ctx.accounts.state;
+        if inp_rollover {
+            if !state_upd.log_rollover {
+                // Another market participant already appended a new log account (please retry transaction)
+                msg!("Please update market data and retry");
+                return Err(ErrorCode::RetrySettlementAccount.into());
+            }
+            let av = ctx.remaining_accounts;
+            let new_settlement_log = av.get(0).unwrap();
+            let market_pk: Pubkey = market.key();
+            log_rollover(state_upd, market_pk, market.max_accounts, acc_settle2, new_settlement_log)?;
+            log_reimburse(market, state_upd, acc_user)?;
+            let mut market_lamports = state_upd.to_account_info().lamports();
+            market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
+            **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+        }
+
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let order_type = match side {
+            Side::Bid => DT::BidOrder,
+            Side::Ask => DT::AskOrder,
+        };
+        let item = map_get(sl, order_type, market.max_orders, inp_order_id);
+        if item.is_none() {
+            msg!("Order not found");
+            return Err(ErrorCode::OrderNotFound.into());
+        }
+        let leaf = item.unwrap();
+        let order = sl.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        let expired: bool = order_expired(order.expiry, clock_ts);
+        if expired {
+            state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            let order_id = leaf.key();
+            let order_owner: Pubkey = leaf.owner();
+            let order_price = Order::price(order_id);
+            let order_qty = order.total_amount();
+            let tokens = match side {
+                Side::Bid => {
+                    let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &order_owner, false, total)?;
+                    state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                    total
+                },
+                Side::Ask => {
+                    let total = order.total_amount();
+                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &order_owner, true, total)?;
+                    state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                    total
+                }
+            };
+            map_remove(sl, order_type, market.max_orders, leaf.key())?;
+            Order::free_index(sl, order_type, leaf.slot())?;
+
+            emit!(ExpireEvent {
+                event_type: 16332991664789055110548783525139174482, // solana/program/aqua-dex/expire_event
+                action_id: state_upd.action_counter,
+                market: market.key(),
+                owner: leaf.owner(),
+                order_side: side as u8,
+                order_id: order_id,
+                price: order_price,
+                quantity: order_qty,
+                tokens: tokens,
+            });
+        } else {
+            msg!("Atellix: Order not expired");
+        }
+
+        Ok(())
+    }
+
+    pub fn poke_oracle<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, PokeOracle<'info>>) -> anchor_lang::Result<()> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        verify_matching_accounts(&market.state, &ctx.accounts.state.key(), Some(String::from("Invalid market state")))?;
+
+        // Carry the last price forward to the current time so the TWAP does not go stale without trades
+        let state = &mut ctx.accounts.state;
+        accumulate_price(state, clock_ts)?;
+        msg!("Atellix: Price Cumulative: {} Timestamp: {}", state.price_cumulative.to_string(), state.price_cumulative_ts.to_string());
+
         Ok(())
     }
+
+    pub fn crank_expirations<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExpireOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_limit: u32,             // Max number of expired orders to remove
+    ) -> anchor_lang::Result<CrankResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+
+        require!(inp_limit > 0, ErrorCode::InvalidParameters);
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+
+        let state_upd = &mut ctx.accounts.state;
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let (order_type, order_dt) = match side {
+            Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+            Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+        };
+        let mut removed: u32 = 0;
+        let mut more: bool = false;
+        loop {
+            let node_res = map_predicate_min(sl, order_type, market.max_orders, |sl, leaf| {
+                let order = sl.index::<Order>(order_dt as u16, leaf.slot() as usize);
+                order_expired(order.expiry, clock_ts)
+            });
+            if node_res.is_none() {
+                break;
+            }
+            let leaf = node_res.unwrap();
+            if removed == inp_limit || !settlement_available(state_upd, acc_settle1, acc_settle2, &leaf.owner())? {
+                more = true;
+                break;
+            }
+            state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            let order = *sl.index::<Order>(order_dt as u16, leaf.slot() as usize);
+            let order_price = Order::price(leaf.key());
+            let order_qty = order.total_amount();
+            let tokens = match side {
+                Side::Bid => {
+                    let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &leaf.owner(), false, total)?;
+                    state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                    total
+                },
+                Side::Ask => {
+                    log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &leaf.owner(), true, order_qty)?;
+                    state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                    order_qty
+                }
+            };
+            map_remove(sl, order_type, market.max_orders, leaf.key())?;
+            Order::free_index(sl, order_type, leaf.slot())?;
+            msg!("Atellix: Expired Order[{}] - Owner: {} {} @ {}",
+                leaf.slot().to_string(),
+                leaf.owner().to_string(),
+                order_qty.to_string(),
+                order_price.to_string(),
+            );
+            emit!(ExpireEvent {
+                event_type: 16332991664789055110548783525139174482, // solana/program/aqua-dex/expire_event
+                action_id: state_upd.action_counter,
+                market: market.key(),
+                owner: leaf.owner(),
+                order_side: side as u8,
+                order_id: leaf.key(),
+                price: order_price,
+                quantity: order_qty,
+                tokens: tokens,
+            });
+            removed = removed + 1;
+        }
+        msg!("Atellix: Removed {} expired orders", removed.to_string());
+
+        Ok(CrankResult {
+            removed: removed,
+            more: more,
+        })
+    }
+
+    pub fn expire_to_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExpireToMarket<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,         // Expired order with "expire_to_market" set
+    ) -> anchor_lang::Result<u64> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
+        }
+        check_global_pause(&ctx.accounts.global_config.to_account_info())?;
+        check_session(market, clock_ts)?;
+        check_breaker(market, market_state, clock_ts)?;
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
+
+        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+
+        let state_upd = &mut ctx.accounts.state;
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let orderbook_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(orderbook_data);
+        let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
+        let tlog = SlabPageAlloc::new(trade_data);
+        let order_type = match side {
+            Side::Bid => DT::BidOrder,
+            Side::Ask => DT::AskOrder,
+        };
+        let item = map_get(ob, order_type, market.max_orders, inp_order_id);
+        if item.is_none() {
+            msg!("Order not found");
+            return Err(ErrorCode::OrderNotFound.into());
+        }
+        let leaf = item.unwrap();
+        let order = *ob.index::<Order>(index_datatype(order_type), leaf.slot() as usize);
+        if !order_expired(order.expiry, clock_ts) {
+            msg!("Order not expired");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        if !order.expires_to_market() {
+            msg!("Order does not execute as a market order on expiry");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        verify_matching_accounts(&leaf.owner(), acc_owner.key, Some(String::from("Invalid order owner")))?;
+        if !settlement_available(state_upd, acc_settle1, acc_settle2, &leaf.owner())? {
+            msg!("Atellix: Settlement logs full");
+            return Err(ErrorCode::SettlementLogFull.into());
+        }
+
+        // Remove the expired order and match its tokens as an immediate or cancel order for the owner
+        // The limit price is the order price moved by at most "expire_slippage_bps" (bids are also limited by the tokens held by the order)
+        state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        let order_price = Order::price(leaf.key());
+        let order_qty = order.total_amount();
+        let slippage: u64 = ((order_price as u128 * market.expire_slippage_bps as u128) / 10000) as u64;
+        map_remove(ob, order_type, market.max_orders, leaf.key())?;
+        Order::free_index(ob, order_type, leaf.slot())?;
+        let market_order = match side {
+            Side::Bid => {
+                state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                let deposit = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                let limit_price = order_price.saturating_add(slippage);
+                StopOrder { amount: order_qty, price: limit_price, deposit: deposit, expiry: 0, order_mode: OrderMode::ImmediateOrCancel as u8 }
+            },
+            Side::Ask => {
+                state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                let limit_price = order_price.saturating_sub(slippage);
+                StopOrder { amount: order_qty, price: limit_price, deposit: order_qty, expiry: 0, order_mode: OrderMode::ImmediateOrCancel as u8 }
+            },
+        };
+        msg!("Atellix: Expire To Market - Owner: {} {} @ {}", leaf.owner().to_string(), order_qty.to_string(), order_price.to_string());
+        // The order's settlement log deposit pays for the owner's new entry, or is returned to the owner if the owner already has an entry
+        let owner_logged = settle_entry(acc_settle1, &leaf.owner())?.is_some() || settle_entry(acc_settle2, &leaf.owner())?.is_some();
+        let (tokens_filled, _) = execute_stop(market, &market.key(), state_upd, ob, tlog, acc_settle1, acc_settle2, side, &leaf.owner(), &market_order,
+            97636615993683834041942623071330571268, // solana/program/aqua-dex/expire_to_market/match
+            clock_ts,
+        )?;
+
+        if owner_logged {
+            let mut market_lamports = state_upd.to_account_info().lamports();
+            let log_rebate = pay_log_rebate(market, state_upd, acc_owner, 1)?;
+            market_lamports = market_lamports.checked_sub(log_rebate).ok_or(error!(ErrorCode::Overflow))?;
+            **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+        }
+
+        emit!(ExpireToMarketEvent {
+            event_type: 283373430774079997840757685913445962803, // solana/program/aqua-dex/expire_to_market
+            action_id: state_upd.action_counter,
+            market: market.key(),
+            owner: leaf.owner(),
+            order_side: side as u8,
+            order_id: leaf.key(),
+            price: order_price,
+            quantity: order_qty,
+            filled: tokens_filled,
+        });
+
+        Ok(tokens_filled)
+    }
+
+    pub fn manager_cancel_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerCancelOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,
+        inp_rollover: bool,
+    ) -> anchor_lang::Result<()> {
+        if !ctx.accounts.market.manager_actions {
+            msg!("Manager actions disabled");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let (side, leaf, order_qty, tokens_out) = manager_settle_order(ctx.accounts, ctx.remaining_accounts, inp_side, inp_order_id, inp_rollover)?;
+
+        emit!(CancelEvent {
+            event_type: 149668793492806786255339444097076784738, // solana/program/aqua-dex/manager_cancel_order
+            action_id: ctx.accounts.state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: leaf.owner(),
+            user: ctx.accounts.manager.key(),
+            market_token: Pubkey::default(),
+            pricing_token: Pubkey::default(),
+            manager: true,
+            order_side: side as u8,
+            order_id: leaf.key(),
+            order_price: Order::price(leaf.key()),
+            order_quantity: order_qty,
+            token_withdrawn: tokens_out,
+        });
+
+        Ok(())
+    }
+    
+    pub fn manager_force_settle<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerCancelOrder<'info>>,
+        inp_side: u8,               // 0 - Bid, 1 - Ask
+        inp_order_id: u128,
+        inp_reason: u8,             // Compliance reason code (recorded in the event)
+        inp_rollover: bool,
+    ) -> anchor_lang::Result<()> {
+        // Independent of "manager_actions", tokens always remain with the owner in the settlement log
+        if !ctx.accounts.market.manager_force {
+            msg!("Manager force settle disabled");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let (side, leaf, order_qty, tokens_settled) = manager_settle_order(ctx.accounts, ctx.remaining_accounts, inp_side, inp_order_id, inp_rollover)?;
+
+        msg!("Atellix: Force Settle - Order: {} Reason: {}", leaf.key().to_string(), inp_reason.to_string());
+        emit!(ForceSettleEvent {
+            event_type: 90739567162455161184207471072376241528, // solana/program/aqua-dex/manager_force_settle
+            action_id: ctx.accounts.state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: leaf.owner(),
+            manager: ctx.accounts.manager.key(),
+            order_side: side as u8,
+            order_id: leaf.key(),
+            order_price: Order::price(leaf.key()),
+            order_quantity: order_qty,
+            token_settled: tokens_settled,
+            reason: inp_reason,
+        });
+
+        Ok(())
+    }
+
+    pub fn extend_log<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ExtendLog<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_user = &ctx.accounts.user.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+
+        // Verify 
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle.key, Some(String::from("Settlement log 2")));
+        if s2.is_err() {
+            // This is expected to happen sometimes due to a race condition between settlement log rollovers and new orders
+            // Reload the current "market" account with the latest settlement log accounts and retry the transaction
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+
+        // Append a settlement log account
+        let state_upd = &mut ctx.accounts.state;
+        if !state_upd.log_rollover {
+            // Another market participant already appended a new log account (please retry transaction)
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+        let av = ctx.remaining_accounts;
+        let new_settlement_log = av.get(0).unwrap();
+        let market_pk: Pubkey = market.key();
+        log_rollover(state_upd, market_pk, market.max_accounts, acc_settle, new_settlement_log)?;
+        log_reimburse(market, state_upd, acc_user)?;
+        let mut market_lamports = state_upd.to_account_info().lamports();
+        market_lamports = market_lamports.checked_sub(market.log_reimburse).ok_or(error!(ErrorCode::Overflow))?;
+        **state_upd.to_account_info().lamports.borrow_mut() = market_lamports;
+
+        Ok(())
+    }
+
+    // Withdraw tokens from the settlement vault (manager)
+    pub fn manager_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerWithdraw<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+        let acc_result = &ctx.accounts.result.to_account_info();
+
+        if *acc_result.key != ctx.accounts.manager.key() {
+            verify_result_account::<WithdrawResult>(acc_result, WITHDRAW_RESULT_LEN)?;
+        }
+
+        // Verify 
+        if !market.manager_actions {
+            msg!("Manager actions disabled");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+        let mut market_tokens: u64 = 0;
+        let mut pricing_tokens: u64 = 0;
+        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
+        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+        verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+        let close_log: bool = settle_header[0].items == 1 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
+        let sl = SlabPageAlloc::new(page_table);
+        let max_accounts = map_len(sl, DT::Account);
+        let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
+        if has_item.is_some() {
+            let log_node = has_item.unwrap();
+            let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            let mut result = WithdrawResult { mkt_tokens: 0, prc_tokens: 0 };
+            if log_entry.mkt_token_balance() > 0 {
+                market_tokens = log_entry.mkt_token_balance();
+                result.set_mkt_tokens(market_tokens);
+                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.mkt_token_balance(),
+                    &ctx.accounts.mkt_vault.to_account_info(),          // From
+                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            if log_entry.prc_token_balance() > 0 {
+                pricing_tokens = log_entry.prc_token_balance();
+                result.set_prc_tokens(pricing_tokens);
+                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, log_entry.prc_token_balance(),
+                    &ctx.accounts.prc_vault.to_account_info(),          // From
+                    &ctx.accounts.user_prc_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            // Remove log entry
+            settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+            AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+            // Write result
+            if *acc_result.key != ctx.accounts.manager.key() {
+                store_struct::<WithdrawResult>(&result, acc_result)?;
+            }
+
+            // Close log if necessary
+            if close_log {
+                let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+                let mut market_lamports = state.to_account_info().lamports();
+                market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+                **state.to_account_info().lamports.borrow_mut() = market_lamports;
+                state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        } else {
+            msg!("Account not found");
+            return Err(ErrorCode::AccountNotFound.into());
+        }
+
+        emit!(WithdrawEvent {
+            event_type: 246174444212986798995680456134066592430, // solana/program/aqua-dex/manager_withdraw
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: ctx.accounts.owner.key(),
+            user: ctx.accounts.manager.key(),
+            market_account: ctx.accounts.user_mkt_token.key(),
+            pricing_account: ctx.accounts.user_prc_token.key(),
+            manager: true,
+            market_tokens: market_tokens,
+            pricing_tokens: pricing_tokens,
+        });
+
+        Ok(())
+    }
+
+    pub fn sweep_dust<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, SweepDust<'info>>,
+        inp_max_sweep: u32,         // Maximum number of entries to remove
+    ) -> anchor_lang::Result<SweepResult> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+
+        // Verify
+        if !market.manager_actions {
+            msg!("Manager actions disabled");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        if market.dust_threshold == 0 {
+            msg!("Dust threshold not set");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_max_sweep > 0, ErrorCode::InvalidParameters);
+
+        let mut swept: u32 = 0;
+        let mut mkt_swept: u64 = 0;
+        let mut prc_swept: u64 = 0;
+        let close_log: bool;
+        {
+            let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+            let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+            let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+            verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+            let sl = SlabPageAlloc::new(page_table);
+            let max_accounts = map_len(sl, DT::Account);
+            let entries = map_traverse(sl, DT::Account, max_accounts);
+            for log_node in entries.iter() {
+                if swept == inp_max_sweep {
+                    break;
+                }
+                let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+                let market_tokens = log_entry.mkt_token_balance();
+                let pricing_tokens = log_entry.prc_token_balance();
+                if market_tokens >= market.dust_threshold || pricing_tokens >= market.dust_threshold {
+                    continue;
+                }
+                // Pricing token dust is added to the fees balance, market token dust is held in the dust balance
+                if market_tokens > 0 {
+                    state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                    state.mkt_dust_balance = state.mkt_dust_balance.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                }
+                if pricing_tokens > 0 {
+                    state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                    state.prc_fees_balance = state.prc_fees_balance.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                }
+                // Remove log entry
+                settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+                AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+                swept = swept + 1;
+                mkt_swept = mkt_swept.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                prc_swept = prc_swept.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+
+                state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+                msg!("Atellix: Swept Dust - Mkt: {} Prc: {} Key: {}", market_tokens.to_string(), pricing_tokens.to_string(), log_node.owner().to_string());
+                emit!(SweepEvent {
+                    event_type: 231545467025619040432217220952214241848, // solana/program/aqua-dex/sweep_dust
+                    action_id: state.action_counter,
+                    market: market.key(),
+                    owner: log_node.owner(),
+                    settlement_log: acc_settle.key(),
+                    market_tokens: market_tokens,
+                    pricing_tokens: pricing_tokens,
+                });
+            }
+            close_log = swept > 0 && settle_header[0].items == 0 &&
+                settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
+        }
+
+        // Close log if all entries were swept
+        if close_log {
+            let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+            let mut market_lamports = state.to_account_info().lamports();
+            market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            **state.to_account_info().lamports.borrow_mut() = market_lamports;
+            state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        }
+        msg!("Atellix: Swept Entries: {} Mkt: {} Prc: {}", swept.to_string(), mkt_swept.to_string(), prc_swept.to_string());
+        Ok(SweepResult { swept: swept, mkt_swept: mkt_swept, prc_swept: prc_swept })
+    }
+
+    pub fn compact_settlement<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CompactSettlement<'info>>,
+        inp_max_entries: u32,       // Maximum number of entries to move
+    ) -> anchor_lang::Result<CompactResult> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+        let acc_target = &ctx.accounts.target.to_account_info();
+
+        // Verify
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        require!(inp_max_entries > 0, ErrorCode::InvalidParameters);
+        if *acc_target.key == *acc_settle.key {
+            msg!("Target settlement log matches source");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        {
+            let target_data: &[u8] = &acc_target.try_borrow_data()?;
+            let (header, _page_table) = array_refs![target_data, size_of::<AccountsHeader>(); .. ;];
+            let target_header: &[AccountsHeader] = cast_slice(header);
+            verify_matching_accounts(&target_header[0].market, &market.key(), Some(String::from("Invalid target market")))?;
+        }
+
+        let mut moved: u32 = 0;
+        let close_log: bool;
+        {
+            let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+            let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+            let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+            verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+            if settle_header[0].prev == Pubkey::default() || settle_header[0].next == Pubkey::default() {
+                msg!("Cannot compact the first or last settlement log");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let sl = SlabPageAlloc::new(page_table);
+            let max_accounts = map_len(sl, DT::Account);
+            let entries = map_traverse(sl, DT::Account, max_accounts);
+            for log_node in entries.iter() {
+                if moved == inp_max_entries {
+                    break;
+                }
+                let log_entry = *sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+                let res = merge_settle_entry(acc_target, &log_node.owner(), &log_entry);
+                if res.is_err() {
+                    msg!("Target settlement log full");
+                    return Err(res.unwrap_err());
+                }
+                // Remove log entry (token balances stay in the settlement log balances)
+                settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+                AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+                moved = moved + 1;
+            }
+            close_log = settle_header[0].items == 0;
+        }
+
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        msg!("Atellix: Compacted {} entries from {} to {}", moved.to_string(), acc_settle.key.to_string(), acc_target.key.to_string());
+        emit!(CompactEvent {
+            event_type: 306285899747853070937499864553024192323, // solana/program/aqua-dex/compact_settlement
+            action_id: state.action_counter,
+            market: market.key(),
+            source_log: acc_settle.key(),
+            target_log: acc_target.key(),
+            moved: moved,
+            closed: close_log,
+        });
+
+        // Close the source log once it is empty
+        if close_log {
+            let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+            let mut market_lamports = state.to_account_info().lamports();
+            market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            **state.to_account_info().lamports.borrow_mut() = market_lamports;
+            state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+        }
+
+        Ok(CompactResult {
+            moved: moved,
+            closed: close_log,
+        })
+    }
+
+    pub fn log_status<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, LogStatus<'info>>) -> anchor_lang::Result<LogStatusResult> {
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let log_data: &[u8] = &acc_settle.try_borrow_data()?;
+        let (header, _page_table) = array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &[AccountsHeader] = cast_slice(header);
+        Ok(LogStatusResult {
+            prev: settle_header[0].prev,
+            next: settle_header[0].next,
+            items: settle_header[0].items,
+        })
+    }
+
+    pub fn list_settlement_logs<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, LogStatus<'info>>,
+        inp_count: u8,          // Max number of settlement logs to return
+    ) -> anchor_lang::Result<SettlementLogList> {
+        require!(inp_count > 0 && inp_count <= MAX_LIST_LOGS, ErrorCode::InvalidParameters);
+        let mut acc_settle = &ctx.accounts.settle.to_account_info();
+        let mut market: Option<Pubkey> = None;
+        let mut result = SettlementLogList { logs: Vec::new(), next: Pubkey::default() };
+
+        // Follow the "next" links through the remaining accounts (in chain order after the starting log)
+        let mut remaining = ctx.remaining_accounts.iter();
+        loop {
+            if *acc_settle.owner != ID {
+                msg!("Invalid settlement log");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let log_data: &[u8] = &acc_settle.try_borrow_data()?;
+            let (header, _page_table) = array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+            let settle_header: &[AccountsHeader] = cast_slice(header);
+            if let Some(market_key) = market {
+                verify_matching_accounts(&settle_header[0].market, &market_key, Some(String::from("Invalid settlement log market")))?;
+            } else {
+                market = Some(settle_header[0].market);
+            }
+            result.logs.push(SettlementLogInfo {
+                settlement_log: acc_settle.key(),
+                items: settle_header[0].items,
+                prev: settle_header[0].prev,
+                next: settle_header[0].next,
+            });
+            result.next = settle_header[0].next;
+            if result.next == Pubkey::default() || result.logs.len() == inp_count as usize {
+                break;
+            }
+            match remaining.next() {
+                Some(acc_next) => {
+                    verify_matching_accounts(&result.next, acc_next.key, Some(String::from("Invalid next settlement log")))?;
+                    acc_settle = acc_next;
+                },
+                None => break,
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn find_settlement<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, LogStatus<'info>>,
+        inp_owner: Pubkey,      // Owner of the settlement log entry
+    ) -> anchor_lang::Result<FindSettlementResult> {
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        if *acc_settle.owner != ID {
+            msg!("Invalid settlement log");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+        let sl = SlabPageAlloc::new(page_table);
+        let max_accounts = map_len(sl, DT::Account);
+        let owner_id: u128 = CritMap::bytes_hash(inp_owner.as_ref());
+        let mut result = FindSettlementResult {
+            market: settle_header[0].market,
+            prev: settle_header[0].prev,
+            next: settle_header[0].next,
+            found: false,
+            mkt_token_balance: 0,
+            prc_token_balance: 0,
+            ts_updated: 0,
+        };
+        if let Some(log_node) = map_get(sl, DT::Account, max_accounts, owner_id) {
+            let entry = sl.index::<AccountEntry>(SettleDT::Account.into(), log_node.slot() as usize);
+            result.found = true;
+            result.mkt_token_balance = entry.mkt_token_balance();
+            result.prc_token_balance = entry.prc_token_balance();
+            result.ts_updated = entry.ts_updated();
+        }
+        Ok(result)
+    }
+
+    pub fn get_market_stats<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, MarketStats<'info>>) -> anchor_lang::Result<MarketStatsResult> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        Ok(MarketStatsResult {
+            mkt_volume: state.mkt_volume,
+            prc_volume: state.prc_volume,
+            price_cumulative: state.price_cumulative,
+            price_cumulative_ts: state.price_cumulative_ts,
+            last_price: state.last_price,
+            last_ts: state.last_ts,
+            active_bid: state.active_bid,
+            active_ask: state.active_ask,
+        })
+    }
+
+    pub fn get_state_snapshot<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, MarketStats<'info>>) -> anchor_lang::Result<StateSnapshot> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        Ok(StateSnapshot {
+            version: STATE_SNAPSHOT_VERSION,
+            settle_a: state.settle_a,
+            settle_b: state.settle_b,
+            log_rollover: state.log_rollover,
+            log_deposit_balance: state.log_deposit_balance,
+            action_counter: state.action_counter,
+            order_counter: state.order_counter,
+            active_bid: state.active_bid,
+            active_ask: state.active_ask,
+            mkt_vault_balance: state.mkt_vault_balance,
+            mkt_order_balance: state.mkt_order_balance,
+            mkt_user_vault_balance: state.mkt_user_vault_balance,
+            mkt_log_balance: state.mkt_log_balance,
+            mkt_dust_balance: state.mkt_dust_balance,
+            prc_vault_balance: state.prc_vault_balance,
+            prc_order_balance: state.prc_order_balance,
+            prc_user_vault_balance: state.prc_user_vault_balance,
+            prc_log_balance: state.prc_log_balance,
+            prc_fees_balance: state.prc_fees_balance,
+            prc_dust_balance: state.prc_dust_balance,
+            last_price: state.last_price,
+            last_ts: state.last_ts,
+            mkt_volume: state.mkt_volume,
+            prc_volume: state.prc_volume,
+            price_cumulative: state.price_cumulative,
+            price_cumulative_ts: state.price_cumulative_ts,
+            breaker_tripped_ts: state.breaker_tripped_ts,
+        })
+    }
+
+    pub fn get_market_addresses<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, MarketInfo<'info>>) -> anchor_lang::Result<MarketAddresses> {
+        let market = &ctx.accounts.market;
+        Ok(MarketAddresses {
+            agent: market.agent,
+            mkt_vault: market.mkt_vault,
+            prc_vault: market.prc_vault,
+            orders: market.orders,
+            trade_log: market.trade_log,
+            settle_0: market.settle_0,
+            state: market.state,
+        })
+    }
+
+    pub fn get_trade_log_range<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TradeLogRange<'info>>) -> anchor_lang::Result<TradeLogRangeResult> {
+        let market = &ctx.accounts.market;
+        let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
+        verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
+        let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
+        let tlog = SlabPageAlloc::new(trade_data);
+        let trade_header = *tlog.header::<TradeLogHeader>(0);
+        let mut result = TradeLogRangeResult {
+            trade_count: trade_header.trade_count,
+            entry_max: trade_header.entry_max,
+            oldest_trade_id: 0,
+            oldest_action_id: 0,
+            newest_trade_id: 0,
+            newest_action_id: 0,
+        };
+        if trade_header.trade_count > 0 {
+            // Entries older than "entry_max" trades have been overwritten
+            let oldest_index = if trade_header.trade_count > trade_header.entry_max {
+                trade_header.trade_count.rem_euclid(trade_header.entry_max)
+            } else {
+                0
+            };
+            let newest_index = (trade_header.trade_count - 1).rem_euclid(trade_header.entry_max);
+            let oldest_entry = tlog.index::<TradeEntry>(0, oldest_index as usize);
+            result.oldest_trade_id = oldest_entry.trade_id;
+            result.oldest_action_id = oldest_entry.action_id;
+            let newest_entry = tlog.index::<TradeEntry>(0, newest_index as usize);
+            result.newest_trade_id = newest_entry.trade_id;
+            result.newest_action_id = newest_entry.action_id;
+        }
+        Ok(result)
+    }
+
+    pub fn get_trade<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TradeLogRange<'info>>,
+        inp_trade_id: u64,          // Trade ID (from 1 to the trade count)
+    ) -> anchor_lang::Result<TradeEntryResult> {
+        let market = &ctx.accounts.market;
+        let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
+        verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
+        let trade_data: &mut[u8] = &mut acc_trade_log.try_borrow_mut_data()?;
+        let tlog = SlabPageAlloc::new(trade_data);
+        let trade_header = *tlog.header::<TradeLogHeader>(0);
+        let trade_count = trade_header.trade_count;
+        let entry_max = trade_header.entry_max;
+
+        // Only the last "entry_max" trades are still in the trade log
+        let resident = inp_trade_id > 0 && inp_trade_id <= trade_count && inp_trade_id > trade_count.saturating_sub(entry_max);
+        if !resident {
+            msg!("Atellix: Trade not found - Trade ID: {} Trade Count: {}", inp_trade_id.to_string(), trade_count.to_string());
+            return Err(ErrorCode::RecordNotFound.into());
+        }
+        let log_index = (inp_trade_id - 1).rem_euclid(entry_max);
+        let entry = *tlog.index::<TradeEntry>(0, log_index as usize);
+        require!(entry.trade_id == inp_trade_id, ErrorCode::RecordNotFound);
+        Ok(TradeEntryResult {
+            event_type: entry.event_type,
+            action_id: entry.action_id,
+            trade_id: entry.trade_id,
+            maker_order_id: entry.maker_order_id,
+            maker_filled: entry.maker_filled,
+            maker: entry.maker,
+            taker: entry.taker,
+            taker_side: entry.taker_side,
+            amount: entry.amount,
+            price: entry.price,
+            ts: entry.ts,
+        })
+    }
+
+    pub fn get_orderbook_depth<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_depth: u8,              // Max price levels per side
+    ) -> anchor_lang::Result<OrderbookDepthResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        require!(inp_depth > 0 && inp_depth <= MAX_DEPTH, ErrorCode::InvalidParameters);
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        Ok(OrderbookDepthResult {
+            bids: orderbook_levels(ob, Side::Bid, market.max_orders, inp_depth, clock_ts),
+            asks: orderbook_levels(ob, Side::Ask, market.max_orders, inp_depth, clock_ts),
+        })
+    }
+
+    pub fn get_top_of_book<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TopOfBook<'info>>) -> anchor_lang::Result<TopOfBookResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        let (best_bid_price, best_bid_qty) = best_level(ob, Side::Bid, market.max_orders, clock_ts);
+        let (best_ask_price, best_ask_qty) = best_level(ob, Side::Ask, market.max_orders, clock_ts);
+        let spread: u64 = if best_bid_price > 0 && best_ask_price > 0 { best_ask_price.saturating_sub(best_bid_price) } else { 0 };
+        Ok(TopOfBookResult {
+            best_bid_price: best_bid_price,
+            best_bid_qty: best_bid_qty,
+            best_ask_price: best_ask_price,
+            best_ask_qty: best_ask_qty,
+            spread: spread,
+            last_price: state.last_price,
+        })
+    }
+
+    pub fn get_market_metrics<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TopOfBook<'info>>) -> anchor_lang::Result<MarketMetrics> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        let (best_bid, _) = best_level(ob, Side::Bid, market.max_orders, clock_ts);
+        let (best_ask, _) = best_level(ob, Side::Ask, market.max_orders, clock_ts);
+        let mut mid_price: u64 = 0;
+        let mut spread_bps: u64 = 0;
+        if best_bid > 0 && best_ask > 0 {
+            // The sum is taken in 128 bits, the mid price of two u64 prices always fits in 64 bits
+            mid_price = ((best_bid as u128 + best_ask as u128) / 2) as u64;
+            let spread: u128 = best_ask.saturating_sub(best_bid) as u128;
+            spread_bps = u64::try_from(spread.checked_mul(10000).ok_or(error!(ErrorCode::Overflow))? / mid_price as u128).map_err(|_| error!(ErrorCode::Overflow))?;
+        }
+        Ok(MarketMetrics {
+            best_bid: best_bid,
+            best_ask: best_ask,
+            mid_price: mid_price,
+            spread_bps: spread_bps,
+            last_price: state.last_price,
+            active_bid: state.active_bid,
+            active_ask: state.active_ask,
+        })
+    }
+
+    pub fn quote_order<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_side: u8,           // 0 - Bid (buy market tokens), 1 - Ask (sell market tokens)
+        inp_by_quantity: bool,  // Fill by quantity (otherwise price)
+        inp_quantity: u64,      // Fill until quantity
+        inp_net_price: u64,     // Fill until net price is reached
+    ) -> anchor_lang::Result<QuoteResult> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        if inp_by_quantity {
+            require!(inp_quantity > 0, ErrorCode::InvalidParameters);
+        } else {
+            require!(inp_net_price > 0, ErrorCode::InvalidParameters);
+        }
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+
+        quote_fill(market, ob, side, inp_by_quantity, inp_quantity, inp_net_price, clock_ts)
+    }
+
+    pub fn bulk_quote<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_side: u8,               // 0 - Bid (buy market tokens), 1 - Ask (sell market tokens)
+        inp_quantities: Vec<u64>,   // Quantities to quote (up to MAX_BULK_QUOTES, each quoted against the same orderbook)
+    ) -> anchor_lang::Result<Vec<QuoteResult>> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        require!(inp_quantities.len() > 0 && inp_quantities.len() <= MAX_BULK_QUOTES as usize, ErrorCode::InvalidParameters);
+        for quantity in inp_quantities.iter() {
+            require!(*quantity > 0, ErrorCode::InvalidParameters);
+        }
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+
+        // Each quote is independent (the orderbook is not changed between quotes)
+        let mut quotes: Vec<QuoteResult> = Vec::new();
+        for quantity in inp_quantities.iter() {
+            quotes.push(quote_fill(market, ob, side, true, *quantity, 0, clock_ts)?);
+        }
+        Ok(quotes)
+    }
+
+    pub fn get_user_exposure<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_owner: Pubkey,      // Order owner
+        inp_max_scan: u32,      // Max number of orders to scan (on both sides of the orderbook)
+        inp_continue: bool,     // Continue a previous scan from "next_side" and "next_key" (otherwise start with the bid side)
+        inp_side: u8,           // Continue from side: 0 - Bid, 1 - Ask
+        inp_key: u128,          // Continue from order key (inclusive)
+    ) -> anchor_lang::Result<ExposureResult> {
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        require!(inp_max_scan > 0, ErrorCode::InvalidParameters);
+        let start_side = if inp_continue { Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))? } else { Side::Bid };
+        let start_key: u128 = if inp_continue { inp_key } else { 0 };
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+
+        // Scan each side in key order (read-only), bid tokens are committed at the order price including hidden quantity
+        let df = decimal_factor(market.mkt_decimals);
+        let mut scanned: u32 = 0;
+        let mut open_bid_count: u32 = 0;
+        let mut open_ask_count: u32 = 0;
+        let mut mkt_committed: u128 = 0;
+        let mut prc_committed: u128 = 0;
+        let mut next_side: u8 = 0;
+        let mut next_key: u128 = 0;
+        let mut more: bool = false;
+        for side in [Side::Bid, Side::Ask] {
+            if side == Side::Bid && start_side == Side::Ask {
+                continue;
+            }
+            let min_key: u128 = if side == start_side { start_key } else { 0 };
+            let (map_type, order_type) = match side {
+                Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+                Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+            };
+            // Every visited order counts against "max_scan", including orders before the continuation key and other owners' orders
+            let next_leaf = map_predicate_min(ob, map_type, market.max_orders, |sl, leaf| {
+                if scanned >= inp_max_scan {
+                    return true;
+                }
+                scanned = scanned + 1;
+                if leaf.key() < min_key {
+                    return false;
+                }
+                if leaf.owner() == inp_owner {
+                    let amount = sl.index::<Order>(order_type as u16, leaf.slot() as usize).total_amount();
+                    match side {
+                        Side::Bid => {
+                            open_bid_count = open_bid_count + 1;
+                            prc_committed = prc_committed.saturating_add((amount as u128).saturating_mul(Order::price(leaf.key()) as u128) / (df as u128));
+                        },
+                        Side::Ask => {
+                            open_ask_count = open_ask_count + 1;
+                            mkt_committed = mkt_committed.saturating_add(amount as u128);
+                        },
+                    };
+                }
+                false
+            });
+            if let Some(leaf) = next_leaf {
+                if leaf.key() < min_key {
+                    msg!("Max scan does not reach the continuation key");
+                    return Err(ErrorCode::InvalidParameters.into());
+                }
+                more = true;
+                next_side = side as u8;
+                next_key = leaf.key();
+                break;
+            }
+        }
+        Ok(ExposureResult {
+            open_bid_count: open_bid_count,
+            open_ask_count: open_ask_count,
+            mkt_committed: u64::try_from(mkt_committed).map_err(|_| error!(ErrorCode::Overflow))?,
+            prc_committed: u64::try_from(prc_committed).map_err(|_| error!(ErrorCode::Overflow))?,
+            more: more,
+            next_side: next_side,
+            next_key: next_key,
+        })
+    }
+
+    pub fn get_book_notional<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_max_scan: u32,      // Max number of orders to scan (on both sides of the orderbook)
+        inp_continue: bool,     // Continue a previous scan from "next_side" and "next_key" (otherwise start with the bid side)
+        inp_side: u8,           // Continue from side: 0 - Bid, 1 - Ask
+        inp_key: u128,          // Continue from order key (inclusive)
+    ) -> anchor_lang::Result<BookNotional> {
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        require!(inp_max_scan > 0, ErrorCode::InvalidParameters);
+        let start_side = if inp_continue { Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))? } else { Side::Bid };
+        let start_key: u128 = if inp_continue { inp_key } else { 0 };
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+
+        // Scan each side in key order (read-only), bids are valued in pricing tokens at the order price and asks in market tokens,
+        // both including hidden quantity
+        let df = decimal_factor(market.mkt_decimals);
+        let mut scanned: u32 = 0;
+        let mut bid_count: u32 = 0;
+        let mut ask_count: u32 = 0;
+        let mut bid_notional: u128 = 0;
+        let mut ask_notional: u128 = 0;
+        let mut next_side: u8 = 0;
+        let mut next_key: u128 = 0;
+        let mut more: bool = false;
+        for side in [Side::Bid, Side::Ask] {
+            if side == Side::Bid && start_side == Side::Ask {
+                continue;
+            }
+            let min_key: u128 = if side == start_side { start_key } else { 0 };
+            let (map_type, order_type) = match side {
+                Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+                Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+            };
+            let mut overflow: bool = false;
+            // Every visited order counts against "max_scan", including orders before the continuation key
+            let next_leaf = map_predicate_min(ob, map_type, market.max_orders, |sl, leaf| {
+                if scanned >= inp_max_scan {
+                    return true;
+                }
+                scanned = scanned + 1;
+                if leaf.key() < min_key {
+                    return false;
+                }
+                let amount = sl.index::<Order>(order_type as u16, leaf.slot() as usize).total_amount();
+                match side {
+                    Side::Bid => {
+                        bid_count = bid_count + 1;
+                        match scale_price(amount, Order::price(leaf.key()), df) {
+                            Ok(tokens) => bid_notional = bid_notional.saturating_add(tokens as u128),
+                            Err(_) => overflow = true,
+                        }
+                    },
+                    Side::Ask => {
+                        ask_count = ask_count + 1;
+                        ask_notional = ask_notional.saturating_add(amount as u128);
+                    },
+                };
+                overflow
+            });
+            if overflow {
+                return Err(ErrorCode::Overflow.into());
+            }
+            if let Some(leaf) = next_leaf {
+                if leaf.key() < min_key {
+                    msg!("Max scan does not reach the continuation key");
+                    return Err(ErrorCode::InvalidParameters.into());
+                }
+                more = true;
+                next_side = side as u8;
+                next_key = leaf.key();
+                break;
+            }
+        }
+        Ok(BookNotional {
+            bid_notional: u64::try_from(bid_notional).map_err(|_| error!(ErrorCode::Overflow))?,
+            ask_notional: u64::try_from(ask_notional).map_err(|_| error!(ErrorCode::Overflow))?,
+            bid_count: bid_count,
+            ask_count: ask_count,
+            more: more,
+            next_side: next_side,
+            next_key: next_key,
+        })
+    }
+
+    pub fn get_order_info<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, OrderbookDepth<'info>>,
+        inp_side: u8,           // 0 - Bid, 1 - Ask
+        inp_order_id: u128,     // Order ID
+        inp_max_scan: u32,      // Max number of orders ahead to scan
+    ) -> anchor_lang::Result<OrderInfo> {
+        let clock = Clock::get()?;
+        let clock_ts = clock.unix_timestamp;
+
+        let market = &ctx.accounts.market;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        require!(inp_max_scan > 0, ErrorCode::InvalidParameters);
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        let side = Side::try_from(inp_side).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        let (map_type, order_type) = match side {
+            Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+            Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+        };
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        let leaf = map_get(ob, map_type, market.max_orders, inp_order_id);
+        if leaf.is_none() {
+            msg!("Order not found");
+            return Err(ErrorCode::OrderNotFound.into());
+        }
+        let leaf = leaf.unwrap();
+        let order = *ob.index::<Order>(order_type as u16, leaf.slot() as usize);
+        let order_price = Order::price(inp_order_id);
+
+        // Walk from the best price to the order (the sequence in the lower 64 bits of the key gives time priority)
+        // Better priced orders count with their hidden quantity, same priced orders count with the displayed quantity
+        // (replenished iceberg slices are re-queued behind the order), and expired orders are skipped
+        let mut scanned: u32 = 0;
+        let mut ahead_quantity: u64 = 0;
+        let mut complete: bool = true;
+        let mut ahead = |sl: &SlabPageAlloc, node: &LeafNode| {
+            if node.key() == inp_order_id {
+                return true;
+            }
+            if scanned >= inp_max_scan {
+                complete = false;
+                return true;
+            }
+            scanned = scanned + 1;
+            let ahead_order = sl.index::<Order>(order_type as u16, node.slot() as usize);
+            if !order_expired(ahead_order.expiry, clock_ts) {
+                let qty = if Order::price(node.key()) == order_price { ahead_order.amount() } else { ahead_order.total_amount() };
+                ahead_quantity = ahead_quantity.saturating_add(qty);
+            }
+            false
+        };
+        match side {
+            Side::Bid => map_predicate_max(ob, map_type, market.max_orders, |sl, node| ahead(sl, node)),
+            Side::Ask => map_predicate_min(ob, map_type, market.max_orders, |sl, node| ahead(sl, node)),
+        };
+
+        Ok(OrderInfo {
+            price: order_price,
+            quantity: order.total_amount(),
+            expiry: order.expiry,
+            owner: leaf.owner(),
+            placed_ts: order.placed_ts,
+            age_seconds: if order.placed_ts > 0 { clock_ts.saturating_sub(order.placed_ts) } else { 0 },
+            ahead_quantity: ahead_quantity,
+            complete: complete,
+        })
+    }
+
+    pub fn order_exists<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TopOfBook<'info>>,
+        inp_order_id: u128,     // Order ID
+        inp_side: u8,           // 0 - Bid, 1 - Ask, 2 - Check both sides
+    ) -> anchor_lang::Result<OrderExistsResult> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        require!(inp_side <= 2, ErrorCode::InvalidParameters);
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        // The lower 64 bits of the key hold the order sequence (inverted for bids), so a key issued by this market identifies its side
+        let seq: u64 = inp_order_id as u64;
+        let issued_side: Option<Side> = if seq < state.order_counter {
+            Some(Side::Ask)
+        } else if !seq < state.order_counter {
+            Some(Side::Bid)
+        } else {
+            None
+        };
+
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        let mut found_side: Option<Side> = None;
+        for side in [Side::Bid, Side::Ask] {
+            if inp_side != 2 && inp_side != side as u8 {
+                continue;
+            }
+            let map_type = match side {
+                Side::Bid => DT::BidOrder,
+                Side::Ask => DT::AskOrder,
+            };
+            if map_get(ob, map_type, market.max_orders, inp_order_id).is_some() {
+                found_side = Some(side);
+                break;
+            }
+        }
+
+        Ok(OrderExistsResult {
+            exists: found_side.is_some(),
+            side: found_side.or(issued_side).map_or(u8::MAX, |side| side as u8),
+            price: Order::price(inp_order_id),
+            filled_or_gone: found_side.is_none() && issued_side.is_some(),
+        })
+    }
+
+    pub fn verify_solvency<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VerifySolvency<'info>>) -> anchor_lang::Result<SolvencyResult> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        // The tracked vault balances include every other balance (the pricing token vault balance includes fees)
+        let mkt_actual = vault_token_balance(acc_mkt_vault, market.mkt_mint_type)?;
+        let prc_actual = vault_token_balance(acc_prc_vault, market.prc_mint_type)?;
+        let mkt_delta: i128 = (mkt_actual as i128) - (state.mkt_vault_balance as i128);
+        let prc_delta: i128 = (prc_actual as i128) - (state.prc_vault_balance as i128);
+        let solvent = mkt_delta >= 0 && prc_delta >= 0;
+        if !solvent {
+            msg!("Atellix: Market insolvent - Market Token Delta: {} Pricing Token Delta: {}", mkt_delta.to_string(), prc_delta.to_string());
+        }
+        Ok(SolvencyResult {
+            mkt_vault_actual: mkt_actual,
+            mkt_vault_balance: state.mkt_vault_balance,
+            mkt_delta: mkt_delta,
+            prc_vault_actual: prc_actual,
+            prc_vault_balance: state.prc_vault_balance,
+            prc_fees_balance: state.prc_fees_balance,
+            prc_delta: prc_delta,
+            solvent: solvent,
+        })
+    }
+
+    pub fn verify_order_counts<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, TopOfBook<'info>>) -> anchor_lang::Result<OrderCountResult> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        // Count the orderbook entries on each side, including expired orders that have not been removed yet
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let ob = SlabPageAlloc::new(order_data);
+        let bid_orders = map_count(ob, DT::BidOrder, market.max_orders);
+        let ask_orders = map_count(ob, DT::AskOrder, market.max_orders);
+        let consistent = bid_orders == state.active_bid && ask_orders == state.active_ask;
+        if !consistent {
+            msg!("Atellix: Order counts inconsistent - Bids: {} (Tracked: {}) Asks: {} (Tracked: {})",
+                bid_orders.to_string(),
+                state.active_bid.to_string(),
+                ask_orders.to_string(),
+                state.active_ask.to_string(),
+            );
+        }
+        Ok(OrderCountResult {
+            active_bid: state.active_bid,
+            active_ask: state.active_ask,
+            bid_orders: bid_orders,
+            ask_orders: ask_orders,
+            consistent: consistent,
+        })
+    }
+
+    // Withdraw lamports for user vault space, etc.
+    pub fn manager_transfer_sol<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerTransferSol<'info>>,
+        inp_withdraw: bool,
+        inp_all: bool,
+        inp_amount: u64,
+    ) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let admin = &ctx.accounts.admin;
+        let state = &mut ctx.accounts.state;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        if admin.vault_manager != *acc_manager.key {
+            msg!("Not vault manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        let mut market_lamports = state.to_account_info().lamports();
+        let mut manager_lamports = acc_manager.lamports();
+
+        if inp_withdraw {
+            let withdraw_amount: u64;
+            if inp_all {
+                withdraw_amount = state.log_deposit_balance;
+            } else {
+                withdraw_amount = inp_amount;
+            }
+            state.log_deposit_balance = state.log_deposit_balance.checked_sub(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
+            market_lamports = market_lamports.checked_sub(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
+            manager_lamports = manager_lamports.checked_add(withdraw_amount).ok_or(error!(ErrorCode::Overflow))?;
+        } else { // Deposit lamports
+            manager_lamports = manager_lamports.checked_sub(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+            market_lamports = market_lamports.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+            state.log_deposit_balance = state.log_deposit_balance.checked_add(inp_amount).ok_or(error!(ErrorCode::Overflow))?;
+        }
+
+        **state.to_account_info().lamports.borrow_mut() = market_lamports;
+        **acc_manager.lamports.borrow_mut() = manager_lamports;
+ 
+        Ok(())
+    }
+
+    pub fn manager_withdraw_fees<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerWithdrawFees<'info>>) -> anchor_lang::Result<u64> {
+        let market = &ctx.accounts.market;
+        let admin = &ctx.accounts.admin;
+        let state = &mut ctx.accounts.state;
+        let acc_auth = &ctx.accounts.auth_data.to_account_info();
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        if admin.fee_manager != *acc_manager.key {
+            msg!("Not fee manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        has_role(&acc_auth, Role::FeeManager, acc_manager.key)?;
+
+        // Fees can only be sent to a token account owned by the fee receiver
+        let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        let ast_offset: usize = 0;
+        verify_fee_receiver(market, mint_type, ctx.remaining_accounts, ast_offset, &ctx.accounts.manager_prc_token.to_account_info())?;
+
+        let fee_tokens = state.prc_fees_balance;
+        if fee_tokens > 0 {
+            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_vault_balance = state.prc_vault_balance.checked_sub(fee_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_fees_balance = 0;
+
+            let seeds = &[market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, ast_offset, fee_tokens, 
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.manager_prc_token.to_account_info(),  // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+
+            emit!(WithdrawEvent {
+                event_type: 68727559793861179499689993618056023286, // solana/program/aqua-dex/manager_withdraw/fees
+                action_id: state.action_counter,
+                market: ctx.accounts.market.key(),
+                owner: Pubkey::default(),
+                user: ctx.accounts.manager.key(),
+                market_account: Pubkey::default(),
+                pricing_account: ctx.accounts.manager_prc_token.key(),
+                manager: true,
+                market_tokens: 0,
+                pricing_tokens: fee_tokens,
+            });
+        }
+        Ok(fee_tokens)
+    }
+
+    pub fn manager_withdraw_dust<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerWithdrawDust<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let admin = &ctx.accounts.admin;
+        let state = &mut ctx.accounts.state;
+        let acc_auth = &ctx.accounts.auth_data.to_account_info();
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        if admin.fee_manager != *acc_manager.key {
+            msg!("Not fee manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        has_role(&acc_auth, Role::FeeManager, acc_manager.key)?;
+
+        // Dust can only be sent to token accounts owned by the fee receiver
+        let mkt_mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        let prc_mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+        let ast_offset: usize = 0;
+        verify_fee_receiver(market, mkt_mint_type, ctx.remaining_accounts, ast_offset, &ctx.accounts.manager_mkt_token.to_account_info())?;
+        verify_fee_receiver(market, prc_mint_type, ctx.remaining_accounts, ast_offset, &ctx.accounts.manager_prc_token.to_account_info())?;
+
+        let market_tokens = state.mkt_dust_balance;
+        let pricing_tokens = state.prc_dust_balance;
+        if market_tokens == 0 && pricing_tokens == 0 {
+            return Ok(());
+        }
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        let seeds = &[market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        if market_tokens > 0 {
+            state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            state.mkt_dust_balance = 0;
+            perform_signed_transfer(ctx.remaining_accounts, signer, mkt_mint_type, ast_offset, market_tokens,
+                &ctx.accounts.mkt_vault.to_account_info(),          // From
+                &ctx.accounts.manager_mkt_token.to_account_info(),  // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+        if pricing_tokens > 0 {
+            state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_dust_balance = 0;
+            perform_signed_transfer(ctx.remaining_accounts, signer, prc_mint_type, ast_offset, pricing_tokens,
+                &ctx.accounts.prc_vault.to_account_info(),          // From
+                &ctx.accounts.manager_prc_token.to_account_info(),  // To
+                &ctx.accounts.agent.to_account_info(),              // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+            )?;
+        }
+
+        emit!(WithdrawEvent {
+            event_type: 111249186825260653624862571194244465519, // solana/program/aqua-dex/manager_withdraw/dust
+            action_id: state.action_counter,
+            market: ctx.accounts.market.key(),
+            owner: Pubkey::default(),
+            user: ctx.accounts.manager.key(),
+            market_account: ctx.accounts.manager_mkt_token.key(),
+            pricing_account: ctx.accounts.manager_prc_token.key(),
+            manager: true,
+            market_tokens: market_tokens,
+            pricing_tokens: pricing_tokens,
+        });
+        Ok(())
+    }
+
+    pub fn manager_update_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerUpdateMarket<'info>>,
+        inp_active: bool,
+        inp_expire_enable: bool,
+        inp_expire_min: i64,
+        inp_min_quantity: u64,
+        inp_min_notional: u64,
+        inp_tick_decimals: u8,
+        inp_tick_size: u64,
+        inp_lot_size: u64,
+        inp_taker_fee: u32,
+        inp_maker_rebate: u32,
+        inp_log_fee: u64,
+        inp_log_rebate: u64,
+        inp_log_reimburse: u64,
+        inp_max_deviation_bps: u32,
+        inp_eviction_enabled: bool,
+        inp_eviction_max: u32,
+        inp_max_orders_per_owner: u32,
+        inp_referral_fee_share: u32,
+        inp_breaker_bps: u32,
+        inp_breaker_cooldown: i64,
+        inp_dust_threshold: u64,
+        inp_session_open: i64,
+        inp_session_close: i64,
+        inp_min_rest_seconds: i64,
+        inp_expire_slippage_bps: u32,
+        inp_log_fee_per_fill: u64,
+        inp_eviction_min_improvement: u64,
+        inp_allow_self_trade: bool,
+        inp_order_cooldown: i64,
+        inp_fee_waiver_until: i64,
+        inp_dust_policy: u8,
+    ) -> anchor_lang::Result<()> {
+        let admin = &mut ctx.accounts.admin;
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        require!(inp_tick_decimals <= 16, ErrorCode::InvalidParameters);
+        require!(!inp_eviction_enabled || inp_eviction_max > 0, ErrorCode::InvalidParameters);
+        if inp_maker_rebate > inp_taker_fee {
+            msg!("Maker rebate exceeds taker fee");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_referral_fee_share <= 10000000, ErrorCode::InvalidParameters);
+        require!(inp_breaker_cooldown >= 0, ErrorCode::InvalidParameters);
+        require!(inp_dust_threshold <= MAX_DUST_THRESHOLD, ErrorCode::InvalidParameters);
+        require!(inp_session_open >= 0 && inp_session_open < SECONDS_PER_DAY, ErrorCode::InvalidParameters);
+        require!(inp_session_close >= 0 && inp_session_close < SECONDS_PER_DAY, ErrorCode::InvalidParameters);
+        if inp_session_open == inp_session_close && inp_session_open != 0 {
+            msg!("Trading session is empty");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_min_rest_seconds >= 0, ErrorCode::InvalidParameters);
+        require!(inp_expire_slippage_bps <= 10000, ErrorCode::InvalidParameters);
+        require!(inp_order_cooldown >= 0, ErrorCode::InvalidParameters);
+        DustPolicy::try_from(inp_dust_policy).or(Err(error!(ErrorCode::InvalidParameters)))?;
+        if market.fee_tiers.iter().any(|tier| tier.volume_threshold > 0 && inp_maker_rebate > tier.taker_fee) {
+            msg!("Maker rebate exceeds fee tier");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        
+        market.active = inp_active;
+        market.expire_enable = inp_expire_enable;
+        market.expire_min = inp_expire_min;
+        market.min_quantity = inp_min_quantity;
+        market.min_notional = inp_min_notional;
+        market.tick_decimals = inp_tick_decimals;
+        market.tick_size = inp_tick_size;
+        market.lot_size = inp_lot_size;
+        market.taker_fee = inp_taker_fee;
+        market.maker_rebate = inp_maker_rebate;
+        market.log_fee = inp_log_fee;
+        market.log_rebate = inp_log_rebate;
+        market.log_reimburse = inp_log_reimburse;
+        market.max_deviation_bps = inp_max_deviation_bps;
+        market.eviction_enabled = inp_eviction_enabled;
+        market.eviction_max = inp_eviction_max;
+        market.max_orders_per_owner = inp_max_orders_per_owner;
+        market.referral_fee_share = inp_referral_fee_share;
+        market.breaker_bps = inp_breaker_bps;
+        market.breaker_cooldown = inp_breaker_cooldown;
+        market.dust_threshold = inp_dust_threshold;
+        market.session_open = inp_session_open;
+        market.session_close = inp_session_close;
+        market.min_rest_seconds = inp_min_rest_seconds;
+        market.expire_slippage_bps = inp_expire_slippage_bps;
+        market.log_fee_per_fill = inp_log_fee_per_fill;
+        market.eviction_min_improvement = inp_eviction_min_improvement;
+        market.allow_self_trade = inp_allow_self_trade;
+        market.order_cooldown = inp_order_cooldown;
+        market.fee_waiver_until = inp_fee_waiver_until;
+        market.dust_policy = inp_dust_policy;
+
+        admin.fee_manager = ctx.accounts.fee_manager.key();
+        admin.vault_manager = ctx.accounts.vault_manager.key();
+
+        Ok(())
+    }
+
+    pub fn manager_set_fee_receiver<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerSetFeeReceiver<'info>>) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        market.fee_receiver = ctx.accounts.fee_receiver.key();
+        msg!("Atellix: Fee Receiver: {}", market.fee_receiver.to_string());
+
+        Ok(())
+    }
+
+    pub fn manager_set_fee_tiers<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerSetFeeTiers<'info>>,
+        inp_fee_tiers: Vec<FeeTier>,    // Taker fee tiers (up to 4, replaces all existing tiers)
+    ) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        require!(inp_fee_tiers.len() <= MAX_FEE_TIERS, ErrorCode::InvalidParameters);
+        let mut fee_tiers = [FeeTier::default(); MAX_FEE_TIERS];
+        for (i, tier) in inp_fee_tiers.iter().enumerate() {
+            if tier.volume_threshold > 0 && tier.taker_fee < market.maker_rebate {
+                msg!("Maker rebate exceeds fee tier");
+                return Err(ErrorCode::InvalidParameters.into());
+            }
+            fee_tiers[i] = *tier;
+            msg!("Atellix: Fee Tier {} - Volume: {} Taker Fee: {}", i.to_string(), tier.volume_threshold.to_string(), tier.taker_fee.to_string());
+        }
+        market.fee_tiers = fee_tiers;
+
+        Ok(())
+    }
+
+    pub fn manager_set_fee_discount<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerSetFeeDiscount<'info>>,
+        inp_discount_bps: u32,      // Taker fee discount when paying with utility tokens in basis points (0 to disable)
+        inp_discount_rate: u64,     // Utility tokens charged per whole pricing token of fee discount
+    ) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_discount_mint = &ctx.accounts.discount_mint.to_account_info();
+        let acc_discount_vault = &ctx.accounts.discount_vault.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        require!(inp_discount_bps <= 10000, ErrorCode::InvalidParameters);
+        if inp_discount_bps > 0 {
+            require!(inp_discount_rate > 0, ErrorCode::InvalidParameters);
+            if *acc_discount_vault.owner != token::ID {
+                msg!("Invalid discount vault");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let vault_acct = load_struct::<SPL_TokenAccount>(acc_discount_vault)?;
+            verify_matching_accounts(&vault_acct.mint, acc_discount_mint.key, Some(String::from("Invalid discount vault mint")))?;
+            market.discount_mint = *acc_discount_mint.key;
+            market.discount_vault = *acc_discount_vault.key;
+        } else {
+            market.discount_mint = Pubkey::default();
+            market.discount_vault = Pubkey::default();
+        }
+        market.discount_bps = inp_discount_bps;
+        market.discount_rate = inp_discount_rate;
+        msg!("Atellix: Fee Discount - Mint: {} Bps: {} Rate: {}", market.discount_mint.to_string(), inp_discount_bps.to_string(), inp_discount_rate.to_string());
+
+        Ok(())
+    }
+
+    pub fn create_user_volume<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CreateUserVolume<'info>>) -> anchor_lang::Result<()> {
+        let volume = &mut ctx.accounts.volume;
+        if volume.owner == Pubkey::default() {
+            volume.market = ctx.accounts.market.key();
+            volume.owner = ctx.accounts.owner.key();
+            volume.volume = 0;
+        }
+
+        Ok(())
+    }
+
+    pub fn manager_reset_breaker<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerResetBreaker<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &ctx.accounts.state.key(), Some(String::from("Invalid market state")))?;
+        let state = &mut ctx.accounts.state;
+        state.breaker_tripped_ts = 0;
+        msg!("Atellix: Circuit breaker reset");
+
+        Ok(())
+    }
+
+    pub fn propose_manager<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ProposeManager<'info>>) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_new_manager = &ctx.accounts.new_manager.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+
+        // The new manager must accept before taking over the market (the default pubkey cancels a pending proposal)
+        market.pending_manager = *acc_new_manager.key;
+        msg!("Atellix: Proposed Manager: {}", acc_new_manager.key.to_string());
+
+        emit!(ManagerEvent {
+            event_type: 2843146032745231125094204648398737666, // solana/program/aqua-dex/propose_manager
+            market: market.key(),
+            manager: market.manager,
+            pending_manager: market.pending_manager,
+            previous_manager: Pubkey::default(),
+        });
+
+        Ok(())
+    }
+
+    pub fn accept_manager<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, AcceptManager<'info>>) -> anchor_lang::Result<()> {
+        let market = &mut ctx.accounts.market;
+        let acc_new_manager = &ctx.accounts.new_manager.to_account_info();
+
+        if market.pending_manager == Pubkey::default() || market.pending_manager != *acc_new_manager.key {
+            msg!("Not pending manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+
+        let prev_manager = market.manager;
+        market.manager = market.pending_manager;
+        market.pending_manager = Pubkey::default();
+        msg!("Atellix: New Manager: {}", acc_new_manager.key.to_string());
+
+        emit!(ManagerEvent {
+            event_type: 113520435212687827918351493854667470388, // solana/program/aqua-dex/accept_manager
+            market: market.key(),
+            manager: market.manager,
+            pending_manager: market.pending_manager,
+            previous_manager: prev_manager,
+        });
+
+        Ok(())
+    }
+
+    // Move tokens from the settlement log to a user's individual vault (vault manager only)
+    // This is optional market "housekeeping". If a market manager moves balances from the settlement logs to user vaults before the
+    // 1st settlement log file fills up then there will never be a need to rollover settlement logs and possibly require repeating trade transactions.
+    // If markets opt out of this housekeeping then the settlement log will grow if more and more users leave settled trades in the logs.
+    // Traders will need to keep their "Market Data" account up-to-date as settlement log rollovers occur unless managers do housekeeping.
+    // Resending trades is automated in the API frontend but requires possibly resending transactions. (Based on "RetrySettlementAccount" error.)
+    // Keeping open slots in the 1st settlement log enables all trades to be processed immediately without any need of a possible data refresh.
+    pub fn vault_deposit<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VaultDeposit<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let admin = &ctx.accounts.admin;
+        let state = &mut ctx.accounts.state;
+        let vault = &mut ctx.accounts.vault;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_settle = &ctx.accounts.settle.to_account_info();
+        let acc_settle_prev = &ctx.accounts.settle_prev.to_account_info();
+        let acc_settle_next = &ctx.accounts.settle_next.to_account_info();
+
+        // Verify
+        if admin.vault_manager != *acc_manager.key {
+            msg!("Not vault manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+
+        if !vault.initialized { // Only initialize once
+            vault.initialized = true;
+            vault.market = ctx.accounts.market.key();
+            vault.owner = ctx.accounts.owner.key();
+            vault.mkt_tokens = 0;
+            vault.prc_tokens = 0;
+        }
+
+        let mut market_tokens: u64 = 0;
+        let mut pricing_tokens: u64 = 0;
+        let owner_id: u128 = CritMap::bytes_hash(acc_owner.key.as_ref());
+        let log_data: &mut[u8] = &mut acc_settle.try_borrow_mut_data()?;
+        let (header, page_table) = mut_array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+        let settle_header: &mut [AccountsHeader] = cast_slice_mut(header);
+        let close_log: bool = settle_header[0].items == 1 && settle_header[0].prev != Pubkey::default() && settle_header[0].next != Pubkey::default();
+        verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+        let sl = SlabPageAlloc::new(page_table);
+        let max_accounts = map_len(sl, DT::Account);
+        let has_item = map_get(sl, DT::Account, max_accounts, owner_id);
+        if has_item.is_some() {
+            let log_node = has_item.unwrap();
+            let log_entry = sl.index::<AccountEntry>(SettleDT::Account as u16, log_node.slot() as usize);
+            if log_entry.mkt_token_balance() > 0 {
+                market_tokens = log_entry.mkt_token_balance();
+                state.mkt_log_balance = state.mkt_log_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                vault.mkt_tokens = vault.mkt_tokens.checked_add(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            if log_entry.prc_token_balance() > 0 {
+                pricing_tokens = log_entry.prc_token_balance();
+                state.prc_log_balance = state.prc_log_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                vault.prc_tokens = vault.prc_tokens.checked_add(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            // Remove log entry
+            settle_header[0].items = settle_header[0].items.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+            map_remove(sl, DT::Account, max_accounts, log_node.key())?;
+            AccountEntry::free_index(sl, DT::Account, log_node.slot())?;
+
+            // Close log if necessary
+            if close_log {
+                let log_lamports = log_close(state, acc_settle, acc_settle_prev, acc_settle_next)?;
+                let mut market_lamports = state.to_account_info().lamports();
+                market_lamports = market_lamports.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+                **state.to_account_info().lamports.borrow_mut() = market_lamports;
+                state.log_deposit_balance = state.log_deposit_balance.checked_add(log_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            }
+        } else {
+            msg!("Account not found");
+            return Err(ErrorCode::AccountNotFound.into());
+        }
+
+        if market_tokens > 0 || pricing_tokens > 0 {
+            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+            emit!(VaultDepositEvent {
+                event_type: 116949236330450057903776475751429156227, // solana/program/aqua-dex/user_vault/deposit
+                action_id: state.action_counter,
+                market: market.key(),
+                owner: *acc_owner.key,
+                vault: vault.key(),
+                market_tokens: market_tokens,
+                market_balance: vault.mkt_tokens,
+                pricing_tokens: pricing_tokens,
+                pricing_balance: vault.prc_tokens,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Users can fund their own vaults with tokens
+    pub fn vault_fund<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VaultFund<'info>>,
+        inp_mkt_amount: u64,        // Market tokens to deposit
+        inp_prc_amount: u64,        // Pricing tokens to deposit
+    ) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let vault = &mut ctx.accounts.vault;
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        // Verify
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        if !market.active {
+            msg!("Market closed");
+            return Err(ErrorCode::MarketClosed.into());
+        }
+        require!(inp_mkt_amount > 0 || inp_prc_amount > 0, ErrorCode::InvalidParameters);
+
+        if !vault.initialized { // Only initialize once
+            vault.initialized = true;
+            vault.market = ctx.accounts.market.key();
+            vault.owner = ctx.accounts.owner.key();
+            vault.mkt_tokens = 0;
+            vault.prc_tokens = 0;
+        }
+
+        if inp_mkt_amount > 0 {
+            let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, inp_mkt_amount, false,
+                &ctx.accounts.user_mkt_token.to_account_info(),  // From
+                &ctx.accounts.mkt_vault.to_account_info(),       // To
+                &ctx.accounts.owner.to_account_info(),           // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+            state.mkt_vault_balance = state.mkt_vault_balance.checked_add(inp_mkt_amount).ok_or(error!(ErrorCode::Overflow))?;
+            state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_add(inp_mkt_amount).ok_or(error!(ErrorCode::Overflow))?;
+            vault.mkt_tokens = vault.mkt_tokens.checked_add(inp_mkt_amount).ok_or(error!(ErrorCode::Overflow))?;
+        }
+        if inp_prc_amount > 0 {
+            let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+            perform_transfer(ctx.remaining_accounts, mint_type, 0, inp_prc_amount, false,
+                &ctx.accounts.user_prc_token.to_account_info(),  // From
+                &ctx.accounts.prc_vault.to_account_info(),       // To
+                &ctx.accounts.owner.to_account_info(),           // Auth
+                &ctx.accounts.spl_token_prog.to_account_info(),  // SPL Token Program
+            )?;
+            state.prc_vault_balance = state.prc_vault_balance.checked_add(inp_prc_amount).ok_or(error!(ErrorCode::Overflow))?;
+            state.prc_user_vault_balance = state.prc_user_vault_balance.checked_add(inp_prc_amount).ok_or(error!(ErrorCode::Overflow))?;
+            vault.prc_tokens = vault.prc_tokens.checked_add(inp_prc_amount).ok_or(error!(ErrorCode::Overflow))?;
+        }
+
+        state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+        emit!(VaultDepositEvent {
+            event_type: 198234967045663261140771418843225894086, // solana/program/aqua-dex/user_vault/fund
+            action_id: state.action_counter,
+            market: market.key(),
+            owner: *acc_owner.key,
+            vault: vault.key(),
+            market_tokens: inp_mkt_amount,
+            market_balance: vault.mkt_tokens,
+            pricing_tokens: inp_prc_amount,
+            pricing_balance: vault.prc_tokens,
+        });
+
+        Ok(())
+    }
+
+    // Users can withdraw tokens from their own vaults
+    pub fn vault_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, VaultWithdraw<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let vault = &mut ctx.accounts.vault;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        // Verify 
+        if vault.owner != *acc_owner.key {
+            msg!("Not owner");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
+            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+            let mut market_tokens: u64 = 0;
+            let mut pricing_tokens: u64 = 0;
+            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            if vault.mkt_tokens > 0 {
+                market_tokens = vault.mkt_tokens;
+                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, market_tokens,
+                    &ctx.accounts.mkt_vault.to_account_info(),          // From
+                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                vault.mkt_tokens = 0;
+                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            if vault.prc_tokens > 0 {
+                pricing_tokens = vault.prc_tokens;
+                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, pricing_tokens,
+                    &ctx.accounts.prc_vault.to_account_info(),          // From
+                    &ctx.accounts.user_prc_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                vault.prc_tokens = 0;
+                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+
+            // Close the vault and transfer lamports to the market
+            let vault_lamports = vault.to_account_info().lamports();
+            **vault.to_account_info().lamports.borrow_mut() = 0;
+            let mut market_lamports = state.to_account_info().lamports();
+            market_lamports = market_lamports.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            **state.to_account_info().lamports.borrow_mut() = market_lamports;
+            state.log_deposit_balance = state.log_deposit_balance.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+
+            emit!(VaultWithdrawEvent {
+                event_type: 222531087088795477156040686028020078326, // solana/program/aqua-dex/user_vault/withdraw
+                action_id: state.action_counter,
+                market: market.key(),
+                owner: *acc_owner.key,
+                user: *acc_owner.key,
+                vault: vault.key(),
+                market_account: ctx.accounts.user_mkt_token.key(),
+                pricing_account: ctx.accounts.user_prc_token.key(),
+                manager: false,
+                market_tokens: market_tokens,
+                pricing_tokens: pricing_tokens,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Manager withdrawal from user vaults
+    pub fn manager_vault_withdraw<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, ManagerVaultWithdraw<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &mut ctx.accounts.state;
+        let vault = &mut ctx.accounts.vault;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        // Verify 
+        if !market.manager_actions {
+            msg!("Manager actions disabled");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.manager != *acc_manager.key {
+            msg!("Not owner");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
+            state.action_counter = state.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+
+            let mut market_tokens: u64 = 0;
+            let mut pricing_tokens: u64 = 0;
+            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            if vault.mkt_tokens > 0 {
+                market_tokens = vault.mkt_tokens;
+                let mint_type = MintType::try_from(market.mkt_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, market_tokens,
+                    &ctx.accounts.mkt_vault.to_account_info(),          // From
+                    &ctx.accounts.user_mkt_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                vault.mkt_tokens = 0;
+                state.mkt_vault_balance = state.mkt_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.mkt_user_vault_balance = state.mkt_user_vault_balance.checked_sub(market_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+            if vault.prc_tokens > 0 {
+                pricing_tokens = vault.prc_tokens;
+                let mint_type = MintType::try_from(market.prc_mint_type).map_err(|_| ErrorCode::InvalidParameters)?;
+                perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, pricing_tokens,
+                    &ctx.accounts.prc_vault.to_account_info(),          // From
+                    &ctx.accounts.user_prc_token.to_account_info(),     // To
+                    &ctx.accounts.agent.to_account_info(),              // Auth
+                    &ctx.accounts.spl_token_prog.to_account_info(),     // SPL Token Program
+                )?;
+                vault.prc_tokens = 0;
+                state.prc_vault_balance = state.prc_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+                state.prc_user_vault_balance = state.prc_user_vault_balance.checked_sub(pricing_tokens).ok_or(error!(ErrorCode::Overflow))?;
+            }
+
+            // Close the vault and transfer lamports to the market
+            let vault_lamports = vault.to_account_info().lamports();
+            **vault.to_account_info().lamports.borrow_mut() = 0;
+            let mut market_lamports = state.to_account_info().lamports();
+            market_lamports = market_lamports.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+            **state.to_account_info().lamports.borrow_mut() = market_lamports;
+            state.log_deposit_balance = state.log_deposit_balance.checked_add(vault_lamports).ok_or(error!(ErrorCode::Overflow))?;
+
+            emit!(VaultWithdrawEvent {
+                event_type: 155648231829618734246883800498177854177, // solana/program/aqua-dex/user_vault/manager_withdraw
+                action_id: state.action_counter,
+                market: market.key(),
+                owner: *acc_owner.key,
+                user: *acc_manager.key,
+                vault: vault.key(),
+                market_account: ctx.accounts.user_mkt_token.key(),
+                pricing_account: ctx.accounts.user_prc_token.key(),
+                manager: true,
+                market_tokens: market_tokens,
+                pricing_tokens: pricing_tokens,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn drain_orderbook<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, DrainOrderbook<'info>>,
+        inp_limit: u32,             // Max number of orders to cancel (bids first, then asks)
+    ) -> anchor_lang::Result<DrainResult> {
+        let market = &ctx.accounts.market;
+        let market_state = &ctx.accounts.state;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_settle1 = &ctx.accounts.settle_a.to_account_info();
+        let acc_settle2 = &ctx.accounts.settle_b.to_account_info();
+
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.active {
+            msg!("Market must be inactive to drain the orderbook");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        require!(inp_limit > 0, ErrorCode::InvalidParameters);
+        verify_matching_accounts(&market.state, &market_state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+
+        let s1 = verify_matching_accounts(&market_state.settle_a, &acc_settle1.key, Some(String::from("Settlement log 1")));
+        let s2 = verify_matching_accounts(&market_state.settle_b, &acc_settle2.key, Some(String::from("Settlement log 2")));
+        if s1.is_err() || s2.is_err() {
+            msg!("Please update market data and retry");
+            return Err(ErrorCode::RetrySettlementAccount.into());
+        }
+
+        let state_upd = &mut ctx.accounts.state;
+        let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+        let sl = SlabPageAlloc::new(order_data);
+        let mut bids_drained: u32 = 0;
+        let mut asks_drained: u32 = 0;
+        let mut more: bool = false;
+        for side in [Side::Bid, Side::Ask] {
+            let (order_type, order_dt) = match side {
+                Side::Bid => (DT::BidOrder, OrderDT::BidOrder),
+                Side::Ask => (DT::AskOrder, OrderDT::AskOrder),
+            };
+            loop {
+                let node_res = map_min(sl, order_type, market.max_orders);
+                if node_res.is_none() {
+                    break;
+                }
+                let leaf = node_res.unwrap();
+                if bids_drained + asks_drained == inp_limit || !settlement_available(state_upd, acc_settle1, acc_settle2, &leaf.owner())? {
+                    more = true;
+                    break;
+                }
+                state_upd.action_counter = state_upd.action_counter.checked_add(1).ok_or(error!(ErrorCode::Overflow))?;
+                let order = *sl.index::<Order>(order_dt as u16, leaf.slot() as usize);
+                let order_price = Order::price(leaf.key());
+                let order_qty = order.total_amount();
+                match side {
+                    Side::Bid => {
+                        let total = scale_price(order_qty, order_price, decimal_factor(market.mkt_decimals))?;
+                        log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &leaf.owner(), false, total)?;
+                        state_upd.active_bid = state_upd.active_bid.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        bids_drained = bids_drained + 1;
+                    },
+                    Side::Ask => {
+                        log_settlement(&market.key(), state_upd, acc_settle1, acc_settle2, &leaf.owner(), true, order_qty)?;
+                        state_upd.active_ask = state_upd.active_ask.checked_sub(1).ok_or(error!(ErrorCode::Overflow))?;
+                        asks_drained = asks_drained + 1;
+                    },
+                };
+                map_remove(sl, order_type, market.max_orders, leaf.key())?;
+                Order::free_index(sl, order_type, leaf.slot())?;
+                msg!("Atellix: Drained Order[{}] - Owner: {} {} @ {}",
+                    leaf.slot().to_string(),
+                    leaf.owner().to_string(),
+                    order_qty.to_string(),
+                    order_price.to_string(),
+                );
+            }
+            if more {
+                break;
+            }
+        }
+        msg!("Atellix: Drained {} bids and {} asks", bids_drained.to_string(), asks_drained.to_string());
+
+        emit!(DrainEvent {
+            event_type: 309217818123143390417740541779023111055, // solana/program/aqua-dex/drain_orderbook
+            action_id: state_upd.action_counter,
+            market: market.key(),
+            bids_drained: bids_drained,
+            asks_drained: asks_drained,
+            more: more,
+        });
+
+        Ok(DrainResult {
+            bids_drained: bids_drained,
+            asks_drained: asks_drained,
+            more: more,
+        })
+    }
+
+    pub fn migrate_pricing_vault<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, MigratePricingVault<'info>>) -> anchor_lang::Result<u64> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_stops = &ctx.accounts.stops.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+        let acc_new_vault = &ctx.accounts.new_prc_vault.to_account_info();
+        let acc_spl = &ctx.accounts.spl_token_prog.to_account_info();
+
+        // Verify
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.active {
+            msg!("Market must be inactive");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        if market.prc_mint_type != MintType::SPLToken as u8 {
+            msg!("Pricing token vault migration requires an SPL token");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+
+        // No open orders may hold pricing tokens
+        if state.active_bid != 0 || state.prc_order_balance != 0 {
+            msg!("Market has open bids");
+            return Err(ErrorCode::MarketNotEmpty.into());
+        }
+        if market.stops != Pubkey::default() {
+            verify_matching_accounts(&market.stops, &acc_stops.key, Some(String::from("Invalid stop book")))?;
+            let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+            let sb = SlabPageAlloc::new(stop_data);
+            if map_min(sb, DT::BidStop, market.max_stops).is_some() {
+                msg!("Market has open stop bids");
+                return Err(ErrorCode::MarketNotEmpty.into());
+            }
+        }
+
+        // Verify the new vault is a pricing token account held by the market agent (the agent's associated token account is the current vault)
+        if *acc_new_vault.key == market.prc_vault {
+            msg!("New vault matches the current vault");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        if *acc_new_vault.owner != Token::id() {
+            msg!("New pricing token vault not initialized");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+        let new_vault = load_struct::<SPL_TokenAccount>(acc_new_vault)?;
+        if new_vault.mint != market.prc_mint || new_vault.owner != *acc_agent.key || new_vault.delegate.is_some() ||
+            new_vault.close_authority.is_some() || new_vault.is_frozen() {
+            msg!("Invalid new pricing token vault");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+
+        // Move the entire vault balance (user, log, fee and dust balances all remain owed by the market)
+        let amount: u64 = vault_token_balance(acc_prc_vault, market.prc_mint_type)?;
+        if amount > 0 {
+            let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+            let signer = &[&seeds[..]];
+            perform_signed_transfer(ctx.remaining_accounts, signer, MintType::SPLToken, 0, amount,
+                acc_prc_vault,                                      // From
+                acc_new_vault,                                      // To
+                acc_agent,                                          // Auth
+                acc_spl,                                            // SPL Token Program
+            )?;
+        }
+        let new_balance: u64 = vault_token_balance(acc_new_vault, market.prc_mint_type)?;
+        let expected_balance: u64 = new_vault.amount.checked_add(amount).ok_or(error!(ErrorCode::Overflow))?;
+        if new_balance != expected_balance {
+            msg!("Pricing token vault transfer mismatch");
+            return Err(ErrorCode::ExternalError.into());
+        }
+
+        let old_vault: Pubkey = market.prc_vault;
+        let market_upd = &mut ctx.accounts.market;
+        market_upd.prc_vault = *acc_new_vault.key;
+        msg!("Atellix: Migrate Pricing Vault: {} -> {} Amount: {}", old_vault.to_string(), acc_new_vault.key.to_string(), amount.to_string());
+
+        emit!(VaultMigrateEvent {
+            event_type: 330454667059675911591527921489410954133, // solana/program/aqua-dex/migrate_pricing_vault
+            market: market_upd.key(),
+            old_vault: old_vault,
+            new_vault: *acc_new_vault.key,
+            amount: amount,
+        });
+
+        Ok(amount)
+    }
+
+    pub fn close_market<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CloseMarket<'info>>) -> anchor_lang::Result<()> {
+        let market = &ctx.accounts.market;
+        let state = &ctx.accounts.state;
+        let acc_agent = &ctx.accounts.agent.to_account_info();
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+        let acc_fee_receiver = &ctx.accounts.fee_receiver.to_account_info();
+        let acc_orders = &ctx.accounts.orders.to_account_info();
+        let acc_stops = &ctx.accounts.stops.to_account_info();
+        let acc_trade_log = &ctx.accounts.trade_log.to_account_info();
+        let acc_mkt_vault = &ctx.accounts.mkt_vault.to_account_info();
+        let acc_prc_vault = &ctx.accounts.prc_vault.to_account_info();
+
+        // Verify
+        if market.manager != *acc_manager.key {
+            msg!("Not manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if market.active {
+            msg!("Market must be inactive");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+        verify_matching_accounts(&market.state, &state.key(), Some(String::from("Invalid market state")))?;
+        verify_matching_accounts(&market.agent, &acc_agent.key, Some(String::from("Invalid market agent")))?;
+        verify_matching_accounts(&market.fee_receiver, &acc_fee_receiver.key, Some(String::from("Invalid fee receiver")))?;
+        verify_matching_accounts(&market.orders, &acc_orders.key, Some(String::from("Invalid orderbook")))?;
+        verify_orderbook_version(market)?;
+        verify_matching_accounts(&market.trade_log, &acc_trade_log.key, Some(String::from("Invalid trade log")))?;
+        verify_matching_accounts(&market.mkt_vault, &acc_mkt_vault.key, Some(String::from("Invalid market token vault")))?;
+        verify_matching_accounts(&market.prc_vault, &acc_prc_vault.key, Some(String::from("Invalid pricing token vault")))?;
+        verify_vault_mint(acc_mkt_vault, &market.mkt_mint, market.mkt_mint_type)?;
+        verify_vault_mint(acc_prc_vault, &market.prc_mint, market.prc_mint_type)?;
+        let use_stops: bool = market.stops != Pubkey::default();
+        if use_stops {
+            verify_matching_accounts(&market.stops, &acc_stops.key, Some(String::from("Invalid stop book")))?;
+        }
+
+        // No user tokens may remain anywhere in the market
+        if state.active_bid != 0 || state.active_ask != 0 ||
+            state.mkt_order_balance != 0 || state.mkt_user_vault_balance != 0 || state.mkt_log_balance != 0 ||
+            state.prc_order_balance != 0 || state.prc_user_vault_balance != 0 || state.prc_log_balance != 0 ||
+            state.prc_fees_balance != 0 {
+            msg!("Market has open orders or token balances");
+            return Err(ErrorCode::MarketNotEmpty.into());
+        }
+        {
+            let order_data: &mut[u8] = &mut acc_orders.try_borrow_mut_data()?;
+            let ob = SlabPageAlloc::new(order_data);
+            if map_min(ob, DT::BidOrder, market.max_orders).is_some() || map_min(ob, DT::AskOrder, market.max_orders).is_some() {
+                msg!("Orderbook not empty");
+                return Err(ErrorCode::MarketNotEmpty.into());
+            }
+        }
+        if use_stops {
+            let stop_data: &mut[u8] = &mut acc_stops.try_borrow_mut_data()?;
+            let sb = SlabPageAlloc::new(stop_data);
+            if map_min(sb, DT::BidStop, market.max_stops).is_some() || map_min(sb, DT::AskStop, market.max_stops).is_some() {
+                msg!("Stop book not empty");
+                return Err(ErrorCode::MarketNotEmpty.into());
+            }
+        }
+
+        // Walk the settlement log chain (passed in order as remaining accounts starting with "settle_0")
+        let mut expected: Pubkey = market.settle_0;
+        let mut log_count: usize = 0;
+        for acc_settle in ctx.remaining_accounts.iter() {
+            verify_matching_accounts(&expected, &acc_settle.key, Some(String::from("Settlement log out of order")))?;
+            if *acc_settle.owner != ID {
+                msg!("Invalid settlement log");
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            let log_data: &[u8] = &acc_settle.try_borrow_data()?;
+            let (header, _page_table) = array_refs![log_data, size_of::<AccountsHeader>(); .. ;];
+            let settle_header: &[AccountsHeader] = cast_slice(header);
+            verify_matching_accounts(&settle_header[0].market, &market.key(), Some(String::from("Invalid market")))?;
+            if settle_header[0].items != 0 {
+                msg!("Settlement log not empty: {}", acc_settle.key.to_string());
+                return Err(ErrorCode::SettlementLogNotEmpty.into());
+            }
+            expected = settle_header[0].next;
+            log_count = log_count + 1;
+        }
+        if log_count == 0 || expected != Pubkey::default() {
+            msg!("Incomplete settlement log chain");
+            return Err(ErrorCode::InvalidAccount.into());
+        }
+
+        // Send residual tokens (dust and unaccounted deposits) to the manager and close the vaults
+        let seeds = &[ctx.accounts.market.to_account_info().key.as_ref(), &[market.agent_nonce]];
+        let signer = &[&seeds[..]];
+        let mut residuals: [u64; 2] = [0, 0];
+        let acc_spl = &ctx.accounts.spl_token_prog.to_account_info();
+        let vaults = [
+            (market.mkt_mint_type, acc_mkt_vault, ctx.accounts.manager_mkt_token.to_account_info()),
+            (market.prc_mint_type, acc_prc_vault, ctx.accounts.manager_prc_token.to_account_info()),
+        ];
+        for (i, (mint_type_id, acc_vault, acc_dest)) in vaults.iter().enumerate() {
+            let mint_type = MintType::try_from(*mint_type_id).map_err(|_| ErrorCode::InvalidParameters)?;
+            match mint_type {
+                MintType::SPLToken => {
+                    let residual = load_struct::<SPL_TokenAccount>(acc_vault)?.amount;
+                    residuals[i] = residual;
+                    if residual > 0 {
+                        msg!("Atellix: Residual Tokens: {} Vault: {}", residual.to_string(), acc_vault.key.to_string());
+                        perform_signed_transfer(ctx.remaining_accounts, signer, mint_type, 0, residual,
+                            acc_vault,                                          // From
+                            acc_dest,                                           // To
+                            acc_agent,                                          // Auth
+                            acc_spl,                                            // SPL Token Program
+                        )?;
+                    }
+                    let in_accounts = SPL_CloseAccount {
+                        account: (*acc_vault).clone(),
+                        destination: acc_fee_receiver.clone(),
+                        authority: acc_agent.clone(),
+                    };
+                    let in_ctx = CpiContext::new_with_signer(acc_spl.clone(), in_accounts, signer);
+                    token::close_account(in_ctx)?;
+                },
+                MintType::AtxSecurityToken => {
+                    // Security token vaults are left open and must already be empty
+                    if load_struct::<AST_TokenAccount>(acc_vault)?.amount > 0 {
+                        msg!("Security token vault not empty: {}", acc_vault.key.to_string());
+                        return Err(ErrorCode::VaultNotEmpty.into());
+                    }
+                },
+            }
+        }
+
+        // Close program data accounts (market, state and admin are closed by Anchor)
+        for acc_settle in ctx.remaining_accounts.iter() {
+            close_data_account(acc_settle, acc_fee_receiver)?;
+        }
+        close_data_account(acc_orders, acc_fee_receiver)?;
+        close_data_account(acc_trade_log, acc_fee_receiver)?;
+        if use_stops {
+            close_data_account(acc_stops, acc_fee_receiver)?;
+        }
+
+        msg!("Atellix: Close Market: {}", ctx.accounts.market.key().to_string());
+        emit!(CloseMarketEvent {
+            event_type: 111572901452289344705298862399853818559, // solana/program/aqua-dex/close_market
+            market: ctx.accounts.market.key(),
+            manager: *acc_manager.key,
+            fee_receiver: *acc_fee_receiver.key,
+            mkt_dust_balance: state.mkt_dust_balance,
+            prc_dust_balance: state.prc_dust_balance,
+            mkt_residual: residuals[0],
+            prc_residual: residuals[1],
+        });
+        Ok(())
+    }
+
+    pub fn close_vault(ctx: Context<CloseVault>) -> anchor_lang::Result<()> {
+        let admin = &ctx.accounts.admin;
+        let vault = &mut ctx.accounts.vault;
+        let acc_manager = &ctx.accounts.manager.to_account_info();
+
+        if admin.vault_manager != *acc_manager.key {
+            msg!("Not vault manager");
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
+            msg!("Vault not empty");
+            return Err(ErrorCode::VaultNotEmpty.into());
+        }
+
+        Ok(())
+    }
+
+    pub fn close_user_accounts<'a, 'b, 'c, 'info>(ctx: Context<'a, 'b, 'c, 'info, CloseUserAccounts<'info>>) -> anchor_lang::Result<u64> {
+        let acc_market = &ctx.accounts.market.to_account_info();
+        let acc_owner = &ctx.accounts.owner.to_account_info();
+
+        // Only after the market is closed by "close_market" (the user vault of an open market is closed by the vault manager with "close_vault")
+        if *acc_market.owner == ID && acc_market.lamports() > 0 {
+            msg!("Market not closed");
+            return Err(ErrorCode::InvalidParameters.into());
+        }
+
+        // Each account is the owner's PDA for the market with the given seed suffix (pass the owner account to skip one)
+        let market_key = acc_market.key;
+        let user_accounts: [(&AccountInfo, &[u8]); 4] = [
+            (&ctx.accounts.user_vault, b""),
+            (&ctx.accounts.user_volume, b"volume"),
+            (&ctx.accounts.user_cooldown, b"cooldown"),
+            (&ctx.accounts.user_client_orders, b"client_orders"),
+        ];
+        let mut total_lamports: u64 = 0;
+        for (acc_user, suffix) in user_accounts.iter() {
+            if *acc_user.key == *acc_owner.key {
+                continue;
+            }
+            let (user_key, _bump) = if suffix.is_empty() {
+                Pubkey::find_program_address(&[market_key.as_ref(), acc_owner.key.as_ref()], &ID)
+            } else {
+                Pubkey::find_program_address(&[market_key.as_ref(), acc_owner.key.as_ref(), suffix], &ID)
+            };
+            verify_matching_accounts(&user_key, acc_user.key, Some(String::from("Invalid user account")))?;
+            if *acc_user.owner != ID {
+                msg!("User account not found: {}", acc_user.key.to_string());
+                return Err(ErrorCode::InvalidAccount.into());
+            }
+            if suffix.is_empty() {
+                // All user vault balances were withdrawn before the market was closed
+                let vault = load_struct::<UserVault>(acc_user)?;
+                if vault.mkt_tokens > 0 || vault.prc_tokens > 0 {
+                    msg!("Vault not empty");
+                    return Err(ErrorCode::VaultNotEmpty.into());
+                }
+            }
+            let lamports = close_data_account(acc_user, acc_owner)?;
+            total_lamports = total_lamports.checked_add(lamports).ok_or(error!(ErrorCode::Overflow))?;
+            msg!("Atellix: Closed User Account: {} Lamports: {}", acc_user.key.to_string(), lamports.to_string());
+        }
+        Ok(total_lamports)
+    }
+
+    pub fn close_trade_result(_ctx: Context<CloseTradeResult>) -> anchor_lang::Result<()> {
+        Ok(())
+    }
+
+    pub fn close_withdraw_result(_ctx: Context<CloseWithdrawResult>) -> anchor_lang::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info> {
+    #[account(init, seeds = [program_id.as_ref()], bump, payer = program_admin, space = 40)]
+    pub root_data: Account<'info, RootData>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&auth_data))]
+    pub auth_data: UncheckedAccount<'info>,
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseAccount<'info> {
+    /// CHECK: ok
+    #[account(mut)]
+    pub data_account: UncheckedAccount<'info>,
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+}
+
+// Remove before deployment
+/*#[derive(Accounts)]
+pub struct Deactivate<'info> {
+    #[account(mut, seeds = [program_id.as_ref()], bump, close = program_admin)]
+    pub root_data: Account<'info, RootData>,
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+}*/
+
+#[derive(Accounts)]
+pub struct SetGlobalConfig<'info> {
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+    #[account(init_if_needed, seeds = [program_id.as_ref(), b"global"], bump, payer = program_admin, space = 41)]
+    pub global_config: Account<'info, GlobalConfig>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetGlobalPause<'info> {
+    #[account(mut, seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: Account<'info, GlobalConfig>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMetadata<'info> {
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+    #[account(init_if_needed, seeds = [program_id.as_ref(), b"metadata"], bump, payer = program_admin, space = 584)]
+    pub program_info: Account<'info, ProgramMetadata>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(_inp_root_nonce: u8)]
+pub struct UpdateRBAC<'info> {
+    #[account(seeds = [program_id.as_ref()], bump = _inp_root_nonce)]
+    pub root_data: Account<'info, RootData>,
+    /// CHECK: ok
+    #[account(mut, constraint = root_data.root_authority == auth_data.key())]
+    pub auth_data: UncheckedAccount<'info>,
+    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
+    pub program: Program<'info, AquaDex>,
+    pub program_data: Account<'info, ProgramData>,
+    #[account(mut)]
+    pub program_admin: Signer<'info>,
+    /// CHECK: ok
+    pub rbac_user: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(inp_agent_nonce: u8)]
+pub struct CreateMarket<'info> {
+    /// CHECK: ok
+    #[account(zero)]
+    pub market: AccountInfo<'info>, 
+    /// CHECK: ok
+    #[account(zero)]
+    pub state: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(init, seeds = [market.key().as_ref(), b"admin"], bump, payer = manager, space = 72)]
+    pub admin: Account<'info, MarketAdmin>,
+    /// CHECK: ok
+    #[account(seeds = [market.key().as_ref()], bump = inp_agent_nonce)]
+    pub agent: AccountInfo<'info>,
+    #[account(mut)]
+    pub manager: Signer<'info>,
+    /// CHECK: ok
+    pub fee_manager: AccountInfo<'info>,
+    /// CHECK: ok
+    pub vault_manager: AccountInfo<'info>,
+    /// CHECK: ok
+    pub fee_receiver: AccountInfo<'info>,   // Owner of the token account that receives commission fees
+    /// CHECK: ok
+    pub mkt_mint: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    pub prc_mint: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&trade_log))]
+    pub trade_log: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&orders))]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&settle_a))]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&settle_b))]
+    pub settle_b: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = associated_token::ID)]
+    /// CHECK: ok
+    pub asc_token_prog: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = sysvar::rent::ID)]
+    pub system_rent: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct OrderContext<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub user: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>, // Deposit market tokens for "Ask" orders
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>, // Withdraw pricing tokens if the order is filled or partially filled
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub trade_log: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub fills: AccountInfo<'info>,          // Fill details result (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_vault: AccountInfo<'info>,     // User vault for limit orders with "use_vault" (otherwise any account)
+    /// CHECK: ok
+    pub referrer: AccountInfo<'info>,       // Receives a share of taker fees (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_volume: AccountInfo<'info>,    // User volume for taker fee tiers (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_cooldown: AccountInfo<'info>,  // User cooldown PDA if the market has an order cooldown (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_client_orders: AccountInfo<'info>, // User client orders PDA for orders with a client order id (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_prev: AccountInfo<'info>,    // Settlement log before "settle_a", closed if "withdraw_first" empties "settle_a" (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub discount_token: AccountInfo<'info>, // Utility token account that pays discounted taker fees (otherwise the user account)
+    /// CHECK: ok
+    #[account(mut)]
+    pub discount_vault: AccountInfo<'info>, // Market discount vault for utility token fees (otherwise the user account)
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>, // Used to wrap SOL for orders with "native_sol"
+    /// CHECK: ok
+    #[account(address = sysvar::rent::ID)]
+    pub system_rent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: AccountInfo<'info>,  // Global pause config (may not be created yet)
+}
+
+#[derive(Accounts)]
+pub struct TransferOrder<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrder<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CancelOrderVault<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, seeds = [market.key().as_ref(), owner.key().as_ref()], bump)]
+    pub vault: AccountInfo<'info>,          // User vault (created if needed)
+    /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ReplaceOrder<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: AccountInfo<'info>,  // Global pause config (may not be created yet)
+}
+
+#[derive(Accounts)]
+pub struct ManagerCancelOrder<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireOrder<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub user: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExpireToMarket<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub user: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub owner: AccountInfo<'info>,          // Order owner (receives the settlement log rebate)
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub trade_log: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: AccountInfo<'info>,  // Global pause config (may not be created yet)
+}
+
+#[derive(Accounts)]
+pub struct CreateStopBook<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&stops))]
+    pub stops: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateMarket<'info> {
+    /// CHECK: ok
+    #[account(mut)]
+    pub market: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub state: AccountInfo<'info>,
+    #[account(mut)]
+    pub manager: Signer<'info>,
+    /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResizeOrderbook<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, constraint = full_account_zero(&new_orders))]
+    pub new_orders: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct StopOrderContext<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub user: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub stops: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: AccountInfo<'info>,  // Global pause config (may not be created yet)
+}
+
+#[derive(Accounts)]
+pub struct TriggerStops<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub stops: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub trade_log: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(seeds = [program_id.as_ref(), b"global"], bump)]
+    pub global_config: AccountInfo<'info>,  // Global pause config (may not be created yet)
+}
+
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_prev: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_next: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawVault<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_prev: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_next: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, seeds = [market.key().as_ref(), owner.key().as_ref()], bump)]
+    pub vault: AccountInfo<'info>,          // User vault (created if needed)
+    /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct BatchWithdraw<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Initialize<'info> {
-    #[account(init, seeds = [program_id.as_ref()], bump, payer = program_admin, space = 40)]
-    pub root_data: Account<'info, RootData>,
+pub struct ReserveSettlement<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, constraint = full_account_zero(&auth_data))]
-    pub auth_data: UncheckedAccount<'info>,
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, AquaDex>,
-    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
-    pub program_data: Account<'info, ProgramData>,
     #[account(mut)]
-    pub program_admin: Signer<'info>,
-    pub system_program: Program<'info, System>,
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CloseAccount<'info> {
+pub struct ManagerWithdraw<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    pub owner: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub data_account: UncheckedAccount<'info>,
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, AquaDex>,
-    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
-    pub program_data: Account<'info, ProgramData>,
+    pub user_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
     #[account(mut)]
-    pub program_admin: Signer<'info>,
+    pub user_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_prev: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_next: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
 }
 
-// Remove before deployment
-/*#[derive(Accounts)]
-pub struct Deactivate<'info> {
-    #[account(mut, seeds = [program_id.as_ref()], bump, close = program_admin)]
-    pub root_data: Account<'info, RootData>,
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, AquaDex>,
-    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
-    pub program_data: Account<'info, ProgramData>,
-    #[account(mut)]
-    pub program_admin: Signer<'info>,
-}*/
+#[derive(Accounts)]
+pub struct LogStatus<'info> {
+    /// CHECK: ok
+    pub settle: AccountInfo<'info>,
+}
 
 #[derive(Accounts)]
-pub struct UpdateMetadata<'info> {
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, AquaDex>,
-    #[account(constraint = program_data.upgrade_authority_address == Some(program_admin.key()))]
-    pub program_data: Account<'info, ProgramData>,
-    #[account(mut)]
-    pub program_admin: Signer<'info>,
-    #[account(init_if_needed, seeds = [program_id.as_ref(), b"metadata"], bump, payer = program_admin, space = 584)]
-    pub program_info: Account<'info, ProgramMetadata>,
-    pub system_program: Program<'info, System>,
+pub struct MarketInfo<'info> {
+    pub market: Account<'info, Market>,
 }
 
 #[derive(Accounts)]
-#[instruction(_inp_root_nonce: u8)]
-pub struct UpdateRBAC<'info> {
-    #[account(seeds = [program_id.as_ref()], bump = _inp_root_nonce)]
-    pub root_data: Account<'info, RootData>,
+pub struct MarketStats<'info> {
+    pub market: Account<'info, Market>,
+    pub state: Account<'info, MarketState>,
+}
+
+#[derive(Accounts)]
+pub struct TradeLogRange<'info> {
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(mut, constraint = root_data.root_authority == auth_data.key())]
-    pub auth_data: UncheckedAccount<'info>,
-    #[account(constraint = program.programdata_address().unwrap() == Some(program_data.key()))]
-    pub program: Program<'info, AquaDex>,
-    pub program_data: Account<'info, ProgramData>,
-    #[account(mut)]
-    pub program_admin: Signer<'info>,
+    pub trade_log: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct TopOfBook<'info> {
+    pub market: Account<'info, Market>,
+    pub state: Account<'info, MarketState>,
     /// CHECK: ok
-    pub rbac_user: AccountInfo<'info>,
+    pub orders: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-#[instruction(inp_agent_nonce: u8)]
-pub struct CreateMarket<'info> {
+pub struct OrderbookDepth<'info> {
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(zero)]
-    pub market: AccountInfo<'info>, 
+    pub orders: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VerifySolvency<'info> {
+    pub market: Account<'info, Market>,
+    pub state: Account<'info, MarketState>,
     /// CHECK: ok
-    #[account(zero)]
-    pub state: AccountInfo<'info>,
+    pub mkt_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(init, seeds = [market.key().as_ref(), b"admin"], bump, payer = manager, space = 72)]
+    pub prc_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VaultDeposit<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
+    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
     pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
-    #[account(seeds = [market.key().as_ref()], bump = inp_agent_nonce)]
-    pub agent: AccountInfo<'info>,
     #[account(mut)]
     pub manager: Signer<'info>,
     /// CHECK: ok
-    pub fee_manager: AccountInfo<'info>,
-    /// CHECK: ok
-    pub vault_manager: AccountInfo<'info>,
+    pub owner: AccountInfo<'info>,
     /// CHECK: ok
-    pub mkt_mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub settle: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub mkt_vault: AccountInfo<'info>,
+    pub settle_prev: AccountInfo<'info>,
     /// CHECK: ok
-    pub prc_mint: AccountInfo<'info>,
+    #[account(mut)]
+    pub settle_next: AccountInfo<'info>,
+    #[account(init_if_needed, seeds = [market.key().as_ref(), owner.key().as_ref()], bump, payer = manager, space = USER_VAULT_LEN)]
+    pub vault: Account<'info, UserVault>,
     /// CHECK: ok
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct VaultFund<'info> {
+    pub market: Account<'info, Market>,
     #[account(mut)]
-    pub prc_vault: AccountInfo<'info>,
+    pub state: Account<'info, MarketState>,
+    #[account(mut)]
+    pub owner: Signer<'info>,
+    #[account(init_if_needed, seeds = [market.key().as_ref(), owner.key().as_ref()], bump, payer = owner, space = USER_VAULT_LEN)]
+    pub vault: Account<'info, UserVault>,
     /// CHECK: ok
-    #[account(mut, constraint = full_account_zero(&trade_log))]
-    pub trade_log: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_mkt_token: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, constraint = full_account_zero(&orders))]
-    pub orders: AccountInfo<'info>,
+    #[account(mut)]
+    pub user_prc_token: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, constraint = full_account_zero(&settle_a))]
-    pub settle_a: AccountInfo<'info>,
+    #[account(mut)]
+    pub mkt_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, constraint = full_account_zero(&settle_b))]
-    pub settle_b: AccountInfo<'info>,
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
     #[account(address = token::ID)]
     pub spl_token_prog: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(address = associated_token::ID)]
-    /// CHECK: ok
-    pub asc_token_prog: AccountInfo<'info>,
-    /// CHECK: ok
     #[account(address = system_program::ID)]
     pub system_program: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(address = sysvar::rent::ID)]
-    pub system_rent: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct OrderContext<'info> {
+pub struct VaultWithdraw<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
     pub agent: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, signer)]
-    pub user: AccountInfo<'info>,
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+    #[account(mut, seeds = [market.key().as_ref(), owner.key().as_ref()], bump)]
+    pub vault: Account<'info, UserVault>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_mkt_token: AccountInfo<'info>, // Deposit market tokens for "Ask" orders
+    pub user_mkt_token: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_prc_token: AccountInfo<'info>, // Withdraw pricing tokens if the order is filled or partially filled
+    pub user_prc_token: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
     pub mkt_vault: AccountInfo<'info>,
@@ -4341,41 +11579,76 @@ pub struct OrderContext<'info> {
     #[account(mut)]
     pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut)]
-    pub orders: AccountInfo<'info>,
+    #[account(mut, signer)]
+    pub result: AccountInfo<'info>,
     /// CHECK: ok
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManagerTransferSol<'info> {
+    pub market: Account<'info, Market>,
     #[account(mut)]
-    pub trade_log: AccountInfo<'info>,
+    pub state: Account<'info, MarketState>,
+    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
+    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
-    #[account(mut)]
-    pub settle_a: AccountInfo<'info>,
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManagerWithdrawFees<'info> {
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_data: Account<'info, RootData>,
     /// CHECK: ok
+    #[account(constraint = root_data.root_authority == auth_data.key())]
+    pub auth_data: UncheckedAccount<'info>,
+    pub market: Account<'info, Market>,
     #[account(mut)]
-    pub settle_b: AccountInfo<'info>,
+    pub state: Account<'info, MarketState>,
+    /// CHECK: ok
+    pub agent: AccountInfo<'info>,
+    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
+    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
     #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub manager_prc_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
     #[account(address = token::ID)]
     pub spl_token_prog: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct CancelOrder<'info> {
+pub struct ManagerWithdrawDust<'info> {
+    #[account(seeds = [program_id.as_ref()], bump)]
+    pub root_data: Account<'info, RootData>,
+    /// CHECK: ok
+    #[account(constraint = root_data.root_authority == auth_data.key())]
+    pub auth_data: UncheckedAccount<'info>,
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
     pub agent: AccountInfo<'info>,
+    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
+    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
     #[account(mut, signer)]
-    pub owner: AccountInfo<'info>,
+    pub manager: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_mkt_token: AccountInfo<'info>,
+    pub manager_mkt_token: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_prc_token: AccountInfo<'info>,
+    pub manager_prc_token: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
     pub mkt_vault: AccountInfo<'info>,
@@ -4383,79 +11656,78 @@ pub struct CancelOrder<'info> {
     #[account(mut)]
     pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut)]
-    pub orders: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
-    /// CHECK: ok
     #[account(address = token::ID)]
     pub spl_token_prog: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ManagerCancelOrder<'info> {
-    pub market: Account<'info, Market>,
+pub struct ManagerUpdateMarket<'info> {
     #[account(mut)]
-    pub state: Account<'info, MarketState>,
+    pub market: Account<'info, Market>,
+    #[account(mut, seeds = [market.key().as_ref(), b"admin"], bump)]
+    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
     #[account(mut, signer)]
     pub manager: AccountInfo<'info>,
     /// CHECK: ok
+    pub fee_manager: AccountInfo<'info>,
+    /// CHECK: ok
+    pub vault_manager: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManagerSetFeeReceiver<'info> {
     #[account(mut)]
-    pub orders: AccountInfo<'info>,
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
     /// CHECK: ok
+    pub fee_receiver: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManagerSetFeeTiers<'info> {
     #[account(mut)]
-    pub settle_a: AccountInfo<'info>,
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(mut)]
-    pub settle_b: AccountInfo<'info>,
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ExpireOrder<'info> {
-    pub market: Account<'info, Market>,
+pub struct ManagerSetFeeDiscount<'info> {
     #[account(mut)]
-    pub state: Account<'info, MarketState>,
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(mut, signer)]
-    pub user: AccountInfo<'info>,
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut)]
-    pub orders: AccountInfo<'info>,
+    pub discount_mint: AccountInfo<'info>,
     /// CHECK: ok
+    pub discount_vault: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateUserVolume<'info> {
+    pub market: Account<'info, Market>,
     #[account(mut)]
-    pub settle_a: AccountInfo<'info>,
+    pub owner: Signer<'info>,
+    #[account(init_if_needed, seeds = [market.key().as_ref(), owner.key().as_ref(), b"volume"], bump, payer = owner, space = 88)]
+    pub volume: Account<'info, UserVolume>,
     /// CHECK: ok
-    #[account(mut)]
-    pub settle_b: AccountInfo<'info>,
+    #[account(address = system_program::ID)]
+    pub system_program: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+pub struct SweepDust<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
-    pub agent: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut, signer)]
-    pub owner: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub user_mkt_token: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub user_prc_token: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub mkt_vault: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub prc_vault: AccountInfo<'info>,
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
     pub settle: AccountInfo<'info>,
@@ -4465,39 +11737,17 @@ pub struct Withdraw<'info> {
     /// CHECK: ok
     #[account(mut)]
     pub settle_next: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(address = token::ID)]
-    pub spl_token_prog: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ManagerWithdraw<'info> {
+pub struct CompactSettlement<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
-    pub agent: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut, signer)]
+    #[account(signer)]
     pub manager: AccountInfo<'info>,
     /// CHECK: ok
-    pub owner: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub user_mkt_token: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub user_prc_token: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub mkt_vault: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub prc_vault: AccountInfo<'info>,
-    /// CHECK: ok
     #[account(mut)]
     pub settle: AccountInfo<'info>,
     /// CHECK: ok
@@ -4507,56 +11757,58 @@ pub struct ManagerWithdraw<'info> {
     #[account(mut)]
     pub settle_next: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
-    /// CHECK: ok
-    #[account(address = token::ID)]
-    pub spl_token_prog: AccountInfo<'info>,
+    #[account(mut)]
+    pub target: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct LogStatus<'info> {
-    /// CHECK: ok
-    pub settle: AccountInfo<'info>,
+pub struct PokeOracle<'info> {
+    pub market: Account<'info, Market>,
+    #[account(mut)]
+    pub state: Account<'info, MarketState>,
 }
 
 #[derive(Accounts)]
-pub struct VaultDeposit<'info> {
+pub struct ManagerResetBreaker<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
-    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
-    pub admin: Account<'info, MarketAdmin>,
-    /// CHECK: ok
-    #[account(mut)]
-    pub manager: Signer<'info>,
-    /// CHECK: ok
-    pub owner: AccountInfo<'info>,
     /// CHECK: ok
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeManager<'info> {
     #[account(mut)]
-    pub settle: AccountInfo<'info>,
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(mut)]
-    pub settle_prev: AccountInfo<'info>,
+    #[account(signer)]
+    pub manager: AccountInfo<'info>,
     /// CHECK: ok
+    pub new_manager: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptManager<'info> {
     #[account(mut)]
-    pub settle_next: AccountInfo<'info>,
-    #[account(init_if_needed, seeds = [market.key().as_ref(), owner.key().as_ref()], bump, payer = manager, space = 89)]
-    pub vault: Account<'info, UserVault>,
+    pub market: Account<'info, Market>,
     /// CHECK: ok
-    #[account(address = system_program::ID)]
-    pub system_program: AccountInfo<'info>,
+    #[account(signer)]
+    pub new_manager: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct VaultWithdraw<'info> {
+pub struct ManagerVaultWithdraw<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
     pub agent: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(signer)]
+    #[account(mut, signer)]
+    pub manager: AccountInfo<'info>,
+    /// CHECK: ok
     pub owner: AccountInfo<'info>,
     #[account(mut, seeds = [market.key().as_ref(), owner.key().as_ref()], bump)]
     pub vault: Account<'info, UserVault>,
@@ -4581,80 +11833,73 @@ pub struct VaultWithdraw<'info> {
 }
 
 #[derive(Accounts)]
-pub struct ManagerTransferSol<'info> {
+pub struct DrainOrderbook<'info> {
     pub market: Account<'info, Market>,
     #[account(mut)]
     pub state: Account<'info, MarketState>,
-    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
-    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
-    #[account(mut, signer)]
+    #[account(signer)]
     pub manager: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub orders: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_a: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub settle_b: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ManagerWithdrawFees<'info> {
-    #[account(seeds = [program_id.as_ref()], bump)]
-    pub root_data: Account<'info, RootData>,
-    /// CHECK: ok
-    #[account(constraint = root_data.root_authority == auth_data.key())]
-    pub auth_data: UncheckedAccount<'info>,
-    pub market: Account<'info, Market>,
+pub struct MigratePricingVault<'info> {
     #[account(mut)]
+    pub market: Account<'info, Market>,
     pub state: Account<'info, MarketState>,
     /// CHECK: ok
     pub agent: AccountInfo<'info>,
-    #[account(seeds = [market.key().as_ref(), b"admin"], bump)]
-    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
-    #[account(mut, signer)]
+    #[account(signer)]
     pub manager: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub manager_prc_token: AccountInfo<'info>,
+    pub stops: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
     pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(address = token::ID)]
-    pub spl_token_prog: AccountInfo<'info>,
-}
-
-#[derive(Accounts)]
-pub struct ManagerUpdateMarket<'info> {
     #[account(mut)]
-    pub market: Account<'info, Market>,
-    #[account(mut, seeds = [market.key().as_ref(), b"admin"], bump)]
-    pub admin: Account<'info, MarketAdmin>,
-    /// CHECK: ok
-    #[account(mut, signer)]
-    pub manager: AccountInfo<'info>,
-    /// CHECK: ok
-    pub fee_manager: AccountInfo<'info>,
+    pub new_prc_vault: AccountInfo<'info>,
     /// CHECK: ok
-    pub vault_manager: AccountInfo<'info>,
+    #[account(address = token::ID)]
+    pub spl_token_prog: AccountInfo<'info>,
 }
 
 #[derive(Accounts)]
-pub struct ManagerVaultWithdraw<'info> {
+pub struct CloseMarket<'info> {
+    #[account(mut, close = fee_receiver)]
     pub market: Account<'info, Market>,
-    #[account(mut)]
+    #[account(mut, close = fee_receiver)]
     pub state: Account<'info, MarketState>,
+    #[account(mut, seeds = [market.key().as_ref(), b"admin"], bump, close = fee_receiver)]
+    pub admin: Account<'info, MarketAdmin>,
     /// CHECK: ok
     pub agent: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut, signer)]
     pub manager: AccountInfo<'info>,
     /// CHECK: ok
-    pub owner: AccountInfo<'info>,
-    #[account(mut, seeds = [market.key().as_ref(), owner.key().as_ref()], bump)]
-    pub vault: Account<'info, UserVault>,
+    #[account(mut)]
+    pub fee_receiver: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_mkt_token: AccountInfo<'info>,
+    pub orders: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
-    pub user_prc_token: AccountInfo<'info>,
+    pub stops: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub trade_log: AccountInfo<'info>,
     /// CHECK: ok
     #[account(mut)]
     pub mkt_vault: AccountInfo<'info>,
@@ -4662,8 +11907,11 @@ pub struct ManagerVaultWithdraw<'info> {
     #[account(mut)]
     pub prc_vault: AccountInfo<'info>,
     /// CHECK: ok
-    #[account(mut, signer)]
-    pub result: AccountInfo<'info>,
+    #[account(mut)]
+    pub manager_mkt_token: AccountInfo<'info>,
+    /// CHECK: ok
+    #[account(mut)]
+    pub manager_prc_token: AccountInfo<'info>,
     /// CHECK: ok
     #[account(address = token::ID)]
     pub spl_token_prog: AccountInfo<'info>,
@@ -4686,6 +11934,27 @@ pub struct CloseVault<'info> {
     pub fee_receiver: AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct CloseUserAccounts<'info> {
+    /// CHECK: ok
+    pub market: AccountInfo<'info>,         // Closed market
+    /// CHECK: ok
+    #[account(mut, signer)]
+    pub owner: AccountInfo<'info>,          // Receives the lamports of the closed accounts
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_vault: AccountInfo<'info>,     // The owner account if there is no user vault
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_volume: AccountInfo<'info>,    // The owner account if there is no user volume account
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_cooldown: AccountInfo<'info>,  // The owner account if there is no cooldown account
+    /// CHECK: ok
+    #[account(mut)]
+    pub user_client_orders: AccountInfo<'info>,     // The owner account if there is no client orders account
+}
+
 #[derive(Accounts)]
 pub struct CloseTradeResult<'info> {
     /// CHECK: ok
@@ -4719,6 +11988,13 @@ pub struct ExtendLog<'info> {
     pub settle: AccountInfo<'info>,
 }
 
+#[account]
+pub struct GlobalConfig {
+    pub authority: Pubkey,      // Can pause trading on all markets
+    pub paused: bool,           // Order placement is paused on all markets
+}
+// Size: 8 + 32 + 1 = 41
+
 #[account]
 pub struct ProgramMetadata {
     pub semvar_major: u32,
@@ -4762,6 +12038,41 @@ pub struct Market {
     pub prc_mint_type: u8,              // Token B mint type
     pub orders: Pubkey,                 // Orderbook Bid/Ask entries
     pub settle_0: Pubkey,               // The start of the settlement log
+    pub layout_version: u8,             // Account layout version (MARKET_LAYOUT_VERSION, the fields below were added after the original layout)
+    pub max_orders: u32,                // Max orders on each side of the orderbook
+    pub max_deviation_bps: u32,         // Max deviation of posted order prices from the last price in basis points (0 for no limit)
+    pub eviction_enabled: bool,         // Allow better priced orders to evict the worst orders from a full orderbook
+    pub eviction_max: u32,              // Max number of orders to evict before aborting
+    pub stops: Pubkey,                  // Stop orders (default pubkey until "create_stop_book" is called)
+    pub max_stops: u32,                 // Max stop orders on each side
+    pub orderbook_version: u8,          // Order layout version of the orderbook (ORDERBOOK_VERSION)
+    pub min_notional: u64,              // Minimum order value in pricing tokens (0 for no minimum)
+    pub max_orders_per_owner: u32,      // Max resting orders for each owner on both sides of the orderbook (0 for no limit)
+    pub pending_manager: Pubkey,        // Proposed market manager (until accepted by the new manager)
+    pub fee_receiver: Pubkey,           // Owner of the token account that receives commission fees
+    pub referral_fee_share: u32,        // Share of net taker fees credited to the order's referrer (same units as fees)
+    pub breaker_bps: u32,               // Max price move from the last price before the circuit breaker halts taker orders in basis points (0 to disable)
+    pub breaker_cooldown: i64,          // Seconds that taker orders are halted after the circuit breaker is tripped
+    pub max_accounts: u32,              // Max accounts in each settlement log
+    pub fee_tiers: [FeeTier; 4],        // Taker fee discounts by cumulative user volume (up to MAX_FEE_TIERS, unused tiers are zero)
+    pub dust_threshold: u64,            // Settlement log entries with both balances below this amount can be removed by "sweep_dust" (0 to disable)
+    pub manager_force: bool,            // Allow "manager_force_settle" to let the market manager pull orders into the settlement log for compliance
+    pub tick_size: u64,                 // Prices must be a multiple of the tick size (0 for no constraint)
+    pub lot_size: u64,                  // Quantities must be a multiple of the lot size (0 for no constraint)
+    pub session_open: i64,              // Daily trading session open (seconds of the UTC day, 0 and 0 for 24 hour trading)
+    pub session_close: i64,             // Daily trading session close (seconds of the UTC day, before the open if the session wraps around midnight)
+    pub min_rest_seconds: i64,          // Minimum seconds an order must rest before it can be cancelled by its owner (0 to disable)
+    pub discount_mint: Pubkey,          // Utility token mint for taker fee discounts (default pubkey if not configured)
+    pub discount_vault: Pubkey,         // SPL token account that receives utility token fees
+    pub discount_bps: u32,              // Taker fee discount when paying with utility tokens in basis points (0 to disable)
+    pub discount_rate: u64,             // Utility tokens charged per whole pricing token of fee discount
+    pub expire_slippage_bps: u32,       // Max price move from the order price when executing "expire_to_market" in basis points (0 for the order price)
+    pub log_fee_per_fill: u64,          // Fee for each settlement log entry created while matching an order (lamports)
+    pub eviction_min_improvement: u64,  // Min amount a posted order's price must improve on the evicted order's price (0 for any better price)
+    pub allow_self_trade: bool,         // Let orders match against the same user's resting orders (for single operator markets)
+    pub order_cooldown: i64,            // Minimum seconds between orders from the same owner (0 to disable)
+    pub fee_waiver_until: i64,          // Taker fees and maker rebates are waived until this timestamp (0 for no waiver)
+    pub dust_policy: u8,                // Handling of partially filled orders left below "min_quantity" (DustPolicy)
 }
 
 #[account]
@@ -4791,7 +12102,46 @@ pub struct MarketState {
     pub prc_fees_balance: u64,          // Token B commission fees balance
     pub last_ts: i64,                   // Timestamp of last event (market created or order filled)
     pub last_price: u64,                // Last price (Do not use as an oracle value, prices should be averaged over some period of time for that purpose.)
+    pub layout_version: u8,             // Account layout version (MARKET_LAYOUT_VERSION, the fields below were added after the original layout)
+    pub mkt_volume: u128,               // Token A cumulative traded volume
+    pub prc_volume: u128,               // Token B cumulative traded volume
+    pub price_cumulative: u128,         // Sum of last price multiplied by seconds in effect (TWAP = difference between 2 samples / elapsed seconds)
+    pub price_cumulative_ts: i64,       // Timestamp of the last price accumulation
+    pub breaker_tripped_ts: i64,        // Timestamp the circuit breaker was last tripped (0 if not tripped)
+    pub mkt_dust_balance: u64,          // Token A dust swept from settlement log entries
+    pub prc_dust_balance: u64,          // Token B dust from rounding of partially filled bids
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    pub volume_threshold: u128,         // Cumulative pricing token volume to reach the tier (0 for an unused tier)
+    pub taker_fee: u32,                 // Taker commission fee for the tier
+}
+
+#[account]
+pub struct UserCooldown {
+    pub market: Pubkey,                 // Market
+    pub owner: Pubkey,                  // Owner
+    pub last_order_ts: i64,             // Timestamp of the owner's last order
+}
+// Size: 8 + 32 + 32 + 8 = 80
+
+#[account]
+pub struct UserClientOrders {
+    pub market: Pubkey,                 // Market
+    pub owner: Pubkey,                  // Owner
+    pub recent_ids: [u128; 16],         // Recent client order ids (up to MAX_CLIENT_ORDERS, duplicates are rejected)
+    pub next_index: u8,                 // Next entry to replace in "recent_ids"
+}
+// Size: 8 + 32 + 32 + 256 + 1 = 329
+
+#[account]
+pub struct UserVolume {
+    pub market: Pubkey,                 // Market
+    pub owner: Pubkey,                  // Owner
+    pub volume: u128,                   // Cumulative pricing token volume as a taker
 }
+// Size: 8 + 32 + 32 + 16 = 88
 
 #[account]
 pub struct UserVault {
@@ -4830,6 +12180,31 @@ impl TradeResult {
     }
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FillRecord {
+    pub maker: Pubkey,                  // Maker order owner
+    pub price: u64,                     // Fill price
+    pub amount: u64,                    // Filled token quantity
+    pub fee: u64,                       // Taker commission fee
+}
+
+#[account]
+pub struct FillsResult {
+    pub fills: Vec<FillRecord>,         // Fills in matching order (up to MAX_FILLS)
+    pub truncated: bool,                // More fills occurred than were recorded
+}
+// Size: 8 + 4 + (MAX_FILLS * 56) + 1 = 909
+
+impl FillsResult {
+    pub fn add_fill(&mut self, maker: &Pubkey, price: u64, amount: u64, fee: u64) {
+        if self.fills.len() < MAX_FILLS as usize {
+            self.fills.push(FillRecord { maker: *maker, price: price, amount: amount, fee: fee });
+        } else {
+            self.truncated = true;
+        }
+    }
+}
+
 #[account]
 pub struct WithdrawResult {
     pub mkt_tokens: u64,                // Market tokens
@@ -4846,6 +12221,91 @@ impl WithdrawResult {
     }
 }
 
+#[account]
+pub struct MarketAddresses {
+    pub agent: Pubkey,                  // Program derived address that owns the vaults
+    pub mkt_vault: Pubkey,              // Vault for Token A
+    pub prc_vault: Pubkey,              // Vault for Token B
+    pub orders: Pubkey,                 // Orderbook
+    pub trade_log: Pubkey,              // Trade log
+    pub settle_0: Pubkey,               // The start of the settlement log
+    pub state: Pubkey,                  // Market state
+}
+
+#[account]
+pub struct MarketStatsResult {
+    pub mkt_volume: u128,               // Token A cumulative traded volume
+    pub prc_volume: u128,               // Token B cumulative traded volume
+    pub price_cumulative: u128,         // Time weighted price accumulator
+    pub price_cumulative_ts: i64,       // Timestamp of the last price accumulation
+    pub last_price: u64,                // Last price
+    pub last_ts: i64,                   // Timestamp of last event
+    pub active_bid: u32,                // Active bid orders in the orderbook
+    pub active_ask: u32,                // Active ask orders in the orderbook
+}
+
+#[account]
+pub struct StateSnapshot {
+    pub version: u16,                   // Snapshot format version ("STATE_SNAPSHOT_VERSION")
+    pub settle_a: Pubkey,               // Settlement log 1 (the active log)
+    pub settle_b: Pubkey,               // Settlement log 2 (the next log)
+    pub log_rollover: bool,             // Settlement log rollover requested
+    pub log_deposit_balance: u64,       // Lamports deposited for new settlement log space
+    pub action_counter: u64,            // Last action id
+    pub order_counter: u64,             // Last order index
+    pub active_bid: u32,                // Active bid orders in the orderbook
+    pub active_ask: u32,                // Active ask orders in the orderbook
+    pub mkt_vault_balance: u64,         // Token A vault total balance
+    pub mkt_order_balance: u64,         // Token A order balance
+    pub mkt_user_vault_balance: u64,    // Token A user vault balance
+    pub mkt_log_balance: u64,           // Token A settlement log balance
+    pub mkt_dust_balance: u64,          // Token A dust balance
+    pub prc_vault_balance: u64,         // Token B vault total balance
+    pub prc_order_balance: u64,         // Token B order balance
+    pub prc_user_vault_balance: u64,    // Token B user vault balance
+    pub prc_log_balance: u64,           // Token B settlement log balance
+    pub prc_fees_balance: u64,          // Token B commission fees balance
+    pub prc_dust_balance: u64,          // Token B dust balance
+    pub last_price: u64,                // Last price
+    pub last_ts: i64,                   // Timestamp of last event
+    pub mkt_volume: u128,               // Token A cumulative traded volume
+    pub prc_volume: u128,               // Token B cumulative traded volume
+    pub price_cumulative: u128,         // Time weighted price accumulator
+    pub price_cumulative_ts: i64,       // Timestamp of the last price accumulation
+    pub breaker_tripped_ts: i64,        // Timestamp the circuit breaker was last tripped (0 if not tripped)
+}
+
+#[account]
+pub struct TradeLogRangeResult {
+    pub trade_count: u64,               // Total trades logged
+    pub entry_max: u64,                 // Max trade entries in the trade log
+    pub oldest_trade_id: u64,           // Oldest trade entry still in the trade log
+    pub oldest_action_id: u64,
+    pub newest_trade_id: u64,           // Newest trade entry
+    pub newest_action_id: u64,
+}
+
+#[account]
+pub struct CancelAllResult {
+    pub cancelled: u32,                 // Number of orders cancelled
+    pub more: bool,                     // More orders may remain (call again with "next_side" and "next_key")
+    pub next_side: u8,                  // Side to continue from
+    pub next_key: u128,                 // Order key to continue from
+}
+
+#[account]
+pub struct SweepResult {
+    pub swept: u32,                     // Entries removed
+    pub mkt_swept: u64,                 // Market tokens moved to the dust balance
+    pub prc_swept: u64,                 // Pricing tokens moved to the fees balance
+}
+
+#[account]
+pub struct CompactResult {
+    pub moved: u32,                     // Entries moved to the target settlement log
+    pub closed: bool,                   // The emptied source settlement log was closed
+}
+
 #[account]
 pub struct LogStatusResult {
     pub prev: Pubkey,
@@ -4853,6 +12313,173 @@ pub struct LogStatusResult {
     pub items: u32,
 }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct SettlementLogInfo {
+    pub settlement_log: Pubkey,         // Settlement log
+    pub items: u32,                     // Number of entries in the log
+    pub prev: Pubkey,                   // Previous settlement log
+    pub next: Pubkey,                   // Next settlement log
+}
+
+#[account]
+pub struct SettlementLogList {
+    pub logs: Vec<SettlementLogInfo>,   // Settlement logs in chain order
+    pub next: Pubkey,                   // Next settlement log after the last one returned (default pubkey at the end of the chain)
+}
+
+#[account]
+pub struct FindSettlementResult {
+    pub market: Pubkey,                 // Market of the settlement log
+    pub prev: Pubkey,                   // Previous settlement log
+    pub next: Pubkey,                   // Next settlement log
+    pub found: bool,                    // The log has an entry for the owner
+    pub mkt_token_balance: u64,         // Market tokens available to withdraw
+    pub prc_token_balance: u64,         // Pricing tokens available to withdraw
+    pub ts_updated: i64,                // Timestamp of the last update to the entry
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct OrderbookLevel {
+    pub price: u64,                     // Price level
+    pub total_quantity: u64,            // Sum of order quantities at this price
+    pub order_count: u32,               // Number of orders at this price
+}
+
+#[account]
+pub struct ReplaceResult {
+    pub order_id: u128,                 // Order ID after the replacement
+    pub priority_kept: bool,            // The price was unchanged and the order kept its time priority
+    pub tokens_deposited: u64,          // Tokens added to the order from the user's token account
+    pub tokens_withdrawn: u64,          // Tokens returned to the user's token account
+}
+
+#[account]
+pub struct DrainResult {
+    pub bids_drained: u32,              // Number of bid orders cancelled
+    pub asks_drained: u32,              // Number of ask orders cancelled
+    pub more: bool,                     // More orders remain in the orderbook
+}
+
+#[account]
+pub struct CrankResult {
+    pub removed: u32,                   // Number of expired orders removed
+    pub more: bool,                     // More expired orders remain on this side of the orderbook
+}
+
+#[account]
+pub struct BookNotional {
+    pub bid_notional: u64,              // Pricing tokens committed to the bid orders scanned
+    pub ask_notional: u64,              // Market tokens committed to the ask orders scanned
+    pub bid_count: u32,                 // Bid orders scanned
+    pub ask_count: u32,                 // Ask orders scanned
+    pub more: bool,                     // More orders remain to be scanned (continue from "next_side" and "next_key")
+    pub next_side: u8,                  // Side to continue the scan
+    pub next_key: u128,                 // Order key to continue the scan
+}
+
+#[account]
+pub struct ExposureResult {
+    pub open_bid_count: u32,            // Owner's resting bid orders scanned
+    pub open_ask_count: u32,            // Owner's resting ask orders scanned
+    pub mkt_committed: u64,             // Market tokens committed to the owner's ask orders
+    pub prc_committed: u64,             // Pricing tokens committed to the owner's bid orders
+    pub more: bool,                     // More orders remain to be scanned (continue from "next_side" and "next_key")
+    pub next_side: u8,                  // Side to continue the scan
+    pub next_key: u128,                 // Order key to continue the scan
+}
+
+#[account]
+pub struct OrderInfo {
+    pub price: u64,                     // Order price
+    pub quantity: u64,                  // Remaining quantity (including hidden quantity)
+    pub expiry: i64,                    // Expiration timestamp (0 for no expiration)
+    pub owner: Pubkey,                  // Order owner
+    pub placed_ts: i64,                 // Placement timestamp (0 for orders migrated without one)
+    pub age_seconds: i64,               // Seconds since the order was placed (0 if the placement time is unknown)
+    pub ahead_quantity: u64,            // Quantity that would be filled before this order
+    pub complete: bool,                 // The walk reached the order (otherwise "ahead_quantity" is a lower bound)
+}
+
+#[account]
+pub struct SolvencyResult {
+    pub mkt_vault_actual: u64,          // Market token vault balance on-chain
+    pub mkt_vault_balance: u64,         // Market token vault balance tracked by the market state
+    pub mkt_delta: i128,                // On-chain minus tracked market tokens (negative if under-collateralized)
+    pub prc_vault_actual: u64,          // Pricing token vault balance on-chain
+    pub prc_vault_balance: u64,         // Pricing token vault balance tracked by the market state (including fees)
+    pub prc_fees_balance: u64,          // Pricing token fees included in the tracked balance
+    pub prc_delta: i128,                // On-chain minus tracked pricing tokens (negative if under-collateralized)
+    pub solvent: bool,                  // Both vaults hold at least the tracked balances
+}
+
+#[account]
+pub struct TradeEntryResult {
+    pub event_type: u128,               // Event type of the match
+    pub action_id: u64,                 // Action ID of the taker order
+    pub trade_id: u64,
+    pub maker_order_id: u128,
+    pub maker_filled: bool,             // The maker order was filled completely
+    pub maker: Pubkey,
+    pub taker: Pubkey,
+    pub taker_side: u8,                 // 0 - Bid, 1 - Ask
+    pub amount: u64,                    // Quantity filled (in market tokens)
+    pub price: u64,
+    pub ts: i64,
+}
+
+#[account]
+pub struct OrderExistsResult {
+    pub exists: bool,                   // The order is in the orderbook
+    pub side: u8,                       // Side of the order: 0 - Bid, 1 - Ask (255 if the key was not issued by this market)
+    pub price: u64,                     // Order price (from the order key)
+    pub filled_or_gone: bool,           // The key was issued by this market but the order is no longer in the orderbook
+}
+
+#[account]
+pub struct OrderCountResult {
+    pub active_bid: u32,                // Active bid orders tracked by the market state
+    pub active_ask: u32,                // Active ask orders tracked by the market state
+    pub bid_orders: u32,                // Bid orders in the orderbook
+    pub ask_orders: u32,                // Ask orders in the orderbook
+    pub consistent: bool,               // The tracked counts match the orderbook on both sides
+}
+
+#[account]
+pub struct QuoteResult {
+    pub tokens_in: u64,                 // Tokens the user would send (pricing tokens including fees for bids, market tokens for asks)
+    pub tokens_out: u64,                // Tokens the user would receive (market tokens for bids, pricing tokens net of fees for asks)
+    pub avg_price: u64,                 // Average fill price (before fees)
+    pub fee: u64,                       // Taker commission fee
+    pub fully_filled: bool,             // The requested quantity or net price would be filled completely
+}
+
+#[account]
+pub struct MarketMetrics {
+    pub best_bid: u64,                  // Highest unexpired bid price (0 if there are no bids)
+    pub best_ask: u64,                  // Lowest unexpired ask price (0 if there are no asks)
+    pub mid_price: u64,                 // Average of the best bid and ask prices, rounded down (0 if either side is empty)
+    pub spread_bps: u64,                // Best ask minus best bid in basis points of the mid price (0 if either side is empty)
+    pub last_price: u64,                // Last traded price
+    pub active_bid: u32,                // Active bid orders in the orderbook
+    pub active_ask: u32,                // Active ask orders in the orderbook
+}
+
+#[account]
+pub struct TopOfBookResult {
+    pub best_bid_price: u64,            // Highest unexpired bid price (0 if there are no bids)
+    pub best_bid_qty: u64,              // Displayed quantity at the best bid price
+    pub best_ask_price: u64,            // Lowest unexpired ask price (0 if there are no asks)
+    pub best_ask_qty: u64,              // Displayed quantity at the best ask price
+    pub spread: u64,                    // Best ask price minus best bid price (0 if either side is empty)
+    pub last_price: u64,                // Last traded price
+}
+
+#[account]
+pub struct OrderbookDepthResult {
+    pub bids: Vec<OrderbookLevel>,      // Best (highest) price first
+    pub asks: Vec<OrderbookLevel>,      // Best (lowest) price first
+}
+
 #[account]
 pub struct RootData {
     pub root_authority: Pubkey,
@@ -4890,6 +12517,10 @@ pub struct MatchEvent {
     pub taker: Pubkey,
     pub taker_side: u8,
     pub taker_fee: u64,
+    pub fee_discount: u64,
+    pub referrer: Pubkey,
+    pub referral_fee: u64,
+    pub rounding_dust: u64,
     pub amount: u64,
     pub price: u64,
     pub ts: i64,
@@ -4904,6 +12535,7 @@ pub struct OrderEvent {
     pub market_token: Pubkey,
     pub pricing_token: Pubkey,
     pub order_side: u8,
+    pub order_mode: u8,
     pub order_id: u128,
     pub filled: bool,
     pub tokens_sent: u64,
@@ -4914,6 +12546,11 @@ pub struct OrderEvent {
     pub order_price: u64,
     pub order_quantity: u64,
     pub expires: i64,
+    pub reduce_only: bool,
+    pub mkt_vault_balance: u64,         // Market state balances after the order
+    pub prc_vault_balance: u64,
+    pub mkt_order_balance: u64,
+    pub prc_order_balance: u64,
 }
 
 #[event]
@@ -4933,6 +12570,91 @@ pub struct CancelEvent {
     pub token_withdrawn: u64,
 }
 
+#[event]
+pub struct ForceSettleEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub manager: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+    pub order_price: u64,
+    pub order_quantity: u64,
+    pub token_settled: u64,
+    pub reason: u8,
+}
+
+#[event]
+pub struct TransferOrderEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub prev_owner: Pubkey,
+    pub new_owner: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+}
+
+#[event]
+pub struct ModifyEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+    pub order_price: u64,
+    pub prev_quantity: u64,
+    pub order_quantity: u64,
+    pub token_withdrawn: u64,
+}
+
+#[event]
+pub struct DrainEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub bids_drained: u32,
+    pub asks_drained: u32,
+    pub more: bool,
+}
+
+#[event]
+pub struct VaultMigrateEvent {
+    pub event_type: u128,
+    pub market: Pubkey,
+    pub old_vault: Pubkey,
+    pub new_vault: Pubkey,
+    pub amount: u64,
+}
+
+#[event]
+pub struct ExpireToMarketEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+    pub price: u64,
+    pub quantity: u64,
+    pub filled: u64,
+}
+
+#[event]
+pub struct DustCancelEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+    pub price: u64,
+    pub quantity: u64,
+    pub tokens: u64,
+}
+
 #[event]
 pub struct ExpireEvent {
     pub event_type: u128,
@@ -4946,6 +12668,101 @@ pub struct ExpireEvent {
     pub tokens: u64,
 }
 
+#[event]
+pub struct ReplaceEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub prev_order_id: u128,
+    pub prev_price: u64,
+    pub prev_quantity: u64,
+    pub order_id: u128,
+    pub order_price: u64,
+    pub order_quantity: u64,
+    pub expires: i64,
+    pub tokens_deposited: u64,
+    pub tokens_withdrawn: u64,
+}
+
+#[event]
+pub struct ReplenishEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub prev_order_id: u128,
+    pub order_id: u128,
+    pub price: u64,
+    pub quantity: u64,
+    pub hidden_quantity: u64,
+}
+
+#[event]
+pub struct EvictEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub order_id: u128,
+    pub price: u64,
+    pub quantity: u64,
+    pub tokens: u64,
+}
+
+#[event]
+pub struct StopEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_side: u8,
+    pub stop_id: u128,
+    pub trigger_price: u64,
+    pub price: u64,
+    pub quantity: u64,
+    pub deposit: u64,
+    pub filled: u64,
+    pub order_id: u128,
+}
+
+#[event]
+pub struct CloseMarketEvent {
+    pub event_type: u128,
+    pub market: Pubkey,
+    pub manager: Pubkey,
+    pub fee_receiver: Pubkey,
+    pub mkt_dust_balance: u64,          // Market token dust swept from settlement log entries
+    pub prc_dust_balance: u64,          // Pricing token rounding dust
+    pub mkt_residual: u64,              // Market tokens sent to the manager from the vault (dust and unaccounted deposits)
+    pub prc_residual: u64,              // Pricing tokens sent to the manager from the vault
+}
+
+#[event]
+pub struct CompactEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub source_log: Pubkey,
+    pub target_log: Pubkey,
+    pub moved: u32,
+    pub closed: bool,
+}
+
+#[event]
+pub struct SweepEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub settlement_log: Pubkey,
+    pub market_tokens: u64,
+    pub pricing_tokens: u64,
+}
+
 #[event]
 pub struct WithdrawEvent {
     pub event_type: u128,
@@ -4960,6 +12777,25 @@ pub struct WithdrawEvent {
     pub pricing_tokens: u64,
 }
 
+#[event]
+pub struct LogRolloverEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub prev_log: Pubkey,
+    pub new_log: Pubkey,
+}
+
+#[event]
+pub struct LogCloseEvent {
+    pub event_type: u128,
+    pub action_id: u64,
+    pub market: Pubkey,
+    pub closed_log: Pubkey,
+    pub prev: Pubkey,
+    pub next: Pubkey,
+}
+
 #[event]
 pub struct SettleEvent {
     pub event_type: u128,
@@ -4999,6 +12835,15 @@ pub struct VaultWithdrawEvent {
     pub pricing_tokens: u64,
 }
 
+#[event]
+pub struct ManagerEvent {
+    pub event_type: u128,
+    pub market: Pubkey,
+    pub manager: Pubkey,                // Current manager
+    pub pending_manager: Pubkey,        // Proposed manager (the default pubkey when accepted or cancelled)
+    pub previous_manager: Pubkey,       // Manager replaced by an accepted proposal (the default pubkey when proposed)
+}
+
 #[error_code]
 pub enum ErrorCode {
     #[msg("Access denied")]
@@ -5025,6 +12870,8 @@ pub enum ErrorCode {
     VaultNotEmpty,
     #[msg("Order not filled")]
     OrderNotFilled,
+    #[msg("Post only order would be filled")]
+    OrderWouldFill,
     #[msg("Internal error")]
     InternalError,
     #[msg("External error")]
@@ -5041,6 +12888,38 @@ pub enum ErrorCode {
     RebateExceedsFee,
     #[msg("Quantity below minimum")]
     QuantityBelowMinimum,
+    #[msg("Price outside of allowed band")]
+    PriceOutsideBand,
+    #[msg("Order value below minimum notional")]
+    NotionalBelowMinimum,
+    #[msg("Order limit exceeded")]
+    OrderLimitExceeded,
+    #[msg("Market halted by circuit breaker")]
+    MarketHalted,
+    #[msg("Market not empty")]
+    MarketNotEmpty,
+    #[msg("Price not a multiple of tick size")]
+    InvalidTickSize,
+    #[msg("Quantity not a multiple of lot size")]
+    InvalidLotSize,
+    #[msg("Market outside of trading session")]
+    MarketOutsideSession,
+    #[msg("Too many expired or same-user orders skipped while matching")]
+    TooManySkippedOrders,
+    #[msg("Order would cross own resting order")]
+    SelfCross,
+    #[msg("Order has not rested long enough to be cancelled")]
+    OrderTooYoung,
+    #[msg("Order value rounds to zero")]
+    AmountTooSmall,
+    #[msg("Account too small")]
+    AccountTooSmall,
+    #[msg("Order placed too soon after the previous order")]
+    OrderTooSoon,
+    #[msg("Duplicate client order id")]
+    DuplicateOrder,
+    #[msg("Orderbook layout must be migrated")]
+    OrderbookMigrationRequired,
     #[msg("Overflow")]
     Overflow,
 }
@@ -1,14 +1,1332 @@
-const anchor = require('@project-serum/anchor');
+const { PublicKey, Keypair, SystemProgram, SYSVAR_RENT_PUBKEY, SYSVAR_CLOCK_PUBKEY, LAMPORTS_PER_SOL } = require('@solana/web3.js')
+const { TOKEN_PROGRAM_ID, ASSOCIATED_TOKEN_PROGRAM_ID, createMint, createAccount, getOrCreateAssociatedTokenAccount, mintTo, getAccount } = require('@solana/spl-token')
+const anchor = require('@project-serum/anchor')
+const assert = require('assert')
+const crypto = require('crypto')
+
+const provider = anchor.AnchorProvider.env()
+anchor.setProvider(provider)
+
+const aquadex = anchor.workspace.AquaDex
+const aquadexPK = aquadex.programId
+const payer = provider.wallet.payer
+const BN = anchor.BN
+
+const SLAB_BYTES = 326 + (16384 * 8)        // Orderbook and settlement log accounts (large enough for the test capacities)
+const TRADE_LOG_BYTES = 130 + (16384 * 4)
+const SECONDS_PER_DAY = 86400
+const SECURITY_TOKEN_PK = new PublicKey('8JxtmFxuhmgoEFmBeZAqBVouj6DDQBwybpJnpqcYUU8M')
+const SECURITY_TOKEN_MINT = new PublicKey('EXnXikUxX8gE8wd2cvTanuycdDWki7yf2cBZhZvM2RU5')    // Loaded at genesis (see Anchor.toml)
+
+const MARKET_DEFAULTS = {
+    mktDecimals: 0,
+    prcDecimals: 0,
+    managerActions: false,
+    managerForce: false,
+    expireEnable: true,
+    expireMin: 1,
+    minQuantity: 1,
+    minNotional: 0,
+    tickDecimals: 0,
+    tickSize: 0,
+    lotSize: 0,
+    maxOrders: 64,
+    maxOrdersPerOwner: 0,
+    maxTrades: 64,
+    maxAccounts: 64,
+    evictionEnabled: false,
+    evictionMax: 1,
+    takerFee: 0,
+    makerRebate: 0,
+    referralFeeShare: 0,
+    logFee: 0,
+    logRebate: 0,
+    logReimburse: 0,
+    evictionMinImprovement: 0,
+    feeWaiverUntil: 0,
+}
+
+async function programAddress(inputs, program = aquadexPK) {
+    const addr = await PublicKey.findProgramAddress(inputs, program)
+    return { 'pubkey': addr[0], 'nonce': addr[1] }
+}
+
+async function associatedTokenAddress(walletAddress, tokenMintAddress) {
+    return programAddress([walletAddress.toBuffer(), TOKEN_PROGRAM_ID.toBuffer(), tokenMintAddress.toBuffer()], ASSOCIATED_TOKEN_PROGRAM_ID)
+}
+
+function createAccountIx(account, space, lamports) {
+    return SystemProgram.createAccount({
+        fromPubkey: provider.wallet.publicKey,
+        newAccountPubkey: account.publicKey,
+        space: space,
+        lamports: lamports,
+        programId: aquadexPK,
+    })
+}
+
+async function rentFor(space) {
+    return provider.connection.getMinimumBalanceForRentExemption(space)
+}
+
+// Create an SPL token market with both mints owned by the provider wallet (config overrides MARKET_DEFAULTS)
+// With "mktSecurityToken" the market token is that security token mint and the market token vault is a security token account
+async function createMarket(config = {}) {
+    const cfg = Object.assign({}, MARKET_DEFAULTS, config)
+    const ast = cfg.mktSecurityToken !== undefined
+    const mktMint = ast ? cfg.mktSecurityToken : await createMint(provider.connection, payer, payer.publicKey, null, cfg.mktMintDecimals ?? cfg.mktDecimals)
+    const prcMint = await createMint(provider.connection, payer, payer.publicKey, null, cfg.prcMintDecimals ?? cfg.prcDecimals)
+    const market = Keypair.generate()
+    const state = Keypair.generate()
+    const orders = Keypair.generate()
+    const tradeLog = Keypair.generate()
+    const settleA = Keypair.generate()
+    const settleB = Keypair.generate()
+    const agent = await programAddress([market.publicKey.toBuffer()])
+    const admin = await programAddress([market.publicKey.toBuffer(), Buffer.from('admin')])
+    const mktVaultUuid = crypto.randomBytes(16)
+    const mktVault = ast ? await securityTokenAddress(mktMint, agent.pubkey, mktVaultUuid) : await associatedTokenAddress(agent.pubkey, mktMint)
+    const prcVault = await associatedTokenAddress(agent.pubkey, prcMint)
+
+    const ta = new anchor.web3.Transaction()
+    ta.add(createAccountIx(market, aquadex.account.market.size, await rentFor(aquadex.account.market.size)))
+    ta.add(createAccountIx(state, aquadex.account.marketState.size, await rentFor(aquadex.account.marketState.size)))
+    ta.add(createAccountIx(tradeLog, TRADE_LOG_BYTES, await rentFor(TRADE_LOG_BYTES)))
+    await provider.sendAndConfirm(ta, [market, state, tradeLog])
+    const tb = new anchor.web3.Transaction()
+    tb.add(createAccountIx(orders, SLAB_BYTES, await rentFor(SLAB_BYTES)))
+    tb.add(createAccountIx(settleA, SLAB_BYTES, await rentFor(SLAB_BYTES)))
+    tb.add(createAccountIx(settleB, SLAB_BYTES, await rentFor(SLAB_BYTES)))
+    await provider.sendAndConfirm(tb, [orders, settleA, settleB])
+
+    await aquadex.methods.createMarket(
+        agent.nonce,
+        mktVault.nonce,
+        prcVault.nonce,
+        cfg.mktDecimals,
+        cfg.prcDecimals,
+        ast ? 1 : 0,                            // Mkt Mint Type - 0: SPL, 1: AST
+        0,                                      // Prc Mint Type
+        cfg.managerActions,
+        cfg.managerForce,
+        cfg.expireEnable,
+        new BN(cfg.expireMin),
+        new BN(cfg.minQuantity),
+        new BN(cfg.minNotional),
+        cfg.tickDecimals,
+        new BN(cfg.tickSize),
+        new BN(cfg.lotSize),
+        cfg.maxOrders,
+        cfg.maxOrdersPerOwner,
+        cfg.maxTrades,
+        cfg.maxAccounts,
+        cfg.evictionEnabled,
+        cfg.evictionMax,
+        cfg.takerFee,
+        cfg.makerRebate,
+        cfg.referralFeeShare,
+        new BN(cfg.logFee),
+        new BN(cfg.logRebate),
+        new BN(cfg.logReimburse),
+        ast ? new BN(mktVaultUuid, 'le') : new BN(0),  // Mkt Token UUID
+        new BN(0),                              // Prc Token UUID
+        new BN(cfg.evictionMinImprovement),
+        new BN(cfg.feeWaiverUntil),
+    ).accounts({
+        market: market.publicKey,
+        state: state.publicKey,
+        admin: admin.pubkey,
+        agent: agent.pubkey,
+        manager: provider.wallet.publicKey,
+        feeManager: provider.wallet.publicKey,
+        vaultManager: provider.wallet.publicKey,
+        feeReceiver: provider.wallet.publicKey,
+        mktMint: mktMint,
+        mktVault: mktVault.pubkey,
+        prcMint: prcMint,
+        prcVault: prcVault.pubkey,
+        tradeLog: tradeLog.publicKey,
+        orders: orders.publicKey,
+        settleA: settleA.publicKey,
+        settleB: settleB.publicKey,
+        splTokenProg: TOKEN_PROGRAM_ID,
+        ascTokenProg: ASSOCIATED_TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        systemRent: SYSVAR_RENT_PUBKEY,
+    }).remainingAccounts(ast ? [{ pubkey: SECURITY_TOKEN_PK, isWritable: false, isSigner: false }] : []).rpc()
+
+    return {
+        market: market.publicKey,
+        state: state.publicKey,
+        agent: agent.pubkey,
+        admin: admin.pubkey,
+        mktMint: mktMint,
+        prcMint: prcMint,
+        mktVault: mktVault.pubkey,
+        prcVault: prcVault.pubkey,
+        orders: orders.publicKey,
+        tradeLog: tradeLog.publicKey,
+        config: cfg,
+    }
+}
+
+// Create a funded user with token accounts for both mints
+async function createUser(mkt, mktTokens = 0, prcTokens = 0) {
+    const user = Keypair.generate()
+    const tx = new anchor.web3.Transaction()
+    tx.add(SystemProgram.transfer({ fromPubkey: provider.wallet.publicKey, toPubkey: user.publicKey, lamports: 10 * LAMPORTS_PER_SOL }))
+    await provider.sendAndConfirm(tx, [])
+    const prcToken = await getOrCreateAssociatedTokenAccount(provider.connection, payer, mkt.prcMint, user.publicKey)
+    if (prcTokens > 0) {
+        await mintTo(provider.connection, payer, mkt.prcMint, prcToken.address, payer, prcTokens)
+    }
+    if (mkt.config.mktSecurityToken !== undefined) {
+        // Security tokens are not minted by the tests, the account starts empty
+        const mktToken = await createSecurityTokenAccount(mkt.mktMint, { keypair: user, publicKey: user.publicKey })
+        return { keypair: user, publicKey: user.publicKey, mktToken: mktToken, prcToken: prcToken.address }
+    }
+    const mktToken = await getOrCreateAssociatedTokenAccount(provider.connection, payer, mkt.mktMint, user.publicKey)
+    if (mktTokens > 0) {
+        await mintTo(provider.connection, payer, mkt.mktMint, mktToken.address, payer, mktTokens)
+    }
+    return { keypair: user, publicKey: user.publicKey, mktToken: mktToken.address, prcToken: prcToken.address }
+}
+
+// Security token accounts are addresses of the security token program derived from the mint, the owner and a UUID
+async function securityTokenAddress(mint, owner, uuid) {
+    return programAddress([mint.toBuffer(), owner.toBuffer(), uuid], SECURITY_TOKEN_PK)
+}
+
+// Create an empty security token account for a user (the same accounts as the "create_account" call in "create_market")
+async function createSecurityTokenAccount(mint, user) {
+    const uuid = crypto.randomBytes(16)
+    const account = await securityTokenAddress(mint, user.publicKey, uuid)
+    const data = Buffer.concat([crypto.createHash('sha256').update('global:create_account').digest().slice(0, 8), uuid])
+    const tx = new anchor.web3.Transaction()
+    tx.add(new anchor.web3.TransactionInstruction({
+        programId: SECURITY_TOKEN_PK,
+        keys: [
+            { pubkey: account.pubkey, isWritable: true, isSigner: false },
+            { pubkey: mint, isWritable: false, isSigner: false },
+            { pubkey: user.publicKey, isWritable: false, isSigner: true },              // Owner
+            { pubkey: provider.wallet.publicKey, isWritable: true, isSigner: true },    // Fee payer
+            { pubkey: SECURITY_TOKEN_PK, isWritable: false, isSigner: false },          // Create auth
+            { pubkey: user.publicKey, isWritable: false, isSigner: false },             // Close auth
+            { pubkey: SystemProgram.programId, isWritable: false, isSigner: false },
+        ],
+        data: data,
+    }))
+    await provider.sendAndConfirm(tx, [user.keypair])
+    return account.pubkey
+}
+
+// Create a temp account for optional result data
+async function createResult(space = aquadex.account.tradeResult.size) {
+    const result = Keypair.generate()
+    const tx = new anchor.web3.Transaction()
+    tx.add(createAccountIx(result, space, await rentFor(space)))
+    await provider.sendAndConfirm(tx, [result])
+    return result
+}
+
+async function createSettlementLog() {
+    const settle = Keypair.generate()
+    const tx = new anchor.web3.Transaction()
+    tx.add(createAccountIx(settle, SLAB_BYTES, await rentFor(SLAB_BYTES)))
+    await provider.sendAndConfirm(tx, [settle])
+    return settle
+}
+
+async function orderAccounts(mkt, user, result) {
+    const state = await aquadex.account.marketState.fetch(mkt.state)
+    const globalConfig = await programAddress([aquadexPK.toBuffer(), Buffer.from('global')])
+    return {
+        market: mkt.market,
+        state: mkt.state,
+        agent: mkt.agent,
+        user: user.publicKey,
+        userMktToken: user.mktToken,
+        userPrcToken: user.prcToken,
+        mktVault: mkt.mktVault,
+        prcVault: mkt.prcVault,
+        orders: mkt.orders,
+        tradeLog: mkt.tradeLog,
+        settleA: state.settleA,
+        settleB: state.settleB,
+        result: result.publicKey,
+        fills: user.publicKey,
+        userVault: user.publicKey,
+        referrer: user.publicKey,
+        userVolume: user.publicKey,
+        userCooldown: user.publicKey,
+        userClientOrders: user.publicKey,
+        discountToken: user.publicKey,
+        discountVault: user.publicKey,
+        settlePrev: user.publicKey,
+        splTokenProg: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        globalConfig: globalConfig.pubkey,
+    }
+}
+
+// Build a limit order transaction ("bid" or "ask")
+async function limitOrderTx(mkt, side, user, qty, price, opts = {}) {
+    const result = opts.result ?? await createResult()
+    const accounts = Object.assign(await orderAccounts(mkt, user, result), opts.accounts ?? {})
+    const args = [
+        new BN(qty),
+        new BN(price),
+        opts.mode ?? 0,                         // Order mode (0 - Limit)
+        new BN(opts.expires ?? 0),              // Order expiry
+        opts.preview ?? false,                  // Preview
+        opts.rollover ?? false,                 // Rollover settlement log
+        false,                                  // Use vault
+        new BN(opts.displayQty ?? 0),           // Display quantity (0 - not an iceberg order)
+        false,                                  // Native SOL
+        false,                                  // Withdraw first
+        opts.rejectSelfCross ?? false,          // Reject self cross
+        false,                                  // Expire to market
+    ]
+    if (side === 'bid') {
+        args.push(opts.byQuote ?? false)        // By quote
+    }
+    args.push(opts.expireBudget ?? 0)           // Expire budget
+    args.push(new BN(opts.clientOrderId ?? 0))  // Client order id
+    const method = (side === 'bid') ? aquadex.methods.limitBid(...args) : aquadex.methods.limitAsk(...args)
+    const tx = await method.accounts(accounts).remainingAccounts(opts.remainingAccounts ?? []).transaction()
+    return { tx: tx, signers: [user.keypair, result], result: result }
+}
+
+async function limitOrder(mkt, side, user, qty, price, opts = {}) {
+    const order = await limitOrderTx(mkt, side, user, qty, price, opts)
+    await provider.sendAndConfirm(order.tx, order.signers)
+    return aquadex.account.tradeResult.fetch(order.result.publicKey)
+}
+
+// Build a market order transaction ("bid" or "ask") by quantity, or by net price with "netPrice"
+async function marketOrderTx(mkt, side, user, opts = {}) {
+    const result = opts.result ?? await createResult()
+    const accounts = Object.assign(await orderAccounts(mkt, user, result), opts.accounts ?? {})
+    const args = [
+        opts.netPrice === undefined,            // By quantity
+        new BN(opts.quantity ?? 0),             // Quantity
+        new BN(opts.netPrice ?? 0),             // Net price
+        new BN(opts.limitPrice ?? 0),           // Limit price (0 for no limit)
+        opts.fill ?? false,                     // Require a complete fill
+        opts.preview ?? false,                  // Preview
+        opts.rollover ?? false,                 // Rollover settlement log
+        false,                                  // Native SOL
+        false,                                  // Reduce only
+        0,                                      // Min fill bps
+        opts.expireBudget ?? 0,                 // Expire budget
+        new BN(opts.clientOrderId ?? 0),        // Client order id
+    ]
+    const method = (side === 'bid') ? aquadex.methods.marketBid(...args) : aquadex.methods.marketAsk(...args)
+    const tx = await method.accounts(accounts).remainingAccounts(opts.remainingAccounts ?? []).transaction()
+    return { tx: tx, signers: [user.keypair, result], result: result }
+}
+
+async function marketOrder(mkt, side, user, opts = {}) {
+    const order = await marketOrderTx(mkt, side, user, opts)
+    await provider.sendAndConfirm(order.tx, order.signers)
+    return aquadex.account.tradeResult.fetch(order.result.publicKey)
+}
+
+async function cancelOrder(mkt, side, user, orderId) {
+    const result = await createResult(aquadex.account.withdrawResult.size)
+    await aquadex.methods.cancelOrder(side === 'bid' ? 0 : 1, orderId).accounts({
+        market: mkt.market,
+        state: mkt.state,
+        agent: mkt.agent,
+        owner: user.publicKey,
+        userMktToken: user.mktToken,
+        userPrcToken: user.prcToken,
+        mktVault: mkt.mktVault,
+        prcVault: mkt.prcVault,
+        orders: mkt.orders,
+        result: result.publicKey,
+        splTokenProg: TOKEN_PROGRAM_ID,
+    }).signers([user.keypair, result]).rpc()
+    return aquadex.account.withdrawResult.fetch(result.publicKey)
+}
+
+// Update market settings (changes override the current values)
+async function updateMarket(mkt, changes) {
+    const m = Object.assign(await aquadex.account.market.fetch(mkt.market), changes)
+    await aquadex.methods.managerUpdateMarket(
+        m.active,
+        m.expireEnable,
+        new BN(m.expireMin),
+        new BN(m.minQuantity),
+        new BN(m.minNotional),
+        m.tickDecimals,
+        new BN(m.tickSize),
+        new BN(m.lotSize),
+        m.takerFee,
+        m.makerRebate,
+        new BN(m.logFee),
+        new BN(m.logRebate),
+        new BN(m.logReimburse),
+        m.maxDeviationBps,
+        m.evictionEnabled,
+        m.evictionMax,
+        m.maxOrdersPerOwner,
+        m.referralFeeShare,
+        m.breakerBps,
+        new BN(m.breakerCooldown),
+        new BN(m.dustThreshold),
+        new BN(m.sessionOpen),
+        new BN(m.sessionClose),
+        new BN(m.minRestSeconds),
+        m.expireSlippageBps,
+        new BN(m.logFeePerFill),
+        new BN(m.evictionMinImprovement),
+        m.allowSelfTrade,
+        new BN(m.orderCooldown),
+        new BN(m.feeWaiverUntil),
+        m.dustPolicy,
+    ).accounts({
+        market: mkt.market,
+        admin: mkt.admin,
+        manager: provider.wallet.publicKey,
+        feeManager: provider.wallet.publicKey,
+        vaultManager: provider.wallet.publicKey,
+    }).rpc()
+}
+
+async function topOfBook(mkt) {
+    return aquadex.methods.getTopOfBook().accounts({ market: mkt.market, state: mkt.state, orders: mkt.orders }).view()
+}
+
+async function orderbookDepth(mkt, depth = 25) {
+    return aquadex.methods.getOrderbookDepth(depth).accounts({ market: mkt.market, orders: mkt.orders }).view()
+}
+
+async function orderInfo(mkt, side, orderId, maxScan = 64) {
+    return aquadex.methods.getOrderInfo(side === 'bid' ? 0 : 1, orderId, maxScan).accounts({ market: mkt.market, orders: mkt.orders }).view()
+}
+
+async function orderExists(mkt, orderId) {
+    const info = await aquadex.methods.orderExists(orderId, 2).accounts({ market: mkt.market, state: mkt.state, orders: mkt.orders }).view()
+    return info.exists
+}
+
+async function tokenBalance(address) {
+    return Number((await getAccount(provider.connection, address)).amount)
+}
+
+// Current cluster time (the Clock sysvar unix_timestamp)
+async function clusterTime() {
+    const info = await provider.connection.getAccountInfo(SYSVAR_CLOCK_PUBKEY)
+    return Number(info.data.readBigInt64LE(32))
+}
+
+function errorName(err) {
+    if (err.error && err.error.errorCode) {
+        return err.error.errorCode.code
+    }
+    const parsed = anchor.AnchorError.parse(err.logs ?? [])
+    return parsed ? parsed.error.errorCode.code : String(err)
+}
+
+async function expectError(promise, code) {
+    try {
+        await promise
+    } catch (err) {
+        assert.strictEqual(errorName(err), code)
+        return
+    }
+    assert.fail('Expected error: ' + code)
+}
+
+// Send a transaction without preflight and return the cluster time it executed at and its error name (null on success)
+async function sendTimed(tx, signers) {
+    tx.feePayer = provider.wallet.publicKey
+    tx.recentBlockhash = (await provider.connection.getLatestBlockhash()).blockhash
+    tx.partialSign(...signers)
+    const signed = await provider.wallet.signTransaction(tx)
+    const sig = await provider.connection.sendRawTransaction(signed.serialize(), { skipPreflight: true })
+    await provider.connection.confirmTransaction(sig, 'confirmed')
+    const info = await provider.connection.getTransaction(sig, { commitment: 'confirmed' })
+    let error = null
+    if (info.meta.err) {
+        const parsed = anchor.AnchorError.parse(info.meta.logMessages)
+        error = parsed ? parsed.error.errorCode.code : JSON.stringify(info.meta.err)
+    }
+    return { ts: info.blockTime, error: error }
+}
+
+function sleep(ms) {
+    return new Promise(resolve => setTimeout(resolve, ms))
+}
+
+// Poll the cluster clock until it reaches a timestamp
+async function waitForClusterTime(ts) {
+    while ((await clusterTime()) < ts) {
+        await sleep(100)
+    }
+}
+
+async function resizeOrderbook(mkt, maxOrders) {
+    const market = await aquadex.account.market.fetch(mkt.market)
+    const newOrders = Keypair.generate()
+    const tx = new anchor.web3.Transaction()
+    tx.add(createAccountIx(newOrders, SLAB_BYTES, await rentFor(SLAB_BYTES)))
+    await provider.sendAndConfirm(tx, [newOrders])
+    await aquadex.methods.resizeOrderbook(maxOrders).accounts({
+        market: mkt.market,
+        manager: provider.wallet.publicKey,
+        orders: market.orders,
+        newOrders: newOrders.publicKey,
+    }).rpc()
+    mkt.orders = newOrders.publicKey
+    return newOrders.publicKey
+}
+
+function depthLevels(levels) {
+    return levels.map(level => [level.price.toNumber(), level.totalQuantity.toNumber(), level.orderCount])
+}
+
+// Create several result accounts at once (for probes that must be sent quickly)
+async function createResults(count, space = aquadex.account.tradeResult.size) {
+    return Promise.all([...Array(count).keys()].map(() => createResult(space)))
+}
+
+// Same comparison as "check_session" in the program
+function inSession(open, close, ts) {
+    if (open === 0 && close === 0) {
+        return true
+    }
+    const day = ((ts % SECONDS_PER_DAY) + SECONDS_PER_DAY) % SECONDS_PER_DAY
+    return (open < close) ? (day >= open && day < close) : (day >= open || day < close)
+}
+
+async function expireOrder(mkt, side, user, orderId) {
+    const state = await aquadex.account.marketState.fetch(mkt.state)
+    return aquadex.methods.expireOrder(side === 'bid' ? 0 : 1, orderId, false).accounts({
+        market: mkt.market,
+        state: mkt.state,
+        user: user.publicKey,
+        orders: mkt.orders,
+        settleA: state.settleA,
+        settleB: state.settleB,
+    }).signers([user.keypair]).transaction()
+}
+
+// Deterministic pseudo-random number generator (mulberry32) for repeatable randomized tests
+function seededRandom(seed) {
+    let t = seed >>> 0
+    return function () {
+        t = (t + 0x6D2B79F5) >>> 0
+        let r = Math.imul(t ^ (t >>> 15), 1 | t)
+        r = (r + Math.imul(r ^ (r >>> 7), 61 | r)) ^ r
+        return ((r ^ (r >>> 14)) >>> 0) / 4294967296
+    }
+}
+
+async function verifyOrderCounts(mkt) {
+    return aquadex.methods.verifyOrderCounts().accounts({ market: mkt.market, state: mkt.state, orders: mkt.orders }).view()
+}
+
+async function verifySolvency(mkt) {
+    return aquadex.methods.verifySolvency().accounts({ market: mkt.market, state: mkt.state, mktVault: mkt.mktVault, prcVault: mkt.prcVault }).view()
+}
+
+async function bookNotional(mkt, maxScan = 1000) {
+    return aquadex.methods.getBookNotional(maxScan, false, 0, new BN(0)).accounts({ market: mkt.market, orders: mkt.orders }).view()
+}
 
 describe('aqua-dex', () => {
 
-  // Configure the client to use the local cluster.
-  anchor.setProvider(anchor.Provider.env());
+    describe('settlement logs', () => {
+        it('stops matching cleanly when both settlement logs are full, then trades after a rollover', async () => {
+            // Each log holds (max_accounts + 1) / 2 entries, so 3 makers fill each log
+            const mkt = await createMarket({ maxAccounts: 5 })
+            const makers = []
+            for (let i = 0; i < 7; i++) {
+                const maker = await createUser(mkt, 10, 0)
+                await limitOrder(mkt, 'ask', maker, 10, 100)
+                makers.push(maker)
+            }
+            const taker = await createUser(mkt, 0, 10000)
+            const res = await limitOrder(mkt, 'bid', taker, 70, 100)
+            assert.strictEqual(res.tokensReceived.toNumber(), 60)
+            assert.strictEqual(res.postedQuantity.toNumber(), 0)
+            var state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.logRollover, true)
+            assert.strictEqual(state.activeAsk, 1)
+            assert.strictEqual(state.activeBid, 0)
+            assert.strictEqual(await tokenBalance(taker.mktToken), 60)
+            assert.strictEqual(await tokenBalance(taker.prcToken), 10000 - 6000)
+
+            // Roll over to a new settlement log and fill the last maker
+            const settle = await createSettlementLog()
+            const res2 = await limitOrder(mkt, 'bid', taker, 10, 100, {
+                rollover: true,
+                remainingAccounts: [{ pubkey: settle.publicKey, isWritable: true, isSigner: false }],
+            })
+            assert.strictEqual(res2.tokensReceived.toNumber(), 10)
+            state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.settleB.toString(), settle.publicKey.toString())
+            assert.strictEqual(state.activeAsk, 0)
+            assert.strictEqual(await tokenBalance(taker.mktToken), 70)
+        })
+    })
+    describe('order expiration', () => {
+        it('treats an order as live at its expiry timestamp and expired one second after', async () => {
+            // Asks at increasing prices expire one second apart, so each probe checks orders before, at and after their expiry
+            const mkt = await createMarket()
+            const count = 5
+            const makers = []
+            for (let i = 0; i < count; i++) {
+                makers.push(await createUser(mkt, 10, 0))
+            }
+            const taker = await createUser(mkt, 0, 100000)
+            const start = (await clusterTime()) + 5
+            const asks = []
+            for (let i = 0; i < count; i++) {
+                const res = await limitOrder(mkt, 'ask', makers[i], 10, 100 + i, { expires: start + i })
+                asks.push({ orderId: res.orderId, expiry: start + i, price: 100 + i })
+            }
+            const offsets = new Set()
+            await waitForClusterTime(start - 1)
+            while (offsets.size < 3) {
+                const now = await clusterTime()
+                assert.ok(now < start + count, 'Missed the expiry window')
+                const order = await marketOrderTx(mkt, 'bid', taker, { quantity: 10 * count, preview: true })
+                const sent = await sendTimed(order.tx, order.signers)
+                assert.strictEqual(sent.error, null)
+                const res = await aquadex.account.tradeResult.fetch(order.result.publicKey)
+                const live = asks.filter(ask => ask.expiry >= sent.ts)
+                assert.strictEqual(res.tokensReceived.toNumber(), 10 * live.length)
+                assert.strictEqual(res.tokensSent.toNumber(), live.reduce((total, ask) => total + (10 * ask.price), 0))
+                for (const ask of asks) {
+                    const offset = sent.ts - ask.expiry
+                    if (offset >= -1 && offset <= 1) {
+                        offsets.add(offset)
+                    }
+                }
+            }
+
+            // "expire_order" uses the same comparison
+            for (const ask of asks) {
+                const before = (await aquadex.account.marketState.fetch(mkt.state)).activeAsk
+                const sent = await sendTimed(await expireOrder(mkt, 'ask', taker, ask.orderId), [taker.keypair])
+                assert.strictEqual(sent.error, null)
+                const after = (await aquadex.account.marketState.fetch(mkt.state)).activeAsk
+                assert.strictEqual(after, sent.ts <= ask.expiry ? before : before - 1)
+            }
+        })
+    })
+    describe('resize orderbook', () => {
+        it('keeps price and time priority when the orderbook is resized', async () => {
+            const mkt = await createMarket({ maxOrders: 16 })
+            const bids = [105, 101, 110, 101, 103]
+            const asks = [120, 115, 130, 115]
+            const bidOrders = []
+            for (const price of bids) {
+                const maker = await createUser(mkt, 0, 10 * price)
+                bidOrders.push({ maker: maker, orderId: (await limitOrder(mkt, 'bid', maker, 10, price)).orderId })
+            }
+            for (const price of asks) {
+                const maker = await createUser(mkt, 10, 0)
+                await limitOrder(mkt, 'ask', maker, 10, price)
+            }
+            const depthBefore = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depthBefore.bids), [[110, 10, 1], [105, 10, 1], [103, 10, 1], [101, 20, 2]])
+            assert.deepStrictEqual(depthLevels(depthBefore.asks), [[115, 20, 2], [120, 10, 1], [130, 10, 1]])
+
+            await resizeOrderbook(mkt, 64)
+            const market = await aquadex.account.market.fetch(mkt.market)
+            assert.strictEqual(market.maxOrders, 64)
+            assert.strictEqual(market.orders.toString(), mkt.orders.toString())
+            const depthAfter = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depthAfter.bids), depthLevels(depthBefore.bids))
+            assert.deepStrictEqual(depthLevels(depthAfter.asks), depthLevels(depthBefore.asks))
+
+            // Sell through the bids down to the first order at 101, which was placed before the second
+            const taker = await createUser(mkt, 40, 0)
+            const res = await marketOrder(mkt, 'ask', taker, { quantity: 40 })
+            assert.strictEqual(res.tokensSent.toNumber(), 40)
+            assert.strictEqual(res.tokensReceived.toNumber(), 10 * (110 + 105 + 103 + 101))
+            await expectError(cancelOrder(mkt, 'bid', bidOrders[1].maker, bidOrders[1].orderId), 'OrderNotFound')
+
+            // New orders are ordered with the copied orders
+            const maker = await createUser(mkt, 10, 0)
+            await limitOrder(mkt, 'ask', maker, 10, 116)
+            const depthNew = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depthNew.bids), [[101, 10, 1]])
+            assert.deepStrictEqual(depthLevels(depthNew.asks), [[115, 20, 2], [116, 10, 1], [120, 10, 1], [130, 10, 1]])
+        })
+    })
+    describe('token decimals', () => {
+        it('rejects market or pricing token decimals above 18', async () => {
+            await expectError(createMarket({ mktDecimals: 19 }), 'InvalidParameters')
+            await expectError(createMarket({ prcDecimals: 19 }), 'InvalidParameters')
+        })
+
+        it('trades with 18 decimal market and pricing tokens', async () => {
+            const mkt = await createMarket({ mktDecimals: 18, prcDecimals: 18 })
+            const one = 10n ** 18n
+            const maker = await createUser(mkt, 2n * one, 0)
+            const taker = await createUser(mkt, 0, 10n * one)
+            await limitOrder(mkt, 'ask', maker, (2n * one).toString(), (5n * one).toString())
+            const res = await marketOrder(mkt, 'bid', taker, { quantity: (2n * one).toString() })
+            assert.strictEqual(res.tokensReceived.toString(), (2n * one).toString())
+            assert.strictEqual(res.tokensSent.toString(), (10n * one).toString())
+            assert.strictEqual((await getAccount(provider.connection, taker.mktToken)).amount, 2n * one)
+            assert.strictEqual((await getAccount(provider.connection, taker.prcToken)).amount, 0n)
+        })
+    })
+    describe('order value', () => {
+        it('rejects the largest quantities whose value rounds to zero pricing tokens', async () => {
+            // 1000 pricing tokens per whole market token (6 decimals), so 1000 units are worth 1 pricing token and 999 are worth 0
+            const mkt = await createMarket({ mktDecimals: 6 })
+            const maker = await createUser(mkt, 10000, 10)
+            for (const side of ['bid', 'ask']) {
+                const order = await limitOrderTx(mkt, side, maker, 999, 1000)
+                await expectError(provider.sendAndConfirm(order.tx, order.signers), 'AmountTooSmall')
+            }
+            const ask = await limitOrder(mkt, 'ask', maker, 1000, 1000)
+            assert.strictEqual(ask.postedQuantity.toNumber(), 1000)
+            const bid = await limitOrder(mkt, 'bid', maker, 2000, 999)
+            assert.strictEqual(bid.postedQuantity.toNumber(), 2000)
+            assert.strictEqual(bid.tokensSent.toNumber(), 1)
+
+            const taker = await createUser(mkt, 1000, 10)
+            for (const side of ['bid', 'ask']) {
+                const order = await marketOrderTx(mkt, side, taker, { quantity: 999 })
+                await expectError(provider.sendAndConfirm(order.tx, order.signers), 'AmountTooSmall')
+            }
+            const res = await marketOrder(mkt, 'bid', taker, { quantity: 1000 })
+            assert.strictEqual(res.tokensReceived.toNumber(), 1000)
+            assert.strictEqual(res.tokensSent.toNumber(), 1)
+        })
+    })
+    describe('result accounts', () => {
+        it('rejects an undersized or wrong-owner result account before trading', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 0, 1000)
+            const small = await createResult(aquadex.account.tradeResult.size - 1)
+            const order = await limitOrderTx(mkt, 'bid', user, 10, 100, { result: small })
+            await expectError(provider.sendAndConfirm(order.tx, order.signers), 'InvalidAccount')
+
+            const foreign = Keypair.generate()
+            const size = aquadex.account.tradeResult.size
+            const tx = new anchor.web3.Transaction()
+            tx.add(SystemProgram.createAccount({
+                fromPubkey: provider.wallet.publicKey,
+                newAccountPubkey: foreign.publicKey,
+                space: size,
+                lamports: await rentFor(size),
+                programId: SystemProgram.programId,
+            }))
+            await provider.sendAndConfirm(tx, [foreign])
+            const order2 = await limitOrderTx(mkt, 'bid', user, 10, 100, { result: foreign })
+            await expectError(provider.sendAndConfirm(order2.tx, order2.signers), 'InvalidAccount')
+
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.activeBid, 0)
+            assert.strictEqual(await tokenBalance(user.prcToken), 1000)
+        })
+    })
+    describe('tick size', () => {
+        it('accepts prices on the tick grid and rejects prices off it', async () => {
+            const mkt = await createMarket({ tickSize: 100 })
+            const user = await createUser(mkt, 100, 100000)
+            await limitOrder(mkt, 'bid', user, 1, 200)
+            await limitOrder(mkt, 'bid', user, 1, 100)         // Price equal to the tick size
+            await limitOrder(mkt, 'ask', user, 1, 300)
+            for (const [side, price] of [['bid', 150], ['bid', 99], ['bid', 101], ['ask', 350], ['ask', 1]]) {
+                const order = await limitOrderTx(mkt, side, user, 1, price)
+                await expectError(provider.sendAndConfirm(order.tx, order.signers), 'InvalidTickSize')
+            }
+            const depth = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depth.bids), [[200, 1, 1], [100, 1, 1]])
+            assert.deepStrictEqual(depthLevels(depth.asks), [[300, 1, 1]])
+
+            // Price-bounded market orders use the same grid
+            const taker = await createUser(mkt, 0, 1000)
+            const order = await marketOrderTx(mkt, 'bid', taker, { quantity: 1, limitPrice: 350 })
+            await expectError(provider.sendAndConfirm(order.tx, order.signers), 'InvalidTickSize')
+            const res = await marketOrder(mkt, 'bid', taker, { quantity: 1, limitPrice: 300 })
+            assert.strictEqual(res.tokensReceived.toNumber(), 1)
+            assert.strictEqual(res.tokensSent.toNumber(), 300)
+        })
+    })
+    describe('order info', () => {
+        it('reports the placement time, age and queue position of an order', async () => {
+            const mkt = await createMarket()
+            const first = await createUser(mkt, 10, 0)
+            const second = await createUser(mkt, 10, 0)
+            const before = await clusterTime()
+            const res1 = await limitOrder(mkt, 'ask', first, 10, 100)
+            const res2 = await limitOrder(mkt, 'ask', second, 4, 100)
+            const after = await clusterTime()
+            await waitForClusterTime(after + 1)
+
+            const info = await orderInfo(mkt, 'ask', res2.orderId)
+            const placed = info.placedTs.toNumber()
+            assert.ok(placed >= before && placed <= after, 'Placement time outside of the order window')
+            const now = await clusterTime()
+            assert.ok(info.ageSeconds.toNumber() > 0 && info.ageSeconds.toNumber() <= now - placed)
+            assert.strictEqual(info.price.toNumber(), 100)
+            assert.strictEqual(info.quantity.toNumber(), 4)
+            assert.strictEqual(info.owner.toString(), second.publicKey.toString())
+            assert.strictEqual(info.aheadQuantity.toNumber(), 10)
+            assert.strictEqual(info.complete, true)
+
+            const info1 = await orderInfo(mkt, 'ask', res1.orderId)
+            assert.strictEqual(info1.aheadQuantity.toNumber(), 0)
+            assert.ok(info1.placedTs.toNumber() <= placed)
+        })
+    })
+    describe('trading session', () => {
+        // Set the session to open and close a few seconds from now, then probe one second before, at and after each boundary
+        async function probeSession(mkt, user, openOffset, closeOffset) {
+            const results = await createResults(24)
+            const start = await sessionStart()
+            const openAt = start + openOffset
+            const closeAt = start + closeOffset
+            const open = openAt % SECONDS_PER_DAY
+            const close = closeAt % SECONDS_PER_DAY
+            await updateMarket(mkt, { sessionOpen: open, sessionClose: close })
+            const seen = new Set()
+            const first = Math.min(openAt, closeAt)
+            const last = Math.max(openAt, closeAt)
+            await waitForClusterTime(first - 2)
+            while (seen.size < 6) {
+                const now = await clusterTime()
+                assert.ok(now <= last + 1 && results.length > 0, 'Missed the session boundaries')
+                const order = await limitOrderTx(mkt, 'bid', user, 1, 100, { preview: true, result: results.pop() })
+                const sent = await sendTimed(order.tx, order.signers)
+                assert.strictEqual(sent.error, inSession(open, close, sent.ts) ? null : 'MarketOutsideSession', 'Session check at ' + sent.ts)
+                for (const [name, boundary] of [['open', openAt], ['close', closeAt]]) {
+                    const offset = sent.ts - boundary
+                    if (offset >= -1 && offset <= 1) {
+                        seen.add(name + offset)
+                    }
+                }
+            }
+        }
+
+        async function sessionStart() {
+            // Keep both boundaries on the same UTC day
+            const now = await clusterTime()
+            const day = now % SECONDS_PER_DAY
+            if (day > SECONDS_PER_DAY - 20) {
+                await waitForClusterTime(now - day + SECONDS_PER_DAY)
+            }
+            return (await clusterTime()) + 3
+        }
+
+        it('trades from the session open up to but not including the close', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 0, 1000)
+            await probeSession(mkt, user, 0, 4)
+        })
+
+        it('wraps a session with a close before the open around midnight', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 0, 1000)
+            // The close time of day is before the open, so trading stops at the close and resumes at the open
+            await probeSession(mkt, user, 4, 0)
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.activeBid, 0)
+        })
+    })
+    describe('order cooldown', () => {
+        it('accepts orders from the end of the cooldown, even if the cooldown account was pre-funded', async () => {
+            const mkt = await createMarket()
+            const cooldown = 3
+            await updateMarket(mkt, { orderCooldown: cooldown })
+            const user = await createUser(mkt, 100, 0)
+            const userCooldown = (await programAddress([mkt.market.toBuffer(), user.publicKey.toBuffer(), Buffer.from('cooldown')])).pubkey
+
+            // Lamports sent to the cooldown address ahead of time do not block the first order
+            const tx = new anchor.web3.Transaction()
+            tx.add(SystemProgram.transfer({ fromPubkey: provider.wallet.publicKey, toPubkey: userCooldown, lamports: 1000000 }))
+            await provider.sendAndConfirm(tx, [])
+
+            const results = await createResults(40)
+            const placeOrder = async () => {
+                assert.ok(results.length > 0, 'Missed the cooldown boundaries')
+                const order = await limitOrderTx(mkt, 'ask', user, 1, 100, { result: results.pop(), accounts: { userCooldown: userCooldown } })
+                return sendTimed(order.tx, order.signers)
+            }
+            const first = await placeOrder()
+            assert.strictEqual(first.error, null)
+            let last = first.ts
+
+            // Probe one second before and exactly at the end of the cooldown
+            const seen = new Set()
+            while (!seen.has(-1) || !seen.has(0)) {
+                const sent = await placeOrder()
+                const offset = sent.ts - (last + cooldown)
+                assert.strictEqual(sent.error, offset < 0 ? 'OrderTooSoon' : null, 'Cooldown check at ' + sent.ts)
+                if (offset >= -1 && offset <= 0) {
+                    seen.add(offset)
+                }
+                if (sent.error === null) {
+                    last = sent.ts
+                }
+            }
+
+            // After the cooldown
+            await waitForClusterTime(last + cooldown + 1)
+            const after = await placeOrder()
+            assert.strictEqual(after.error, null)
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.ok(state.activeAsk >= 3)
+        })
+    })
+    describe('skipped orders', () => {
+        it('counts each skipped expired order once while filling many live orders behind them', async () => {
+            const mkt = await createMarket()
+            const expiredCount = 30
+            const liveCount = 10
+            const liveMaker = await createUser(mkt, liveCount, 0)
+            for (let i = 0; i < liveCount; i++) {
+                await limitOrder(mkt, 'ask', liveMaker, 1, 200 + i)
+            }
+            // Expired orders at a better price are found again by the search for each fill
+            const expiredMaker = await createUser(mkt, expiredCount, 0)
+            let lastExpiry = 0
+            for (let i = 0; i < expiredCount; i++) {
+                lastExpiry = (await clusterTime()) + 5
+                await limitOrder(mkt, 'ask', expiredMaker, 1, 100, { expires: lastExpiry })
+            }
+            await waitForClusterTime(lastExpiry + 1)
+
+            const taker = await createUser(mkt, 0, 100000)
+            const res = await marketOrder(mkt, 'bid', taker, { quantity: 5 })
+            assert.strictEqual(res.tokensReceived.toNumber(), 5)
+            assert.strictEqual(res.tokensSent.toNumber(), 200 + 201 + 202 + 203 + 204)
+            const res2 = await limitOrder(mkt, 'bid', taker, 5, 209)
+            assert.strictEqual(res2.tokensReceived.toNumber(), 5)
+            assert.strictEqual(res2.postedQuantity.toNumber(), 0)
+
+            // Each order removed up to 10 of the expired orders after matching
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.activeAsk, expiredCount - 20)
+            assert.strictEqual(state.activeBid, 0)
+        })
+        it('fails instead of stopping early when the skip limit is reached', async () => {
+            const mkt = await createMarket({ maxOrders: 256 })
+            // The user's own asks are skipped, one more than the limit is ahead of a live ask
+            const user = await createUser(mkt, 101, 100000)
+            for (let i = 0; i < 101; i++) {
+                await limitOrder(mkt, 'ask', user, 1, 100)
+            }
+            const maker = await createUser(mkt, 1, 0)
+            await limitOrder(mkt, 'ask', maker, 1, 101)
+
+            const bid = await limitOrderTx(mkt, 'bid', user, 1, 101)
+            await expectError(provider.sendAndConfirm(bid.tx, bid.signers), 'TooManySkippedOrders')
+            const market = await marketOrderTx(mkt, 'bid', user, { quantity: 1 })
+            await expectError(provider.sendAndConfirm(market.tx, market.signers), 'TooManySkippedOrders')
+
+            // Another user fills against the first ask
+            const taker = await createUser(mkt, 0, 1000)
+            const res = await marketOrder(mkt, 'bid', taker, { quantity: 1 })
+            assert.strictEqual(res.tokensReceived.toNumber(), 1)
+            assert.strictEqual(res.tokensSent.toNumber(), 100)
+        })
+    })
+    describe('self cross', () => {
+        it('rejects posting an order that crosses the user\'s own resting order', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 100, 100000)
+            await limitOrder(mkt, 'ask', user, 10, 100)
+            for (const price of [100, 101]) {
+                const order = await limitOrderTx(mkt, 'bid', user, 10, price, { rejectSelfCross: true })
+                await expectError(provider.sendAndConfirm(order.tx, order.signers), 'SelfCross')
+            }
+            const res = await limitOrder(mkt, 'bid', user, 10, 99, { rejectSelfCross: true })
+            assert.strictEqual(res.postedQuantity.toNumber(), 10)
+            const order = await limitOrderTx(mkt, 'ask', user, 10, 99, { rejectSelfCross: true })
+            await expectError(provider.sendAndConfirm(order.tx, order.signers), 'SelfCross')
+
+            // Without the check the crossing order is posted and does not fill against the user's own order
+            const crossed = await limitOrder(mkt, 'bid', user, 10, 100)
+            assert.strictEqual(crossed.tokensReceived.toNumber(), 0)
+            assert.strictEqual(crossed.postedQuantity.toNumber(), 10)
+            const top = await topOfBook(mkt)
+            assert.strictEqual(top.bestBidPrice.toNumber(), 100)
+            assert.strictEqual(top.bestAskPrice.toNumber(), 100)
+        })
+    })
+    describe('route to user vault', () => {
+        it('credits the released tokens to the user vault without a token transfer', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 10, 10000)
+            const ask = await limitOrder(mkt, 'ask', user, 10, 100)
+            const bid = await limitOrder(mkt, 'bid', user, 5, 90)
+            const vault = (await programAddress([mkt.market.toBuffer(), user.publicKey.toBuffer()])).pubkey
+            const cancelToVault = (side, orderId) => aquadex.methods.cancelOrderToVault(side === 'bid' ? 0 : 1, orderId).accounts({
+                market: mkt.market,
+                state: mkt.state,
+                owner: user.publicKey,
+                orders: mkt.orders,
+                vault: vault,
+                systemProgram: SystemProgram.programId,
+            }).signers([user.keypair]).rpc()
+
+            // The vault is created by the first cancellation
+            await cancelToVault('ask', ask.orderId)
+            await cancelToVault('bid', bid.orderId)
+            const userVault = await aquadex.account.userVault.fetch(vault)
+            assert.strictEqual(userVault.mktTokens.toNumber(), 10)
+            assert.strictEqual(userVault.prcTokens.toNumber(), 450)
+            assert.strictEqual(await tokenBalance(user.mktToken), 0)
+            assert.strictEqual(await tokenBalance(mkt.mktVault), 10)
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.mktUserVaultBalance.toNumber(), 10)
+            assert.strictEqual(state.prcUserVaultBalance.toNumber(), 450)
+            assert.strictEqual(state.activeAsk + state.activeBid, 0)
+        })
+        it('credits settled tokens to the user vault and removes the settlement log entry', async () => {
+            const mkt = await createMarket()
+            const maker = await createUser(mkt, 10, 0)
+            const taker = await createUser(mkt, 0, 1000)
+            await limitOrder(mkt, 'ask', maker, 10, 100)
+            await marketOrder(mkt, 'bid', taker, { quantity: 10 })
+            const vault = (await programAddress([mkt.market.toBuffer(), maker.publicKey.toBuffer()])).pubkey
+            const before = await aquadex.account.marketState.fetch(mkt.state)
+            await aquadex.methods.withdrawToVault(true, true).accounts({
+                market: mkt.market,
+                state: mkt.state,
+                owner: maker.publicKey,
+                settle: before.settleA,
+                settlePrev: maker.publicKey,
+                settleNext: maker.publicKey,
+                vault: vault,
+                systemProgram: SystemProgram.programId,
+            }).signers([maker.keypair]).rpc()
+
+            const userVault = await aquadex.account.userVault.fetch(vault)
+            assert.strictEqual(userVault.prcTokens.toNumber(), 1000)
+            assert.strictEqual(await tokenBalance(maker.prcToken), 0)
+            const after = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(after.prcLogBalance.toNumber(), before.prcLogBalance.toNumber() - 1000)
+            assert.strictEqual(after.prcUserVaultBalance.toNumber(), 1000)
+        })
+    })
+    describe('migrate pricing vault', () => {
+        it('moves the pricing vault balance to another token account held by the market agent', async () => {
+            const mkt = await createMarket()
+            const maker = await createUser(mkt, 10, 0)
+            const taker = await createUser(mkt, 0, 1000)
+            await limitOrder(mkt, 'ask', maker, 10, 100)
+            await marketOrder(mkt, 'bid', taker, { quantity: 10 })
+            assert.strictEqual(await tokenBalance(mkt.prcVault), 1000)
+            await updateMarket(mkt, { active: false })
+
+            const migrate = (newPrcVault) => aquadex.methods.migratePricingVault().accounts({
+                market: mkt.market,
+                state: mkt.state,
+                agent: mkt.agent,
+                manager: provider.wallet.publicKey,
+                stops: provider.wallet.publicKey,
+                prcVault: mkt.prcVault,
+                newPrcVault: newPrcVault,
+                splTokenProg: TOKEN_PROGRAM_ID,
+            }).rpc()
+
+            // The new vault must be a pricing token account owned by the agent
+            const userAccount = await createAccount(provider.connection, payer, mkt.prcMint, maker.publicKey, Keypair.generate())
+            await expectError(migrate(userAccount), 'InvalidAccount')
+            const mktAccount = await createAccount(provider.connection, payer, mkt.mktMint, mkt.agent, Keypair.generate())
+            await expectError(migrate(mktAccount), 'InvalidAccount')
+            await expectError(migrate(mkt.prcVault), 'InvalidParameters')
+
+            const newVault = await createAccount(provider.connection, payer, mkt.prcMint, mkt.agent, Keypair.generate())
+            await migrate(newVault)
+            assert.strictEqual(await tokenBalance(mkt.prcVault), 0)
+            assert.strictEqual(await tokenBalance(newVault), 1000)
+            const market = await aquadex.account.market.fetch(mkt.market)
+            assert.ok(market.prcVault.equals(newVault))
+        })
+    })
+    describe('bid by quote amount', () => {
+        it('keeps the cost and the taker fee within the quote amount', async () => {
+            // 1% taker fee
+            const mkt = await createMarket({ takerFee: 100000 })
+            const maker = await createUser(mkt, 100, 0)
+            await limitOrder(mkt, 'ask', maker, 100, 100)
+            const user = await createUser(mkt, 0, 10000)
+            const res = await limitOrder(mkt, 'bid', user, 1000, 100, { byQuote: true })
+            // 990 tokens net of the fee buys 9 tokens at 100, the tokens sent include the fee
+            assert.strictEqual(res.tokensReceived.toNumber(), 9)
+            assert.strictEqual(res.tokensFee.toNumber(), 9)
+            assert.strictEqual(res.tokensSent.toNumber(), 909)
+        })
+    })
+    describe('eviction', () => {
+        it('evicts only when the price improves on the evicted order by at least the minimum', async () => {
+            const mkt = await createMarket({ maxOrders: 2, evictionEnabled: true, evictionMinImprovement: 5 })
+            const maker = await createUser(mkt, 40, 10000)
+            const bidIds = []
+            const askIds = []
+            for (const price of [100, 101]) {
+                bidIds.push((await limitOrder(mkt, 'bid', maker, 10, price)).orderId)
+            }
+            for (const price of [200, 199]) {
+                askIds.push((await limitOrder(mkt, 'ask', maker, 10, price)).orderId)
+            }
+
+            // One below the margin over the lowest priority order (bid at 100, ask at 200)
+            const user = await createUser(mkt, 20, 10000)
+            const bid = await limitOrderTx(mkt, 'bid', user, 10, 104)
+            await expectError(provider.sendAndConfirm(bid.tx, bid.signers), 'OrderbookFull')
+            const ask = await limitOrderTx(mkt, 'ask', user, 10, 196)
+            await expectError(provider.sendAndConfirm(ask.tx, ask.signers), 'OrderbookFull')
+
+            // Exactly at the margin
+            await limitOrder(mkt, 'bid', user, 10, 105)
+            await limitOrder(mkt, 'ask', user, 10, 195)
+            assert.strictEqual(await orderExists(mkt, bidIds[0]), false)
+            assert.strictEqual(await orderExists(mkt, askIds[0]), false)
+            const depth = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depth.bids), [[105, 10, 1], [101, 10, 1]])
+            assert.deepStrictEqual(depthLevels(depth.asks), [[195, 10, 1], [199, 10, 1]])
+        })
+
+        it('rejects an order at the same price as a full book of identical orders', async () => {
+            const mkt = await createMarket({ maxOrders: 3, evictionEnabled: true })
+            const maker = await createUser(mkt, 30, 10000)
+            for (let i = 0; i < 3; i++) {
+                await limitOrder(mkt, 'bid', maker, 10, 100)
+                await limitOrder(mkt, 'ask', maker, 10, 200)
+            }
+            const user = await createUser(mkt, 10, 10000)
+            const bid = await limitOrderTx(mkt, 'bid', user, 10, 100)
+            await expectError(provider.sendAndConfirm(bid.tx, bid.signers), 'OrderbookFull')
+            const ask = await limitOrderTx(mkt, 'ask', user, 10, 200)
+            await expectError(provider.sendAndConfirm(ask.tx, ask.signers), 'OrderbookFull')
+            const depth = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(depth.bids), [[100, 30, 3]])
+            assert.deepStrictEqual(depthLevels(depth.asks), [[200, 30, 3]])
+
+            // A better price still evicts the lowest priority order
+            await limitOrder(mkt, 'bid', user, 10, 101)
+            const after = await orderbookDepth(mkt)
+            assert.deepStrictEqual(depthLevels(after.bids), [[101, 10, 1], [100, 20, 2]])
+        })
+    })
+    describe('empty orderbook', () => {
+        it('returns a zeroed result for market orders with no liquidity', async () => {
+            const mkt = await createMarket()
+            const user = await createUser(mkt, 100, 10000)
+            for (const side of ['bid', 'ask']) {
+                const res = await marketOrder(mkt, side, user, { quantity: 10 })
+                assert.strictEqual(res.tokensReceived.toNumber(), 0)
+                assert.strictEqual(res.tokensSent.toNumber(), 0)
+                assert.strictEqual(res.tokensFee.toNumber(), 0)
+                assert.strictEqual(res.postedQuantity.toNumber(), 0)
+                assert.strictEqual(res.orderId.toString(), '0')
+                const byPrice = await marketOrder(mkt, side, user, { netPrice: 1000 })
+                assert.strictEqual(byPrice.tokensReceived.toNumber(), 0)
+                assert.strictEqual(byPrice.tokensSent.toNumber(), 0)
+
+                const order = await marketOrderTx(mkt, side, user, { quantity: 10, fill: true })
+                await expectError(provider.sendAndConfirm(order.tx, order.signers), 'OrderNotFilled')
+            }
+            assert.strictEqual(await tokenBalance(user.mktToken), 100)
+            assert.strictEqual(await tokenBalance(user.prcToken), 10000)
+            assert.strictEqual(await tokenBalance(mkt.mktVault), 0)
+            assert.strictEqual(await tokenBalance(mkt.prcVault), 0)
+        })
+    })
+    describe('security token preview', () => {
+        // The security token program and mint are loaded at genesis from "tests/deps" (see Anchor.toml and tests/deps/fetch.sh)
+        it('fails the preview of an ask larger than the security token balance', async () => {
+            const mkt = await createMarket({ mktSecurityToken: SECURITY_TOKEN_MINT, mktDecimals: 6 })
+            const user = await createUser(mkt, 0, 0)
+            const remainingAccounts = [
+                { pubkey: SECURITY_TOKEN_PK, isWritable: false, isSigner: false },
+                { pubkey: SECURITY_TOKEN_PK, isWritable: false, isSigner: false },  // From: User auth
+                { pubkey: SECURITY_TOKEN_PK, isWritable: false, isSigner: false },  // To: Market auth
+            ]
+
+            // The new account holds no security tokens
+            const order = await limitOrderTx(mkt, 'ask', user, 1000000, 1, { preview: true, remainingAccounts: remainingAccounts })
+            await expectError(provider.sendAndConfirm(order.tx, order.signers), 'InsufficientTokens')
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.activeAsk, 0)
+            assert.strictEqual(state.actionCounter.toString(), '0')
+        })
+    })
+    describe('client order ids', () => {
+        let mkt = null
+        const clientOrders = async (user) => ({
+            userClientOrders: (await programAddress([mkt.market.toBuffer(), user.publicKey.toBuffer(), Buffer.from('client_orders')])).pubkey,
+        })
+
+        it('rejects an order reusing one of the owner\'s recent client order ids', async () => {
+            mkt = await createMarket()
+            const user = await createUser(mkt, 0, 10000)
+            const other = await createUser(mkt, 0, 10000)
+            await limitOrder(mkt, 'bid', user, 1, 100, { clientOrderId: 7, accounts: await clientOrders(user) })
+            const order = await limitOrderTx(mkt, 'bid', user, 1, 100, { clientOrderId: 7, accounts: await clientOrders(user) })
+            await expectError(provider.sendAndConfirm(order.tx, order.signers), 'DuplicateOrder')
+            await limitOrder(mkt, 'bid', user, 1, 100, { clientOrderId: 8, accounts: await clientOrders(user) })
+            await limitOrder(mkt, 'bid', other, 1, 100, { clientOrderId: 7, accounts: await clientOrders(other) })
+
+            const state = await aquadex.account.marketState.fetch(mkt.state)
+            assert.strictEqual(state.activeBid, 3)
+        })
+
+        it('writes results with a client order id into result accounts of the previous size', async () => {
+            // Result accounts created for the TradeResult layout before client order ids (8 byte discriminator + 48 bytes)
+            mkt = await createMarket()
+            const maker = await createUser(mkt, 10, 0)
+            const user = await createUser(mkt, 0, 10000)
+            const result = await createResult(56)
+            await limitOrder(mkt, 'ask', maker, 5, 100)
+            await limitOrder(mkt, 'bid', user, 2, 100, { clientOrderId: 11, result: result, accounts: await clientOrders(user) })
+            const info = await provider.connection.getAccountInfo(result.publicKey)
+            assert.strictEqual(info.data.length, 56)
+            const first = aquadex.coder.accounts.decode('TradeResult', info.data)
+            assert.strictEqual(first.tokensReceived.toNumber(), 2)
+            assert.strictEqual(first.tokensSent.toNumber(), 200)
+            assert.strictEqual(first.postedQuantity.toNumber(), 0)
+
+            // The account already holds a TradeResult and is reused for the next order
+            const res = await limitOrder(mkt, 'bid', user, 4, 100, { clientOrderId: 12, result: result, accounts: await clientOrders(user) })
+            assert.strictEqual(res.tokensReceived.toNumber(), 3)
+            assert.strictEqual(res.postedQuantity.toNumber(), 1)
+            assert.ok(!res.orderId.isZero())
+        })
+    })
+    describe('randomized trading', () => {
+        it('keeps order counts and vault balances consistent with the orderbook', async () => {
+            // A small book with eviction enabled so that full sides evict, and some orders expire a few seconds after they are posted
+            const maxOrders = 4
+            const mkt = await createMarket({ maxOrders: maxOrders, evictionEnabled: true })
+            const random = seededRandom(2076)
+            const pick = (list) => list[Math.floor(random() * list.length)]
+            const mktSupply = 1000
+            const prcSupply = 1000000
+            const users = []
+            for (let i = 0; i < 3; i++) {
+                users.push(await createUser(mkt, mktSupply, prcSupply))
+            }
+            let resting = []
+            let evictions = 0
+            let expirations = 0
+            // Orders may have been filled, evicted or expired since they were posted
+            const openOrders = async () => {
+                const open = []
+                for (const order of resting) {
+                    try {
+                        await orderInfo(mkt, order.side, order.orderId)
+                        open.push(order)
+                    } catch (err) {
+                        assert.strictEqual(errorName(err), 'OrderNotFound')
+                    }
+                }
+                return open
+            }
+            for (let step = 0; step < 60; step++) {
+                const user = pick(users)
+                const side = pick(['bid', 'ask'])
+                const qty = 1 + Math.floor(random() * 5)
+                const price = (side === 'bid' ? 93 : 98) + Math.floor(random() * 10)
+                const expiring = random() < 0.3
+                const action = random()
+                if (action < 0.6) {
+                    const before = await aquadex.account.marketState.fetch(mkt.state)
+                    const full = (side === 'bid' ? before.activeBid : before.activeAsk) === maxOrders
+                    const expires = expiring ? (await clusterTime()) + 1 + Math.floor(random() * 3) : 0
+                    const order = await limitOrderTx(mkt, side, user, qty, price, { expires: expires })
+                    try {
+                        await provider.sendAndConfirm(order.tx, order.signers)
+                    } catch (err) {
+                        // A full side only evicts for a better price
+                        assert.strictEqual(errorName(err), 'OrderbookFull')
+                        continue
+                    }
+                    const res = await aquadex.account.tradeResult.fetch(order.result.publicKey)
+                    if (res.postedQuantity.toNumber() > 0) {
+                        resting.push({ side: side, user: user, orderId: res.orderId, expires: expires })
+                        if (full) {
+                            evictions++
+                        }
+                    }
+                } else if (action < 0.7) {
+                    resting = await openOrders()
+                    if (resting.length > 0) {
+                        const order = pick(resting)
+                        await cancelOrder(mkt, order.side, order.user, order.orderId)
+                        resting = resting.filter(item => item !== order)
+                    }
+                } else if (action < 0.85) {
+                    // Expired orders stay on the orderbook (and in the counts) until they are removed
+                    resting = await openOrders()
+                    const now = await clusterTime()
+                    const expired = resting.filter(item => item.expires !== 0 && item.expires < now)
+                    if (expired.length > 0) {
+                        const order = pick(expired)
+                        await provider.sendAndConfirm(await expireOrder(mkt, order.side, user, order.orderId), [user.keypair])
+                        await expectError(orderInfo(mkt, order.side, order.orderId), 'OrderNotFound')
+                        resting = resting.filter(item => item !== order)
+                        expirations++
+                    }
+                } else {
+                    await marketOrder(mkt, side, user, { quantity: qty })
+                }
+
+                const counts = await verifyOrderCounts(mkt)
+                const depth = await orderbookDepth(mkt)
+                const state = await aquadex.account.marketState.fetch(mkt.state)
+                const levelOrders = (levels) => levels.reduce((total, level) => total + level.orderCount, 0)
+                assert.ok(counts.consistent, 'order counts at step ' + step)
+                assert.strictEqual(counts.bidOrders, state.activeBid)
+                assert.strictEqual(counts.askOrders, state.activeAsk)
+                assert.ok(state.activeBid <= maxOrders && state.activeAsk <= maxOrders)
+
+                // The displayed depth skips expired orders that have not been removed yet
+                assert.ok(state.activeBid >= levelOrders(depth.bids))
+                assert.ok(state.activeAsk >= levelOrders(depth.asks))
+
+                // The vaults hold exactly the tracked balances, which cover the tokens committed to the orderbook
+                const solvency = await verifySolvency(mkt)
+                assert.ok(solvency.solvent, 'solvent at step ' + step)
+                assert.strictEqual(solvency.mktDelta.toString(), '0')
+                assert.strictEqual(solvency.prcDelta.toString(), '0')
+                const notional = await bookNotional(mkt)
+                assert.ok(!notional.more)
+                assert.ok(state.mktVaultBalance.gte(notional.askNotional))
+                assert.ok(state.prcVaultBalance.sub(state.prcFeesBalance).gte(notional.bidNotional))
+
+                // Tokens are only moved between the users and the vaults
+                let mktTotal = await tokenBalance(mkt.mktVault)
+                let prcTotal = await tokenBalance(mkt.prcVault)
+                for (const item of users) {
+                    mktTotal += await tokenBalance(item.mktToken)
+                    prcTotal += await tokenBalance(item.prcToken)
+                }
+                assert.strictEqual(mktTotal, mktSupply * users.length)
+                assert.strictEqual(prcTotal, prcSupply * users.length)
+            }
 
-  it('Is initialized!', async () => {
-    // Add your test here.
-    const program = anchor.workspace.AquaDex;
-    const tx = await program.rpc.initialize();
-    console.log("Your transaction signature", tx);
-  });
-});
+            // Remove the remaining expired orders, after which the displayed depth matches the counts exactly
+            resting = (await openOrders()).filter(item => item.expires !== 0)
+            if (resting.length > 0) {
+                await waitForClusterTime(Math.max(...resting.map(item => item.expires)) + 1)
+            }
+            for (const order of resting) {
+                await provider.sendAndConfirm(await expireOrder(mkt, order.side, users[0], order.orderId), [users[0].keypair])
+                expirations++
+            }
+            const counts = await verifyOrderCounts(mkt)
+            const depth = await orderbookDepth(mkt)
+            assert.ok(counts.consistent)
+            assert.strictEqual(counts.bidOrders, depth.bids.reduce((total, level) => total + level.orderCount, 0))
+            assert.strictEqual(counts.askOrders, depth.asks.reduce((total, level) => total + level.orderCount, 0))
+            assert.ok(evictions > 0, 'no orders were evicted')
+            assert.ok(expirations > 0, 'no orders were expired')
+        })
+    })
+})
@@ -0,0 +1,6 @@
+#!/bin/bash
+
+# Security token program and mint used by the tests, loaded at genesis by "anchor test" (see Anchor.toml)
+cd $(dirname $0)
+solana program dump --url devnet 8JxtmFxuhmgoEFmBeZAqBVouj6DDQBwybpJnpqcYUU8M security_token.so
+solana account --url devnet EXnXikUxX8gE8wd2cvTanuycdDWki7yf2cBZhZvM2RU5 --output json --output-file security_token_mint.json